@@ -0,0 +1,937 @@
+//! Routing of the requests on sharded collections to the nodes holding their shards.
+//!
+//! Searches are sent to all shards at once and the knbn nearest neighbours of their results
+//! are kept. A shard that fails or does not answer within the timeout is left out: the
+//! result is then flagged as partial, with the ids of the missing shards. Insertions and
+//! deletions must reach all the shards they concern.
+//!
+//! The shard map is saved to a CBOR file after each change, so that a coordinator reopened
+//! from it routes as before. The shards of a lost node are never created again empty: they
+//! stay missing until the node comes back, or until a replica of their collection on a live
+//! node, see replication, is promoted.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, RwLock};
+use tonic::transport::{Channel, Endpoint};
+use tonic::Status;
+
+use crate::cluster::replication::Phase;
+use crate::cluster::shard::{self, Shard, ShardMap};
+use crate::interfaces::grpc::vector_service::{
+    admin_service_client::AdminServiceClient, replication_service_client::ReplicationServiceClient,
+    vector_service_client::VectorServiceClient, CreateCollectionRequest, DeleteRequest,
+    DeleteResponse, DropCollectionRequest, FollowRequest, FreezeRequest, InsertRequest,
+    NamedVectors, Neighbours, SearchRequest, SearchResult, UnfollowRequest,
+};
+
+/// time after which a shard that has not answered a search is left out of its results
+pub const DEFAULT_SHARD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// time the new node of a moving shard has to catch up with the writes of its former node
+pub const MOVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// interval between two checks of the progress of a moving shard
+const CATCH_UP_POLL: Duration = Duration::from_millis(50);
+
+/// name of the file of data_dir holding the shard map
+pub const SHARDS_FILE: &str = "shards.cbor";
+
+/// a shard moved from a node to another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardMove {
+    pub shard: u32,
+    pub from: String,
+    pub to: String,
+}
+
+pub struct Coordinator {
+    shards: RwLock<ShardMap>,
+    timeout: Duration,
+    /// channels to the nodes by address, connected on first use
+    channels: Mutex<HashMap<String, Channel>>,
+    /// held by the writes, and alone by the cutover of a shard move
+    writes: tokio::sync::RwLock<()>,
+    /// file the shard map is saved to, None to keep it in memory only. Locked by the
+    /// saves, so that the last one writes the latest map.
+    path: Option<Mutex<PathBuf>>,
+}
+
+impl Default for Coordinator {
+    fn default() -> Self {
+        Coordinator::new(DEFAULT_SHARD_TIMEOUT)
+    }
+}
+
+impl Coordinator {
+    pub fn new(timeout: Duration) -> Self {
+        Coordinator {
+            shards: RwLock::new(ShardMap::default()),
+            timeout,
+            channels: Mutex::new(HashMap::new()),
+            writes: tokio::sync::RwLock::new(()),
+            path: None,
+        }
+    }
+
+    /// a coordinator saving its shard map to path, starting from the one saved there if any
+    pub fn open(timeout: Duration, path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut coordinator = Coordinator::new(timeout);
+        if path.is_file() {
+            let shards: ShardMap = serde_cbor::from_reader(BufReader::new(File::open(path)?))?;
+            coordinator.shards = RwLock::new(shards);
+        }
+        coordinator.path = Some(Mutex::new(path.to_path_buf()));
+        Ok(coordinator)
+    }
+
+    /// writes the shard map to the file of the coordinator, if any
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path.lock(),
+            None => return,
+        };
+        let shards = self.shards.read().clone();
+        let written = (|| -> Result<(), Box<dyn Error>> {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            // written aside then renamed, so that the map is never seen partly written
+            let partial = path.with_extension("partial");
+            let mut writer = BufWriter::new(File::create(&partial)?);
+            serde_cbor::to_writer(&mut writer, &shards)?;
+            writer.flush()?;
+            drop(writer);
+            std::fs::rename(&partial, &*path)?;
+            Ok(())
+        })();
+        if let Err(e) = written {
+            log::warn!("shard map not saved to {}: {}", path.display(), e);
+        }
+    }
+
+    /// the shards of collection, None when it is not sharded
+    pub fn shards(&self, collection: &str) -> Option<Vec<Shard>> {
+        self.shards.read().shards(collection).map(<[Shard]>::to_vec)
+    }
+
+    pub fn shard_map(&self) -> ShardMap {
+        self.shards.read().clone()
+    }
+
+    fn channel(&self, address: &str) -> Result<Channel, Box<dyn Error>> {
+        let mut channels = self.channels.lock();
+        if let Some(channel) = channels.get(address) {
+            return Ok(channel.clone());
+        }
+        let channel = Endpoint::from_shared(address.to_string())
+            .map_err(|e| format!("invalid node address {}: {}", address, e))?
+            .connect_lazy();
+        channels.insert(address.to_string(), channel.clone());
+        Ok(channel)
+    }
+
+    /// creates the collection of each shard on its node, request being the creation of the
+    /// sharded collection, and records the shards once all are created. When a shard cannot
+    /// be created, those created before it are dropped.
+    pub async fn create_collection(
+        &self,
+        request: CreateCollectionRequest,
+        addresses: &[String],
+    ) -> Result<(), Status> {
+        let nb_shard = match request.shard_count {
+            0 => addresses.len() as u32,
+            nb_shard => nb_shard,
+        };
+        let shards = ShardMap::default()
+            .assign(&request.name, nb_shard, addresses)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?
+            .to_vec();
+        if self.shards(&request.name).is_some() {
+            return Err(Status::already_exists(format!(
+                "collection {} is already sharded",
+                request.name
+            )));
+        }
+        for (rank, shard) in shards.iter().enumerate() {
+            let shard_request = CreateCollectionRequest {
+                name: shard.collection.clone(),
+                shard_addresses: Vec::new(),
+                shard_count: 0,
+                ..request.clone()
+            };
+            let created = match self.channel(&shard.address).map_err(invalid_address) {
+                Ok(channel) => AdminServiceClient::new(channel)
+                    .create_collection(shard_request)
+                    .await
+                    .map(|_| ()),
+                Err(status) => Err(status),
+            };
+            if let Err(e) = created {
+                self.drop_shards(&request.name, &shards[..rank]).await;
+                return Err(Status::unavailable(format!(
+                    "shard {} at {}: {}",
+                    shard.id,
+                    shard.address,
+                    e.message()
+                )));
+            }
+        }
+        let assigned = self
+            .shards
+            .write()
+            .assign(&request.name, nb_shard, addresses)
+            .map(|_| ())
+            // the error is not Send, it must not be held across the drop
+            .map_err(|e| Status::already_exists(e.to_string()));
+        if let Err(status) = assigned {
+            self.drop_shards(&request.name, &shards).await;
+            return Err(status);
+        }
+        self.save();
+        Ok(())
+    }
+
+    /// moves the shards of collection with their points to their owners among nodes, see
+    /// shard::owner. The shards of nodes which are not among nodes are left where they are,
+    /// see promote. Returns the moves made.
+    pub async fn rebalance(
+        &self,
+        collection: &str,
+        nodes: &[String],
+    ) -> Result<Vec<ShardMove>, Status> {
+        let mut moves = Vec::new();
+        for shard in self
+            .shards_of(collection)
+            .map_err(|e| Status::not_found(e.to_string()))?
+        {
+            let owner = shard::owner(&shard.collection, nodes)
+                .ok_or_else(|| Status::failed_precondition("no node to place the shards on"))?;
+            if owner == shard.address || !nodes.contains(&shard.address) {
+                continue;
+            }
+            self.move_shard(collection, shard.id, &owner).await?;
+            moves.push(ShardMove {
+                shard: shard.id,
+                from: shard.address,
+                to: owner,
+            });
+        }
+        Ok(moves)
+    }
+
+    /// moves a shard of collection and its points to the node at address while it keeps
+    /// serving. The new node follows the collection of the shard until it has caught up,
+    /// then the writes are held, the collection of the shard is frozen on its former node so
+    /// that no other client writes to it, the new node applies the last writes and the shard
+    /// map switches to it. The collection of the shard is dropped from its former node once
+    /// the searches sent there are over.
+    pub async fn move_shard(
+        &self,
+        collection: &str,
+        shard_id: u32,
+        address: &str,
+    ) -> Result<(), Status> {
+        let shard = self
+            .shards_of(collection)
+            .map_err(|e| Status::not_found(e.to_string()))?
+            .into_iter()
+            .find(|shard| shard.id == shard_id)
+            .ok_or_else(|| {
+                Status::not_found(format!(
+                    "collection {} has no shard {}",
+                    collection, shard_id
+                ))
+            })?;
+        if shard.address == address {
+            return Ok(());
+        }
+        let mut target =
+            ReplicationServiceClient::new(self.channel(address).map_err(invalid_address)?);
+        let follow = FollowRequest {
+            collection: shard.collection.clone(),
+            leader: shard.address.clone(),
+        };
+        target.follow(follow).await?;
+        let unfollow = UnfollowRequest {
+            collection: shard.collection.clone(),
+        };
+        let mut source =
+            AdminServiceClient::new(self.channel(&shard.address).map_err(invalid_address)?);
+        let freeze = |frozen| FreezeRequest {
+            collection: shard.collection.clone(),
+            frozen,
+        };
+        let moved = async {
+            self.wait_caught_up(&mut target, &shard).await?;
+            let _writes = self.writes.write().await;
+            source.clone().freeze(freeze(true)).await?;
+            // the writes made since are the last ones of the former node
+            self.wait_caught_up(&mut target, &shard).await?;
+            target.unfollow(unfollow.clone()).await?;
+            self.shards
+                .write()
+                .move_shard(collection, shard_id, address);
+            self.save();
+            Ok::<(), Status>(())
+        }
+        .await;
+        if let Err(status) = moved {
+            // the shard stays on its node, the copy is left behind
+            let _ = target.unfollow(unfollow).await;
+            if let Err(e) = source.freeze(freeze(false)).await {
+                log::warn!(
+                    "shard {} of {} left frozen on {}: {}",
+                    shard_id,
+                    collection,
+                    shard.address,
+                    e.message()
+                );
+            }
+            return Err(Status::aborted(format!(
+                "shard {} of {} not moved to {}: {}",
+                shard_id,
+                collection,
+                address,
+                status.message()
+            )));
+        }
+        tokio::time::sleep(self.timeout).await;
+        let request = DropCollectionRequest {
+            name: shard.collection.clone(),
+        };
+        if let Err(e) = source.drop_collection(request).await {
+            log::warn!(
+                "moved shard {} of {} not dropped from {}: {}",
+                shard_id,
+                collection,
+                shard.address,
+                e.message()
+            );
+        }
+        Ok(())
+    }
+
+    /// waits until the node of target streams the writes of the collection of shard and
+    /// has applied all those its node made so far
+    async fn wait_caught_up(
+        &self,
+        target: &mut ReplicationServiceClient<Channel>,
+        shard: &Shard,
+    ) -> Result<(), Status> {
+        let mut source =
+            AdminServiceClient::new(self.channel(&shard.address).map_err(invalid_address)?);
+        let last_seq = source
+            .list_collections(())
+            .await?
+            .into_inner()
+            .collections
+            .into_iter()
+            .find(|info| info.name == shard.collection)
+            .map(|info| info.last_seq)
+            .ok_or_else(|| Status::not_found(format!("no collection {}", shard.collection)))?;
+        let start = Instant::now();
+        loop {
+            let replicas = target.list_replicas(()).await?.into_inner().replicas;
+            let replica = replicas
+                .iter()
+                .find(|replica| replica.collection == shard.collection)
+                .ok_or_else(|| Status::aborted("the new node stopped following the shard"))?;
+            if replica.phase == Phase::Streaming.to_string() && replica.applied_seq >= last_seq {
+                return Ok(());
+            }
+            if start.elapsed() > MOVE_TIMEOUT {
+                return Err(Status::deadline_exceeded(format!(
+                    "the new node applied {} writes of {}",
+                    replica.applied_seq, last_seq
+                )));
+            }
+            tokio::time::sleep(CATCH_UP_POLL).await;
+        }
+    }
+
+    /// hands a shard of collection whose node is lost over to the node among nodes whose
+    /// replica of its collection, following the lost node, applied most of its writes. The
+    /// replica stops following and takes the writes of the shard. Returns the address of
+    /// the node, None when no node holds a copy of the shard.
+    pub async fn promote(
+        &self,
+        collection: &str,
+        shard_id: u32,
+        nodes: &[String],
+    ) -> Result<Option<String>, Status> {
+        let shard = self
+            .shards_of(collection)
+            .map_err(|e| Status::not_found(e.to_string()))?
+            .into_iter()
+            .find(|shard| shard.id == shard_id)
+            .ok_or_else(|| {
+                Status::not_found(format!(
+                    "collection {} has no shard {}",
+                    collection, shard_id
+                ))
+            })?;
+        let mut best: Option<(String, u64)> = None;
+        for address in nodes.iter().filter(|address| **address != shard.address) {
+            let mut client =
+                ReplicationServiceClient::new(self.channel(address).map_err(invalid_address)?);
+            let replicas = match client.list_replicas(()).await {
+                Ok(response) => response.into_inner().replicas,
+                Err(status) => {
+                    log::debug!("replicas of {} not listed: {}", address, status.message());
+                    continue;
+                }
+            };
+            let applied_seq = replicas
+                .iter()
+                .find(|replica| {
+                    replica.collection == shard.collection
+                        && replica.leader == shard.address
+                        && replica.copied
+                })
+                .map(|replica| replica.applied_seq);
+            if let Some(applied_seq) = applied_seq {
+                if best
+                    .as_ref()
+                    .is_none_or(|(_, best_seq)| applied_seq > *best_seq)
+                {
+                    best = Some((address.clone(), applied_seq));
+                }
+            }
+        }
+        let address = match best {
+            Some((address, _)) => address,
+            None => return Ok(None),
+        };
+        let mut client =
+            ReplicationServiceClient::new(self.channel(&address).map_err(invalid_address)?);
+        let unfollow = UnfollowRequest {
+            collection: shard.collection.clone(),
+        };
+        client.unfollow(unfollow).await.map_err(|e| {
+            Status::unavailable(format!(
+                "replica of shard {} at {}: {}",
+                shard_id,
+                address,
+                e.message()
+            ))
+        })?;
+        // the writes read the shards under the gate
+        let _writes = self.writes.write().await;
+        if !self
+            .shards
+            .write()
+            .move_shard(collection, shard_id, &address)
+        {
+            return Err(Status::not_found(format!(
+                "collection {} has no shard {}",
+                collection, shard_id
+            )));
+        }
+        self.save();
+        Ok(Some(address))
+    }
+
+    /// forgets the shards of collection and drops their collections, those of nodes that
+    /// cannot be reached are left behind
+    pub async fn drop_collection(&self, collection: &str) -> Result<(), Status> {
+        let shards = self.shards.write().remove(collection).ok_or_else(|| {
+            Status::not_found(format!("collection {} is not sharded", collection))
+        })?;
+        self.save();
+        self.drop_shards(collection, &shards).await;
+        Ok(())
+    }
+
+    /// drops the collections of shards of collection, those of nodes that cannot be reached
+    /// are left behind
+    async fn drop_shards(&self, collection: &str, shards: &[Shard]) {
+        for shard in shards {
+            let request = DropCollectionRequest {
+                name: shard.collection.clone(),
+            };
+            let dropped = match self.channel(&shard.address).map_err(invalid_address) {
+                Ok(channel) => AdminServiceClient::new(channel)
+                    .drop_collection(request)
+                    .await
+                    .map(|_| ()),
+                Err(status) => Err(status),
+            };
+            if let Err(e) = dropped {
+                log::warn!(
+                    "shard {} of {} not dropped: {}",
+                    shard.id,
+                    collection,
+                    e.message()
+                );
+            }
+        }
+    }
+
+    /// the shards of collection, an error when it is not sharded
+    fn shards_of(&self, collection: &str) -> Result<Vec<Shard>, Box<dyn Error>> {
+        self.shards(collection)
+            .ok_or_else(|| format!("collection {} is not sharded", collection).into())
+    }
+
+    /// splits the points of request by shard and inserts them, failing if a shard fails
+    pub async fn insert(&self, request: InsertRequest) -> Result<(), Status> {
+        // the shards are read under the gate, so that no write reaches a shard that moved
+        let _writes = self.writes.read().await;
+        let shards = &self
+            .shards_of(&request.collection)
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        let mut parts: Vec<InsertRequest> = shards
+            .iter()
+            .map(|shard| InsertRequest {
+                collection: shard.collection.clone(),
+                named_vectors: request
+                    .named_vectors
+                    .iter()
+                    .map(|named| NamedVectors {
+                        name: named.name.clone(),
+                        data: Vec::new(),
+                    })
+                    .collect(),
+                ..InsertRequest::default()
+            })
+            .collect();
+        for (rank, d_id) in request.ids.iter().enumerate() {
+            let part = &mut parts[ShardMap::shard_of(shards, *d_id as usize).id as usize];
+            part.ids.push(*d_id);
+            if let Some(v) = request.data.get(rank) {
+                part.data.push(v.clone());
+            }
+            if let Some(payload) = request.payloads.get(rank) {
+                part.payloads.push(payload.clone());
+            }
+            for (named, part_named) in request
+                .named_vectors
+                .iter()
+                .zip(part.named_vectors.iter_mut())
+            {
+                if let Some(v) = named.data.get(rank) {
+                    part_named.data.push(v.clone());
+                }
+            }
+        }
+        let mut tasks = Vec::new();
+        for (shard, part) in shards.iter().zip(parts) {
+            if part.ids.is_empty() {
+                continue;
+            }
+            let mut client =
+                VectorServiceClient::new(self.channel(&shard.address).map_err(invalid_address)?);
+            let shard = shard.clone();
+            tasks.push(tokio::spawn(
+                async move { (shard, client.insert(part).await) },
+            ));
+        }
+        for task in tasks {
+            let (shard, result) = task.await.map_err(|e| Status::internal(e.to_string()))?;
+            result.map_err(|e| {
+                Status::unavailable(format!(
+                    "shard {} at {}: {}",
+                    shard.id,
+                    shard.address,
+                    e.message()
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// deletes the ids from their shards, returns the number of ids found
+    pub async fn delete(&self, request: DeleteRequest) -> Result<DeleteResponse, Status> {
+        let _writes = self.writes.read().await;
+        let shards = &self
+            .shards_of(&request.collection)
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        let mut ids: Vec<Vec<u32>> = vec![Vec::new(); shards.len()];
+        for d_id in request.ids {
+            ids[ShardMap::shard_of(shards, d_id as usize).id as usize].push(d_id);
+        }
+        let mut nb_deleted = 0;
+        for (shard, ids) in shards.iter().zip(ids) {
+            if ids.is_empty() {
+                continue;
+            }
+            let mut client =
+                VectorServiceClient::new(self.channel(&shard.address).map_err(invalid_address)?);
+            let part = DeleteRequest {
+                ids,
+                collection: shard.collection.clone(),
+            };
+            let response = client.delete(part).await.map_err(|e| {
+                Status::unavailable(format!(
+                    "shard {} at {}: {}",
+                    shard.id,
+                    shard.address,
+                    e.message()
+                ))
+            })?;
+            nb_deleted += response.into_inner().nb_deleted;
+        }
+        Ok(DeleteResponse { nb_deleted })
+    }
+
+    /// searches all shards and keeps the knbn nearest neighbours of each query among their
+    /// results. Shards that fail or time out are listed in the missing shards of the result.
+    pub async fn search(
+        &self,
+        request: SearchRequest,
+        shards: &[Shard],
+    ) -> Result<SearchResult, Status> {
+        let mut tasks = Vec::new();
+        for shard in shards {
+            let mut client =
+                VectorServiceClient::new(self.channel(&shard.address).map_err(invalid_address)?);
+            let part = SearchRequest {
+                collection: shard.collection.clone(),
+                ..request.clone()
+            };
+            let timeout = self.timeout;
+            let shard_id = shard.id;
+            tasks.push(tokio::spawn(async move {
+                (
+                    shard_id,
+                    tokio::time::timeout(timeout, client.search(part)).await,
+                )
+            }));
+        }
+        let mut merged: Vec<Neighbours> = vec![Neighbours::default(); request.data.len()];
+        let mut missing_shards = Vec::new();
+        for task in tasks {
+            let (shard_id, result) = task.await.map_err(|e| Status::internal(e.to_string()))?;
+            match result {
+                Ok(Ok(response)) => {
+                    for (all, found) in merged.iter_mut().zip(response.into_inner().neighbours) {
+                        all.neighbour.extend(found.neighbour);
+                    }
+                }
+                Ok(Err(e)) => {
+                    log::warn!(
+                        "shard {} of {} failed: {}",
+                        shard_id,
+                        request.collection,
+                        e.message()
+                    );
+                    missing_shards.push(shard_id);
+                }
+                Err(_) => {
+                    log::warn!("shard {} of {} timed out", shard_id, request.collection);
+                    missing_shards.push(shard_id);
+                }
+            }
+        }
+        if missing_shards.len() == shards.len() {
+            return Err(Status::unavailable(format!(
+                "no shard of {} answered",
+                request.collection
+            )));
+        }
+        for neighbours in merged.iter_mut() {
+            neighbours
+                .neighbour
+                .sort_by(|a, b| a.distance.total_cmp(&b.distance).then(a.d_id.cmp(&b.d_id)));
+            neighbours.neighbour.truncate(request.knbn as usize);
+        }
+        // the points of sharded collections are not proven
+        Ok(SearchResult {
+            neighbours: merged,
+            partial: !missing_shards.is_empty(),
+            missing_shards,
+            root_cid: String::new(),
+            proof_blocks: Vec::new(),
+        })
+    }
+}
+
+/// the status of a node address which cannot be connected to
+fn invalid_address(e: Box<dyn Error>) -> Status {
+    Status::invalid_argument(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use tonic::Code;
+
+    use crate::cluster::membership::{GossipConfig, Membership};
+    use crate::cluster::replication::Replication;
+    use crate::cluster::shard::shard_collection;
+    use crate::hnsw_graph::bench::gen_random_matrix_f32;
+    use crate::index::{IndexConfig, IndexKind};
+    use crate::interfaces::api::VectorAPI;
+    use crate::interfaces::grpc::vector_service::FloatArray;
+    use crate::interfaces::grpc::{local_listener, serve_grpc};
+
+    /// starts a node serving api on a free port, returns its address
+    async fn start_node(api: Arc<VectorAPI>) -> String {
+        let (listener, address) = local_listener().await;
+        let membership = Arc::new(Membership::new(
+            &address,
+            Vec::new(),
+            GossipConfig::default(),
+        ));
+        let coordinator = Arc::new(Coordinator::default());
+        let replication = Arc::new(Replication::default());
+        tokio::spawn(async move {
+            serve_grpc(api, coordinator, replication, membership, listener)
+                .await
+                .unwrap()
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_scatter_gather_search() {
+        let config = IndexConfig {
+            kind: IndexKind::Flat,
+            ..IndexConfig::default()
+        };
+        let mut addresses = Vec::new();
+        for _ in 0..2 {
+            addresses.push(start_node(Arc::new(VectorAPI::new(config.clone()))).await);
+        }
+
+        let coordinator = Coordinator::default();
+        let request = CreateCollectionRequest {
+            name: "docs".to_string(),
+            index_type: "flat".to_string(),
+            ..CreateCollectionRequest::default()
+        };
+        coordinator
+            .create_collection(request.clone(), &addresses)
+            .await
+            .unwrap();
+        let shards = coordinator.shards("docs").unwrap();
+        let data = gen_random_matrix_f32(8, 200);
+        let insert = InsertRequest {
+            data: data
+                .iter()
+                .map(|v| FloatArray { values: v.clone() })
+                .collect(),
+            ids: (0..200).collect(),
+            collection: "docs".to_string(),
+            ..InsertRequest::default()
+        };
+        coordinator.insert(insert).await.unwrap();
+        let deleted = DeleteRequest {
+            ids: vec![0, 1, 500],
+            collection: "docs".to_string(),
+        };
+        assert_eq!(coordinator.delete(deleted).await.unwrap().nb_deleted, 2);
+
+        // the neighbours come from both shards, the query itself first
+        let search = SearchRequest {
+            data: vec![
+                FloatArray {
+                    values: data[7].clone(),
+                },
+                FloatArray {
+                    values: data[8].clone(),
+                },
+            ],
+            knbn: 10,
+            ef: 10,
+            ..SearchRequest::default()
+        };
+        let result = coordinator.search(search.clone(), &shards).await.unwrap();
+        assert!(!result.partial);
+        assert_eq!(result.neighbours[0].neighbour.len(), 10);
+        assert_eq!(result.neighbours[0].neighbour[0].d_id, 7);
+        assert_eq!(result.neighbours[1].neighbour[0].d_id, 8);
+        let shard_of = |d_id: u32| ShardMap::shard_of(&shards, d_id as usize).id;
+        assert!(result.neighbours[0]
+            .neighbour
+            .iter()
+            .any(|n| shard_of(n.d_id) == 0));
+        assert!(result.neighbours[0]
+            .neighbour
+            .iter()
+            .any(|n| shard_of(n.d_id) == 1));
+
+        // a shard on a node that is down leaves the result partial
+        let mut down = shards.clone();
+        down[1].address = local_listener().await.1;
+        let result = coordinator.search(search, &down).await.unwrap();
+        assert!(result.partial);
+        assert_eq!(result.missing_shards, vec![1]);
+        assert!(result.neighbours[0]
+            .neighbour
+            .iter()
+            .all(|n| shard_of(n.d_id) == 0));
+    }
+
+    #[tokio::test]
+    async fn test_move_shards_under_writes() {
+        let config = IndexConfig {
+            kind: IndexKind::Flat,
+            ..IndexConfig::default()
+        };
+        let mut apis = Vec::new();
+        let mut addresses = Vec::new();
+        for _ in 0..2 {
+            let api = Arc::new(VectorAPI::new(config.clone()));
+            addresses.push(start_node(Arc::clone(&api)).await);
+            apis.push(api);
+        }
+
+        // all the shards start on the first node
+        let coordinator = Coordinator::new(Duration::from_millis(200));
+        let request = CreateCollectionRequest {
+            name: "docs".to_string(),
+            index_type: "flat".to_string(),
+            shard_count: 4,
+            ..CreateCollectionRequest::default()
+        };
+        coordinator
+            .create_collection(request, &addresses[..1])
+            .await
+            .unwrap();
+        let data = gen_random_matrix_f32(8, 400);
+        let insert = |from: usize, to: usize| InsertRequest {
+            data: data[from..to]
+                .iter()
+                .map(|v| FloatArray { values: v.clone() })
+                .collect(),
+            ids: (from as u32..to as u32).collect(),
+            collection: "docs".to_string(),
+            ..InsertRequest::default()
+        };
+        coordinator.insert(insert(0, 100)).await.unwrap();
+
+        // the points inserted while the shards move all end up on their shards
+        let writes = async {
+            for from in (100..300).step_by(10) {
+                coordinator.insert(insert(from, from + 10)).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let (moves, ()) = tokio::join!(coordinator.rebalance("docs", &addresses), writes);
+        let moves = moves.unwrap();
+        assert!(!moves.is_empty());
+        let shards = coordinator.shards("docs").unwrap();
+        for shard in &shards {
+            assert_eq!(
+                Some(shard.address.clone()),
+                shard::owner(&shard.collection, &addresses)
+            );
+        }
+        let nb_point = |shards: &[Shard]| -> usize {
+            shards
+                .iter()
+                .map(|shard| {
+                    let rank = addresses.iter().position(|a| *a == shard.address).unwrap();
+                    let collection = apis[rank].collection(&shard.collection).unwrap();
+                    collection.stats().nb_point
+                })
+                .sum()
+        };
+        assert_eq!(nb_point(&shards), 300);
+        for shard_move in &moves {
+            assert_eq!(shard_move.to, addresses[1]);
+            let moved = shard_collection("docs", shard_move.shard);
+            assert!(apis[0].collection(&moved).is_err());
+        }
+
+        // the moved shards no longer follow their former node and accept writes
+        coordinator.insert(insert(300, 400)).await.unwrap();
+        assert_eq!(nb_point(&shards), 400);
+        assert!(coordinator
+            .rebalance("docs", &addresses)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // the shards of a node that is not among the nodes stay where they are
+        assert!(coordinator
+            .rebalance("docs", &addresses[..1])
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(coordinator.shards("docs").unwrap(), shards);
+        for shard_move in &moves {
+            let moved = shard_collection("docs", shard_move.shard);
+            assert!(apis[0].collection(&moved).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_collection_rollback() {
+        let config = IndexConfig {
+            kind: IndexKind::Flat,
+            ..IndexConfig::default()
+        };
+        let api = Arc::new(VectorAPI::new(config));
+        // the listener is dropped at once, nothing serves its address
+        let addresses = vec![start_node(Arc::clone(&api)).await, local_listener().await.1];
+        // a name whose first shard is created before one on the node that is down
+        let name = (0..)
+            .map(|rank| format!("docs{}", rank))
+            .find(|name| {
+                let shards = ShardMap::default()
+                    .assign(name, 16, &addresses)
+                    .unwrap()
+                    .to_vec();
+                shards[0].address == addresses[0]
+                    && shards.iter().any(|shard| shard.address == addresses[1])
+            })
+            .unwrap();
+        let coordinator = Coordinator::new(Duration::from_millis(200));
+        let request = CreateCollectionRequest {
+            name: name.clone(),
+            index_type: "flat".to_string(),
+            shard_count: 16,
+            ..CreateCollectionRequest::default()
+        };
+        let status = coordinator
+            .create_collection(request, &addresses)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        assert!(coordinator.shards(&name).is_none());
+        assert!(api
+            .list_collections()
+            .iter()
+            .all(|(collection, _)| !collection.starts_with(&name)));
+    }
+
+    #[tokio::test]
+    async fn test_shard_map_saved() {
+        let config = IndexConfig {
+            kind: IndexKind::Flat,
+            ..IndexConfig::default()
+        };
+        let address = start_node(Arc::new(VectorAPI::new(config))).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SHARDS_FILE);
+        let coordinator = Coordinator::open(DEFAULT_SHARD_TIMEOUT, &path).unwrap();
+        for name in ["docs", "images"] {
+            let request = CreateCollectionRequest {
+                name: name.to_string(),
+                index_type: "flat".to_string(),
+                shard_count: 2,
+                ..CreateCollectionRequest::default()
+            };
+            coordinator
+                .create_collection(request, &[address.clone()])
+                .await
+                .unwrap();
+        }
+        coordinator.drop_collection("images").await.unwrap();
+
+        // the reopened coordinator has the shards left
+        let reopened = Coordinator::open(DEFAULT_SHARD_TIMEOUT, &path).unwrap();
+        assert_eq!(reopened.shards("docs"), coordinator.shards("docs"));
+        assert!(reopened.shards("images").is_none());
+    }
+}
@@ -0,0 +1,613 @@
+//! Multi-writer merge of collections.
+//!
+//! Nodes accepting writes to the same collection while disconnected, such as edge devices
+//! working offline, converge once they reconnect. Each point is its vector and payload, or a
+//! tombstone once deleted, stamped by a hybrid logical clock: the wall clock of the node
+//! which wrote it, a counter telling apart the writes of a millisecond and the id of the node.
+//! A node stamps its writes after every stamp it has seen, and stamps order the writes of all
+//! nodes the same way, so the last write of a point wins wherever the writes meet: merging
+//! states in any order, any number of times, gives the same collection.
+//!
+//! Each node numbers the changes of the points of a collection. Merging with a peer sends the
+//! points changed since the last merge with it and receives those the peer changed, in
+//! batches, but for the points last written by the peer itself. Only the points newer than
+//! the local ones are applied, the index replacing or deleting them in place, see
+//! AnnIndex::insert.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tonic::transport::Endpoint;
+use tonic::Status;
+
+use crate::hnsw_graph::hnsw::DataId;
+use crate::interfaces::api::VectorAPI;
+use crate::interfaces::grpc::vector_service::{
+    replication_service_client::ReplicationServiceClient, MergeRequest,
+};
+use crate::ipfs_storage::block::{Block, BlockStore, Link};
+
+/// number of points of the changes sent at once
+pub const MERGE_BATCH: usize = 256;
+
+/// largest dimension of the points whose changes are merged
+pub const MAX_MERGE_DIMENSION: usize = 1 << 16;
+
+/// bytes of payload a batch of changes makes room for, per point
+const PAYLOAD_BYTES: usize = 64 << 10;
+
+/// number of points of a block of persisted stamps
+const CHUNK_SIZE: usize = 1024;
+
+/// A reading of a hybrid logical clock. Readings compare by time, counter, then node.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Timestamp {
+    /// milliseconds since the epoch
+    pub millis: u64,
+    pub counter: u32,
+    pub node: u64,
+}
+
+/// A hybrid logical clock. Its readings follow the wall clock but never go back, and come
+/// after the readings of other nodes it observed.
+pub struct Clock {
+    node: u64,
+    /// time and counter of the last reading
+    last: Mutex<(u64, u32)>,
+}
+
+impl Clock {
+    pub fn new(node: u64) -> Self {
+        Clock {
+            node,
+            last: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn node(&self) -> u64 {
+        self.node
+    }
+
+    /// a reading after every earlier reading and every reading observed
+    pub fn now(&self) -> Timestamp {
+        let physical = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let mut last = self.last.lock();
+        if physical > last.0 {
+            *last = (physical, 0);
+        } else {
+            last.1 += 1;
+        }
+        Timestamp {
+            millis: last.0,
+            counter: last.1,
+            node: self.node,
+        }
+    }
+
+    /// makes the next readings come after stamp, read by another node
+    pub fn observe(&self, stamp: Timestamp) {
+        let mut last = self.last.lock();
+        *last = (*last).max((stamp.millis, stamp.counter));
+    }
+}
+
+/// the stamp of the last write of a point
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PointStamp {
+    pub d_id: DataId,
+    pub stamp: Timestamp,
+    pub deleted: bool,
+}
+
+/// a point as of its last write, as it is sent to peers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub d_id: DataId,
+    pub stamp: Timestamp,
+    /// the vector and the payload of the point, null when it has none. None once deleted.
+    pub point: Option<(Vec<f32>, Value)>,
+}
+
+impl Change {
+    pub fn point_stamp(&self) -> PointStamp {
+        PointStamp {
+            d_id: self.d_id,
+            stamp: self.stamp,
+            deleted: self.point.is_none(),
+        }
+    }
+}
+
+/// how far a node merged a collection with a peer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cursor {
+    /// epoch of the point set of the peer, 0 before the first merge
+    pub epoch: u64,
+    /// node of the clock of the peer, None before the first merge
+    pub node: Option<u64>,
+    /// seq of the last change of the peer received
+    pub received: u64,
+    /// seq of the last change of this node sent
+    pub sent: u64,
+}
+
+/// what a merge with peers exchanged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    pub nb_sent: usize,
+    pub nb_received: usize,
+    /// points received newer than the local ones, which replaced them
+    pub nb_applied: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    stamp: Timestamp,
+    deleted: bool,
+    /// seq of the change
+    seq: u64,
+}
+
+#[derive(Default)]
+struct SetState {
+    entries: HashMap<DataId, Entry>,
+    /// the point of each change still current, by seq
+    changes: BTreeMap<u64, DataId>,
+    last_seq: u64,
+    cursors: HashMap<String, Cursor>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StampChunk {
+    entries: Vec<PointStamp>,
+}
+
+/// The stamps of the last writes of the points of a collection, deleted or not. Changes are
+/// numbered from 1 in the order they are recorded, the epoch tells apart the numberings of
+/// the successive sets of a collection.
+pub struct PointSet {
+    epoch: u64,
+    state: Mutex<SetState>,
+}
+
+impl Default for PointSet {
+    fn default() -> Self {
+        PointSet {
+            epoch: rand::random(),
+            state: Mutex::new(SetState::default()),
+        }
+    }
+}
+
+impl PointSet {
+    pub fn from_stamps(stamps: Vec<PointStamp>) -> Self {
+        let set = PointSet::default();
+        set.record(stamps);
+        set
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.state.lock().last_seq
+    }
+
+    /// the greatest stamp of the points, None when there are none
+    pub fn max_stamp(&self) -> Option<Timestamp> {
+        self.state
+            .lock()
+            .entries
+            .values()
+            .map(|entry| entry.stamp)
+            .max()
+    }
+
+    /// records the last writes of points, each as a new change
+    pub fn record(&self, stamps: impl IntoIterator<Item = PointStamp>) {
+        let mut state = self.state.lock();
+        for stamp in stamps {
+            state.last_seq += 1;
+            let entry = Entry {
+                stamp: stamp.stamp,
+                deleted: stamp.deleted,
+                seq: state.last_seq,
+            };
+            if let Some(old) = state.entries.insert(stamp.d_id, entry) {
+                state.changes.remove(&old.seq);
+            }
+            state.changes.insert(entry.seq, stamp.d_id);
+        }
+    }
+
+    /// records a write of the points of ids, stamped stamp
+    pub fn record_write(&self, ids: &[DataId], stamp: Timestamp, deleted: bool) {
+        self.record(ids.iter().map(|d_id| PointStamp {
+            d_id: *d_id,
+            stamp,
+            deleted,
+        }))
+    }
+
+    /// the changes newer than the points of the set, the last one of each point, by id
+    pub fn newer(&self, changes: Vec<Change>) -> Vec<Change> {
+        let mut latest: HashMap<DataId, Change> = HashMap::new();
+        for change in changes {
+            match latest.get(&change.d_id) {
+                Some(kept) if kept.stamp >= change.stamp => {}
+                _ => {
+                    latest.insert(change.d_id, change);
+                }
+            }
+        }
+        let state = self.state.lock();
+        let mut newer: Vec<Change> = latest
+            .into_values()
+            .filter(|change| {
+                state
+                    .entries
+                    .get(&change.d_id)
+                    .is_none_or(|entry| entry.stamp < change.stamp)
+            })
+            .collect();
+        newer.sort_by_key(|change| change.d_id);
+        newer
+    }
+
+    /// up to max points changed after seq since, by seq, but those last written by node
+    /// skipped. Also returns the seq to ask from next time.
+    pub fn changed(&self, since: u64, max: usize, skipped: Option<u64>) -> (Vec<PointStamp>, u64) {
+        let state = self.state.lock();
+        let mut changed = Vec::new();
+        let mut next = state.last_seq.max(since);
+        for (seq, d_id) in state.changes.range(since + 1..) {
+            let entry = state.entries[d_id];
+            if Some(entry.stamp.node) == skipped {
+                continue;
+            }
+            if changed.len() == max {
+                break;
+            }
+            changed.push(PointStamp {
+                d_id: *d_id,
+                stamp: entry.stamp,
+                deleted: entry.deleted,
+            });
+            if changed.len() == max {
+                next = *seq;
+            }
+        }
+        (changed, next)
+    }
+
+    /// the stamps of all points, by id
+    pub fn stamps(&self) -> Vec<PointStamp> {
+        let mut stamps: Vec<PointStamp> = self
+            .state
+            .lock()
+            .entries
+            .iter()
+            .map(|(d_id, entry)| PointStamp {
+                d_id: *d_id,
+                stamp: entry.stamp,
+                deleted: entry.deleted,
+            })
+            .collect();
+        stamps.sort_by_key(|stamp| stamp.d_id);
+        stamps
+    }
+
+    /// how far the set was merged with peer
+    pub fn cursor(&self, peer: &str) -> Cursor {
+        self.state
+            .lock()
+            .cursors
+            .get(peer)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set_cursor(&self, peer: &str, cursor: Cursor) {
+        self.state.lock().cursors.insert(peer.to_string(), cursor);
+    }
+
+    /// writes the stamps to store in chunks, returns their links
+    pub fn persist(&self, store: &dyn BlockStore) -> Result<Vec<Link>, Box<dyn Error>> {
+        let mut links = Vec::new();
+        for entries in self.stamps().chunks(CHUNK_SIZE) {
+            let block = Block::encode(&StampChunk {
+                entries: entries.to_vec(),
+            })?;
+            links.push(Link(block.cid));
+            store.put(block)?;
+        }
+        Ok(links)
+    }
+
+    /// reads the stamps persisted in chunks, as changes of a new epoch
+    pub fn load(links: &[Link], store: &dyn BlockStore) -> Result<Self, Box<dyn Error>> {
+        let mut stamps = Vec::new();
+        for link in links {
+            let chunk: StampChunk = store.get_block(&link.0)?.decode()?;
+            stamps.extend(chunk.entries);
+        }
+        Ok(PointSet::from_stamps(stamps))
+    }
+
+    /// writes the stamps to a CBOR file
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_cbor::to_writer(&mut writer, &self.stamps())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// reads stamps written by save, as changes of a new epoch
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let stamps: Vec<PointStamp> = serde_cbor::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(PointSet::from_stamps(stamps))
+    }
+}
+
+/// the largest message of a batch of changes of points of dimension, see MERGE_BATCH. A
+/// collection without points may receive points of any dimension up to MAX_MERGE_DIMENSION.
+pub fn max_batch_bytes(dimension: usize) -> usize {
+    let dimension = match dimension {
+        0 => MAX_MERGE_DIMENSION,
+        dimension => dimension,
+    };
+    // a float takes 5 bytes in CBOR, the id and the stamp of a point less than 64
+    MERGE_BATCH * (dimension * 5 + PAYLOAD_BYTES + 64)
+}
+
+/// merges collection with the collection of the same name on each of peers in turn, both
+/// ending with the last write of every point either had
+pub async fn merge(
+    api: &VectorAPI,
+    collection: &str,
+    peers: &[String],
+) -> Result<MergeStats, Status> {
+    let points = api
+        .collection(collection)
+        .map_err(|e| Status::not_found(e.to_string()))?;
+    let epoch = points.points().epoch();
+    let mut stats = MergeStats::default();
+    for peer in peers {
+        let channel = Endpoint::from_shared(peer.clone())
+            .map_err(|e| Status::invalid_argument(format!("invalid peer {}: {}", peer, e)))?
+            .connect()
+            .await
+            .map_err(|e| Status::unavailable(format!("{}: {}", peer, e)))?;
+        let mut client = ReplicationServiceClient::new(channel)
+            .max_decoding_message_size(max_batch_bytes(points.stats().dimension));
+        let mut cursor = points.points().cursor(peer);
+        loop {
+            let (_, sent, outgoing) = api
+                .changes(collection, epoch, cursor.sent, MERGE_BATCH, cursor.node)
+                .map_err(|e| Status::failed_precondition(e.to_string()))?;
+            let request = MergeRequest {
+                collection: collection.to_string(),
+                changes: serde_cbor::to_vec(&outgoing)
+                    .map_err(|e| Status::internal(e.to_string()))?,
+                epoch: cursor.epoch,
+                since_seq: cursor.received,
+                node: api.clock().node(),
+            };
+            let response = client.merge(request).await?.into_inner();
+            let incoming: Vec<Change> = serde_cbor::from_slice(&response.changes)
+                .map_err(|e| Status::data_loss(format!("invalid changes from {}: {}", peer, e)))?;
+            let nb_incoming = incoming.len();
+            let nb_applied = api
+                .merge(collection, incoming)
+                .map_err(|e| Status::internal(format!("changes of {} not merged: {}", peer, e)))?;
+            stats.nb_sent += outgoing.len();
+            stats.nb_received += nb_incoming;
+            stats.nb_applied += nb_applied;
+            // a peer whose set changed epoch lost the changes sent before, send them again
+            let replaced = cursor.epoch != 0 && cursor.epoch != response.epoch;
+            cursor = Cursor {
+                epoch: response.epoch,
+                node: Some(response.node),
+                received: response.seq,
+                sent: if replaced { 0 } else { sent },
+            };
+            points.points().set_cursor(peer, cursor);
+            if !replaced && outgoing.len() < MERGE_BATCH && nb_incoming < MERGE_BATCH {
+                break;
+            }
+        }
+        log::info!(
+            "merged {} with {}: {} points sent, {} received, {} applied",
+            collection,
+            peer,
+            stats.nb_sent,
+            stats.nb_received,
+            stats.nb_applied
+        );
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::cluster::coordinator::Coordinator;
+    use crate::cluster::membership::{GossipConfig, Membership};
+    use crate::cluster::replication::Replication;
+    use crate::index::{IndexConfig, IndexKind};
+    use crate::interfaces::api::SearchOptions;
+    use crate::interfaces::grpc::{local_listener, serve_grpc};
+
+    fn stamp(millis: u64, node: u64) -> Timestamp {
+        Timestamp {
+            millis,
+            counter: 0,
+            node,
+        }
+    }
+
+    #[test]
+    fn test_clock_and_point_set() {
+        let clock = Clock::new(1);
+        let first = clock.now();
+        assert!(clock.now() > first);
+        // a reading of a node ahead in time is followed
+        let ahead = Timestamp {
+            millis: first.millis + 60_000,
+            counter: 5,
+            node: 2,
+        };
+        clock.observe(ahead);
+        assert!(clock.now() > ahead);
+
+        let set = PointSet::default();
+        let change = |d_id, stamp, deleted: bool| Change {
+            d_id,
+            stamp,
+            point: (!deleted).then(|| (vec![d_id as f32], Value::Null)),
+        };
+        set.record(vec![change(1, stamp(10, 1), false).point_stamp()]);
+        let newer = set.newer(vec![
+            change(1, stamp(5, 2), false),
+            change(2, stamp(20, 2), false),
+            change(2, stamp(30, 2), true),
+            change(1, stamp(10, 2), true),
+        ]);
+        // the latest change of each point, when newer than the point
+        assert_eq!(
+            newer,
+            vec![change(1, stamp(10, 2), true), change(2, stamp(30, 2), true)]
+        );
+        set.record(newer.iter().map(Change::point_stamp));
+        assert!(set.newer(newer.clone()).is_empty());
+        assert_eq!(set.max_stamp(), Some(stamp(30, 2)));
+
+        // changes are numbered, a point being listed at its last change
+        assert_eq!(set.last_seq(), 3);
+        let (changed, next) = set.changed(0, 1, None);
+        assert_eq!((changed[0].d_id, next), (1, 2));
+        let (changed, next) = set.changed(next, 10, None);
+        assert_eq!((changed.len(), changed[0].d_id, next), (1, 2, 3));
+        assert_eq!(set.changed(3, 10, None), (Vec::new(), 3));
+        // the points last written by a node are not sent back to it
+        assert_eq!(set.changed(0, 10, Some(2)), (Vec::new(), 3));
+
+        let dir = tempfile::tempdir().unwrap();
+        set.save(&dir.path().join("stamps.cbor")).unwrap();
+        let opened = PointSet::open(&dir.path().join("stamps.cbor")).unwrap();
+        assert_eq!(opened.stamps(), set.stamps());
+        assert_ne!(opened.epoch(), set.epoch());
+    }
+
+    #[tokio::test]
+    async fn test_offline_writers_converge() {
+        let config = IndexConfig {
+            kind: IndexKind::Hnsw,
+            ..IndexConfig::default()
+        };
+        let apis: Vec<Arc<VectorAPI>> = (0..2)
+            .map(|_| Arc::new(VectorAPI::new(config.clone())))
+            .collect();
+        let (listener, address) = local_listener().await;
+        let membership = Arc::new(Membership::new(
+            &address,
+            Vec::new(),
+            GossipConfig::default(),
+        ));
+        let served = serve_grpc(
+            Arc::clone(&apis[1]),
+            Arc::new(Coordinator::default()),
+            Arc::new(Replication::default()),
+            membership,
+            listener,
+        );
+        tokio::spawn(async move { served.await.unwrap() });
+        let peers = vec![address];
+
+        // both nodes write while disconnected, node 1 last
+        let data: Vec<Vec<f32>> = (0..600).map(|i| vec![1., i as f32, 0.5]).collect();
+        let points: Vec<(&Vec<f32>, DataId)> = data.iter().zip(0..).collect();
+        apis[0]
+            .parallel_insert("", &points[..400].to_vec())
+            .unwrap();
+        apis[0].delete("", &[7]).unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        apis[1]
+            .parallel_insert("", &points[300..].to_vec())
+            .unwrap();
+        let moved = vec![-5., 1., 3.];
+        apis[1]
+            .insert_with_payloads(
+                "",
+                &vec![(&moved, 7)],
+                vec![serde_json::json!({"moved": true})],
+            )
+            .unwrap();
+        apis[1].delete("", &[350]).unwrap();
+
+        let stats = merge(&apis[0], "", &peers).await.unwrap();
+        // node 0 does not send the points node 1 wrote since, 300 to 399 but 350, nor receive
+        // back its own
+        assert_eq!((stats.nb_sent, stats.nb_received), (301, 301));
+        assert_eq!(stats.nb_applied, 301);
+        let search = |api: &VectorAPI, query: &Vec<f32>| {
+            let options = SearchOptions {
+                payload_fields: Some(Vec::new()),
+                ..SearchOptions::default()
+            };
+            let found = api
+                .search("", &vec![query.clone()], 1, 50, &options)
+                .unwrap();
+            found[0][0].clone()
+        };
+        for api in &apis {
+            assert_eq!(api.collection("").unwrap().stats().nb_point, 599);
+            // 7 came back with the vector and the payload of its last write
+            let (nearest, payload) = search(api, &moved);
+            assert_eq!(nearest.d_id, 7);
+            assert_eq!(payload, Some(serde_json::json!({"moved": true})));
+            assert_ne!(search(api, &data[350]).0.d_id, 350);
+        }
+
+        // only the changes made since are exchanged
+        apis[1].delete("", &[0]).unwrap();
+        let stats = merge(&apis[0], "", &peers).await.unwrap();
+        assert_eq!((stats.nb_received, stats.nb_applied), (1, 1));
+        assert_eq!(apis[0].collection("").unwrap().stats().nb_point, 598);
+
+        // a point overwritten by one node takes its new vector on both, and of a point
+        // overwritten by both the last write wins
+        let overwritten = vec![4., -2., 1.];
+        let first = vec![-1., 3., -2.];
+        let last = vec![2., 2., -5.];
+        apis[0]
+            .parallel_insert("", &vec![(&overwritten, 10), (&first, 30)])
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        apis[1].parallel_insert("", &vec![(&last, 30)]).unwrap();
+        let stats = merge(&apis[0], "", &peers).await.unwrap();
+        assert_eq!((stats.nb_sent, stats.nb_received), (2, 1));
+        for api in &apis {
+            assert_eq!(api.collection("").unwrap().stats().nb_point, 598);
+            assert_eq!(search(api, &overwritten).0.d_id, 10);
+            assert_ne!(search(api, &data[10]).0.d_id, 10);
+            assert_eq!(search(api, &last).0.d_id, 30);
+            assert_ne!(search(api, &first).0.d_id, 30);
+        }
+    }
+}
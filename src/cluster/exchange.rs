@@ -0,0 +1,297 @@
+//! Exchange of the blocks of published collections between nodes, in the manner of bitswap.
+//!
+//! A node syncing a root walks its DAG from the root: the blocks already in its block store
+//! are read locally, the others are gathered in want-lists sent to its peers in turn. A peer
+//! answers with the wanted blocks it has, up to a batch size, and tells which of the others it
+//! has and which it does not. Blocks are checked against their CID before being stored and
+//! their links walked in turn. Only the blocks missing from the store cross the network, so a
+//! node holding an older root of a collection fetches the blocks that changed, and a fresh
+//! node bootstraps from its peers alone.
+
+use std::collections::HashSet;
+use std::error::Error;
+
+use cid::Cid;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Status;
+
+use crate::interfaces::grpc::vector_service::{
+    block_service_client::BlockServiceClient, BlockBatch, BlockData, ResolveRequest, WantList,
+};
+use crate::ipfs_storage::block::{Block, BlockStore, MAX_BLOCK_BYTES};
+
+/// number of CIDs of a want-list
+const WANT_BATCH: usize = 256;
+
+/// size of the blocks sent in answer to a want-list, a batch holds at least one block
+pub const MAX_BATCH_BYTES: usize = 2 << 20;
+
+/// size of the largest answer to a want-list: blocks up to the batch size or a single larger
+/// block, with the CIDs of the others
+const MAX_ANSWER_BYTES: usize = MAX_BATCH_BYTES + MAX_BLOCK_BYTES + WANT_BATCH * 64;
+
+/// a peer and its client
+type Peer = (String, BlockServiceClient<Channel>);
+
+/// what a sync fetched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    /// blocks of the DAG of the root
+    pub nb_block: usize,
+    /// blocks fetched from the peers, the others were in the store
+    pub nb_fetched: usize,
+    pub nb_byte: usize,
+}
+
+/// answers a want-list with the blocks of store
+pub fn answer(store: &dyn BlockStore, wants: &[Cid]) -> Result<BlockBatch, Box<dyn Error>> {
+    let mut batch = BlockBatch::default();
+    let mut size = 0;
+    for cid in wants {
+        let bytes = cid.to_bytes();
+        match store.get(cid)? {
+            Some(data) if batch.blocks.is_empty() || size + data.len() <= MAX_BATCH_BYTES => {
+                size += data.len();
+                batch.blocks.push(BlockData { cid: bytes, data });
+            }
+            Some(_) => batch.have.push(bytes),
+            None => batch.dont_have.push(bytes),
+        }
+    }
+    Ok(batch)
+}
+
+/// the root of collection on the first of peers which published it, and the signed manifest
+/// of the root when it has one
+pub async fn resolve(collection: &str, peers: &[String]) -> Result<(Cid, Option<Cid>), Status> {
+    let peers = connect(peers).map_err(|e| Status::invalid_argument(e.to_string()))?;
+    for (address, mut client) in peers {
+        let request = ResolveRequest {
+            collection: collection.to_string(),
+        };
+        match client.resolve(request).await {
+            Ok(response) => {
+                let response = response.into_inner();
+                let invalid = |e: cid::Error| {
+                    Status::data_loss(format!("invalid CID from {}: {}", address, e))
+                };
+                let root = Cid::try_from(response.root_cid.as_str()).map_err(invalid)?;
+                let manifest = match response.manifest_cid.as_str() {
+                    "" => None,
+                    manifest => Some(Cid::try_from(manifest).map_err(invalid)?),
+                };
+                return Ok((root, manifest));
+            }
+            Err(status) => log::debug!(
+                "{} has no root for {}: {}",
+                address,
+                collection,
+                status.message()
+            ),
+        }
+    }
+    Err(Status::not_found(format!(
+        "no peer published collection {}",
+        collection
+    )))
+}
+
+/// fetches the blocks of the DAG of root missing from store from peers, which are asked in
+/// turn for each want-list
+pub async fn sync(
+    root: Cid,
+    store: &dyn BlockStore,
+    peers: &[String],
+) -> Result<SyncStats, Status> {
+    let mut peers = connect(peers).map_err(|e| Status::invalid_argument(e.to_string()))?;
+    let mut stats = SyncStats::default();
+    let mut visited = HashSet::new();
+    let mut to_visit = vec![root];
+    let mut wanted = Vec::new();
+    loop {
+        while let Some(cid) = to_visit.pop() {
+            if !visited.insert(cid) {
+                continue;
+            }
+            stats.nb_block += 1;
+            match store
+                .get(&cid)
+                .map_err(|e| Status::internal(e.to_string()))?
+            {
+                Some(data) => to_visit.extend(links(&Block { cid, data }).map_err(data_loss)?),
+                None => wanted.push(cid),
+            }
+        }
+        if wanted.is_empty() {
+            return Ok(stats);
+        }
+        let wants: Vec<Cid> = wanted.drain(..wanted.len().min(WANT_BATCH)).collect();
+        for block in fetch(&mut peers, wants).await? {
+            stats.nb_fetched += 1;
+            stats.nb_byte += block.data.len();
+            to_visit.extend(links(&block).map_err(data_loss)?);
+            store
+                .put(block)
+                .map_err(|e| Status::internal(e.to_string()))?;
+        }
+    }
+}
+
+/// the block of cid, from the first of peers which has it
+pub async fn fetch_block(cid: Cid, peers: &[String]) -> Result<Block, Status> {
+    let mut peers = connect(peers).map_err(|e| Status::invalid_argument(e.to_string()))?;
+    fetch(&mut peers, vec![cid])
+        .await?
+        .pop()
+        .ok_or_else(|| Status::not_found(format!("no peer has block {}", cid)))
+}
+
+/// asks the peers in turn for the wanted blocks, fails when none of them has some
+async fn fetch(peers: &mut [Peer], wants: Vec<Cid>) -> Result<Vec<Block>, Status> {
+    let mut missing: HashSet<Cid> = wants.into_iter().collect();
+    let mut blocks = Vec::new();
+    for (address, client) in peers.iter_mut() {
+        // a peer sends the blocks it has in batches, until it has no more
+        while !missing.is_empty() {
+            let wants = WantList {
+                cids: missing.iter().map(Cid::to_bytes).collect(),
+            };
+            let batch = match client.exchange(wants).await {
+                Ok(batch) => batch.into_inner(),
+                Err(status) => {
+                    log::warn!(
+                        "block exchange with {} failed: {}",
+                        address,
+                        status.message()
+                    );
+                    break;
+                }
+            };
+            if batch.blocks.is_empty() {
+                break;
+            }
+            for data in batch.blocks {
+                let block = Block {
+                    cid: parse_cid(&data.cid).map_err(data_loss)?,
+                    data: data.data,
+                };
+                if !missing.remove(&block.cid) || !block.verify() {
+                    return Err(Status::data_loss(format!(
+                        "{} sent a block not wanted or not matching its CID {}",
+                        address, block.cid
+                    )));
+                }
+                blocks.push(block);
+            }
+        }
+    }
+    match missing.iter().next() {
+        Some(cid) => Err(Status::not_found(format!("no peer has block {}", cid))),
+        None => Ok(blocks),
+    }
+}
+
+fn connect(peers: &[String]) -> Result<Vec<Peer>, Box<dyn Error>> {
+    peers
+        .iter()
+        .map(|address| {
+            let channel = Endpoint::from_shared(address.clone())
+                .map_err(|e| format!("invalid peer {}: {}", address, e))?
+                .connect_lazy();
+            let client =
+                BlockServiceClient::new(channel).max_decoding_message_size(MAX_ANSWER_BYTES);
+            Ok((address.clone(), client))
+        })
+        .collect()
+}
+
+pub fn parse_cid(bytes: &[u8]) -> Result<Cid, Box<dyn Error>> {
+    Cid::try_from(bytes).map_err(|e| format!("invalid CID: {}", e).into())
+}
+
+fn links(block: &Block) -> Result<Vec<Cid>, Box<dyn Error>> {
+    block
+        .links()
+        .map_err(|e| format!("block {}: {}", block.cid, e).into())
+}
+
+/// a peer sent a block which is not valid
+fn data_loss(e: Box<dyn Error>) -> Status {
+    Status::data_loss(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::cluster::coordinator::Coordinator;
+    use crate::cluster::membership::{GossipConfig, Membership};
+    use crate::cluster::replication::Replication;
+    use crate::hnsw_graph::bench::gen_random_matrix_f32;
+    use crate::hnsw_graph::hnsw::DataId;
+    use crate::index::{IndexConfig, IndexKind};
+    use crate::interfaces::api::VectorAPI;
+    use crate::interfaces::grpc::{local_listener, serve_grpc};
+    use crate::ipfs_storage::block::RAW;
+
+    #[tokio::test]
+    async fn test_sync_fetches_missing_blocks() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let apis: Vec<Arc<VectorAPI>> = dirs
+            .iter()
+            .map(|dir| {
+                Arc::new(VectorAPI::new(IndexConfig {
+                    kind: IndexKind::Flat,
+                    data_dir: dir.path().to_path_buf(),
+                    ..IndexConfig::default()
+                }))
+            })
+            .collect();
+        let (listener, address) = local_listener().await;
+        let server = Arc::clone(&apis[0]);
+        let membership = Arc::new(Membership::new(
+            &address,
+            Vec::new(),
+            GossipConfig::default(),
+        ));
+        tokio::spawn(async move {
+            let coordinator = Arc::new(Coordinator::default());
+            let replication = Arc::new(Replication::default());
+            serve_grpc(server, coordinator, replication, membership, listener)
+                .await
+                .unwrap()
+        });
+        let peers = vec![address];
+
+        let data = gen_random_matrix_f32(8, 1600);
+        let points: Vec<(&Vec<f32>, DataId)> = data.iter().zip(0..).collect();
+        apis[0]
+            .parallel_insert("", &points[..1500].to_vec())
+            .unwrap();
+        let first = apis[0].publish("").unwrap();
+        assert_eq!(resolve("default", &peers).await.unwrap(), (first, None));
+        assert!(resolve("other", &peers).await.is_err());
+
+        // a fresh node fetches every block
+        let stats = sync(first, apis[1].blocks(), &peers).await.unwrap();
+        assert_eq!(stats.nb_fetched, stats.nb_block);
+        assert_eq!(apis[1].checkout("", &first, None).unwrap(), 1500);
+
+        // then only those that changed, the first chunk of vectors being the same
+        apis[0]
+            .parallel_insert("", &points[1500..].to_vec())
+            .unwrap();
+        let second = apis[0].publish("").unwrap();
+        let stats = sync(second, apis[1].blocks(), &peers).await.unwrap();
+        assert!(stats.nb_fetched > 0 && stats.nb_fetched < stats.nb_block);
+        assert_eq!(apis[1].checkout("", &second, None).unwrap(), 1600);
+        assert_eq!(apis[1].published(""), Some(second));
+        let stats = sync(second, apis[1].blocks(), &peers).await.unwrap();
+        assert_eq!(stats.nb_fetched, 0);
+
+        // a block no peer has fails the sync
+        let unknown = Block::new(RAW, vec![1, 2, 3]).cid;
+        assert!(sync(unknown, apis[1].blocks(), &peers).await.is_err());
+    }
+}
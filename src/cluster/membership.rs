@@ -0,0 +1,859 @@
+//! Membership of the nodes of a cluster and detection of their failures.
+//!
+//! Each node starts from a list of seed nodes and, at every gossip interval, increments its
+//! heartbeat and exchanges its view of the cluster with a random live node, and with a seed
+//! it does not know alive. Both keep the newest state of each node, by generation then
+//! heartbeat. A node whose heartbeat stops increasing is suspected, then declared dead, and
+//! the shards it held for the collections coordinated by this node are handed over to
+//! replicas of their collections, or left missing until it comes back. When nodes join, the
+//! shards of these collections are moved with their points to their owners among the live
+//! nodes.
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::{Mutex, RwLock};
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tonic::transport::Endpoint;
+use tonic::Status;
+
+use crate::cluster::coordinator::Coordinator;
+use crate::cluster::shard::Shard;
+use crate::interfaces::api::VectorAPI;
+use crate::interfaces::grpc::vector_service::{
+    cluster_service_client::ClusterServiceClient, ClusterView as PbClusterView, GossipMessage,
+    NodeState, ShardInfo,
+};
+
+/// number of membership events kept
+const MAX_EVENTS: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    pub interval: Duration,
+    /// silence after which a node is suspected
+    pub suspect_after: Duration,
+    /// silence after which a node is declared dead
+    pub dead_after: Duration,
+}
+
+impl GossipConfig {
+    /// a node is suspected after 3 intervals without news and dead after 8
+    pub fn new(interval: Duration) -> Self {
+        GossipConfig {
+            interval,
+            suspect_after: interval * 3,
+            dead_after: interval * 8,
+        }
+    }
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig::new(Duration::from_secs(1))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeStatus {
+    Alive,
+    Suspect,
+    Dead,
+    /// the node announced it was stopping
+    Left,
+}
+
+impl NodeStatus {
+    /// whether requests may still be sent to the node
+    pub fn is_live(&self) -> bool {
+        matches!(self, NodeStatus::Alive | NodeStatus::Suspect)
+    }
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            NodeStatus::Alive => "alive",
+            NodeStatus::Suspect => "suspect",
+            NodeStatus::Dead => "dead",
+            NodeStatus::Left => "left",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for NodeStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alive" => Ok(NodeStatus::Alive),
+            "suspect" => Ok(NodeStatus::Suspect),
+            "dead" => Ok(NodeStatus::Dead),
+            "left" => Ok(NodeStatus::Left),
+            _ => Err(format!("unknown node status {}", s)),
+        }
+    }
+}
+
+/// what this node knows of a node of the cluster
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeInfo {
+    /// gRPC endpoint of the node, as http://host:port
+    pub address: String,
+    /// start time of the node in ms
+    pub generation: u64,
+    pub heartbeat: u64,
+    pub status: NodeStatus,
+    pub collections: Vec<String>,
+    /// shards of the sharded collections coordinated by the node
+    pub shards: Vec<Shard>,
+    /// ms since the node was last heard of
+    pub last_seen_ms: u64,
+}
+
+impl NodeInfo {
+    fn version(&self) -> (u64, u64) {
+        (self.generation, self.heartbeat)
+    }
+}
+
+impl From<&NodeInfo> for NodeState {
+    fn from(node: &NodeInfo) -> Self {
+        NodeState {
+            address: node.address.clone(),
+            generation: node.generation,
+            heartbeat: node.heartbeat,
+            status: node.status.to_string(),
+            collections: node.collections.clone(),
+            shards: node
+                .shards
+                .iter()
+                .map(|shard| ShardInfo {
+                    id: shard.id,
+                    address: shard.address.clone(),
+                    collection: shard.collection.clone(),
+                })
+                .collect(),
+            last_seen_ms: node.last_seen_ms,
+        }
+    }
+}
+
+impl TryFrom<NodeState> for NodeInfo {
+    type Error = String;
+
+    fn try_from(node: NodeState) -> Result<Self, Self::Error> {
+        Ok(NodeInfo {
+            status: node.status.parse()?,
+            address: node.address,
+            generation: node.generation,
+            heartbeat: node.heartbeat,
+            collections: node.collections,
+            shards: node
+                .shards
+                .into_iter()
+                .map(|shard| Shard {
+                    id: shard.id,
+                    address: shard.address,
+                    collection: shard.collection,
+                })
+                .collect(),
+            last_seen_ms: node.last_seen_ms,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    NodeJoined {
+        address: String,
+    },
+    NodeLeft {
+        address: String,
+    },
+    NodeFailed {
+        address: String,
+    },
+    /// a shard of a lost node was handed over to a replica of its collection on another node
+    ShardPromoted {
+        collection: String,
+        shard: u32,
+        from: String,
+        to: String,
+    },
+    /// a shard was moved with its points to its owner among the live nodes
+    ShardMoved {
+        collection: String,
+        shard: u32,
+        from: String,
+        to: String,
+    },
+}
+
+impl fmt::Display for ClusterEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClusterEvent::NodeJoined { address } => write!(f, "node {} joined", address),
+            ClusterEvent::NodeLeft { address } => write!(f, "node {} left", address),
+            ClusterEvent::NodeFailed { address } => write!(f, "node {} failed", address),
+            ClusterEvent::ShardPromoted {
+                collection,
+                shard,
+                from,
+                to,
+            } => write!(
+                f,
+                "shard {} of {} promoted from {} to {}",
+                shard, collection, from, to
+            ),
+            ClusterEvent::ShardMoved {
+                collection,
+                shard,
+                from,
+                to,
+            } => write!(
+                f,
+                "shard {} of {} moved from {} to {}",
+                shard, collection, from, to
+            ),
+        }
+    }
+}
+
+/// the cluster as seen by a node
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterView {
+    pub address: String,
+    pub nodes: Vec<NodeInfo>,
+    pub events: Vec<ClusterEvent>,
+}
+
+impl From<ClusterView> for PbClusterView {
+    fn from(view: ClusterView) -> Self {
+        PbClusterView {
+            address: view.address,
+            nodes: view.nodes.iter().map(NodeState::from).collect(),
+            events: view.events.iter().map(ClusterEvent::to_string).collect(),
+        }
+    }
+}
+
+pub struct Membership {
+    /// the address of this node as other nodes reach it
+    address: String,
+    seeds: Vec<String>,
+    config: GossipConfig,
+    /// the nodes by address with the instant their version last changed, this node included
+    nodes: RwLock<HashMap<String, (NodeInfo, Instant)>>,
+    /// nodes dead or gone whose shards are not all handed over to replicas
+    lost: Mutex<Vec<String>>,
+    /// nodes joined since the last rebalancing
+    joined: AtomicBool,
+    rebalancing: AtomicBool,
+    events: Mutex<VecDeque<ClusterEvent>>,
+    notifier: broadcast::Sender<ClusterEvent>,
+}
+
+impl Membership {
+    pub fn new(address: &str, seeds: Vec<String>, config: GossipConfig) -> Self {
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let local = NodeInfo {
+            address: address.to_string(),
+            generation,
+            heartbeat: 0,
+            status: NodeStatus::Alive,
+            collections: Vec::new(),
+            shards: Vec::new(),
+            last_seen_ms: 0,
+        };
+        let mut nodes = HashMap::new();
+        nodes.insert(address.to_string(), (local, Instant::now()));
+        Membership {
+            address: address.to_string(),
+            seeds: seeds.into_iter().filter(|seed| seed != address).collect(),
+            config,
+            nodes: RwLock::new(nodes),
+            lost: Mutex::new(Vec::new()),
+            joined: AtomicBool::new(false),
+            rebalancing: AtomicBool::new(false),
+            events: Mutex::new(VecDeque::new()),
+            notifier: broadcast::channel(MAX_EVENTS).0,
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// the events happening from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ClusterEvent> {
+        self.notifier.subscribe()
+    }
+
+    /// the nodes of the cluster sorted by address, this node included
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .nodes
+            .read()
+            .values()
+            .map(|(node, seen)| NodeInfo {
+                last_seen_ms: seen.elapsed().as_millis() as u64,
+                ..node.clone()
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.address.cmp(&b.address));
+        nodes
+    }
+
+    /// addresses of the nodes requests may be sent to, sorted
+    pub fn live_nodes(&self) -> Vec<String> {
+        self.nodes()
+            .into_iter()
+            .filter(|node| node.status.is_live())
+            .map(|node| node.address)
+            .collect()
+    }
+
+    pub fn view(&self) -> ClusterView {
+        ClusterView {
+            address: self.address.clone(),
+            nodes: self.nodes(),
+            events: self.events.lock().iter().cloned().collect(),
+        }
+    }
+
+    fn record(&self, event: ClusterEvent) {
+        log::info!("{}", event);
+        let mut events = self.events.lock();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+        // no receiver is not an error
+        let _ = self.notifier.send(event);
+    }
+
+    /// increments the heartbeat of this node and updates what it holds
+    fn update_local(&self, collections: Vec<String>, shards: Vec<Shard>) {
+        let mut nodes = self.nodes.write();
+        if let Some((local, seen)) = nodes.get_mut(&self.address) {
+            local.heartbeat += 1;
+            local.collections = collections;
+            local.shards = shards;
+            *seen = Instant::now();
+        }
+    }
+
+    /// keeps the newest state of each node, the status of this node decides whether a node
+    /// is suspect or dead
+    pub fn merge(&self, states: Vec<NodeInfo>) {
+        let mut events = Vec::new();
+        {
+            let mut nodes = self.nodes.write();
+            for mut state in states {
+                if state.address == self.address {
+                    continue;
+                }
+                let known = nodes.get(&state.address).map(|(node, _)| node);
+                if known.is_some_and(|node| node.version() >= state.version()) {
+                    continue;
+                }
+                // newer news of a node means that it is alive, unless it left
+                if state.status != NodeStatus::Left {
+                    state.status = NodeStatus::Alive;
+                }
+                match (known.map(|node| node.status), state.status) {
+                    (Some(NodeStatus::Left), NodeStatus::Left) => {}
+                    (_, NodeStatus::Left) => {
+                        events.push(ClusterEvent::NodeLeft {
+                            address: state.address.clone(),
+                        });
+                        self.lost.lock().push(state.address.clone());
+                    }
+                    (None | Some(NodeStatus::Dead | NodeStatus::Left), _) => {
+                        events.push(ClusterEvent::NodeJoined {
+                            address: state.address.clone(),
+                        });
+                        // the shards left on the node are served again
+                        self.lost.lock().retain(|address| *address != state.address);
+                        self.joined.store(true, Ordering::Relaxed);
+                    }
+                    _ => {}
+                }
+                state.last_seen_ms = 0;
+                nodes.insert(state.address.clone(), (state, Instant::now()));
+            }
+        }
+        for event in events {
+            self.record(event);
+        }
+    }
+
+    /// suspects the nodes silent for too long and declares dead those silent for longer
+    fn detect_failures(&self) {
+        let mut failed = Vec::new();
+        {
+            let mut nodes = self.nodes.write();
+            for (address, (node, seen)) in nodes.iter_mut() {
+                if *address == self.address || !node.status.is_live() {
+                    continue;
+                }
+                let silence = seen.elapsed();
+                if silence > self.config.dead_after {
+                    node.status = NodeStatus::Dead;
+                    failed.push(address.clone());
+                } else if silence > self.config.suspect_after {
+                    node.status = NodeStatus::Suspect;
+                }
+            }
+        }
+        for address in failed {
+            self.lost.lock().push(address.clone());
+            self.record(ClusterEvent::NodeFailed { address });
+        }
+    }
+
+    /// sends the view of this node to the node at address and merges its view
+    async fn exchange(&self, address: &str) -> Result<(), Status> {
+        let channel = Endpoint::from_shared(address.to_string())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?
+            .connect_timeout(self.config.interval)
+            .timeout(self.config.interval)
+            .connect()
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let message = GossipMessage {
+            nodes: self.nodes().iter().map(NodeState::from).collect(),
+        };
+        let response = ClusterServiceClient::new(channel)
+            .gossip(message)
+            .await?
+            .into_inner();
+        let states =
+            parse_states(response.nodes).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        self.merge(states);
+        Ok(())
+    }
+
+    /// gossips with a random live node and a random seed not known alive
+    async fn gossip_round(&self) {
+        let peers: Vec<String> = self
+            .live_nodes()
+            .into_iter()
+            .filter(|address| *address != self.address)
+            .collect();
+        let seeds: Vec<&String> = self
+            .seeds
+            .iter()
+            .filter(|seed| !peers.contains(seed))
+            .collect();
+        // the thread rng cannot be held across the exchanges
+        let targets: Vec<String> = {
+            let mut rng = rand::thread_rng();
+            let peer = peers.choose(&mut rng).cloned();
+            peer.into_iter()
+                .chain(seeds.choose(&mut rng).map(|seed| seed.to_string()))
+                .collect()
+        };
+        for target in targets {
+            if let Err(status) = self.exchange(&target).await {
+                log::debug!("gossip with {} failed: {}", target, status.message());
+            }
+        }
+    }
+
+    /// hands the shards of the lost nodes over to replicas of their collections on live
+    /// nodes. The shards without replicas stay missing, their lost nodes are kept for the next
+    /// round until they come back.
+    async fn promote_shards(&self, coordinator: &Coordinator) {
+        let lost: Vec<String> = std::mem::take(&mut *self.lost.lock());
+        for address in lost {
+            let shard_map = coordinator.shard_map();
+            let mut promoted_all = true;
+            for collection in shard_map.collections() {
+                let shards = shard_map.shards(&collection).unwrap_or_default();
+                for shard in shards.iter().filter(|shard| shard.address == address) {
+                    match coordinator
+                        .promote(&collection, shard.id, &self.live_nodes())
+                        .await
+                    {
+                        Ok(Some(target)) => self.record(ClusterEvent::ShardPromoted {
+                            collection: collection.clone(),
+                            shard: shard.id,
+                            from: address.clone(),
+                            to: target,
+                        }),
+                        Ok(None) => promoted_all = false,
+                        Err(status) => {
+                            log::warn!(
+                                "shard {} of {} not promoted: {}",
+                                shard.id,
+                                collection,
+                                status.message()
+                            );
+                            promoted_all = false;
+                        }
+                    }
+                }
+            }
+            let live = self
+                .nodes
+                .read()
+                .get(&address)
+                .is_some_and(|(node, _)| node.status.is_live());
+            if !promoted_all && !live {
+                self.lost.lock().push(address);
+            }
+        }
+    }
+
+    /// rebalances the collections of coordinator in the background when nodes joined, one
+    /// rebalancing at a time
+    fn start_rebalance(self: &Arc<Self>, coordinator: &Arc<Coordinator>) {
+        if self.rebalancing.load(Ordering::Relaxed) || !self.joined.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        self.rebalancing.store(true, Ordering::Relaxed);
+        let (membership, coordinator) = (Arc::clone(self), Arc::clone(coordinator));
+        tokio::spawn(async move {
+            membership.rebalance(&coordinator).await;
+            membership.rebalancing.store(false, Ordering::Relaxed);
+        });
+    }
+
+    /// moves the shards of the collections of coordinator to their owners among the live
+    /// nodes
+    async fn rebalance(&self, coordinator: &Coordinator) {
+        for collection in coordinator.shard_map().collections() {
+            match coordinator.rebalance(&collection, &self.live_nodes()).await {
+                Ok(moves) => {
+                    for shard_move in moves {
+                        self.record(ClusterEvent::ShardMoved {
+                            collection: collection.clone(),
+                            shard: shard_move.shard,
+                            from: shard_move.from,
+                            to: shard_move.to,
+                        });
+                    }
+                }
+                Err(status) => log::warn!(
+                    "collection {} not rebalanced: {}",
+                    collection,
+                    status.message()
+                ),
+            }
+        }
+    }
+
+    /// gossips at every interval until this node leaves
+    pub async fn run(self: Arc<Self>, api: Arc<VectorAPI>, coordinator: Arc<Coordinator>) {
+        loop {
+            let shard_map = coordinator.shard_map();
+            let shards = shard_map
+                .collections()
+                .iter()
+                .flat_map(|collection| shard_map.shards(collection).unwrap_or_default().to_vec())
+                .collect();
+            let collections = api
+                .list_collections()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            self.update_local(collections, shards);
+            if self.has_left() {
+                return;
+            }
+            self.gossip_round().await;
+            self.detect_failures();
+            self.promote_shards(&coordinator).await;
+            self.start_rebalance(&coordinator);
+            tokio::time::sleep(self.config.interval).await;
+        }
+    }
+
+    fn has_left(&self) -> bool {
+        self.nodes
+            .read()
+            .get(&self.address)
+            .is_some_and(|(local, _)| local.status == NodeStatus::Left)
+    }
+
+    /// tells the live nodes that this node is stopping, so that its shards are handed over
+    /// without waiting for its failure to be detected
+    pub async fn leave(&self) {
+        if let Some((local, _)) = self.nodes.write().get_mut(&self.address) {
+            local.status = NodeStatus::Left;
+            local.heartbeat += 1;
+        }
+        for address in self.live_nodes() {
+            if address == self.address {
+                continue;
+            }
+            if let Err(status) = self.exchange(&address).await {
+                log::debug!("{} not told of the leave: {}", address, status.message());
+            }
+        }
+    }
+}
+
+/// the states of a gossip message
+pub fn parse_states(states: Vec<NodeState>) -> Result<Vec<NodeInfo>, Box<dyn Error>> {
+    Ok(states
+        .into_iter()
+        .map(NodeInfo::try_from)
+        .collect::<Result<_, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::cluster::replication::Replication;
+    use crate::cluster::shard::{self, ShardMap};
+    use crate::hnsw_graph::bench::gen_random_matrix_f32;
+    use crate::index::{IndexConfig, IndexKind};
+    use crate::interfaces::grpc::vector_service::{
+        CreateCollectionRequest, FloatArray, InsertRequest, SearchRequest,
+    };
+    use crate::interfaces::grpc::{local_listener, serve_grpc};
+
+    /// whether done becomes true within 5s
+    async fn wait_for(done: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if done() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    struct Node {
+        api: Arc<VectorAPI>,
+        coordinator: Arc<Coordinator>,
+        replication: Arc<Replication>,
+        membership: Arc<Membership>,
+    }
+
+    impl Node {
+        /// serves the node on listener and starts its gossip
+        fn start(&self, listener: TcpListener) -> (JoinHandle<()>, JoinHandle<()>) {
+            let served = serve_grpc(
+                Arc::clone(&self.api),
+                Arc::clone(&self.coordinator),
+                Arc::clone(&self.replication),
+                Arc::clone(&self.membership),
+                listener,
+            );
+            let server = tokio::spawn(async move { served.await.unwrap() });
+            let gossip = tokio::spawn(
+                Arc::clone(&self.membership)
+                    .run(Arc::clone(&self.api), Arc::clone(&self.coordinator)),
+            );
+            (server, gossip)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failure_promotes_replicas() {
+        let config = IndexConfig {
+            kind: IndexKind::Flat,
+            ..IndexConfig::default()
+        };
+        let gossip = GossipConfig::new(Duration::from_millis(50));
+        let mut listeners = Vec::new();
+        for _ in 0..3 {
+            listeners.push(local_listener().await);
+        }
+        listeners.sort_by(|a, b| a.1.cmp(&b.1));
+        let addresses: Vec<String> = listeners
+            .iter()
+            .map(|(_, address)| address.clone())
+            .collect();
+        let mut nodes = Vec::new();
+        let mut tasks = Vec::new();
+        for (listener, address) in listeners {
+            // the first node is the seed of the others
+            let node = Node {
+                api: Arc::new(VectorAPI::new(config.clone())),
+                coordinator: Arc::new(Coordinator::default()),
+                replication: Arc::new(Replication::default()),
+                membership: Arc::new(Membership::new(
+                    &address,
+                    addresses[..1].to_vec(),
+                    gossip.clone(),
+                )),
+            };
+            tasks.push(node.start(listener));
+            nodes.push(node);
+        }
+
+        let (coordinator, membership) = (&nodes[0].coordinator, &nodes[0].membership);
+        assert!(wait_for(|| membership.live_nodes() == addresses).await);
+        // the other nodes learn of each other through the seed
+        assert!(wait_for(|| nodes[2].membership.live_nodes() == addresses).await);
+
+        // a name giving the last node several shards
+        let name = (0..)
+            .map(|rank| format!("docs{}", rank))
+            .find(|name| {
+                let shards = ShardMap::default()
+                    .assign(name, 6, &addresses)
+                    .unwrap()
+                    .to_vec();
+                shards
+                    .iter()
+                    .filter(|shard| shard.address == addresses[2])
+                    .count()
+                    >= 2
+            })
+            .unwrap();
+        let request = CreateCollectionRequest {
+            name: name.clone(),
+            index_type: "flat".to_string(),
+            shard_count: 6,
+            ..CreateCollectionRequest::default()
+        };
+        coordinator
+            .create_collection(request, &addresses)
+            .await
+            .unwrap();
+        let data = gen_random_matrix_f32(8, 300);
+        let insert = InsertRequest {
+            data: data
+                .iter()
+                .map(|v| FloatArray { values: v.clone() })
+                .collect(),
+            ids: (0..300).collect(),
+            collection: name.clone(),
+            ..InsertRequest::default()
+        };
+        coordinator.insert(insert).await.unwrap();
+        let shards = coordinator.shards(&name).unwrap();
+        let lost: Vec<Shard> = shards
+            .iter()
+            .filter(|shard| shard.address == addresses[2])
+            .cloned()
+            .collect();
+
+        // the second node replicates the first shard of the last node
+        let promoted = lost[0].clone();
+        nodes[1]
+            .replication
+            .follow(
+                Arc::clone(&nodes[1].api),
+                &promoted.collection,
+                &addresses[2],
+            )
+            .unwrap();
+        let nb_point = |rank: usize, collection: &str| {
+            nodes[rank]
+                .api
+                .collection(collection)
+                .map_or(0, |collection| collection.stats().nb_point)
+        };
+        let nb_promoted = nb_point(2, &promoted.collection);
+        assert!(nb_promoted > 0);
+        assert!(
+            wait_for(|| nodes[1].replication.status()[0].copied
+                && nb_point(1, &promoted.collection) == nb_promoted)
+            .await
+        );
+
+        let (server, gossip) = &tasks[2];
+        server.abort();
+        gossip.abort();
+        assert!(
+            wait_for(
+                || coordinator.shards(&name).unwrap()[promoted.id as usize].address == addresses[1]
+            )
+            .await
+        );
+        let view = membership.view();
+        assert_eq!(view.nodes[2].status, NodeStatus::Dead);
+        assert!(view.events.contains(&ClusterEvent::NodeFailed {
+            address: addresses[2].clone()
+        }));
+        assert!(view.events.contains(&ClusterEvent::ShardPromoted {
+            collection: name.clone(),
+            shard: promoted.id,
+            from: addresses[2].clone(),
+            to: addresses[1].clone(),
+        }));
+        assert!(nodes[1].replication.leader(&promoted.collection).is_none());
+
+        // the shards without replica are left on the lost node, never created empty
+        let shards = coordinator.shards(&name).unwrap();
+        for shard in &lost[1..] {
+            assert_eq!(shards[shard.id as usize].address, addresses[2]);
+            assert!(nodes[0].api.collection(&shard.collection).is_err());
+            assert!(nodes[1].api.collection(&shard.collection).is_err());
+        }
+        // their points are missing from the results, those of the promoted shard are found
+        let search = |d_id: usize| SearchRequest {
+            data: vec![FloatArray {
+                values: data[d_id].clone(),
+            }],
+            knbn: 1,
+            ef: 10,
+            collection: name.clone(),
+            ..SearchRequest::default()
+        };
+        let d_id = (0..300)
+            .find(|d_id| ShardMap::shard_of(&shards, *d_id).id == promoted.id)
+            .unwrap();
+        // a new coordinator, whose channels to the lost node are not connected yet
+        let searcher = Coordinator::new(Duration::from_millis(200));
+        let result = searcher.search(search(d_id), &shards).await.unwrap();
+        assert!(result.partial);
+        assert_eq!(
+            result.missing_shards,
+            lost[1..].iter().map(|shard| shard.id).collect::<Vec<_>>()
+        );
+        assert_eq!(result.neighbours[0].neighbour[0].d_id, d_id as u32);
+
+        // the node comes back with its shards, the promoted one moves back to it
+        let address = addresses[2].trim_start_matches("http://");
+        let listener = TcpListener::bind(address).await.unwrap();
+        let _tasks = nodes[2].start(listener);
+        assert!(wait_for(|| membership.live_nodes() == addresses).await);
+        assert!(
+            wait_for(
+                || coordinator.shards(&name).unwrap()[promoted.id as usize].address == addresses[2]
+            )
+            .await
+        );
+        let shards = coordinator.shards(&name).unwrap();
+        for shard in &shards {
+            assert_eq!(
+                Some(shard.address.clone()),
+                shard::owner(&shard.collection, &addresses)
+            );
+        }
+        let searcher = Coordinator::new(Duration::from_millis(200));
+        let result = searcher.search(search(d_id), &shards).await.unwrap();
+        assert!(!result.partial);
+        let total: usize = shards
+            .iter()
+            .map(|shard| {
+                let rank = addresses.iter().position(|a| *a == shard.address).unwrap();
+                nb_point(rank, &shard.collection)
+            })
+            .sum();
+        assert_eq!(total, 300);
+    }
+}
@@ -0,0 +1,24 @@
+//! Collections spread over several d_celestica processes.
+//!
+//! A sharded collection is split into shards by point id, each shard being a collection of
+//! its own on one node. The node which created the sharded collection is its coordinator: it
+//! routes insertions and deletions to the shards of their ids, sends searches to all shards
+//! and merges their results.
+//!
+//! A collection may also follow the collection of the same name on a leader node, whose
+//! writes it replays to serve searches, see replication.
+//!
+//! The nodes know each other by gossip, see membership, which hands the shards of the nodes
+//! that fail or leave over to replicas of their collections.
+//!
+//! Published collections are synced between nodes block by block, see exchange.
+//!
+//! Nodes that accept writes to the same collection while disconnected converge once they
+//! merge their points, last writer wins by hybrid logical clock, see crdt.
+
+pub mod coordinator;
+pub mod crdt;
+pub mod exchange;
+pub mod membership;
+pub mod replication;
+pub mod shard;
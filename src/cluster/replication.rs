@@ -0,0 +1,579 @@
+//! Leader/follower replication of collections.
+//!
+//! Every collection numbers its writes and keeps the last ones in a write log. A follower
+//! starts from a snapshot of the collection of its leader, then streams the writes following
+//! the snapshot from the log and applies them to its own copy, which serves searches. When
+//! the writes it needs have left the log, or the leader restarted, it takes a new snapshot.
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tonic::transport::Endpoint;
+use tonic::{Code, Status};
+
+use crate::cluster::crdt::Change;
+use crate::hnsw_graph::hnsw::DataId;
+use crate::index::sparse::SparseVector;
+use crate::interfaces::api::{default_name, Collection, NamedVectors, VectorAPI};
+use crate::interfaces::grpc::vector_service::{
+    replication_service_client::ReplicationServiceClient, LogEntry, ReplicateRequest,
+    SnapshotRequest,
+};
+
+/// number of points of the writes kept in a write log, the last write is always kept
+pub const LOG_CAPACITY: usize = 100_000;
+
+/// maximum number of writes read from the log at once by a stream
+const STREAM_BATCH: usize = 256;
+
+/// time after which an idle stream sends a heartbeat carrying the seq of the leader
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// time a follower waits before reconnecting to its leader
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// largest message a follower reads from its leader, a snapshot coming in a single message
+const MAX_SNAPSHOT_BYTES: usize = 2 << 30;
+
+/// a write applied to a collection, as it is sent to the followers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WriteOp {
+    Insert {
+        ids: Vec<DataId>,
+        vectors: Vec<Vec<f32>>,
+        payloads: Vec<Value>,
+    },
+    InsertNamed {
+        ids: Vec<DataId>,
+        vectors: Vec<NamedVectors>,
+        payloads: Vec<Value>,
+    },
+    InsertSparse {
+        data: Vec<(SparseVector, DataId)>,
+        payloads: Vec<Value>,
+    },
+    InsertMulti {
+        vector: String,
+        data: Vec<(Vec<Vec<f32>>, DataId)>,
+        payloads: Vec<Value>,
+    },
+    Delete {
+        ids: Vec<DataId>,
+    },
+    /// the points of peers merged, see crdt
+    Merge {
+        changes: Vec<Change>,
+    },
+}
+
+impl WriteOp {
+    fn nb_point(&self) -> usize {
+        match self {
+            WriteOp::Insert { ids, .. }
+            | WriteOp::InsertNamed { ids, .. }
+            | WriteOp::Delete { ids } => ids.len(),
+            WriteOp::InsertSparse { data, .. } => data.len(),
+            WriteOp::InsertMulti { data, .. } => data.len(),
+            WriteOp::Merge { changes } => changes.len(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct LogState {
+    /// seq of the last write, 0 before the first one
+    last_seq: u64,
+    /// writes are only kept once a follower asked for them
+    enabled: bool,
+    /// the last writes, by increasing seq without gaps
+    entries: VecDeque<(u64, WriteOp)>,
+    nb_point: usize,
+}
+
+/// The numbered writes of a collection. The epoch tells apart the logs of the successive
+/// collections of a name, whose seqs all start from 1.
+pub struct WriteLog {
+    epoch: u64,
+    state: Mutex<LogState>,
+    appended: Notify,
+}
+
+impl Default for WriteLog {
+    fn default() -> Self {
+        WriteLog {
+            epoch: rand::random(),
+            state: Mutex::new(LogState::default()),
+            appended: Notify::new(),
+        }
+    }
+}
+
+impl WriteLog {
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.state.lock().last_seq
+    }
+
+    /// keeps the next writes in the log
+    pub fn enable(&self) {
+        self.state.lock().enabled = true;
+    }
+
+    /// numbers a write and keeps it when the log is enabled, op is only called then.
+    /// Returns the seq of the write.
+    pub fn append(&self, op: impl FnOnce() -> WriteOp) -> u64 {
+        let seq = {
+            let mut state = self.state.lock();
+            state.last_seq += 1;
+            let seq = state.last_seq;
+            if state.enabled {
+                let op = op();
+                state.nb_point += op.nb_point();
+                state.entries.push_back((seq, op));
+                while state.nb_point > LOG_CAPACITY && state.entries.len() > 1 {
+                    if let Some((_, evicted)) = state.entries.pop_front() {
+                        state.nb_point -= evicted.nb_point();
+                    }
+                }
+            }
+            seq
+        };
+        self.appended.notify_waiters();
+        seq
+    }
+
+    /// up to max writes from seq from on, None when some of them are no longer in the log
+    pub fn since(&self, from: u64, max: usize) -> Option<Vec<(u64, WriteOp)>> {
+        let state = self.state.lock();
+        if from > state.last_seq + 1 {
+            return None;
+        }
+        if from == state.last_seq + 1 {
+            return Some(Vec::new());
+        }
+        let first = state.entries.front().map(|(seq, _)| *seq)?;
+        if from < first {
+            return None;
+        }
+        Some(
+            state
+                .entries
+                .iter()
+                .skip((from - first) as usize)
+                .take(max)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// sends the writes of collection from seq from on to tx as they are appended to its log,
+/// until tx is closed. An idle stream sends heartbeats, entries without write.
+pub async fn stream_log(
+    collection: Arc<Collection>,
+    epoch: u64,
+    mut from: u64,
+    tx: mpsc::Sender<Result<LogEntry, Status>>,
+) {
+    let log = collection.log();
+    log.enable();
+    if epoch != log.epoch() {
+        let _ = tx
+            .send(Err(Status::out_of_range(
+                "the collection was replaced, take a snapshot",
+            )))
+            .await;
+        return;
+    }
+    loop {
+        // created before reading the log so that no write is missed
+        let appended = log.appended.notified();
+        let entries = match log.since(from, STREAM_BATCH) {
+            Some(entries) => entries,
+            None => {
+                let message = format!("write {} is no longer in the log, take a snapshot", from);
+                let _ = tx.send(Err(Status::out_of_range(message))).await;
+                return;
+            }
+        };
+        if entries.is_empty() {
+            if tokio::time::timeout(HEARTBEAT_INTERVAL, appended)
+                .await
+                .is_err()
+            {
+                let heartbeat = LogEntry {
+                    leader_seq: log.last_seq(),
+                    ..LogEntry::default()
+                };
+                if tx.send(Ok(heartbeat)).await.is_err() {
+                    return;
+                }
+            }
+            continue;
+        }
+        let leader_seq = log.last_seq();
+        for (seq, op) in entries {
+            let entry = serde_cbor::to_vec(&op)
+                .map(|op| LogEntry {
+                    seq,
+                    op,
+                    leader_seq,
+                })
+                .map_err(|e| Status::internal(e.to_string()));
+            let failed = entry.is_err();
+            if tx.send(entry).await.is_err() || failed {
+                return;
+            }
+            from = seq + 1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Connecting,
+    Snapshot,
+    Streaming,
+    Disconnected,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Connecting => "connecting",
+            Phase::Snapshot => "snapshot",
+            Phase::Streaming => "streaming",
+            Phase::Disconnected => "disconnected",
+        };
+        f.write_str(name)
+    }
+}
+
+/// a collection copied from the same collection of a leader
+pub struct Follower {
+    /// gRPC endpoint of the leader, as http://host:port
+    pub leader: String,
+    /// seq of the last write of the leader applied
+    applied_seq: AtomicU64,
+    /// seq of the last write of the leader known to the follower
+    leader_seq: AtomicU64,
+    phase: Mutex<Phase>,
+    /// whether a snapshot of the collection of the leader was restored
+    copied: AtomicBool,
+    stopped: AtomicBool,
+    /// the task running the follower, aborted when it stops
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// where a follower stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FollowerStatus {
+    pub collection: String,
+    pub leader: String,
+    pub applied_seq: u64,
+    pub leader_seq: u64,
+    pub phase: Phase,
+    /// whether the collection holds a snapshot of the one of the leader
+    pub copied: bool,
+}
+
+impl FollowerStatus {
+    /// number of writes of the leader not applied yet
+    pub fn lag(&self) -> u64 {
+        self.leader_seq.saturating_sub(self.applied_seq)
+    }
+}
+
+impl Follower {
+    fn new(leader: &str) -> Self {
+        Follower {
+            leader: leader.to_string(),
+            applied_seq: AtomicU64::new(0),
+            leader_seq: AtomicU64::new(0),
+            phase: Mutex::new(Phase::Connecting),
+            copied: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            task: Mutex::new(None),
+        }
+    }
+
+    /// stops the follower, at once even when it waits for the leader
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(task) = self.task.lock().take() {
+            task.abort();
+        }
+    }
+
+    fn set_phase(&self, phase: Phase) {
+        *self.phase.lock() = phase;
+    }
+
+    /// follows the leader until stopped, reconnecting after failures
+    async fn run(self: Arc<Self>, api: Arc<VectorAPI>, collection: String) {
+        // the epoch of the log of the leader the copy comes from, None until a snapshot
+        let mut epoch = None;
+        while !self.stopped.load(Ordering::Relaxed) {
+            if let Err(status) = self.sync(&api, &collection, &mut epoch).await {
+                log::warn!(
+                    "replication of {} from {}: {}",
+                    collection,
+                    self.leader,
+                    status.message()
+                );
+                if status.code() == Code::OutOfRange {
+                    epoch = None;
+                }
+                self.set_phase(Phase::Disconnected);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+
+    /// takes a snapshot when epoch is None, then applies the writes streamed by the leader
+    async fn sync(
+        &self,
+        api: &VectorAPI,
+        collection: &str,
+        epoch: &mut Option<u64>,
+    ) -> Result<(), Status> {
+        self.set_phase(Phase::Connecting);
+        let channel = Endpoint::from_shared(self.leader.clone())
+            .map_err(|e| Status::invalid_argument(format!("invalid leader address: {}", e)))?
+            .connect()
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let mut client =
+            ReplicationServiceClient::new(channel).max_decoding_message_size(MAX_SNAPSHOT_BYTES);
+        let log_epoch = match *epoch {
+            Some(log_epoch) => log_epoch,
+            None => {
+                self.set_phase(Phase::Snapshot);
+                let request = SnapshotRequest {
+                    collection: collection.to_string(),
+                };
+                let snapshot = client.snapshot(request).await?.into_inner();
+                api.restore(collection, &snapshot.car)
+                    .map_err(|e| Status::internal(format!("snapshot not restored: {}", e)))?;
+                self.applied_seq.store(snapshot.seq, Ordering::Relaxed);
+                self.copied.store(true, Ordering::Relaxed);
+                self.leader_seq.fetch_max(snapshot.seq, Ordering::Relaxed);
+                *epoch = Some(snapshot.epoch);
+                snapshot.epoch
+            }
+        };
+        let request = ReplicateRequest {
+            collection: collection.to_string(),
+            epoch: log_epoch,
+            from_seq: self.applied_seq.load(Ordering::Relaxed) + 1,
+        };
+        let mut entries = client.replicate(request).await?.into_inner();
+        self.set_phase(Phase::Streaming);
+        while let Some(entry) = entries.message().await? {
+            if self.stopped.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            self.leader_seq.store(entry.leader_seq, Ordering::Relaxed);
+            if entry.op.is_empty() {
+                continue;
+            }
+            let op: WriteOp = serde_cbor::from_slice(&entry.op)
+                .map_err(|e| Status::internal(format!("invalid write {}: {}", entry.seq, e)))?;
+            api.replay(collection, op)
+                .map_err(|e| Status::internal(format!("write {} not applied: {}", entry.seq, e)))?;
+            self.applied_seq.store(entry.seq, Ordering::Relaxed);
+        }
+        // the leader closed the stream
+        Err(Status::unavailable("stream closed by the leader"))
+    }
+}
+
+/// the collections of this node which follow a leader
+#[derive(Default)]
+pub struct Replication {
+    followers: RwLock<HashMap<String, Arc<Follower>>>,
+}
+
+impl Replication {
+    /// starts copying collection from leader, the local collection of this name is replaced
+    /// by a snapshot of the one of the leader
+    pub fn follow(
+        &self,
+        api: Arc<VectorAPI>,
+        collection: &str,
+        leader: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if leader.is_empty() {
+            return Err("a leader is needed".into());
+        }
+        let name = default_name(collection);
+        let mut followers = self.followers.write();
+        if let Some(follower) = followers.get(name) {
+            return Err(format!("collection {} already follows {}", name, follower.leader).into());
+        }
+        let follower = Arc::new(Follower::new(leader));
+        followers.insert(name.to_string(), Arc::clone(&follower));
+        api.set_leader(name, Some(leader));
+        let task = tokio::spawn(Arc::clone(&follower).run(api, name.to_string()));
+        *follower.task.lock() = Some(task);
+        Ok(())
+    }
+
+    /// stops following the leader, the collection keeps its points and accepts writes again
+    pub fn unfollow(&self, api: &VectorAPI, collection: &str) -> Result<(), Box<dyn Error>> {
+        let name = default_name(collection);
+        let follower = self
+            .followers
+            .write()
+            .remove(name)
+            .ok_or_else(|| format!("collection {} does not follow a leader", name))?;
+        follower.stop();
+        api.set_leader(name, None);
+        Ok(())
+    }
+
+    /// the leader of collection, None when it does not follow one
+    pub fn leader(&self, collection: &str) -> Option<String> {
+        self.followers
+            .read()
+            .get(default_name(collection))
+            .map(|follower| follower.leader.clone())
+    }
+
+    /// where each follower stands, sorted by collection
+    pub fn status(&self) -> Vec<FollowerStatus> {
+        let mut status: Vec<FollowerStatus> = self
+            .followers
+            .read()
+            .iter()
+            .map(|(collection, follower)| FollowerStatus {
+                collection: collection.clone(),
+                leader: follower.leader.clone(),
+                applied_seq: follower.applied_seq.load(Ordering::Relaxed),
+                leader_seq: follower.leader_seq.load(Ordering::Relaxed),
+                phase: *follower.phase.lock(),
+                copied: follower.copied.load(Ordering::Relaxed),
+            })
+            .collect();
+        status.sort_by(|a, b| a.collection.cmp(&b.collection));
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::coordinator::Coordinator;
+    use crate::cluster::membership::{GossipConfig, Membership};
+    use crate::index::{IndexConfig, IndexKind};
+    use crate::interfaces::api::SearchOptions;
+    use crate::interfaces::grpc::{local_listener, serve_grpc};
+
+    #[test]
+    fn test_write_log() {
+        let log = WriteLog::default();
+        let delete = |id| move || WriteOp::Delete { ids: vec![id] };
+        // writes are numbered but not kept before a follower asks for them
+        assert_eq!(log.append(delete(0)), 1);
+        assert_eq!(log.since(1, 10), None);
+        assert_eq!(log.since(2, 10), Some(Vec::new()));
+        log.enable();
+        for id in 1..=LOG_CAPACITY {
+            log.append(delete(id));
+        }
+        assert_eq!(log.last_seq(), LOG_CAPACITY as u64 + 1);
+        assert_eq!(
+            log.since(2, 1).unwrap(),
+            vec![(2, WriteOp::Delete { ids: vec![1] })]
+        );
+        // the oldest write leaves the log when it is full
+        log.append(delete(0));
+        assert_eq!(log.since(2, 10), None);
+        assert_eq!(log.since(3, 10).unwrap()[0].0, 3);
+        assert_eq!(log.since(LOG_CAPACITY as u64 + 4, 10), None);
+    }
+
+    #[tokio::test]
+    async fn test_follower_catches_up() {
+        let config = IndexConfig {
+            kind: IndexKind::Flat,
+            ..IndexConfig::default()
+        };
+        let (listener, address) = local_listener().await;
+        let leader = Arc::new(VectorAPI::new(config.clone()));
+        let data: Vec<Vec<f32>> = (0..20).map(|i| vec![1., i as f32]).collect();
+        let points: Vec<(&Vec<f32>, DataId)> = data.iter().zip(0..).collect();
+        // written before the follower comes, it gets them from the snapshot
+        leader.parallel_insert("", &points[..10].to_vec()).unwrap();
+        let server = Arc::clone(&leader);
+        let membership = Arc::new(Membership::new(
+            &address,
+            Vec::new(),
+            GossipConfig::default(),
+        ));
+        tokio::spawn(async move {
+            let coordinator = Arc::new(Coordinator::default());
+            let replication = Arc::new(Replication::default());
+            serve_grpc(server, coordinator, replication, membership, listener)
+                .await
+                .unwrap()
+        });
+
+        let follower = Arc::new(VectorAPI::new(config));
+        let replication = Replication::default();
+        // the default collection is followed whether it is named or not
+        replication
+            .follow(Arc::clone(&follower), "", &address)
+            .unwrap();
+        assert!(replication
+            .follow(Arc::clone(&follower), "default", "http://other")
+            .is_err());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        leader.parallel_insert("", &points[10..].to_vec()).unwrap();
+        leader.delete("", &[0, 1]).unwrap();
+
+        let mut status = replication.status();
+        for _ in 0..50 {
+            if status[0].applied_seq == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            status = replication.status();
+        }
+        assert_eq!(status[0].phase, Phase::Streaming);
+        assert_eq!((status[0].applied_seq, status[0].lag()), (3, 0));
+        assert_eq!(follower.collection("").unwrap().stats().nb_point, 18);
+        let found = follower
+            .search("", &vec![vec![1., 15.]], 1, 10, &SearchOptions::default())
+            .unwrap();
+        assert_eq!(found[0][0].0.d_id, 15);
+        // only the leader writes to the collection
+        assert!(follower.parallel_insert("", &points[..1].to_vec()).is_err());
+        assert!(follower.delete("default", &[2]).is_err());
+
+        replication.unfollow(&follower, "default").unwrap();
+        assert!(replication.leader("").is_none());
+        follower.delete("default", &[2]).unwrap();
+
+        // a follower stopped at once leaves the collection to the one following next
+        replication
+            .follow(Arc::clone(&follower), "", &address)
+            .unwrap();
+        replication.unfollow(&follower, "").unwrap();
+        assert!(replication.status().is_empty());
+        assert!(replication.unfollow(&follower, "default").is_err());
+        follower.delete("", &[3]).unwrap();
+    }
+}
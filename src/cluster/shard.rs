@@ -0,0 +1,169 @@
+//! Assignment of the points of sharded collections to shards, and of shards to nodes.
+//!
+//! A point goes to the shard given by the jump consistent hash of its id, and a shard to
+//! the node of greatest rendezvous weight for the name of its collection. When a node joins
+//! or leaves, only the shards for which it is or was that node move.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hnsw_graph::hnsw::DataId;
+
+/// a part of a sharded collection, a collection of the node at address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    pub id: u32,
+    /// gRPC endpoint of the node, as http://host:port
+    pub address: String,
+    /// name of the collection of the shard on its node
+    pub collection: String,
+}
+
+/// name of the collection holding a shard of collection on its node, so that a node can
+/// hold several shards of a collection
+pub fn shard_collection(collection: &str, shard_id: u32) -> String {
+    format!("{}_shard{}", collection, shard_id)
+}
+
+/// the shards of each sharded collection
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShardMap {
+    collections: HashMap<String, Vec<Shard>>,
+}
+
+impl ShardMap {
+    /// splits collection into nb_shard shards placed on addresses by rendezvous hashing
+    pub fn assign(
+        &mut self,
+        collection: &str,
+        nb_shard: u32,
+        addresses: &[String],
+    ) -> Result<&[Shard], Box<dyn Error>> {
+        if addresses.is_empty() || nb_shard == 0 {
+            return Err("a sharded collection needs at least one shard and one node".into());
+        }
+        if self.collections.contains_key(collection) {
+            return Err(format!("collection {} is already sharded", collection).into());
+        }
+        let shards = (0..nb_shard)
+            .map(|id| {
+                let collection = shard_collection(collection, id);
+                Shard {
+                    id,
+                    address: owner(&collection, addresses).unwrap_or_default(),
+                    collection,
+                }
+            })
+            .collect();
+        Ok(self
+            .collections
+            .entry(collection.to_string())
+            .or_insert(shards))
+    }
+
+    pub fn remove(&mut self, collection: &str) -> Option<Vec<Shard>> {
+        self.collections.remove(collection)
+    }
+
+    /// moves a shard to the node at address, returns false for an unknown shard
+    pub fn move_shard(&mut self, collection: &str, shard_id: u32, address: &str) -> bool {
+        let shard = self
+            .collections
+            .get_mut(collection)
+            .and_then(|shards| shards.iter_mut().find(|shard| shard.id == shard_id));
+        match shard {
+            Some(shard) => {
+                shard.address = address.to_string();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn shards(&self, collection: &str) -> Option<&[Shard]> {
+        self.collections.get(collection).map(Vec::as_slice)
+    }
+
+    /// the shard holding d_id, by jump consistent hash of its id
+    pub fn shard_of(shards: &[Shard], d_id: DataId) -> &Shard {
+        &shards[jump_hash(mix(d_id as u64), shards.len()) as usize]
+    }
+
+    /// sharded collections, sorted by name
+    pub fn collections(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.collections.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+/// finalizer of splitmix64, spreads the bits of keys that differ little
+fn mix(key: u64) -> u64 {
+    let mut key = key;
+    key = (key ^ (key >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    key = (key ^ (key >> 27)).wrapping_mul(0x94d049bb133111eb);
+    key ^ (key >> 31)
+}
+
+/// 64 bits FNV-1a hash, stable across processes unlike the hasher of HashMap
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// the bucket of key among nb_bucket, which only changes for 1 / (nb_bucket + 1) of the keys
+/// when a bucket is added (Lamping and Veach)
+pub fn jump_hash(key: u64, nb_bucket: usize) -> u32 {
+    let mut key = key;
+    let mut bucket: i64 = -1;
+    let mut next: i64 = 0;
+    while next < nb_bucket as i64 {
+        bucket = next;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket.max(0) as u32
+}
+
+/// the node of greatest rendezvous weight for key, None without nodes
+pub fn owner(key: &str, nodes: &[String]) -> Option<String> {
+    nodes
+        .iter()
+        .max_by_key(|node| {
+            let weight = mix(fnv1a(key.as_bytes()) ^ mix(fnv1a(node.as_bytes())));
+            (weight, node.as_str())
+        })
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimal_movement() {
+        // adding a shard moves about 1 / nb_shard of the ids, all to the new shard
+        let moved: Vec<u64> = (0..10_000u64)
+            .filter(|id| jump_hash(mix(*id), 8) != jump_hash(mix(*id), 9))
+            .collect();
+        assert!(moved.len() > 800 && moved.len() < 1400);
+        assert!(moved.iter().all(|id| jump_hash(mix(*id), 9) == 8));
+
+        // adding a node only takes shards to it
+        let nodes: Vec<String> = (0..4).map(|i| format!("http://node{}:50051", i)).collect();
+        let more: Vec<String> = (0..5).map(|i| format!("http://node{}:50051", i)).collect();
+        let mut nb_moved = 0;
+        for shard in 0..64 {
+            let key = shard_collection("docs", shard);
+            let (before, after) = (owner(&key, &nodes).unwrap(), owner(&key, &more).unwrap());
+            if before != after {
+                assert_eq!(after, more[4]);
+                nb_moved += 1;
+            }
+        }
+        assert!(nb_moved > 0 && nb_moved < 32);
+    }
+}
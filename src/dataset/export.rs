@@ -0,0 +1,198 @@
+//! Writing of vectors and search results to files, for offline analysis.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
+
+use crate::dataset::npy;
+use crate::dataset::vecs::{self, VecsKind};
+use crate::dataset::VectorFormat;
+use crate::hnsw_graph::hnsw::{DataId, Neighbour};
+
+/// returns the path of the file holding the ids of vectors written to a npy or fvecs file.
+/// It has one id per line so that it can be given back to the loader as a sidecar.
+pub fn ids_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".ids");
+    PathBuf::from(name)
+}
+
+/// Writes (id, vector) pairs to a npy, fvecs or JSON Lines file.
+/// Formats without room for ids get a sidecar id file, see ids_path.
+pub struct VectorWriter {
+    format: VectorFormat,
+    out: BufWriter<File>,
+    ids: Option<BufWriter<File>>,
+    dimension: usize,
+    nb_expected: usize,
+    nb_written: usize,
+}
+
+impl VectorWriter {
+    /// nb_vector must be the exact number of vectors written, as npy files record it first
+    pub fn create(
+        path: &Path,
+        format: VectorFormat,
+        dimension: usize,
+        nb_vector: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let ids = match format {
+            VectorFormat::Npy => {
+                npy::write_f32_header(&mut out, nb_vector, dimension)?;
+                Some(BufWriter::new(File::create(ids_path(path))?))
+            }
+            VectorFormat::Fvecs => Some(BufWriter::new(File::create(ids_path(path))?)),
+            VectorFormat::JsonLines => None,
+            _ => return Err(format!("cannot export vectors as {:?}", format).into()),
+        };
+        Ok(VectorWriter {
+            format,
+            out,
+            ids,
+            dimension,
+            nb_expected: nb_vector,
+            nb_written: 0,
+        })
+    }
+
+    pub fn write(&mut self, id: DataId, vector: &[f32]) -> Result<(), Box<dyn Error>> {
+        if vector.len() != self.dimension {
+            return Err(format!(
+                "vector {} has dimension {}, expected {}",
+                id,
+                vector.len(),
+                self.dimension
+            )
+            .into());
+        }
+        match self.format {
+            VectorFormat::Npy => {
+                for x in vector {
+                    self.out.write_all(&x.to_le_bytes())?;
+                }
+            }
+            VectorFormat::Fvecs => vecs::write_vecs(&mut self.out, VecsKind::F32, vector)?,
+            _ => {
+                serde_json::to_writer(&mut self.out, &json!({ "id": id, "vector": vector }))?;
+                self.out.write_all(b"\n")?;
+            }
+        }
+        if let Some(ids) = self.ids.as_mut() {
+            writeln!(ids, "{}", id)?;
+        }
+        self.nb_written += 1;
+        Ok(())
+    }
+
+    /// flushes the files and returns the number of vectors written
+    pub fn finish(mut self) -> Result<usize, Box<dyn Error>> {
+        if self.format == VectorFormat::Npy && self.nb_written != self.nb_expected {
+            return Err(format!(
+                "npy header announces {} vectors, {} written",
+                self.nb_expected, self.nb_written
+            )
+            .into());
+        }
+        self.out.flush()?;
+        if let Some(ids) = self.ids.as_mut() {
+            ids.flush()?;
+        }
+        Ok(self.nb_written)
+    }
+}
+
+/// Writes the results of a batch of queries, one query after the other.
+/// `.ivecs` files get the neighbour ids only (the usual ground truth layout),
+/// JSON Lines files get `{"query": rank, "neighbours": [{"id", "distance"}]}`.
+pub fn write_search_results(path: &Path, results: &[Vec<Neighbour>]) -> Result<(), Box<dyn Error>> {
+    let format = VectorFormat::from_path(path);
+    let mut out = BufWriter::new(File::create(path)?);
+    for (rank, neighbours) in results.iter().enumerate() {
+        match format {
+            Some(VectorFormat::Ivecs) => {
+                let ids: Vec<f32> = neighbours.iter().map(|n| n.d_id as f32).collect();
+                vecs::write_vecs(&mut out, VecsKind::I32, &ids)?;
+            }
+            Some(VectorFormat::JsonLines) => {
+                let neighbours: Vec<_> = neighbours
+                    .iter()
+                    .map(|n| json!({ "id": n.d_id, "distance": n.distance }))
+                    .collect();
+                serde_json::to_writer(
+                    &mut out,
+                    &json!({ "query": rank, "neighbours": neighbours }),
+                )?;
+                out.write_all(b"\n")?;
+            }
+            _ => {
+                return Err(format!(
+                    "search results are written as .ivecs or .jsonl, not {}",
+                    path.display()
+                )
+                .into())
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::{read_batches, IdSource, LoadOptions};
+    use crate::hnsw_graph::hnsw::PointId;
+
+    #[test]
+    fn test_export_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let vectors: Vec<(DataId, Vec<f32>)> =
+            (0..20).map(|i| (100 + i, vec![i as f32, 1., 2.])).collect();
+        for format in [
+            VectorFormat::Npy,
+            VectorFormat::Fvecs,
+            VectorFormat::JsonLines,
+        ] {
+            let path = dir.path().join(format!("vectors.{:?}", format));
+            let mut writer = VectorWriter::create(&path, format, 3, vectors.len()).unwrap();
+            for (id, v) in &vectors {
+                writer.write(*id, v).unwrap();
+            }
+            assert_eq!(writer.finish().unwrap(), 20);
+
+            let ids = match format {
+                VectorFormat::JsonLines => IdSource::Column("id".to_string()),
+                _ => IdSource::Sidecar(ids_path(&path)),
+            };
+            let options = LoadOptions {
+                format: Some(format),
+                ids,
+                ..LoadOptions::default()
+            };
+            let reloaded: Vec<(DataId, Vec<f32>)> = read_batches(&path, &options)
+                .unwrap()
+                .flat_map(|b| b.unwrap())
+                .collect();
+            assert_eq!(reloaded, vectors);
+        }
+    }
+
+    #[test]
+    fn test_write_search_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let results = vec![vec![
+            Neighbour::new(3, 0.5, PointId(0, 1)),
+            Neighbour::new(7, 0.75, PointId(0, 2)),
+        ]];
+        let path = dir.path().join("results.jsonl");
+        write_search_results(&path, &results).unwrap();
+        let line = std::fs::read_to_string(&path).unwrap();
+        let value: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(value["neighbours"][1]["id"], 7);
+        assert!(write_search_results(&dir.path().join("results.txt"), &results).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+//! Bulk loading of vectors from standard file formats.
+//!
+//! Files are streamed: records are read lazily and grouped in batches so that a
+//! dataset larger than memory can be inserted with `Hnsw::parallel_insert_slice`.
+
+pub mod export;
+pub mod npy;
+pub mod text;
+pub mod vecs;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::hnsw_graph::dist::Distance;
+use crate::hnsw_graph::hnsw::{DataId, Hnsw};
+
+use self::npy::NpyReader;
+use self::text::{CsvReader, JsonLinesReader};
+use self::vecs::{VecsKind, VecsReader};
+
+/// A vector read from a file, with its id if the file provides one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub id: Option<DataId>,
+    pub vector: Vec<f32>,
+}
+
+pub type RecordIter = Box<dyn Iterator<Item = Result<Record, Box<dyn Error>>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorFormat {
+    Fvecs,
+    Ivecs,
+    Bvecs,
+    Npy,
+    JsonLines,
+    Csv,
+}
+
+impl VectorFormat {
+    /// guesses the format from the file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| e.parse().ok())
+    }
+}
+
+impl FromStr for VectorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fvecs" => Ok(VectorFormat::Fvecs),
+            "ivecs" => Ok(VectorFormat::Ivecs),
+            "bvecs" => Ok(VectorFormat::Bvecs),
+            "npy" => Ok(VectorFormat::Npy),
+            "jsonl" | "ndjson" => Ok(VectorFormat::JsonLines),
+            "csv" => Ok(VectorFormat::Csv),
+            _ => Err(format!("unknown vector format {}", s)),
+        }
+    }
+}
+
+/// Where the id of each vector comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdSource {
+    /// the rank of the vector in the file, starting at the given offset
+    RowNumber(DataId),
+    /// a field of JSON Lines records or a column of a CSV file with header
+    Column(String),
+    /// a text file with one id per line, in the order of the vectors
+    Sidecar(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// None to guess the format from the file extension
+    pub format: Option<VectorFormat>,
+    pub ids: IdSource,
+    /// number of vectors handed to each parallel insertion
+    pub batch_size: usize,
+    /// field holding the vector in JSON Lines objects
+    pub vector_field: String,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            format: None,
+            ids: IdSource::RowNumber(0),
+            batch_size: 10000,
+            vector_field: "vector".to_string(),
+        }
+    }
+}
+
+/// opens path and returns an iterator on its records
+pub fn open_records(path: &Path, options: &LoadOptions) -> Result<RecordIter, Box<dyn Error>> {
+    let format = match options.format {
+        Some(format) => format,
+        None => VectorFormat::from_path(path)
+            .ok_or_else(|| format!("cannot guess the format of {}", path.display()))?,
+    };
+    let id_column = match &options.ids {
+        IdSource::Column(column) => Some(column.as_str()),
+        _ => None,
+    };
+    if id_column.is_some() && !matches!(format, VectorFormat::JsonLines | VectorFormat::Csv) {
+        return Err(format!("{:?} files have no id column", format).into());
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let records: RecordIter = match format {
+        VectorFormat::Fvecs => Box::new(VecsReader::new(reader, VecsKind::F32)),
+        VectorFormat::Ivecs => Box::new(VecsReader::new(reader, VecsKind::I32)),
+        VectorFormat::Bvecs => Box::new(VecsReader::new(reader, VecsKind::U8)),
+        VectorFormat::Npy => Box::new(NpyReader::new(reader)?),
+        VectorFormat::JsonLines => Box::new(JsonLinesReader::new(
+            reader,
+            &options.vector_field,
+            id_column,
+        )),
+        VectorFormat::Csv => Box::new(CsvReader::new(reader, id_column)?),
+    };
+    Ok(records)
+}
+
+/// An iterator on batches of (id, vector) read from a file.
+/// All vectors must have the same dimension.
+pub struct Batches {
+    records: RecordIter,
+    ids: IdSource,
+    sidecar: Option<Lines<BufReader<File>>>,
+    batch_size: usize,
+    row: usize,
+    dimension: Option<usize>,
+    failed: bool,
+}
+
+impl Batches {
+    fn next_id(&mut self, record: &Record) -> Result<DataId, Box<dyn Error>> {
+        match &self.ids {
+            IdSource::RowNumber(offset) => Ok(offset + self.row),
+            IdSource::Column(column) => record
+                .id
+                .ok_or_else(|| format!("row {} has no {} id", self.row, column).into()),
+            IdSource::Sidecar(path) => match self.sidecar.as_mut().and_then(|l| l.next()) {
+                Some(line) => Ok(line?.trim().parse::<DataId>()?),
+                None => Err(format!("{} has fewer ids than vectors", path.display()).into()),
+            },
+        }
+    }
+
+    fn next_batch(&mut self) -> Result<Vec<(DataId, Vec<f32>)>, Box<dyn Error>> {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        while batch.len() < self.batch_size {
+            let record = match self.records.next() {
+                Some(record) => record?,
+                None => break,
+            };
+            let dimension = *self.dimension.get_or_insert(record.vector.len());
+            if record.vector.len() != dimension {
+                return Err(format!(
+                    "row {} has dimension {}, expected {}",
+                    self.row,
+                    record.vector.len(),
+                    dimension
+                )
+                .into());
+            }
+            let id = self.next_id(&record)?;
+            batch.push((id, record.vector));
+            self.row += 1;
+        }
+        Ok(batch)
+    }
+
+    /// number of vectors read so far
+    pub fn nb_read(&self) -> usize {
+        self.row
+    }
+}
+
+impl Iterator for Batches {
+    type Item = Result<Vec<(DataId, Vec<f32>)>, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.next_batch() {
+            Ok(batch) if batch.is_empty() => None,
+            Ok(batch) => Some(Ok(batch)),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// opens path and returns an iterator on batches of (id, vector)
+pub fn read_batches(path: &Path, options: &LoadOptions) -> Result<Batches, Box<dyn Error>> {
+    let sidecar = match &options.ids {
+        IdSource::Sidecar(ids_path) => Some(BufReader::new(File::open(ids_path)?).lines()),
+        _ => None,
+    };
+    Ok(Batches {
+        records: open_records(path, options)?,
+        ids: options.ids.clone(),
+        sidecar,
+        batch_size: options.batch_size.max(1),
+        row: 0,
+        dimension: None,
+        failed: false,
+    })
+}
+
+/// Progress of a bulk load, reported after each batch
+#[derive(Debug, Clone, Copy)]
+pub struct LoadProgress {
+    pub nb_loaded: usize,
+    pub elapsed: Duration,
+}
+
+impl LoadProgress {
+    /// vectors loaded per second
+    pub fn rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0. {
+            self.nb_loaded as f64 / secs
+        } else {
+            0.
+        }
+    }
+}
+
+/// streams the vectors of path into hnsw, calling progress after each batch.
+/// Returns the number of vectors inserted.
+pub fn bulk_load<D, F>(
+    hnsw: &Hnsw<f32, D>,
+    path: &Path,
+    options: &LoadOptions,
+    mut progress: F,
+) -> Result<usize, Box<dyn Error>>
+where
+    D: Distance<f32> + Send + Sync,
+    F: FnMut(&LoadProgress),
+{
+    let start = Instant::now();
+    let mut nb_loaded = 0;
+    for batch in read_batches(path, options)? {
+        let batch = batch?;
+        let data: Vec<(&[f32], usize)> = batch
+            .iter()
+            .map(|(id, vector)| (vector.as_slice(), *id))
+            .collect();
+        hnsw.parallel_insert_slice(&data);
+        nb_loaded += batch.len();
+        progress(&LoadProgress {
+            nb_loaded,
+            elapsed: start.elapsed(),
+        });
+    }
+    log::info!(
+        "bulk loaded {} vectors from {} in {:?}",
+        nb_loaded,
+        path.display(),
+        start.elapsed()
+    );
+    Ok(nb_loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnsw_graph::dist::DistCosine;
+    use std::io::Write;
+
+    #[test]
+    fn test_bulk_load_with_sidecar_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let vectors_path = dir.path().join("vectors.csv");
+        let ids_path = dir.path().join("ids.txt");
+        let mut vectors = File::create(&vectors_path).unwrap();
+        let mut ids = File::create(&ids_path).unwrap();
+        for i in 0..250 {
+            writeln!(vectors, "{},{},1", i, 250 - i).unwrap();
+            writeln!(ids, "{}", 1000 + i).unwrap();
+        }
+        drop(vectors);
+        drop(ids);
+
+        let options = LoadOptions {
+            ids: IdSource::Sidecar(ids_path),
+            batch_size: 100,
+            ..LoadOptions::default()
+        };
+        let batches: Vec<_> = read_batches(&vectors_path, &options)
+            .unwrap()
+            .map(|b| b.unwrap())
+            .collect();
+        assert_eq!(
+            batches.iter().map(|b| b.len()).collect::<Vec<_>>(),
+            vec![100, 100, 50]
+        );
+        assert_eq!(batches[2][49].0, 1249);
+
+        let hnsw = Hnsw::<f32, DistCosine>::new(16, 250, 16, 100, DistCosine {});
+        let mut nb_reports = 0;
+        let nb_loaded = bulk_load(&hnsw, &vectors_path, &options, |_| nb_reports += 1).unwrap();
+        assert_eq!(nb_loaded, 250);
+        assert_eq!(nb_reports, 3);
+        assert_eq!(hnsw.get_nb_point(), 250);
+        assert_eq!(hnsw.search(&[10., 240., 1.], 1, 50)[0].d_id, 1010);
+    }
+
+    #[test]
+    fn test_dimension_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vectors.jsonl");
+        std::fs::write(&path, "[1, 2]\n[1, 2, 3]\n").unwrap();
+        let results: Vec<_> = read_batches(&path, &LoadOptions::default())
+            .unwrap()
+            .collect();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+}
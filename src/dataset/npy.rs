@@ -0,0 +1,245 @@
+//! Reader and writer for NumPy `.npy` files holding a two dimensional array in C order,
+//! one vector per row (<https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html>).
+
+use std::error::Error;
+use std::io::{Read, Write};
+
+use crate::dataset::Record;
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// little endian element types we can convert to f32
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NpyDtype {
+    F4,
+    F8,
+    I4,
+    I8,
+    U1,
+}
+
+impl NpyDtype {
+    fn parse(descr: &str) -> Result<Self, Box<dyn Error>> {
+        match descr {
+            "<f4" => Ok(NpyDtype::F4),
+            "<f8" => Ok(NpyDtype::F8),
+            "<i4" => Ok(NpyDtype::I4),
+            "<i8" => Ok(NpyDtype::I8),
+            "|u1" | "<u1" => Ok(NpyDtype::U1),
+            _ => Err(format!("unsupported npy dtype {}", descr).into()),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            NpyDtype::F4 | NpyDtype::I4 => 4,
+            NpyDtype::F8 | NpyDtype::I8 => 8,
+            NpyDtype::U1 => 1,
+        }
+    }
+
+    fn to_f32(&self, bytes: &[u8]) -> f32 {
+        match self {
+            NpyDtype::F4 => f32::from_le_bytes(bytes.try_into().unwrap()),
+            NpyDtype::F8 => f64::from_le_bytes(bytes.try_into().unwrap()) as f32,
+            NpyDtype::I4 => i32::from_le_bytes(bytes.try_into().unwrap()) as f32,
+            NpyDtype::I8 => i64::from_le_bytes(bytes.try_into().unwrap()) as f32,
+            NpyDtype::U1 => bytes[0] as f32,
+        }
+    }
+}
+
+/// returns the text following `'key':` in the header dictionary
+fn header_value<'a>(header: &'a str, key: &str) -> Result<&'a str, Box<dyn Error>> {
+    let pattern = format!("'{}':", key);
+    let start = header
+        .find(&pattern)
+        .ok_or_else(|| format!("npy header has no {}", key))?;
+    Ok(header[start + pattern.len()..].trim_start())
+}
+
+/// parses the header dictionary, returns the dtype and the shape of the array
+pub(crate) fn parse_header(header: &str) -> Result<(NpyDtype, Vec<usize>), Box<dyn Error>> {
+    let descr = header_value(header, "descr")?;
+    let descr = descr
+        .strip_prefix('\'')
+        .and_then(|d| d.split('\'').next())
+        .ok_or("malformed npy descr")?;
+    let dtype = NpyDtype::parse(descr)?;
+    if header_value(header, "fortran_order")?.starts_with("True") {
+        return Err("fortran ordered npy arrays are not supported".into());
+    }
+    let shape = header_value(header, "shape")?;
+    let end = shape.find(')').ok_or("malformed npy shape")?;
+    let shape = shape[..end]
+        .trim_start_matches('(')
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()?;
+    Ok((dtype, shape))
+}
+
+/// writes the preamble and header of a version 1.0 npy file holding nb_row f32 vectors
+/// of dimension dim. The rows must follow as little endian f32.
+pub fn write_f32_header<W: Write>(
+    writer: &mut W,
+    nb_row: usize,
+    dim: usize,
+) -> std::io::Result<()> {
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        nb_row, dim
+    );
+    // the data must start on a 64 bytes boundary, the header ends with a newline
+    let preamble_len = MAGIC.len() + 4;
+    while (preamble_len + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[1u8, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())
+}
+
+pub struct NpyReader<R: Read> {
+    reader: R,
+    dtype: NpyDtype,
+    dim: usize,
+    nb_row: usize,
+    row: usize,
+}
+
+impl<R: Read> NpyReader<R> {
+    /// reads the header. The array must have shape (n, dim) or (dim,) for a single vector.
+    pub fn new(mut reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut preamble = [0u8; 8];
+        reader.read_exact(&mut preamble)?;
+        if &preamble[..6] != MAGIC {
+            return Err("not a npy file".into());
+        }
+        let header_len = match preamble[6] {
+            1 => {
+                let mut len = [0u8; 2];
+                reader.read_exact(&mut len)?;
+                u16::from_le_bytes(len) as usize
+            }
+            2 | 3 => {
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len)?;
+                u32::from_le_bytes(len) as usize
+            }
+            v => return Err(format!("unsupported npy version {}", v).into()),
+        };
+        let mut header = vec![0u8; header_len];
+        reader.read_exact(&mut header)?;
+        let (dtype, shape) = parse_header(&String::from_utf8(header)?)?;
+        let (nb_row, dim) = match shape.as_slice() {
+            [dim] => (1, *dim),
+            [nb_row, dim] => (*nb_row, *dim),
+            _ => {
+                return Err(format!("expected a 2 dimensional array, got shape {:?}", shape).into())
+            }
+        };
+        Ok(NpyReader {
+            reader,
+            dtype,
+            dim,
+            nb_row,
+            row: 0,
+        })
+    }
+
+    /// number of vectors in the file
+    pub fn len(&self) -> usize {
+        self.nb_row
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nb_row == 0
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dim
+    }
+}
+
+impl<R: Read> Iterator for NpyReader<R> {
+    type Item = Result<Record, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.row >= self.nb_row {
+            return None;
+        }
+        self.row += 1;
+        let size = self.dtype.size();
+        let mut bytes = vec![0u8; self.dim * size];
+        if let Err(e) = self.reader.read_exact(&mut bytes) {
+            return Some(Err(e.into()));
+        }
+        let vector = bytes
+            .chunks_exact(size)
+            .map(|c| self.dtype.to_f32(c))
+            .collect();
+        Some(Ok(Record { id: None, vector }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn npy_bytes(header: &str, data: &[u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([1u8, 0]);
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let (dtype, shape) =
+            parse_header("{'descr': '<f4', 'fortran_order': False, 'shape': (10, 128), }").unwrap();
+        assert_eq!(dtype, NpyDtype::F4);
+        assert_eq!(shape, vec![10, 128]);
+        assert!(
+            parse_header("{'descr': '<f4', 'fortran_order': True, 'shape': (10, 128), }").is_err()
+        );
+        assert!(
+            parse_header("{'descr': '<c8', 'fortran_order': False, 'shape': (10, 128), }").is_err()
+        );
+    }
+
+    #[test]
+    fn test_read_npy() {
+        let mut data = Vec::new();
+        for x in [1.0f64, 2.0, 3.0, 4.0, 5.0, 6.0] {
+            data.extend(x.to_le_bytes());
+        }
+        let bytes = npy_bytes(
+            "{'descr': '<f8', 'fortran_order': False, 'shape': (3, 2), }\n",
+            &data,
+        );
+        let reader = NpyReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.len(), 3);
+        assert_eq!(reader.dimension(), 2);
+        let vectors: Vec<Vec<f32>> = reader.map(|r| r.unwrap().vector).collect();
+        assert_eq!(vectors, vec![vec![1., 2.], vec![3., 4.], vec![5., 6.]]);
+
+        let mut bytes = Vec::new();
+        write_f32_header(&mut bytes, 2, 2).unwrap();
+        assert_eq!(bytes.len() % 64, 0);
+        for x in [1.0f32, 2.0, 3.0, 4.0] {
+            bytes.extend(x.to_le_bytes());
+        }
+        let reader = NpyReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.dimension(), 2);
+        let vectors: Vec<Vec<f32>> = reader.map(|r| r.unwrap().vector).collect();
+        assert_eq!(vectors, vec![vec![1., 2.], vec![3., 4.]]);
+    }
+}
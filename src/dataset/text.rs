@@ -0,0 +1,214 @@
+//! Readers for text formats: JSON Lines and CSV.
+
+use std::error::Error;
+use std::io::{BufRead, Lines};
+
+use serde_json::Value;
+
+use crate::dataset::Record;
+use crate::hnsw_graph::hnsw::DataId;
+
+fn json_to_id(value: &Value) -> Result<DataId, Box<dyn Error>> {
+    match value {
+        Value::Number(n) => n
+            .as_u64()
+            .map(|id| id as DataId)
+            .ok_or_else(|| format!("id {} is not a positive integer", n).into()),
+        Value::String(s) => Ok(s.trim().parse::<DataId>()?),
+        _ => Err(format!("id {} is not an integer", value).into()),
+    }
+}
+
+fn json_to_vector(value: &Value) -> Result<Vec<f32>, Box<dyn Error>> {
+    value
+        .as_array()
+        .ok_or("vector is not a JSON array")?
+        .iter()
+        .map(|x| {
+            x.as_f64()
+                .map(|x| x as f32)
+                .ok_or_else(|| format!("{} is not a number", x).into())
+        })
+        .collect()
+}
+
+/// Reads one JSON value per line. A line is either an array of numbers or an object
+/// holding the vector in vector_field and possibly an id in id_field.
+pub struct JsonLinesReader<R: BufRead> {
+    lines: Lines<R>,
+    vector_field: String,
+    id_field: Option<String>,
+}
+
+impl<R: BufRead> JsonLinesReader<R> {
+    pub fn new(reader: R, vector_field: &str, id_field: Option<&str>) -> Self {
+        JsonLinesReader {
+            lines: reader.lines(),
+            vector_field: vector_field.to_string(),
+            id_field: id_field.map(|f| f.to_string()),
+        }
+    }
+
+    fn parse(&self, line: &str) -> Result<Record, Box<dyn Error>> {
+        let value: Value = serde_json::from_str(line)?;
+        if value.is_array() {
+            return Ok(Record {
+                id: None,
+                vector: json_to_vector(&value)?,
+            });
+        }
+        let vector = value
+            .get(&self.vector_field)
+            .ok_or_else(|| format!("no field {} in line", self.vector_field))?;
+        let id = match &self.id_field {
+            Some(field) => Some(json_to_id(
+                value
+                    .get(field)
+                    .ok_or_else(|| format!("no field {} in line", field))?,
+            )?),
+            None => None,
+        };
+        Ok(Record {
+            id,
+            vector: json_to_vector(vector)?,
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for JsonLinesReader<R> {
+    type Item = Result<Record, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if !line.trim().is_empty() {
+                return Some(self.parse(&line));
+            }
+        }
+    }
+}
+
+/// Reads comma separated numbers, one vector per line.
+/// The first line is a header if one of its cells is not a number. A header is required
+/// to take ids from a column, every other column is a component of the vector.
+pub struct CsvReader<R: BufRead> {
+    lines: Lines<R>,
+    id_column: Option<usize>,
+    /// first data line, read while looking for a header
+    pending: Option<String>,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    pub fn new(reader: R, id_column: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let mut lines = reader.lines();
+        let first = match lines.next() {
+            Some(line) => line?,
+            None => {
+                return Ok(CsvReader {
+                    lines,
+                    id_column: None,
+                    pending: None,
+                })
+            }
+        };
+        let cells: Vec<&str> = first.split(',').map(|c| c.trim()).collect();
+        let is_header = cells.iter().any(|c| c.parse::<f32>().is_err());
+        let id_column = match id_column {
+            Some(name) if is_header => Some(
+                cells
+                    .iter()
+                    .position(|c| *c == name)
+                    .ok_or_else(|| format!("no column {} in CSV header", name))?,
+            ),
+            Some(name) => {
+                return Err(format!("CSV file has no header to find column {}", name).into())
+            }
+            None => None,
+        };
+        let pending = if is_header { None } else { Some(first) };
+        Ok(CsvReader {
+            lines,
+            id_column,
+            pending,
+        })
+    }
+
+    fn parse(&self, line: &str) -> Result<Record, Box<dyn Error>> {
+        let mut id = None;
+        let mut vector = Vec::new();
+        for (i, cell) in line.split(',').map(|c| c.trim()).enumerate() {
+            if Some(i) == self.id_column {
+                id = Some(cell.parse::<DataId>()?);
+            } else {
+                vector.push(cell.parse::<f32>()?);
+            }
+        }
+        Ok(Record { id, vector })
+    }
+}
+
+impl<R: BufRead> Iterator for CsvReader<R> {
+    type Item = Result<Record, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.pending.take() {
+                Some(line) => line,
+                None => match self.lines.next()? {
+                    Ok(line) => line,
+                    Err(e) => return Some(Err(e.into())),
+                },
+            };
+            if !line.trim().is_empty() {
+                return Some(self.parse(&line));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_json_lines() {
+        let input = "{\"id\": 7, \"vector\": [1, 2.5]}\n\n{\"id\": \"8\", \"vector\": [3, 4]}\n";
+        let records: Vec<Record> = JsonLinesReader::new(Cursor::new(input), "vector", Some("id"))
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, Some(7));
+        assert_eq!(records[0].vector, vec![1., 2.5]);
+        assert_eq!(records[1].id, Some(8));
+
+        let records: Vec<Record> = JsonLinesReader::new(Cursor::new("[0.5, 1]\n"), "vector", None)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(records[0].vector, vec![0.5, 1.]);
+        assert_eq!(records[0].id, None);
+    }
+
+    #[test]
+    fn test_csv() {
+        let input = "x,key,y\n1.0,10,2.0\n3.0,11,4.0\n";
+        let records: Vec<Record> = CsvReader::new(Cursor::new(input), Some("key"))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].id, Some(11));
+        assert_eq!(records[1].vector, vec![3., 4.]);
+
+        // without header the first line is data
+        let records: Vec<Record> = CsvReader::new(Cursor::new("1,2\n3,4\n"), None)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert!(CsvReader::new(Cursor::new("1,2\n"), Some("key")).is_err());
+    }
+}
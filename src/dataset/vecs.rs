@@ -0,0 +1,159 @@
+//! Readers and writer for the `.fvecs`, `.ivecs` and `.bvecs` formats of the TEXMEX corpus.
+//! Each vector is stored as its dimension (little endian i32) followed by its components
+//! as f32, i32 or u8.
+
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+
+use crate::dataset::Record;
+
+/// largest dimension read, well above the ones of embedding models
+const MAX_DIMENSION: i32 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VecsKind {
+    F32,
+    I32,
+    U8,
+}
+
+impl VecsKind {
+    fn component_size(&self) -> usize {
+        match self {
+            VecsKind::F32 | VecsKind::I32 => 4,
+            VecsKind::U8 => 1,
+        }
+    }
+}
+
+/// fills buf, returns false if the stream was at its end before the first byte
+pub(crate) fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "truncated vector",
+                ))
+            }
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// writes one vector in the layout of kind
+pub fn write_vecs<W: Write>(writer: &mut W, kind: VecsKind, vector: &[f32]) -> std::io::Result<()> {
+    writer.write_all(&(vector.len() as i32).to_le_bytes())?;
+    for x in vector {
+        match kind {
+            VecsKind::F32 => writer.write_all(&x.to_le_bytes())?,
+            VecsKind::I32 => writer.write_all(&(*x as i32).to_le_bytes())?,
+            VecsKind::U8 => writer.write_all(&[*x as u8])?,
+        }
+    }
+    Ok(())
+}
+
+pub struct VecsReader<R: Read> {
+    reader: R,
+    kind: VecsKind,
+}
+
+impl<R: Read> VecsReader<R> {
+    pub fn new(reader: R, kind: VecsKind) -> Self {
+        VecsReader { reader, kind }
+    }
+
+    fn read_vector(&mut self) -> Result<Option<Vec<f32>>, Box<dyn Error>> {
+        let mut dim_bytes = [0u8; 4];
+        if !read_exact_or_eof(&mut self.reader, &mut dim_bytes)? {
+            return Ok(None);
+        }
+        let dim = i32::from_le_bytes(dim_bytes);
+        if dim <= 0 || dim > MAX_DIMENSION {
+            return Err(format!("invalid vector dimension {}", dim).into());
+        }
+        let mut bytes = vec![0u8; dim as usize * self.kind.component_size()];
+        self.reader.read_exact(&mut bytes)?;
+        let vector = match self.kind {
+            VecsKind::F32 => bytes
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect(),
+            VecsKind::I32 => bytes
+                .chunks_exact(4)
+                .map(|c| i32::from_le_bytes([c[0], c[1], c[2], c[3]]) as f32)
+                .collect(),
+            VecsKind::U8 => bytes.iter().map(|b| *b as f32).collect(),
+        };
+        Ok(Some(vector))
+    }
+}
+
+impl<R: Read> Iterator for VecsReader<R> {
+    type Item = Result<Record, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_vector() {
+            Ok(Some(vector)) => Some(Ok(Record { id: None, vector })),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_fvecs_and_bvecs() {
+        let mut fvecs = Vec::new();
+        for v in [[1.0f32, 2.0, 3.0], [4.0, 5.0, 6.0]] {
+            fvecs.extend(3i32.to_le_bytes());
+            for x in v {
+                fvecs.extend(x.to_le_bytes());
+            }
+        }
+        let vectors: Vec<Vec<f32>> = VecsReader::new(Cursor::new(fvecs), VecsKind::F32)
+            .map(|r| r.unwrap().vector)
+            .collect();
+        assert_eq!(vectors, vec![vec![1., 2., 3.], vec![4., 5., 6.]]);
+
+        let mut bvecs = Vec::new();
+        bvecs.extend(2i32.to_le_bytes());
+        bvecs.extend([7u8, 255]);
+        let vectors: Vec<Vec<f32>> = VecsReader::new(Cursor::new(bvecs), VecsKind::U8)
+            .map(|r| r.unwrap().vector)
+            .collect();
+        assert_eq!(vectors, vec![vec![7., 255.]]);
+
+        let mut ivecs = Vec::new();
+        write_vecs(&mut ivecs, VecsKind::I32, &[3., 1., 4.]).unwrap();
+        let vectors: Vec<Vec<f32>> = VecsReader::new(Cursor::new(ivecs), VecsKind::I32)
+            .map(|r| r.unwrap().vector)
+            .collect();
+        assert_eq!(vectors, vec![vec![3., 1., 4.]]);
+
+        // a header announcing a huge dimension is not allocated
+        let mut huge = Vec::new();
+        huge.extend(i32::MAX.to_le_bytes());
+        assert!(VecsReader::new(Cursor::new(huge), VecsKind::F32)
+            .next()
+            .unwrap()
+            .is_err());
+
+        // a truncated vector is an error, not the end of the file
+        let mut truncated = Vec::new();
+        truncated.extend(3i32.to_le_bytes());
+        truncated.extend(1.0f32.to_le_bytes());
+        let mut reader = VecsReader::new(Cursor::new(truncated), VecsKind::F32);
+        assert!(reader.next().unwrap().is_err());
+    }
+}
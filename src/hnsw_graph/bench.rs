@@ -0,0 +1,353 @@
+//! Benchmark of Hnsw: build time, memory, and recall@k and queries per second at several ef.
+//! Exact neighbours are computed by brute force over the indexed points.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+use std::time::Instant;
+
+use cpu_time::ProcessTime;
+use prettytable::{format, row, Table};
+use rand::distributions::Uniform;
+use rand::prelude::*;
+use rayon::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::dataset::{read_batches, LoadOptions};
+use crate::hnsw_graph::dist::Distance;
+use crate::hnsw_graph::hnsw::{
+    DataId, Hnsw, Neighbour, PointDistance, PointId, PointIdWithOrder, PointIndexation,
+};
+use crate::index::{nearest, AnnIndex};
+
+pub fn gen_random_vector_f32(nbrow: usize) -> Vec<f32> {
+    let mut rng = thread_rng();
+    let unif = Uniform::<f32>::new(0., 1.);
+    (0..nbrow).map(|_| rng.sample(unif)).collect()
+}
+
+/// return nbcolumn vectors of dimension nbrow
+pub fn gen_random_matrix_f32(nbrow: usize, nbcolumn: usize) -> Vec<Vec<f32>> {
+    let mut rng = thread_rng();
+    let unif = Uniform::<f32>::new(0., 1.);
+    (0..nbcolumn)
+        .map(|_| (0..nbrow).map(|_| rng.sample(unif)).collect())
+        .collect()
+}
+
+/// returns the nb_neighbours points of refdata nearest to data, sorted by increasing distance.
+/// Only the nb_neighbours nearest points seen so far are kept.
+pub fn brute_force_neighbours<T: Serialize + DeserializeOwned + Copy + Send + Sync>(
+    nb_neighbours: usize,
+    refdata: &PointIndexation<T>,
+    distance: PointDistance<T>,
+    data: &[T],
+) -> Vec<PointIdWithOrder> {
+    let candidates = refdata.into_iter().map(|point| {
+        let dist_p = distance.eval(data, point.get_v());
+        Neighbour::new(point.get_origin_id(), dist_p, point.get_point_id())
+    });
+    nearest(candidates, nb_neighbours)
+        .into_iter()
+        .map(|n| PointIdWithOrder::new(n.p_id, n.distance))
+        .collect()
+}
+
+/// reads all the vectors of a dataset file, in any format the loader knows
+pub fn read_vectors(path: &Path) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+    let mut vectors = Vec::new();
+    for batch in read_batches(path, &LoadOptions::default())? {
+        vectors.extend(batch?.into_iter().map(|(_, v)| v));
+    }
+    Ok(vectors)
+}
+
+/// exact neighbours of each query among the points of hnsw, by increasing distance
+pub fn exact_neighbours<D>(
+    hnsw: &Hnsw<f32, D>,
+    queries: &[Vec<f32>],
+    knbn: usize,
+) -> Vec<Vec<PointId>>
+where
+    D: Distance<f32> + Default + Send + Sync + 'static,
+{
+    if hnsw.get_nb_point() == 0 {
+        return queries.iter().map(|_| Vec::new()).collect();
+    }
+    queries
+        .par_iter()
+        .map(|q| {
+            let neighbours = brute_force_neighbours(
+                knbn,
+                hnsw.get_point_indexation(),
+                Box::new(D::default()),
+                q,
+            );
+            neighbours.iter().map(|n| n.point_id).collect()
+        })
+        .collect()
+}
+
+/// returns copies of at most nb_sample vectors of index, chosen uniformly
+pub fn sample_vectors(
+    index: &dyn AnnIndex,
+    nb_sample: usize,
+) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+    let mut rng = thread_rng();
+    let mut sample = Vec::with_capacity(nb_sample);
+    let mut nb_seen = 0;
+    // reservoir sampling
+    index.scan(&mut |_, v| {
+        nb_seen += 1;
+        if sample.len() < nb_sample {
+            sample.push(v.to_vec());
+        } else {
+            let slot = rng.gen_range(0..nb_seen);
+            if slot < nb_sample {
+                sample[slot] = v.to_vec();
+            }
+        }
+        Ok(())
+    })?;
+    Ok(sample)
+}
+
+/// recall of searches against exact searches over a set of queries
+#[derive(Debug, Clone, Serialize)]
+pub struct RecallEstimate {
+    pub nb_query: usize,
+    pub recall: f32,
+    /// missed_by_rank[r] is the number of queries whose exact neighbour of rank r was not found
+    pub missed_by_rank: Vec<usize>,
+}
+
+/// compares the knbn neighbours found by index at ef with the exact ones
+pub fn estimate_recall(
+    index: &dyn AnnIndex,
+    queries: &[Vec<f32>],
+    knbn: usize,
+    ef: usize,
+) -> RecallEstimate {
+    let results: Vec<(Vec<Neighbour>, Vec<Neighbour>)> = queries
+        .par_iter()
+        .map(|query| {
+            (
+                index.exact_search(query, knbn),
+                index.search(query, knbn, ef),
+            )
+        })
+        .collect();
+    let mut missed_by_rank = vec![0; knbn];
+    let mut nb_expected = 0;
+    let mut nb_found = 0;
+    for (exact, found) in &results {
+        let found: HashSet<DataId> = found.iter().map(|n| n.d_id).collect();
+        nb_expected += exact.len();
+        for (rank, neighbour) in exact.iter().enumerate() {
+            if found.contains(&neighbour.d_id) {
+                nb_found += 1;
+            } else {
+                missed_by_rank[rank] += 1;
+            }
+        }
+    }
+    RecallEstimate {
+        nb_query: queries.len(),
+        recall: if nb_expected > 0 {
+            nb_found as f32 / nb_expected as f32
+        } else {
+            1.
+        },
+        missed_by_rank,
+    }
+}
+
+/// resident set size of the process in bytes, None where /proc is not available
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+/// Hnsw parameters and search settings of a benchmark
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchConfig {
+    pub max_nb_connection: usize,
+    pub ef_construction: usize,
+    pub max_layer: usize,
+    pub knbn: usize,
+    pub ef_values: Vec<usize>,
+}
+
+/// search results at one ef
+#[derive(Debug, Clone, Serialize)]
+pub struct EfRun {
+    pub ef: usize,
+    /// mean fraction of the knbn exact neighbours found
+    pub recall: f32,
+    /// serial queries per second
+    pub qps: f64,
+    pub mean_latency_us: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub config: BenchConfig,
+    pub nb_point: usize,
+    pub dimension: usize,
+    pub nb_query: usize,
+    pub build_time_s: f64,
+    pub build_cpu_time_s: f64,
+    /// growth of the resident memory during the build, if it can be measured
+    pub memory_bytes: Option<u64>,
+    pub runs: Vec<EfRun>,
+}
+
+impl BenchReport {
+    pub fn print_table(&self) {
+        println!(
+            "{} points of dimension {}, max_nb_connection {}, ef_construction {}",
+            self.nb_point,
+            self.dimension,
+            self.config.max_nb_connection,
+            self.config.ef_construction
+        );
+        println!(
+            "build time {:.2}s (cpu {:.2}s), memory {}",
+            self.build_time_s,
+            self.build_cpu_time_s,
+            match self.memory_bytes {
+                Some(bytes) => format!("{:.1} MiB", bytes as f64 / (1024. * 1024.)),
+                None => "unknown".to_string(),
+            }
+        );
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+        table.set_titles(row![
+            "ef",
+            format!("recall@{}", self.config.knbn),
+            "QPS",
+            "latency (us)"
+        ]);
+        for run in &self.runs {
+            table.add_row(row![
+                run.ef,
+                format!("{:.4}", run.recall),
+                format!("{:.0}", run.qps),
+                format!("{:.1}", run.mean_latency_us)
+            ]);
+        }
+        table.printstd();
+    }
+}
+
+/// builds a Hnsw over data with the distance D, then runs the queries at each ef of config
+pub fn run_bench<D>(data: &[Vec<f32>], queries: &[Vec<f32>], config: &BenchConfig) -> BenchReport
+where
+    D: Distance<f32> + Default + Send + Sync + 'static,
+{
+    let memory_before = resident_memory();
+    let cpu_start = ProcessTime::now();
+    let start = Instant::now();
+    let hnsw = Hnsw::<f32, D>::new(
+        config.max_nb_connection,
+        data.len(),
+        config.max_layer,
+        config.ef_construction,
+        D::default(),
+    );
+    let data_with_id: Vec<(&[f32], usize)> = data
+        .iter()
+        .enumerate()
+        .map(|(i, v)| (v.as_slice(), i))
+        .collect();
+    hnsw.parallel_insert_slice(&data_with_id);
+    let build_time_s = start.elapsed().as_secs_f64();
+    let build_cpu_time_s = cpu_start.elapsed().as_secs_f64();
+    let memory_bytes = match (memory_before, resident_memory()) {
+        (Some(before), Some(after)) => Some(after.saturating_sub(before)),
+        _ => None,
+    };
+    log::info!("bench index built in {:.2}s", build_time_s);
+
+    let knbn = config.knbn;
+    let exact: Vec<HashSet<PointId>> = exact_neighbours(&hnsw, queries, knbn)
+        .into_iter()
+        .map(|neighbours| neighbours.into_iter().collect())
+        .collect();
+
+    let mut runs = Vec::with_capacity(config.ef_values.len());
+    for &ef in &config.ef_values {
+        let mut nb_found = 0;
+        let start = Instant::now();
+        for (query, exact) in queries.iter().zip(exact.iter()) {
+            let neighbours = hnsw.search(query, knbn, ef);
+            nb_found += neighbours
+                .iter()
+                .filter(|n| exact.contains(&n.p_id))
+                .count();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        let nb_query = queries.len().max(1) as f64;
+        runs.push(EfRun {
+            ef,
+            recall: nb_found as f32 / (queries.len().max(1) * knbn) as f32,
+            qps: if elapsed > 0. { nb_query / elapsed } else { 0. },
+            mean_latency_us: elapsed * 1.0e6 / nb_query,
+        });
+    }
+
+    BenchReport {
+        config: config.clone(),
+        nb_point: hnsw.get_nb_point(),
+        dimension: data.first().map_or(0, |v| v.len()),
+        nb_query: queries.len(),
+        build_time_s,
+        build_cpu_time_s,
+        memory_bytes,
+        runs,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnsw_graph::dist::DistCosine;
+
+    #[test]
+    fn test_run_bench() {
+        let data = gen_random_matrix_f32(10, 2000);
+        let queries = gen_random_matrix_f32(10, 20);
+        let config = BenchConfig {
+            max_nb_connection: 16,
+            ef_construction: 200,
+            max_layer: 16,
+            knbn: 10,
+            ef_values: vec![10, 100],
+        };
+        let report = run_bench::<DistCosine>(&data, &queries, &config);
+        assert_eq!(report.nb_point, 2000);
+        assert_eq!(report.runs.len(), 2);
+        // a larger ef cannot do much worse
+        assert!(report.runs[1].recall > 0.8);
+        assert!(report.runs[1].recall + 0.05 >= report.runs[0].recall);
+    }
+
+    #[test]
+    fn test_estimate_recall() {
+        let data = gen_random_matrix_f32(10, 1000);
+        let hnsw = Hnsw::<f32, DistCosine>::new(16, data.len(), 16, 200, DistCosine {});
+        let data_with_id = data.iter().zip(0..data.len()).collect();
+        hnsw.parallel_insert(&data_with_id);
+
+        let queries = sample_vectors(&hnsw, 50).unwrap();
+        assert_eq!(queries.len(), 50);
+        let estimate = estimate_recall(&hnsw, &queries, 10, 100);
+        assert_eq!(estimate.nb_query, 50);
+        assert_eq!(estimate.missed_by_rank.len(), 10);
+        assert!(estimate.recall > 0.8);
+        let nb_missed: usize = estimate.missed_by_rank.iter().sum();
+        assert!((1. - nb_missed as f32 / 500. - estimate.recall).abs() < 1.0e-4);
+    }
+}
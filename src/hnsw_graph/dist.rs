@@ -37,7 +37,8 @@ impl Distance<f32> for DistCosine {
             });
 
         if res.1 > zero && res.2 > zero {
-            1. - res.0 / (res.1 * res.2).sqrt()
+            // rounding takes parallel vectors slightly below 0
+            (1. - res.0 / (res.1 * res.2).sqrt()).max(zero)
         } else {
             0.
         }
@@ -60,6 +61,20 @@ impl Distance<f32> for DistDot {
     }
 }
 
+/// Euclidean distance
+#[derive(Default)]
+pub struct DistL2;
+
+impl Distance<f32> for DistL2 {
+    fn eval(&self, va: &[f32], vb: &[f32]) -> f32 {
+        va.iter()
+            .zip(vb.iter())
+            .map(|t| (*t.0 - *t.1) * (*t.0 - *t.1))
+            .sum::<f32>()
+            .sqrt()
+    }
+}
+
 /// This structure is to let user define their own distance with closures.
 pub struct DistFn<T: Copy + Clone + Sized + Send + Sync> {
     dist_function: Box<dyn Fn(&[T], &[T]) -> f32 + Send + Sync>,
@@ -1,6 +1,7 @@
 use crate::hnsw_graph::dist::Distance;
+use crate::hnsw_graph::mmap::PointData;
 use cpu_time::ProcessTime;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use parking_lot::{Mutex, RwLock, RwLockReadGuard};
 use rayon::prelude::*;
 use std::cmp::Ordering;
@@ -33,6 +34,9 @@ pub struct PointId(pub u8, pub i32);
 /// TODO : change to CID
 pub type DataId = usize;
 
+/// predicate on the ids of points, true for the points a filtered search may return
+pub type IdFilter<'a> = dyn Fn(DataId) -> bool + Sync + 'a;
+
 pub type PointDistance<T> = Box<dyn Distance<T>>;
 
 /// A structure containing internal pointId with distance to this pointId.
@@ -115,7 +119,8 @@ impl Neighbour {
 #[derive(Debug, Clone)]
 pub struct Point<T: Clone + Send + Sync> {
     /// The data of this point, coming from hnsw client and associated to origin_id,
-    v: Vec<T>,
+    /// in RAM or mapped from a file
+    v: PointData<T>,
     /// an id coming from client using hnsw, should identify point uniquely
     origin_id: DataId,
     /// a point id identifying point as stored in our structure
@@ -127,13 +132,18 @@ pub struct Point<T: Clone + Send + Sync> {
 
 impl<T: Clone + Send + Sync> Point<T> {
     pub fn new(v: &[T], origin_id: usize, p_id: PointId) -> Self {
+        Point::with_data(PointData::from(v.to_vec()), origin_id, p_id)
+    }
+
+    /// a point whose vector is already stored, possibly in a mapped file
+    pub fn with_data(v: PointData<T>, origin_id: usize, p_id: PointId) -> Self {
         let mut neighbours = Vec::with_capacity(NB_LAYER_MAX as usize);
         // CAVEAT, perhaps pass nb layer as arg ?
         for _ in 0..NB_LAYER_MAX {
             neighbours.push(Vec::<Arc<PointWithOrder<T>>>::new());
         }
         Point {
-            v: v.to_vec(),
+            v,
             origin_id,
             p_id,
             neighbours: Arc::new(RwLock::new(neighbours)),
@@ -142,7 +152,12 @@ impl<T: Clone + Send + Sync> Point<T> {
 
     /// get a reference to vector data
     pub fn get_v(&self) -> &[T] {
-        &self.v.as_slice()
+        &self.v
+    }
+
+    /// true if the vector is read in place from a mapped file
+    pub fn is_mapped(&self) -> bool {
+        self.v.is_mapped()
     }
 
     /// return coordinates in indexation
@@ -204,9 +219,9 @@ impl<T: Clone + Send + Sync> Point<T> {
 pub(crate) struct PointWithOrder<T: Clone + Send + Sync> {
     /// the identifier of the point for which we store a distance to a point for which
     ///  we made a request.
-    point_ref: Arc<Point<T>>,
+    pub(crate) point_ref: Arc<Point<T>>,
     /// The distance to a point_ref to the request point (not represented in the structure)
-    dist_to_ref: f32,
+    pub(crate) dist_to_ref: f32,
 }
 
 impl<T: Clone + Send + Sync> PartialEq for PointWithOrder<T> {
@@ -312,6 +327,8 @@ pub struct PointIndexation<T: Clone + Send + Sync> {
     pub(crate) nb_point: Arc<RwLock<usize>>,
     /// curent enter_point: an Arc RwLock on a possible Arc Point
     pub(crate) entry_point: Arc<RwLock<Option<Arc<Point<T>>>>>,
+    /// ids of the points stored for each origin id
+    pub(crate) origins: RwLock<HashMap<DataId, Vec<PointId>>>,
 }
 
 // A point indexation may contain circular references. To deallocate these after a point indexation goes out of scope,
@@ -383,6 +400,7 @@ impl<T: Clone + Send + Sync> PointIndexation<T> {
             layer_g,
             nb_point: Arc::new(RwLock::new(0)),
             entry_point: Arc::new(RwLock::new(None)),
+            origins: RwLock::new(HashMap::new()),
         }
     }
 
@@ -431,6 +449,11 @@ impl<T: Clone + Send + Sync> PointIndexation<T> {
             new_point = Arc::new(point);
             log::trace!("definitive pushing of point {:?}", p_id);
             points_by_layer_ref[p_id.0 as usize].push(Arc::clone(&new_point));
+            self.origins
+                .write()
+                .entry(origin_id)
+                .or_default()
+                .push(p_id);
         } // close write lock on points_by_layer
 
         let nb_point;
@@ -536,6 +559,15 @@ impl<T: Clone + Send + Sync> PointIndexation<T> {
         }
     }
 
+    /// returns the ids of the points stored for an origin id
+    pub fn get_origin_points(&self, origin_id: DataId) -> Vec<PointId> {
+        self.origins
+            .read()
+            .get(&origin_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     /// get an iterator on the points stored in a given layer
     pub fn get_layer_iterator(&self, layer: usize) -> IterPointLayer<T> {
         IterPointLayer::new(&self, layer)
@@ -678,6 +710,12 @@ pub struct Hnsw<T: Clone + Send + Sync, D: Distance<T>> {
     // TODO check how it works
     /// insertion mode or searching mode. This flag prevents a internal thread to do a write when searching with other threads.
     pub(crate) searching: bool,
+    /// origin ids of deleted points. They stay in the graph to route searches
+    /// but are not returned by search.
+    pub(crate) deleted: RwLock<HashSet<DataId>>,
+    /// points replaced by a later point of the same origin id, see supersede. Like deleted
+    /// points they stay in the graph but are not returned by search.
+    pub(crate) superseded: RwLock<HashSet<PointId>>,
 }
 
 impl<T: Clone + Send + Sync, D: Distance<T> + Send + Sync> Hnsw<T, D> {
@@ -720,6 +758,8 @@ impl<T: Clone + Send + Sync, D: Distance<T> + Send + Sync> Hnsw<T, D> {
             data_dimension: 0,
             dist_f: f,
             searching: false,
+            deleted: RwLock::new(HashSet::new()),
+            superseded: RwLock::new(HashSet::new()),
         }
     }
 
@@ -763,6 +803,62 @@ impl<T: Clone + Send + Sync, D: Distance<T> + Send + Sync> Hnsw<T, D> {
         self.keep_pruned = flag;
     }
 
+    /// marks the points with origin id d_id as deleted. Returns false if it was already deleted.
+    pub fn mark_deleted(&self, d_id: DataId) -> bool {
+        self.deleted.write().insert(d_id)
+    }
+
+    pub fn is_deleted(&self, d_id: DataId) -> bool {
+        self.deleted.read().contains(&d_id)
+    }
+
+    /// returns the number of deleted origin ids
+    pub fn get_nb_deleted(&self) -> usize {
+        self.deleted.read().len()
+    }
+
+    /// supersedes the points with origin ids in ids, which are no longer deleted, so that the
+    /// points inserted next with these ids replace them. Returns the number of points superseded.
+    pub fn supersede(&self, ids: &HashSet<DataId>) -> usize {
+        let mut deleted = self.deleted.write();
+        let mut superseded = self.superseded.write();
+        let origins = self.get_point_indexation().origins.read();
+        let mut nb_superseded = 0;
+        for d_id in ids {
+            for p_id in origins.get(d_id).into_iter().flatten() {
+                if superseded.insert(*p_id) {
+                    nb_superseded += 1;
+                }
+            }
+        }
+        deleted.retain(|d_id| !ids.contains(d_id));
+        nb_superseded
+    }
+
+    /// returns the vector of a live point of an origin id, None if it was deleted or never inserted
+    pub fn get_origin_data(&self, origin_id: DataId) -> Option<Vec<T>> {
+        if self.deleted.read().contains(&origin_id) {
+            return None;
+        }
+        let superseded = self.superseded.read();
+        let p_id = self
+            .get_point_indexation()
+            .get_origin_points(origin_id)
+            .into_iter()
+            .rev()
+            .find(|p_id| !superseded.contains(p_id))?;
+        self.get_point_indexation().get_point_data(&p_id)
+    }
+
+    pub fn is_superseded(&self, p_id: PointId) -> bool {
+        self.superseded.read().contains(&p_id)
+    }
+
+    /// returns the number of superseded points
+    pub fn get_nb_superseded(&self) -> usize {
+        self.superseded.read().len()
+    }
+
     /// retrieves the distance used in Hnsw construction
     pub fn get_distance(&self) -> &D {
         &self.dist_f
@@ -821,6 +917,20 @@ impl<T: Clone + Send + Sync, D: Distance<T> + Send + Sync> Hnsw<T, D> {
         entry_point: Arc<Point<T>>,
         ef: usize,
         layer: u8,
+    ) -> BinaryHeap<Arc<PointWithOrder<T>>> {
+        self.search_layer_filter(point, entry_point, ef, layer, None)
+    }
+
+    /// search_layer returning only the points whose origin id is accepted by filter.
+    /// Rejected points are still expanded, so that the search passes through them, and the
+    /// search goes on until ef accepted points are found or the layer is exhausted.
+    fn search_layer_filter(
+        &self,
+        point: &[T],
+        entry_point: Arc<Point<T>>,
+        ef: usize,
+        layer: u8,
+        filter: Option<&IdFilter>,
     ) -> BinaryHeap<Arc<PointWithOrder<T>>> {
         //
         trace!(
@@ -829,6 +939,12 @@ impl<T: Clone + Send + Sync, D: Distance<T> + Send + Sync> Hnsw<T, D> {
             layer,
             ef
         );
+        // only filtered searches skip superseded points, insertions link to them as to others
+        let superseded = filter.map(|_| self.superseded.read());
+        let accepted = |p: &Point<T>| {
+            filter.is_none_or(|f| f(p.origin_id))
+                && superseded.as_ref().is_none_or(|s| !s.contains(&p.p_id))
+        };
         //
         // here we allocate a binary_heap on values not on reference because we want to return
         // log2(skiplist_size) must be greater than 1.
@@ -858,29 +974,33 @@ impl<T: Clone + Send + Sync, D: Distance<T> + Send + Sync> Hnsw<T, D> {
             &entry_point,
             -dist_to_entry_point,
         )));
-        return_points.push(Arc::new(PointWithOrder::new(
-            &entry_point,
-            dist_to_entry_point,
-        )));
+        if accepted(&entry_point) {
+            return_points.push(Arc::new(PointWithOrder::new(
+                &entry_point,
+                dist_to_entry_point,
+            )));
+        }
         // at the beginning candidate_points contains point passed as arg in layer entry_point_id.0
-        while candidate_points.len() > 0 {
-            // get nearest point in candidate_points
-            let c = candidate_points.pop().unwrap();
-            // f farthest point to
-            let f = return_points.peek().unwrap();
-            assert!(f.dist_to_ref >= 0.);
+        while let Some(c) = candidate_points.pop() {
             assert!(c.dist_to_ref <= 0.);
-            log::trace!(
-                "comparaing c : {:?} f : {:?}",
-                -(c.dist_to_ref),
-                f.dist_to_ref
-            );
-            if -(c.dist_to_ref) > f.dist_to_ref {
-                // this comparison requires that we are sure that distances compared are distances to the same point :
-                // This is the case we compare distance to point passed as arg.
-                log::trace!("fast return from search_layer, nb points : {:?} \n \t c {:?} \n \t f {:?} dists: {:?}  {:?}",
-                                return_points.len(), c.point_ref.p_id, f.point_ref.p_id, -(c.dist_to_ref), f.dist_to_ref);
-                return return_points;
+            // f farthest point to, none while the filter rejected every visited point
+            if let Some(f) = return_points.peek() {
+                assert!(f.dist_to_ref >= 0.);
+                log::trace!(
+                    "comparaing c : {:?} f : {:?}",
+                    -(c.dist_to_ref),
+                    f.dist_to_ref
+                );
+                // with a filter, the search goes on until ef accepted points are found
+                if -(c.dist_to_ref) > f.dist_to_ref
+                    && (filter.is_none() || return_points.len() >= ef)
+                {
+                    // this comparison requires that we are sure that distances compared are distances to the same point :
+                    // This is the case we compare distance to point passed as arg.
+                    log::trace!("fast return from search_layer, nb points : {:?} \n \t c {:?} \n \t f {:?} dists: {:?}  {:?}",
+                                    return_points.len(), c.point_ref.p_id, f.point_ref.p_id, -(c.dist_to_ref), f.dist_to_ref);
+                    return return_points;
+                }
             }
             // now we scan neighborhood of c in layer and increment visited_point, candidate_points
             // and optimize candidate_points so that it contains points with lowest distances to point arg
@@ -898,27 +1018,22 @@ impl<T: Clone + Send + Sync, D: Distance<T> + Send + Sync> Hnsw<T, D> {
                 if visited_point_id.contains_key(&e.point_ref.p_id) != true {
                     visited_point_id.insert(e.point_ref.p_id, Arc::clone(&e.point_ref));
                     log::trace!("             visited insertion {:?}", e.point_ref.p_id);
-                    let f_opt = return_points.peek();
-                    if f_opt.is_none() {
-                        // do some debug info, dumped distance is from e to c! as e is in c neighbours
-                        debug!("return points empty when inserting {:?}", e.point_ref.p_id);
-                        return return_points;
-                    }
-                    let f = f_opt.unwrap();
                     let e_dist_to_p = self.dist_f.eval(point, &e.point_ref.v);
-                    let f_dist_to_p = f.dist_to_ref;
+                    let f_dist_to_p = return_points.peek().map_or(f32::MAX, |f| f.dist_to_ref);
                     if e_dist_to_p < f_dist_to_p || return_points.len() < ef {
-                        let e_prime = Arc::new(PointWithOrder::new(&e.point_ref, e_dist_to_p));
                         // a neighbour of neighbour is better, we insert it into candidate with the distance to point
                         log::trace!(
                             "                inserting new candidate {:?}",
-                            e_prime.point_ref.p_id
+                            e.point_ref.p_id
                         );
                         candidate_points
                             .push(Arc::new(PointWithOrder::new(&e.point_ref, -e_dist_to_p)));
-                        return_points.push(Arc::clone(&e_prime));
-                        if return_points.len() > ef {
-                            return_points.pop();
+                        if accepted(&e.point_ref) {
+                            return_points
+                                .push(Arc::new(PointWithOrder::new(&e.point_ref, e_dist_to_p)));
+                            if return_points.len() > ef {
+                                return_points.pop();
+                            }
                         }
                     }
                 }
@@ -1273,7 +1388,6 @@ impl<T: Clone + Send + Sync, D: Distance<T> + Send + Sync> Hnsw<T, D> {
                 println!("   neighbours {:?} ", n.point_ref.p_id);
             }
         }
-
     }
 
     /// A utility to get printed info on how many points there are in each layer.
@@ -1335,22 +1449,9 @@ impl<T: Clone + Send + Sync, D: Distance<T> + Send + Sync> Hnsw<T, D> {
         knn_neighbours
     }
 
-    /// search the first knbn nearest neigbours of a data and returns a Vector of Neighbour.
-    /// The parameter ef controls the width of the search in the lowest level, it must be greater
-    /// than number of neighbours asked.
-    /// A rule of thumb could be between knbn and max_nb_connection.
-    pub fn search(&self, data: &[T], knbn: usize, ef_arg: usize) -> Vec<Neighbour> {
-        //
-        let entry_point;
-        {
-            // a lock on an option an a Arc<Point>
-            let entry_point_opt_ref = self.layer_indexed_points.entry_point.read();
-            if entry_point_opt_ref.is_none() {
-                return Vec::<Neighbour>::new();
-            } else {
-                entry_point = Arc::clone((*entry_point_opt_ref).as_ref().unwrap());
-            }
-        }
+    /// entry point of layer 0 for data, found by a greedy descent of the upper layers
+    fn search_entry_point(&self, data: &[T]) -> Option<Arc<Point<T>>> {
+        let entry_point = Arc::clone(self.layer_indexed_points.entry_point.read().as_ref()?);
         //
         let mut dist_to_entry = self.dist_f.eval(data, &entry_point.as_ref().v);
         let mut pivot = Arc::clone(&entry_point);
@@ -1375,27 +1476,83 @@ impl<T: Clone + Send + Sync, D: Distance<T> + Send + Sync> Hnsw<T, D> {
                 pivot = Arc::clone(new_pivot.as_ref().unwrap());
             }
         }
-          // ef must be greater than knbn. Possibly it should be between knbn and self.max_nb_connection
+        Some(pivot)
+    }
+
+    /// search the first knbn nearest neigbours of a data and returns a Vector of Neighbour.
+    /// The parameter ef controls the width of the search in the lowest level, it must be greater
+    /// than number of neighbours asked.
+    /// A rule of thumb could be between knbn and max_nb_connection.
+    pub fn search(&self, data: &[T], knbn: usize, ef_arg: usize) -> Vec<Neighbour> {
+        let pivot = match self.search_entry_point(data) {
+            Some(pivot) => pivot,
+            None => return Vec::<Neighbour>::new(),
+        };
+        // ef must be greater than knbn. Possibly it should be between knbn and self.max_nb_connection
         let ef = ef_arg.max(knbn);
-        // now search with asked ef in layer 0
-        let neighbours_heap = self.search_layer(data, pivot, ef, 0);
-        // go from heap of points with negative dist to a sorted vec of increasing points with > 0 distances.
-        let neighbours = neighbours_heap.into_sorted_vec();
-        // get the min of K and ef points into a vector.
-        //
-        let last = knbn.min(ef).min(neighbours.len());
-        let knn_neighbours: Vec<Neighbour> = neighbours[0..last]
-            .iter()
-            .map(|p| {
-                Neighbour::new(
-                    p.as_ref().point_ref.origin_id,
-                    p.as_ref().dist_to_ref,
-                    p.as_ref().point_ref.p_id,
-                )
-            })
-            .collect();
+        // deleted and superseded points are skipped after the search, widen it so that enough remain
+        let deleted = self.deleted.read();
+        let superseded = self.superseded.read();
+        let wanted = knbn.min(ef);
+        let mut search_ef = ef + (deleted.len() + superseded.len()).min(ef);
+        loop {
+            // now search with asked ef in layer 0
+            let neighbours_heap = self.search_layer(data, Arc::clone(&pivot), search_ef, 0);
+            // go from heap of points with negative dist to a sorted vec of increasing points with > 0 distances.
+            let neighbours = neighbours_heap.into_sorted_vec();
+            // get the min of K and ef points into a vector.
+            //
+            let knn_neighbours: Vec<Neighbour> = neighbours
+                .iter()
+                .filter(|p| {
+                    !deleted.contains(&p.as_ref().point_ref.origin_id)
+                        && !superseded.contains(&p.as_ref().point_ref.p_id)
+                })
+                .take(wanted)
+                .map(|p| {
+                    Neighbour::new(
+                        p.as_ref().point_ref.origin_id,
+                        p.as_ref().dist_to_ref,
+                        p.as_ref().point_ref.p_id,
+                    )
+                })
+                .collect();
+            // until the live points are enough or every point reached was visited
+            if knn_neighbours.len() >= wanted
+                || neighbours.len() < search_ef
+                || search_ef >= self.get_nb_point()
+            {
+                return knn_neighbours;
+            }
+            search_ef *= 2;
+        }
+    }
 
-        knn_neighbours
+    /// search restricted to the points whose id is accepted by filter. The filter is evaluated
+    /// during the traversal of layer 0, rejected points are traversed but never returned.
+    /// The more points the filter rejects, the more points are visited: with very selective
+    /// filters a brute force scan of the accepted points is cheaper.
+    pub fn search_filter(
+        &self,
+        data: &[T],
+        knbn: usize,
+        ef_arg: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        let pivot = match self.search_entry_point(data) {
+            Some(pivot) => pivot,
+            None => return Vec::<Neighbour>::new(),
+        };
+        let ef = ef_arg.max(knbn);
+        let deleted = self.deleted.read();
+        let accepted = |d_id: DataId| !deleted.contains(&d_id) && filter(d_id);
+        let neighbours_heap = self.search_layer_filter(data, pivot, ef, 0, Some(&accepted));
+        neighbours_heap
+            .into_sorted_vec()
+            .iter()
+            .take(knbn)
+            .map(|p| Neighbour::new(p.point_ref.origin_id, p.dist_to_ref, p.point_ref.p_id))
+            .collect()
     }
 
     fn search_with_id(
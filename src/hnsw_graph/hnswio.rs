@@ -0,0 +1,540 @@
+//! Persistence of a Hnsw structure as content addressed blocks.
+//!
+//! An index is stored as a manifest block (the root of the DAG) holding the parameters
+//! of the Hnsw and links to chunks of vectors and chunks of adjacency lists.
+//! Reloading rebuilds the layers and the neighbourhoods exactly as they were dumped,
+//! so no insertion is replayed.
+//!
+//! An index can also be saved to a local directory, its vectors in a VectorFile that is
+//! memory mapped when the index is opened again, so that opening does not read them.
+
+use std::any::type_name;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use cid::Cid;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::hnsw_graph::dist::Distance;
+use crate::hnsw_graph::hnsw::{DataId, Hnsw, Point, PointId, PointWithOrder};
+use crate::hnsw_graph::mmap::{Mappable, PointData, VectorFile};
+use crate::ipfs_storage::block::{Block, BlockStore, Link};
+
+/// number of points stored in a vector or graph block
+const CHUNK_SIZE: usize = 1024;
+
+/// version of the block layout, bumped on incompatible changes
+const FORMAT_VERSION: u32 = 1;
+
+/// files of an index saved to a directory
+const MANIFEST_FILE: &str = "manifest.cbor";
+const VECTOR_FILE: &str = "vectors.hvec";
+const GRAPH_FILE: &str = "graph.cbor";
+
+/// The root block of a persisted index
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexManifest {
+    pub format_version: u32,
+    /// type name of the distance, checked at reload
+    pub distance: String,
+    pub dimension: usize,
+    pub nb_point: usize,
+    pub max_nb_connection: usize,
+    pub max_layer: usize,
+    pub ef_construction: usize,
+    pub entry_point: Option<PointId>,
+    /// chunks of StoredPoint
+    pub vectors: Vec<Link>,
+    /// chunks of StoredNeighbours, in the same order as vectors
+    pub graph: Vec<Link>,
+    /// origin ids of deleted points, sorted
+    #[serde(default)]
+    pub deleted: Vec<DataId>,
+    /// superseded points, sorted
+    #[serde(default)]
+    pub superseded: Vec<PointId>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredPoint<T> {
+    p_id: PointId,
+    origin_id: DataId,
+    v: Vec<T>,
+}
+
+/// neighbours of a point by layer, as (point, distance) pairs
+#[derive(Serialize, Deserialize)]
+struct StoredNeighbours {
+    p_id: PointId,
+    layers: Vec<Vec<(PointId, f32)>>,
+}
+
+/// a point of an index saved to a directory, its vector is the row of same rank in the vector file
+#[derive(Serialize, Deserialize)]
+struct SavedPoint {
+    origin_id: DataId,
+    neighbours: StoredNeighbours,
+}
+
+/// neighbours of point by layer, without the empty upper layers
+fn stored_neighbours<T: Clone + Send + Sync>(point: &Point<T>) -> StoredNeighbours {
+    let mut layers: Vec<Vec<(PointId, f32)>> = point
+        .neighbours
+        .read()
+        .iter()
+        .map(|layer| {
+            layer
+                .iter()
+                .map(|n| (n.point_ref.get_point_id(), n.dist_to_ref))
+                .collect()
+        })
+        .collect();
+    while layers.last().is_some_and(|l| l.is_empty()) {
+        layers.pop();
+    }
+    StoredNeighbours {
+        p_id: point.get_point_id(),
+        layers,
+    }
+}
+
+fn manifest_of<T, D>(hnsw: &Hnsw<T, D>, vectors: Vec<Link>, graph: Vec<Link>) -> IndexManifest
+where
+    T: Clone + Send + Sync,
+    D: Distance<T> + Send + Sync,
+{
+    let point_indexation = hnsw.get_point_indexation();
+    let mut deleted: Vec<DataId> = hnsw.deleted.read().iter().copied().collect();
+    deleted.sort_unstable();
+    let mut superseded: Vec<PointId> = hnsw.superseded.read().iter().copied().collect();
+    superseded.sort_unstable();
+    IndexManifest {
+        format_version: FORMAT_VERSION,
+        distance: type_name::<D>().to_string(),
+        dimension: point_indexation.get_data_dimension(),
+        nb_point: point_indexation.get_nb_point(),
+        max_nb_connection: hnsw.max_nb_connection,
+        max_layer: hnsw.max_layer,
+        ef_construction: hnsw.ef_construction,
+        entry_point: point_indexation
+            .entry_point
+            .read()
+            .as_ref()
+            .map(|p| p.get_point_id()),
+        vectors,
+        graph,
+        deleted,
+        superseded,
+    }
+}
+
+fn flush_chunk<S: Serialize>(
+    chunk: &mut Vec<S>,
+    links: &mut Vec<Link>,
+    store: &dyn BlockStore,
+) -> Result<(), Box<dyn Error>> {
+    if !chunk.is_empty() {
+        let block = Block::encode(chunk)?;
+        links.push(Link(block.cid));
+        store.put(block)?;
+        chunk.clear();
+    }
+    Ok(())
+}
+
+/// dumps hnsw into store and returns the CID of its manifest.
+/// Insertions must not run concurrently with the dump.
+pub fn dump_to_store<T, D>(hnsw: &Hnsw<T, D>, store: &dyn BlockStore) -> Result<Cid, Box<dyn Error>>
+where
+    T: Clone + Send + Sync + Serialize,
+    D: Distance<T> + Send + Sync,
+{
+    let point_indexation = hnsw.get_point_indexation();
+    let mut vectors = Vec::new();
+    let mut graph = Vec::new();
+    let mut points = Vec::<StoredPoint<T>>::with_capacity(CHUNK_SIZE);
+    let mut adjacency = Vec::<StoredNeighbours>::with_capacity(CHUNK_SIZE);
+    // the point iterator requires an entry point
+    if point_indexation.get_nb_point() > 0 {
+        for point in point_indexation {
+            points.push(StoredPoint {
+                p_id: point.get_point_id(),
+                origin_id: point.get_origin_id(),
+                v: point.get_v().to_vec(),
+            });
+            adjacency.push(stored_neighbours(&point));
+            if points.len() == CHUNK_SIZE {
+                flush_chunk(&mut points, &mut vectors, store)?;
+                flush_chunk(&mut adjacency, &mut graph, store)?;
+            }
+        }
+    }
+    flush_chunk(&mut points, &mut vectors, store)?;
+    flush_chunk(&mut adjacency, &mut graph, store)?;
+
+    let manifest = manifest_of(hnsw, vectors, graph);
+    let block = Block::encode(&manifest)?;
+    let root = block.cid;
+    store.put(block)?;
+    log::info!(
+        "dumped {} points in {} blocks, root {}",
+        manifest.nb_point,
+        manifest.vectors.len() + manifest.graph.len() + 1,
+        root
+    );
+    Ok(root)
+}
+
+/// reads the manifest of a persisted index
+pub fn load_manifest(root: &Cid, store: &dyn BlockStore) -> Result<IndexManifest, Box<dyn Error>> {
+    let manifest: IndexManifest = store.get_block(root)?.decode()?;
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(format!(
+            "unsupported index format version {}",
+            manifest.format_version
+        )
+        .into());
+    }
+    Ok(manifest)
+}
+
+/// the points of a chunk of vectors of the index of manifest, without the deleted and the
+/// superseded ones
+pub fn chunk_points(
+    manifest: &IndexManifest,
+    chunk: &Block,
+) -> Result<Vec<(DataId, Vec<f32>)>, Box<dyn Error>> {
+    let chunk: Vec<StoredPoint<f32>> = chunk.decode()?;
+    if chunk
+        .iter()
+        .any(|stored| stored.v.len() != manifest.dimension)
+    {
+        return Err("inconsistent chunk of vectors in index".into());
+    }
+    Ok(chunk
+        .into_iter()
+        .filter(|stored| {
+            manifest.deleted.binary_search(&stored.origin_id).is_err()
+                && manifest.superseded.binary_search(&stored.p_id).is_err()
+        })
+        .map(|stored| (stored.origin_id, stored.v))
+        .collect())
+}
+
+/// rebuilds a Hnsw from the index persisted under root. dist_f must be the distance
+/// the index was built with.
+pub fn load_from_store<T, D>(
+    root: &Cid,
+    store: &dyn BlockStore,
+    dist_f: D,
+) -> Result<Hnsw<T, D>, Box<dyn Error>>
+where
+    T: Clone + Send + Sync + DeserializeOwned,
+    D: Distance<T> + Send + Sync,
+{
+    let manifest = load_manifest(root, store)?;
+    if manifest.distance != type_name::<D>() {
+        return Err(format!(
+            "index was built with distance {}, not {}",
+            manifest.distance,
+            type_name::<D>()
+        )
+        .into());
+    }
+    let mut points = Vec::with_capacity(manifest.nb_point);
+    for link in &manifest.vectors {
+        let chunk: Vec<StoredPoint<T>> = store.get_block(&link.0)?.decode()?;
+        for stored in chunk {
+            points.push(Point::new(&stored.v, stored.origin_id, stored.p_id));
+        }
+    }
+    let mut neighbours = Vec::with_capacity(manifest.nb_point);
+    for link in &manifest.graph {
+        let chunk: Vec<StoredNeighbours> = store.get_block(&link.0)?.decode()?;
+        neighbours.extend(chunk);
+    }
+    let hnsw = rebuild(&manifest, points, neighbours, dist_f)?;
+    log::info!("loaded {} points from index {}", manifest.nb_point, root);
+    Ok(hnsw)
+}
+
+/// builds a Hnsw from its manifest, its points and their neighbourhoods,
+/// without replaying any insertion
+fn rebuild<T, D>(
+    manifest: &IndexManifest,
+    mut points: Vec<Point<T>>,
+    neighbourhoods: Vec<StoredNeighbours>,
+    dist_f: D,
+) -> Result<Hnsw<T, D>, Box<dyn Error>>
+where
+    T: Clone + Send + Sync,
+    D: Distance<T> + Send + Sync,
+{
+    let hnsw = Hnsw::new(
+        manifest.max_nb_connection,
+        manifest.nb_point,
+        manifest.max_layer,
+        manifest.ef_construction,
+        dist_f,
+    );
+    let max_layer = hnsw.get_max_level();
+
+    // points must be pushed in rank order so that their PointId matches their slot in layer
+    points.sort_unstable_by_key(|p| p.get_point_id());
+    let mut layers: Vec<Vec<Arc<Point<T>>>> = (0..max_layer).map(|_| Vec::new()).collect();
+    for point in points {
+        let p_id = point.get_point_id();
+        let layer = p_id.0 as usize;
+        if layer >= max_layer || layers[layer].len() as i32 != p_id.1 {
+            return Err(format!("inconsistent point id {:?} in index", p_id).into());
+        }
+        layers[layer].push(Arc::new(point));
+    }
+    let get_point = |p_id: &PointId| -> Result<Arc<Point<T>>, Box<dyn Error>> {
+        layers
+            .get(p_id.0 as usize)
+            .and_then(|layer| layer.get(p_id.1 as usize))
+            .cloned()
+            .ok_or_else(|| format!("unknown point id {:?} in index", p_id).into())
+    };
+
+    for stored in neighbourhoods {
+        let point = get_point(&stored.p_id)?;
+        let mut neighbours = point.neighbours.write();
+        if stored.layers.len() > neighbours.len() {
+            return Err(format!(
+                "point {:?} has neighbours in {} layers, at most {} expected",
+                stored.p_id,
+                stored.layers.len(),
+                neighbours.len()
+            )
+            .into());
+        }
+        for (l, layer) in stored.layers.iter().enumerate() {
+            for (n_id, dist) in layer {
+                let neighbour = get_point(n_id)?;
+                neighbours[l].push(Arc::new(PointWithOrder::new(&neighbour, *dist)));
+            }
+        }
+    }
+
+    let point_indexation = hnsw.get_point_indexation();
+    let entry_point = match manifest.entry_point {
+        Some(p_id) => Some(get_point(&p_id)?),
+        None => None,
+    };
+    let mut origins = point_indexation.origins.write();
+    for point in layers.iter().flatten() {
+        origins
+            .entry(point.get_origin_id())
+            .or_default()
+            .push(point.get_point_id());
+    }
+    drop(origins);
+    *point_indexation.points_by_layer.write() = layers;
+    *point_indexation.nb_point.write() = manifest.nb_point;
+    *point_indexation.entry_point.write() = entry_point;
+    hnsw.deleted
+        .write()
+        .extend(manifest.deleted.iter().copied());
+    hnsw.superseded
+        .write()
+        .extend(manifest.superseded.iter().copied());
+    Ok(hnsw)
+}
+
+/// writes value as CBOR to path, through a file renamed once complete
+fn write_cbor<S: Serialize>(path: &Path, value: &S) -> Result<(), Box<dyn Error>> {
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_cbor::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// saves hnsw to dir, created if needed. Its vectors go to a VectorFile mapped by
+/// load_from_dir. Insertions must not run concurrently with the save.
+pub fn save_to_dir<T, D>(hnsw: &Hnsw<T, D>, dir: &Path) -> Result<(), Box<dyn Error>>
+where
+    T: Mappable,
+    D: Distance<T> + Send + Sync,
+{
+    fs::create_dir_all(dir)?;
+    let point_indexation = hnsw.get_point_indexation();
+    let mut points: Vec<Arc<Point<T>>> = Vec::with_capacity(point_indexation.get_nb_point());
+    // the point iterator requires an entry point
+    if point_indexation.get_nb_point() > 0 {
+        points.extend(point_indexation);
+    }
+    let manifest = manifest_of(hnsw, Vec::new(), Vec::new());
+    VectorFile::write(
+        &dir.join(VECTOR_FILE),
+        manifest.dimension,
+        points.len(),
+        points.iter().map(|point| point.get_v()),
+    )?;
+    let saved: Vec<SavedPoint> = points
+        .iter()
+        .map(|point| SavedPoint {
+            origin_id: point.get_origin_id(),
+            neighbours: stored_neighbours(point),
+        })
+        .collect();
+    write_cbor(&dir.join(GRAPH_FILE), &saved)?;
+    // the manifest is written last, it marks a complete save
+    write_cbor(&dir.join(MANIFEST_FILE), &manifest)?;
+    log::info!("saved {} points to {}", manifest.nb_point, dir.display());
+    Ok(())
+}
+
+/// reads the manifest of an index saved to dir
+pub fn load_dir_manifest(dir: &Path) -> Result<IndexManifest, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(dir.join(MANIFEST_FILE))?);
+    let manifest: IndexManifest = serde_cbor::from_reader(reader)?;
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(format!(
+            "unsupported index format version {}",
+            manifest.format_version
+        )
+        .into());
+    }
+    Ok(manifest)
+}
+
+/// opens the index saved to dir. Its vectors are mapped, not read.
+pub fn load_from_dir<T, D>(dir: &Path, dist_f: D) -> Result<Hnsw<T, D>, Box<dyn Error>>
+where
+    T: Mappable,
+    D: Distance<T> + Send + Sync,
+{
+    let manifest = load_dir_manifest(dir)?;
+    if manifest.distance != type_name::<D>() {
+        return Err(format!(
+            "index was built with distance {}, not {}",
+            manifest.distance,
+            type_name::<D>()
+        )
+        .into());
+    }
+    let vector_file = VectorFile::open(&dir.join(VECTOR_FILE))?;
+    let saved: Vec<SavedPoint> =
+        serde_cbor::from_reader(BufReader::new(File::open(dir.join(GRAPH_FILE))?))?;
+    if saved.len() != manifest.nb_point || vector_file.get_nb_vector() != manifest.nb_point {
+        return Err(format!("incomplete index in {}", dir.display()).into());
+    }
+    if manifest.nb_point > 0 && vector_file.get_dimension() != manifest.dimension {
+        return Err(format!("vectors of {} have the wrong dimension", dir.display()).into());
+    }
+    let mut points = Vec::with_capacity(saved.len());
+    let mut neighbours = Vec::with_capacity(saved.len());
+    for (row, point) in saved.into_iter().enumerate() {
+        let v: PointData<T> = vector_file.row(row)?;
+        points.push(Point::with_data(v, point.origin_id, point.neighbours.p_id));
+        neighbours.push(point.neighbours);
+    }
+    let hnsw = rebuild(&manifest, points, neighbours, dist_f)?;
+    log::info!("opened {} points from {}", manifest.nb_point, dir.display());
+    Ok(hnsw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnsw_graph::bench::gen_random_matrix_f32;
+    use crate::hnsw_graph::dist::DistCosine;
+    use crate::hnsw_graph::hnsw::NB_LAYER_MAX;
+    use crate::ipfs_storage::block::MemoryBlockStore;
+
+    #[test]
+    fn test_dump_and_reload() {
+        let data = gen_random_matrix_f32(10, 3000);
+        let hnsw = Hnsw::<f32, DistCosine>::new(16, data.len(), 16, 100, DistCosine {});
+        let data_with_id = data.iter().zip(0..data.len()).collect();
+        hnsw.parallel_insert(&data_with_id);
+
+        let store = MemoryBlockStore::new();
+        let root = dump_to_store(&hnsw, &store).unwrap();
+        // dumping is deterministic
+        assert_eq!(dump_to_store(&hnsw, &store).unwrap(), root);
+
+        let reloaded: Hnsw<f32, DistCosine> =
+            load_from_store(&root, &store, DistCosine {}).unwrap();
+        assert_eq!(reloaded.get_nb_point(), hnsw.get_nb_point());
+        assert_eq!(
+            reloaded.get_max_level_observed(),
+            hnsw.get_max_level_observed()
+        );
+        for query in data.iter().take(50) {
+            let expected: Vec<DataId> = hnsw.search(query, 10, 50).iter().map(|n| n.d_id).collect();
+            let found: Vec<DataId> = reloaded
+                .search(query, 10, 50)
+                .iter()
+                .map(|n| n.d_id)
+                .collect();
+            assert_eq!(expected, found);
+        }
+    }
+
+    #[test]
+    fn test_too_many_layers_rejected() {
+        let hnsw = Hnsw::<f32, DistCosine>::new(16, 10, 16, 100, DistCosine {});
+        let manifest = manifest_of(&hnsw, Vec::new(), Vec::new());
+        let p_id = PointId(0, 0);
+        let points = vec![Point::new(&[1., 0.], 0, p_id)];
+        let neighbours = vec![StoredNeighbours {
+            p_id,
+            layers: vec![Vec::new(); NB_LAYER_MAX as usize + 1],
+        }];
+        assert!(rebuild(&manifest, points, neighbours, DistCosine {}).is_err());
+    }
+
+    #[test]
+    fn test_save_and_open_mapped() {
+        let data = gen_random_matrix_f32(10, 2000);
+        let hnsw = Hnsw::<f32, DistCosine>::new(16, data.len(), 16, 100, DistCosine {});
+        let data_with_id = data.iter().zip(0..data.len()).collect();
+        hnsw.parallel_insert(&data_with_id);
+        hnsw.mark_deleted(3);
+        // point 4 takes the vector of point 5
+        assert_eq!(hnsw.supersede(&[4].into_iter().collect()), 1);
+        hnsw.insert_slice((data[5].as_slice(), 4));
+
+        let dir = tempfile::tempdir().unwrap();
+        save_to_dir(&hnsw, dir.path()).unwrap();
+        let opened: Hnsw<f32, DistCosine> = load_from_dir(dir.path(), DistCosine {}).unwrap();
+        assert_eq!(opened.get_nb_point(), hnsw.get_nb_point());
+        assert!(opened.is_deleted(3));
+        assert_eq!(opened.get_nb_superseded(), 1);
+        assert_ne!(opened.search(&data[4], 1, 50)[0].d_id, 4);
+        let mut found: Vec<DataId> = opened
+            .search(&data[5], 2, 50)
+            .iter()
+            .map(|n| n.d_id)
+            .collect();
+        found.sort_unstable();
+        assert_eq!(found, vec![4, 5]);
+        let point_indexation = opened.get_point_indexation();
+        assert!(point_indexation.into_iter().all(|point| point.is_mapped()));
+        for query in data.iter().take(50) {
+            let expected: Vec<DataId> = hnsw.search(query, 10, 50).iter().map(|n| n.d_id).collect();
+            let found: Vec<DataId> = opened
+                .search(query, 10, 50)
+                .iter()
+                .map(|n| n.d_id)
+                .collect();
+            assert_eq!(expected, found);
+        }
+
+        // an opened index accepts insertions and can be saved over its own files
+        opened.insert_slice((data[0].as_slice(), 5000));
+        save_to_dir(&opened, dir.path()).unwrap();
+        let reopened: Hnsw<f32, DistCosine> = load_from_dir(dir.path(), DistCosine {}).unwrap();
+        assert_eq!(reopened.get_nb_point(), data.len() + 2);
+        assert_eq!(reopened.search(&data[0], 2, 50).len(), 2);
+    }
+}
@@ -0,0 +1,230 @@
+//! Vectors stored row after row in a file and read in place through a memory map.
+//!
+//! Points opened from a VectorFile do not copy their vector: the OS pages it in on first
+//! access and may evict it under memory pressure, so an index opens in the time needed to
+//! map the file and its vectors need not fit in RAM.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::mem::size_of;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+
+const MAGIC: &[u8; 4] = b"HVEC";
+
+const FILE_VERSION: u32 = 1;
+
+/// written in native byte order, to refuse files written on a machine of another endianness
+const BYTE_ORDER_MARK: u32 = 0x01020304;
+
+/// a multiple of the alignment of all Mappable types, so that rows stay aligned
+const HEADER_SIZE: usize = 32;
+
+/// Scalar types whose vectors can be read in place from a file.
+///
+/// # Safety
+/// Every bit pattern of size_of::<Self>() bytes must be a valid value of the type.
+pub unsafe trait Mappable: Copy + Send + Sync + 'static {}
+
+unsafe impl Mappable for f32 {}
+unsafe impl Mappable for f64 {}
+unsafe impl Mappable for u8 {}
+unsafe impl Mappable for u16 {}
+unsafe impl Mappable for u32 {}
+unsafe impl Mappable for u64 {}
+unsafe impl Mappable for i8 {}
+unsafe impl Mappable for i16 {}
+unsafe impl Mappable for i32 {}
+unsafe impl Mappable for i64 {}
+
+fn as_bytes<T: Mappable>(v: &[T]) -> &[u8] {
+    // Mappable types have no padding
+    unsafe { std::slice::from_raw_parts(v.as_ptr() as *const u8, std::mem::size_of_val(v)) }
+}
+
+/// A memory mapped file of vectors of the same dimension
+pub struct VectorFile {
+    map: Mmap,
+    dimension: usize,
+    nb_vector: usize,
+    scalar_size: usize,
+}
+
+impl VectorFile {
+    /// writes nb_vector vectors of dimension dimension to path. The file is written aside and
+    /// renamed, so that a file still mapped by an index can be replaced.
+    pub fn write<'a, T, I>(
+        path: &Path,
+        dimension: usize,
+        nb_vector: usize,
+        vectors: I,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        T: Mappable,
+        I: Iterator<Item = &'a [T]>,
+    {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend(MAGIC);
+        header.extend(FILE_VERSION.to_le_bytes());
+        header.extend(BYTE_ORDER_MARK.to_ne_bytes());
+        header.extend((size_of::<T>() as u32).to_le_bytes());
+        header.extend((dimension as u64).to_le_bytes());
+        header.extend((nb_vector as u64).to_le_bytes());
+        writer.write_all(&header)?;
+        let mut nb_written = 0;
+        for v in vectors {
+            if v.len() != dimension {
+                return Err(
+                    format!("vector of dimension {}, expected {}", v.len(), dimension).into(),
+                );
+            }
+            writer.write_all(as_bytes(v))?;
+            nb_written += 1;
+        }
+        if nb_written != nb_vector {
+            return Err(format!("{} vectors written, {} announced", nb_written, nb_vector).into());
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// maps the file at path
+    pub fn open(path: &Path) -> Result<Arc<Self>, Box<dyn Error>> {
+        let file = File::open(path)?;
+        // the file is only replaced by rename, never modified in place
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < HEADER_SIZE || &map[0..4] != MAGIC {
+            return Err(format!("{} is not a vector file", path.display()).into());
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(map[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(map[at..at + 8].try_into().unwrap());
+        if u32_at(4) != FILE_VERSION {
+            return Err(format!("unsupported vector file version {}", u32_at(4)).into());
+        }
+        if u32::from_ne_bytes(map[8..12].try_into().unwrap()) != BYTE_ORDER_MARK {
+            return Err("vector file written with another byte order".into());
+        }
+        let scalar_size = u32_at(12) as usize;
+        let dimension = u64_at(16) as usize;
+        let nb_vector = u64_at(24) as usize;
+        if map.len() != HEADER_SIZE + nb_vector * dimension * scalar_size {
+            return Err(format!("vector file {} is truncated", path.display()).into());
+        }
+        Ok(Arc::new(VectorFile {
+            map,
+            dimension,
+            nb_vector,
+            scalar_size,
+        }))
+    }
+
+    pub fn get_dimension(&self) -> usize {
+        self.dimension
+    }
+
+    pub fn get_nb_vector(&self) -> usize {
+        self.nb_vector
+    }
+
+    /// returns the vector of rank row, read in place
+    pub fn row<T: Mappable>(self: &Arc<Self>, row: usize) -> Result<PointData<T>, Box<dyn Error>> {
+        if size_of::<T>() != self.scalar_size {
+            return Err(format!(
+                "vector file holds scalars of {} bytes, not {}",
+                self.scalar_size,
+                size_of::<T>()
+            )
+            .into());
+        }
+        if row >= self.nb_vector {
+            return Err(format!("row {} out of {} vectors", row, self.nb_vector).into());
+        }
+        Ok(PointData(Storage::Mapped {
+            file: Arc::clone(self),
+            offset: HEADER_SIZE + row * self.dimension * self.scalar_size,
+            len: self.dimension,
+        }))
+    }
+}
+
+#[derive(Clone)]
+enum Storage<T> {
+    Owned(Vec<T>),
+    /// only built by VectorFile::row, which checks that T is Mappable and of the file scalar size
+    Mapped {
+        file: Arc<VectorFile>,
+        offset: usize,
+        len: usize,
+    },
+}
+
+/// The vector of a point, in RAM or read in place from a VectorFile
+#[derive(Clone)]
+pub struct PointData<T>(Storage<T>);
+
+impl<T> PointData<T> {
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.0, Storage::Mapped { .. })
+    }
+}
+
+impl<T> From<Vec<T>> for PointData<T> {
+    fn from(v: Vec<T>) -> Self {
+        PointData(Storage::Owned(v))
+    }
+}
+
+impl<T> Deref for PointData<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match &self.0 {
+            Storage::Owned(v) => v,
+            // the map is page aligned and rows are aligned on the scalar size
+            Storage::Mapped { file, offset, len } => unsafe {
+                std::slice::from_raw_parts(file.map.as_ptr().add(*offset) as *const T, *len)
+            },
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PointData<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vector_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vectors.hvec");
+        let data = vec![vec![1f32, 2., 3.], vec![4., 5., 6.]];
+        VectorFile::write(&path, 3, 2, data.iter().map(|v| v.as_slice())).unwrap();
+        let file = VectorFile::open(&path).unwrap();
+        assert_eq!(file.get_nb_vector(), 2);
+        let row: PointData<f32> = file.row(1).unwrap();
+        assert!(row.is_mapped());
+        assert_eq!(&row[..], &[4., 5., 6.]);
+        assert!(file.row::<f32>(2).is_err());
+        assert!(file.row::<f64>(0).is_err());
+
+        // a mapped file can be replaced while its rows are in use
+        VectorFile::write(&path, 3, 1, data.iter().take(1).map(|v| v.as_slice())).unwrap();
+        assert_eq!(&row[..], &[4., 5., 6.]);
+        assert_eq!(VectorFile::open(&path).unwrap().get_nb_vector(), 1);
+        assert!(VectorFile::write(&path, 3, 5, data.iter().map(|v| v.as_slice())).is_err());
+    }
+}
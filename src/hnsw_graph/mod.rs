@@ -1,6 +1,9 @@
+pub mod bench;
 pub mod dist;
 pub mod graph;
 pub mod hnsw;
+pub mod hnswio;
+pub mod mmap;
 pub mod neighbor;
 pub mod node;
 mod tests;
@@ -4,80 +4,22 @@
 //! parallel test corresponds to random data in 25 dimensions k = 10, dist Cosine
 
 #![allow(dead_code)]
-use rand::distributions::Uniform;
-use rand::prelude::*;
-
-use skiplist::OrderedSkipList;
-
-use serde::{de::DeserializeOwned, Serialize};
-
-use crate::hnsw_graph::hnsw;
-
 #[allow(unused_imports)] // necessary for rls
 use crate::hnsw_graph::dist;
 
-pub use self::hnsw::*;
-
-pub fn gen_random_vector_f32(nbrow: usize) -> Vec<f32> {
-    let mut rng = thread_rng();
-    let unif = Uniform::<f32>::new(0., 1.);
-    let vec = (0..nbrow)
-        .into_iter()
-        .map(|_| rng.sample(unif))
-        .collect::<Vec<f32>>();
-    vec
-}
-
-/// return nbcolumn vectors of dimension nbrow
-pub fn gen_random_matrix_f32(nbrow: usize, nbcolumn: usize) -> Vec<Vec<f32>> {
-    let mut rng = thread_rng();
-    let unif = Uniform::<f32>::new(0., 1.);
-    let mut data = Vec::with_capacity(nbcolumn);
-    for _ in 0..nbcolumn {
-        let column = (0..nbrow)
-            .into_iter()
-            .map(|_| rng.sample(unif))
-            .collect::<Vec<f32>>();
-        data.push(column);
-    }
-    return data;
-}
-
-fn brute_force_neighbours<T: Serialize + DeserializeOwned + Copy + Send + Sync>(
-    nb_neighbours: usize,
-    refdata: &PointIndexation<T>,
-    distance: PointDistance<T>,
-    data: &Vec<T>,
-) -> OrderedSkipList<PointIdWithOrder> {
-    let mut neighbours = OrderedSkipList::<PointIdWithOrder>::with_capacity(refdata.get_nb_point());
-
-    let mut ptiter = refdata.into_iter();
-    let mut more = true;
-    while more {
-        if let Some(point) = ptiter.next() {
-            let dist_p = distance.eval(data, point.get_v());
-            let ordered_point = PointIdWithOrder::new(point.get_point_id(), dist_p);
-            //            log::debug!(" brute force inserting {:?}", ordered_point);
-            if neighbours.len() < nb_neighbours {
-                neighbours.insert(ordered_point);
-            } else {
-                neighbours.insert(ordered_point);
-                neighbours.pop_back();
-            }
-        } else {
-            more = false;
-        }
-    } // end while
-    neighbours
-} // end of brute_force_2
-
 //================================================================================================
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::hnsw_graph::bench::{
+        brute_force_neighbours, gen_random_matrix_f32, gen_random_vector_f32,
+    };
+    use crate::hnsw_graph::hnsw::*;
     use cpu_time::ProcessTime;
+    use rand::distributions::Uniform;
+    use rand::prelude::*;
     use std::time::Duration;
 
     use dist::l2_normalize;
@@ -0,0 +1,1316 @@
+//! Disk resident graph index in the style of DiskANN.
+//!
+//! The vectors and the adjacency lists of a Vamana graph live in a file read with positional
+//! I/O. Only the product quantized codes of the vectors, the nodes nearest to the medoid and
+//! the vectors inserted since the last merge stay in RAM. A search walks the graph with the
+//! codes, reads the beam_width best candidates of each hop in one batch, and ranks the nodes
+//! read with their full vectors.
+//!
+//! Inserted vectors are buffered and merged with the graph in a new file once max_buffer of
+//! them are pending. The merge runs in the background: until the new file replaces the former
+//! one, searches are served by the former graph and the vectors being merged, and the writes
+//! go to a new buffer. Building a graph holds its vectors in RAM, serving it does not.
+
+use std::any::type_name;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use cid::Cid;
+use parking_lot::{Mutex, RwLock};
+use rand::prelude::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::hnsw_graph::dist::{DistCosine, DistDot, DistL2, Distance};
+use crate::hnsw_graph::hnsw::{DataId, IdFilter, Neighbour, PointId};
+use crate::index::kmeans::nearest_centroid;
+use crate::index::quantizer::{Quantization, Quantizer};
+use crate::index::{nearest, put_root, AnnIndex, DistanceKind, IndexKind, IndexStats, Visitor};
+use crate::ipfs_storage::block::{Block, BlockStore, Link, RAW};
+
+const MAGIC: &[u8; 4] = b"DANN";
+
+const FILE_VERSION: u32 = 1;
+
+/// bytes reserved for the header at the start of a graph file
+const HEADER_SIZE: u64 = 64;
+
+const FORMAT_VERSION: u32 = 1;
+
+/// maximum number of vectors the product quantizer is trained on
+const PQ_TRAIN_SIZE: usize = 65536;
+
+/// number of nodes read at once when scanning a graph file
+const SCAN_BATCH: usize = 1024;
+
+/// size of the blocks a graph file is cut in when persisted
+const FILE_CHUNK_SIZE: usize = 1 << 20;
+
+/// number of points of a block of buffered vectors
+const CHUNK_SIZE: usize = 1024;
+
+/// number of neighbours asked first by range_search, doubled until the radius is passed
+const RANGE_FIRST_KNBN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DiskParams {
+    /// maximum number of neighbours of a node, R in the Vamana paper
+    pub max_degree: usize,
+    /// size of the candidate list while building, L in the Vamana paper
+    pub list_size: usize,
+    /// pruning factor, larger values keep longer edges
+    pub alpha: f32,
+    /// number of bytes of the codes kept in RAM, 0 for a quarter of the dimension
+    pub nb_subspace: usize,
+    /// number of nodes read per hop
+    pub beam_width: usize,
+    /// number of nodes around the medoid cached in RAM
+    pub cache_size: usize,
+    /// number of buffered vectors that triggers a merge into the graph file
+    pub max_buffer: usize,
+}
+
+impl Default for DiskParams {
+    fn default() -> Self {
+        DiskParams {
+            max_degree: 64,
+            list_size: 100,
+            alpha: 1.2,
+            nb_subspace: 0,
+            beam_width: 4,
+            cache_size: 4096,
+            max_buffer: 100_000,
+        }
+    }
+}
+
+impl DiskParams {
+    /// the largest divisor of dimension not above the asked number of subspaces
+    fn subspaces(&self, dimension: usize) -> usize {
+        let asked = if self.nb_subspace > 0 {
+            self.nb_subspace
+        } else {
+            dimension / 4
+        };
+        (1..=asked.clamp(1, dimension.max(1)))
+            .rev()
+            .find(|m| dimension % m == 0)
+            .unwrap_or(1)
+    }
+}
+
+/// fixed size header of a graph file, followed by the node records, the CBOR encoded
+/// quantizer and the codes
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    dimension: usize,
+    max_degree: usize,
+    nb_node: usize,
+    medoid: u32,
+    quantizer_len: usize,
+}
+
+impl Header {
+    /// id, vector, degree and max_degree neighbour slots
+    fn node_size(&self) -> usize {
+        8 + 4 * self.dimension + 4 + 4 * self.max_degree
+    }
+
+    fn node_offset(&self, node: u32) -> u64 {
+        HEADER_SIZE + node as u64 * self.node_size() as u64
+    }
+
+    /// the quantizer follows the node records, and the codes follow the quantizer
+    fn quantizer_offset(&self) -> u64 {
+        HEADER_SIZE + (self.nb_node * self.node_size()) as u64
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE as usize);
+        bytes.extend(MAGIC);
+        bytes.extend(FILE_VERSION.to_le_bytes());
+        bytes.extend((self.dimension as u32).to_le_bytes());
+        bytes.extend((self.max_degree as u32).to_le_bytes());
+        bytes.extend((self.nb_node as u64).to_le_bytes());
+        bytes.extend(self.medoid.to_le_bytes());
+        bytes.extend((self.quantizer_len as u64).to_le_bytes());
+        bytes.resize(HEADER_SIZE as usize, 0);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        if &bytes[0..4] != MAGIC {
+            return Err("not a graph file".into());
+        }
+        if u32_at(4) != FILE_VERSION {
+            return Err(format!("unsupported graph file version {}", u32_at(4)).into());
+        }
+        Ok(Header {
+            dimension: u32_at(8) as usize,
+            max_degree: u32_at(12) as usize,
+            nb_node: u64_at(16) as usize,
+            medoid: u32_at(24),
+            quantizer_len: u64_at(28) as usize,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    d_id: DataId,
+    vector: Vec<f32>,
+    neighbours: Vec<u32>,
+}
+
+impl Node {
+    fn decode(bytes: &[u8], header: &Header) -> Self {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let vector_end = 8 + 4 * header.dimension;
+        let degree = (u32_at(vector_end) as usize).min(header.max_degree);
+        Node {
+            d_id: u64::from_le_bytes(bytes[0..8].try_into().unwrap()) as DataId,
+            vector: bytes[8..vector_end]
+                .chunks_exact(4)
+                .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+                .collect(),
+            neighbours: (0..degree)
+                .map(|rank| u32_at(vector_end + 4 + 4 * rank))
+                .collect(),
+        }
+    }
+
+    fn encode(
+        d_id: DataId,
+        vector: &[f32],
+        neighbours: &[u32],
+        header: &Header,
+        out: &mut Vec<u8>,
+    ) {
+        let start = out.len();
+        out.extend((d_id as u64).to_le_bytes());
+        for x in vector {
+            out.extend(x.to_le_bytes());
+        }
+        out.extend((neighbours.len() as u32).to_le_bytes());
+        for neighbour in neighbours {
+            out.extend(neighbour.to_le_bytes());
+        }
+        out.resize(start + header.node_size(), 0);
+    }
+}
+
+/// A graph file opened for search
+struct DiskGraph {
+    file: File,
+    path: PathBuf,
+    header: Header,
+    quantizer: Quantizer,
+    /// code of each node, in node order
+    codes: Vec<u8>,
+    /// sorted ids of the nodes
+    ids: Vec<DataId>,
+    /// nodes nearest to the medoid in hops
+    cache: HashMap<u32, Node>,
+    /// blocks of the file once persisted, the file never changes
+    chunks: Mutex<Vec<Link>>,
+}
+
+impl DiskGraph {
+    /// writes the graph built over data, a matrix of the dimension of quantizer, to a new file
+    fn write(
+        path: &Path,
+        ids: &[DataId],
+        data: &[f32],
+        graph: &[Vec<u32>],
+        medoid: u32,
+        max_degree: usize,
+        quantizer: &Quantizer,
+    ) -> Result<(), Box<dyn Error>> {
+        let dim = quantizer.dimension();
+        let quantizer_bytes = serde_cbor::to_vec(quantizer)?;
+        let header = Header {
+            dimension: dim,
+            max_degree,
+            nb_node: ids.len(),
+            medoid,
+            quantizer_len: quantizer_bytes.len(),
+        };
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&header.to_bytes())?;
+        let mut record = Vec::with_capacity(header.node_size());
+        for (node, d_id) in ids.iter().enumerate() {
+            record.clear();
+            Node::encode(
+                *d_id,
+                &data[node * dim..(node + 1) * dim],
+                &graph[node],
+                &header,
+                &mut record,
+            );
+            writer.write_all(&record)?;
+        }
+        writer.write_all(&quantizer_bytes)?;
+        let mut codes = Vec::with_capacity(ids.len() * quantizer.code_size());
+        for v in data.chunks_exact(dim) {
+            quantizer.encode(v, &mut codes);
+        }
+        writer.write_all(&codes)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// opens a graph file, loads its codes and caches up to cache_size nodes.
+    /// The graph owns the file and removes it when dropped.
+    fn open(path: &Path, cache_size: usize) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path)?;
+        let mut header_bytes = vec![0; HEADER_SIZE as usize];
+        file.read_exact_at(&mut header_bytes, 0)?;
+        let header = Header::from_bytes(&header_bytes)?;
+        let mut quantizer_bytes = vec![0; header.quantizer_len];
+        file.read_exact_at(&mut quantizer_bytes, header.quantizer_offset())?;
+        let quantizer: Quantizer = serde_cbor::from_slice(&quantizer_bytes)?;
+        let mut codes = vec![0; header.nb_node * quantizer.code_size()];
+        file.read_exact_at(
+            &mut codes,
+            header.quantizer_offset() + header.quantizer_len as u64,
+        )?;
+        let mut graph = DiskGraph {
+            file,
+            path: path.to_path_buf(),
+            header,
+            quantizer,
+            codes,
+            ids: Vec::new(),
+            cache: HashMap::new(),
+            chunks: Mutex::new(Vec::new()),
+        };
+        let mut ids = Vec::with_capacity(header.nb_node);
+        graph.for_each_node(|_, content| {
+            ids.push(content.d_id);
+            Ok(())
+        })?;
+        ids.sort_unstable();
+        graph.ids = ids;
+        graph.fill_cache(cache_size)?;
+        Ok(graph)
+    }
+
+    /// caches the nodes met by a breadth first walk from the medoid
+    fn fill_cache(&mut self, cache_size: usize) -> Result<(), Box<dyn Error>> {
+        if self.header.nb_node == 0 {
+            return Ok(());
+        }
+        let mut cache = HashMap::new();
+        let mut queue = VecDeque::from([self.header.medoid]);
+        let mut seen = HashSet::from([self.header.medoid]);
+        while let Some(node) = queue.pop_front() {
+            if cache.len() >= cache_size {
+                break;
+            }
+            let content = self.read_node(node)?;
+            for neighbour in &content.neighbours {
+                if seen.insert(*neighbour) {
+                    queue.push_back(*neighbour);
+                }
+            }
+            cache.insert(node, content);
+        }
+        self.cache = cache;
+        Ok(())
+    }
+
+    fn read_node(&self, node: u32) -> io::Result<Node> {
+        let mut bytes = vec![0; self.header.node_size()];
+        self.file
+            .read_exact_at(&mut bytes, self.header.node_offset(node))?;
+        Ok(Node::decode(&bytes, &self.header))
+    }
+
+    /// the nodes of a hop, from the cache or read in parallel from the file
+    fn read_nodes(&self, nodes: &[u32]) -> io::Result<Vec<Node>> {
+        nodes
+            .par_iter()
+            .map(|node| match self.cache.get(node) {
+                Some(content) => Ok(content.clone()),
+                None => self.read_node(*node),
+            })
+            .collect()
+    }
+
+    /// calls visit on every node in file order
+    fn for_each_node<F>(&self, mut visit: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(u32, Node) -> Result<(), Box<dyn Error>>,
+    {
+        let node_size = self.header.node_size();
+        let mut bytes = Vec::new();
+        for start in (0..self.header.nb_node).step_by(SCAN_BATCH) {
+            let nb = SCAN_BATCH.min(self.header.nb_node - start);
+            bytes.resize(nb * node_size, 0);
+            self.file
+                .read_exact_at(&mut bytes, self.header.node_offset(start as u32))?;
+            for (rank, record) in bytes.chunks_exact(node_size).enumerate() {
+                visit((start + rank) as u32, Node::decode(record, &self.header))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn code(&self, node: u32) -> &[u8] {
+        let code_size = self.quantizer.code_size();
+        &self.codes[node as usize * code_size..(node as usize + 1) * code_size]
+    }
+
+    fn contains(&self, d_id: DataId) -> bool {
+        self.ids.binary_search(&d_id).is_ok()
+    }
+
+    /// the raw blocks of the file, put in store. The file is only read again when store
+    /// lacks some of the blocks of the last call.
+    fn persist(&self, store: &dyn BlockStore) -> Result<Vec<Link>, Box<dyn Error>> {
+        let mut chunks = self.chunks.lock();
+        if !chunks.is_empty() {
+            let mut stored = true;
+            for link in chunks.iter() {
+                stored &= store.has(&link.0)?;
+            }
+            if stored {
+                return Ok(chunks.clone());
+            }
+        }
+        let mut file = File::open(&self.path)?;
+        let mut links = Vec::new();
+        loop {
+            let mut chunk = Vec::with_capacity(FILE_CHUNK_SIZE);
+            (&mut file)
+                .take(FILE_CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            let block = Block::new(RAW, chunk);
+            links.push(Link(block.cid));
+            store.put(block)?;
+        }
+        *chunks = links.clone();
+        Ok(links)
+    }
+}
+
+impl Drop for DiskGraph {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("cannot remove graph file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// greedy search of an in memory graph from start.
+/// Returns the expanded nodes with their distance to the target.
+fn greedy_search<F: Fn(u32) -> f32>(
+    graph: &[Vec<u32>],
+    start: u32,
+    list_size: usize,
+    dist_to: F,
+) -> Vec<(f32, u32)> {
+    let mut candidates = vec![(dist_to(start), start, false)];
+    let mut seen = HashSet::from([start]);
+    let mut visited = Vec::new();
+    while let Some(rank) = candidates.iter().position(|c| !c.2) {
+        candidates[rank].2 = true;
+        let (distance, node, _) = candidates[rank];
+        visited.push((distance, node));
+        for neighbour in &graph[node as usize] {
+            if seen.insert(*neighbour) {
+                let candidate = (dist_to(*neighbour), *neighbour, false);
+                let at = candidates.partition_point(|c| c.0 <= candidate.0);
+                candidates.insert(at, candidate);
+            }
+        }
+        candidates.truncate(list_size);
+    }
+    visited
+}
+
+/// keeps at most max_degree of the candidate neighbours of p, skipping a candidate when
+/// a kept one is alpha times nearer to it than p is
+fn robust_prune<F: Fn(u32, u32) -> f32>(
+    p: u32,
+    mut candidates: Vec<(f32, u32)>,
+    alpha: f32,
+    max_degree: usize,
+    dist: &F,
+) -> Vec<u32> {
+    candidates.retain(|c| c.1 != p);
+    candidates.sort_unstable_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    candidates.dedup_by_key(|c| c.1);
+    let mut kept = Vec::with_capacity(max_degree);
+    let mut pruned = vec![false; candidates.len()];
+    for rank in 0..candidates.len() {
+        if pruned[rank] {
+            continue;
+        }
+        let chosen = candidates[rank].1;
+        kept.push(chosen);
+        if kept.len() == max_degree {
+            break;
+        }
+        for other in rank + 1..candidates.len() {
+            if !pruned[other] && alpha * dist(chosen, candidates[other].1) <= candidates[other].0 {
+                pruned[other] = true;
+            }
+        }
+    }
+    kept
+}
+
+/// builds a Vamana graph over data, a matrix of dimension dim.
+/// Returns the adjacency lists and the medoid, the entry point of searches.
+fn build_graph<F>(data: &[f32], dim: usize, params: &DiskParams, dist: &F) -> (Vec<Vec<u32>>, u32)
+where
+    F: Fn(&[f32], &[f32]) -> f32,
+{
+    let nb_node = data.len() / dim;
+    if nb_node == 0 {
+        return (Vec::new(), 0);
+    }
+    let vector = move |node: u32| &data[node as usize * dim..(node as usize + 1) * dim];
+    let node_dist = |a: u32, b: u32| dist(vector(a), vector(b));
+
+    let mut mean = vec![0.; dim];
+    for v in data.chunks_exact(dim) {
+        for (m, x) in mean.iter_mut().zip(v) {
+            *m += x / nb_node as f32;
+        }
+    }
+    let medoid = nearest_centroid(data, dim, &mean, dist).0 as u32;
+
+    // start from a random regular graph
+    let mut rng = thread_rng();
+    let degree = params.max_degree.min(nb_node - 1);
+    let mut graph: Vec<Vec<u32>> = (0..nb_node)
+        .map(|node| {
+            rand::seq::index::sample(&mut rng, nb_node, degree + 1)
+                .iter()
+                .filter(|other| *other != node)
+                .take(degree)
+                .map(|other| other as u32)
+                .collect()
+        })
+        .collect();
+
+    // a first pass without long edges, then one with them
+    for alpha in [1., params.alpha] {
+        let mut order: Vec<u32> = (0..nb_node as u32).collect();
+        order.shuffle(&mut rng);
+        for p in order {
+            let mut candidates =
+                greedy_search(&graph, medoid, params.list_size, |node| node_dist(p, node));
+            candidates.extend(
+                graph[p as usize]
+                    .iter()
+                    .map(|node| (node_dist(p, *node), *node)),
+            );
+            let neighbours = robust_prune(p, candidates, alpha, params.max_degree, &node_dist);
+            for neighbour in &neighbours {
+                let back = &mut graph[*neighbour as usize];
+                if back.contains(&p) {
+                    continue;
+                }
+                back.push(p);
+                if back.len() > params.max_degree {
+                    let candidates = back
+                        .iter()
+                        .map(|node| (node_dist(*neighbour, *node), *node))
+                        .collect();
+                    graph[*neighbour as usize] =
+                        robust_prune(*neighbour, candidates, alpha, params.max_degree, &node_dist);
+                }
+            }
+            graph[p as usize] = neighbours;
+        }
+    }
+    (graph, medoid)
+}
+
+/// The buffered vectors taken by a running merge
+struct Merging {
+    frozen: Arc<HashMap<DataId, Vec<f32>>>,
+    /// ids of the live graph nodes and frozen vectors deleted or replaced since the merge
+    /// started, all of them nodes of the new graph
+    deleted: HashSet<DataId>,
+}
+
+struct DiskState {
+    dimension: usize,
+    /// None until the first merge
+    graph: Option<Arc<DiskGraph>>,
+    /// vectors inserted since the last merge started
+    buffer: HashMap<DataId, Vec<f32>>,
+    /// ids of graph nodes deleted or replaced by a buffered vector when the last merge
+    /// started, or since then without a merge running
+    deleted: HashSet<DataId>,
+    merging: Option<Merging>,
+}
+
+impl DiskState {
+    fn nb_point(&self) -> usize {
+        let nb_node = self.graph.as_ref().map_or(0, |graph| graph.ids.len());
+        let (nb_frozen, nb_unmerged) = self.merging.as_ref().map_or((0, 0), |merging| {
+            (merging.frozen.len(), merging.deleted.len())
+        });
+        nb_node + nb_frozen + self.buffer.len() - self.deleted.len() - nb_unmerged
+    }
+
+    fn nb_deleted(&self) -> usize {
+        self.deleted.len()
+            + self
+                .merging
+                .as_ref()
+                .map_or(0, |merging| merging.deleted.len())
+    }
+
+    fn on_graph(&self, d_id: DataId) -> bool {
+        self.graph
+            .as_ref()
+            .is_some_and(|graph| graph.contains(d_id))
+    }
+
+    /// whether the graph node of d_id is deleted or replaced
+    fn is_deleted(&self, d_id: DataId) -> bool {
+        self.deleted.contains(&d_id)
+            || self
+                .merging
+                .as_ref()
+                .is_some_and(|merging| merging.deleted.contains(&d_id))
+    }
+
+    /// whether d_id is a live node of the graph or a live vector of the running merge
+    fn is_merged(&self, d_id: DataId) -> bool {
+        match &self.merging {
+            None => self.on_graph(d_id) && !self.deleted.contains(&d_id),
+            Some(merging) => {
+                !merging.deleted.contains(&d_id)
+                    && (merging.frozen.contains_key(&d_id)
+                        || (self.on_graph(d_id) && !self.deleted.contains(&d_id)))
+            }
+        }
+    }
+
+    /// marks a merged point deleted, in the set of the running merge if any
+    fn unmerge(&mut self, d_id: DataId) -> bool {
+        if !self.is_merged(d_id) {
+            return false;
+        }
+        match &mut self.merging {
+            Some(merging) => merging.deleted.insert(d_id),
+            None => self.deleted.insert(d_id),
+        }
+    }
+
+    fn put(&mut self, d_id: DataId, v: Vec<f32>) {
+        self.unmerge(d_id);
+        self.buffer.insert(d_id, v);
+    }
+
+    fn remove(&mut self, d_id: DataId) -> bool {
+        let in_buffer = self.buffer.remove(&d_id).is_some();
+        let merged = self.unmerge(d_id);
+        in_buffer || merged
+    }
+
+    /// the buffered vectors and the live vectors of the running merge
+    fn pending(&self) -> impl Iterator<Item = (&DataId, &Vec<f32>)> {
+        let frozen = self.merging.iter().flat_map(|merging| {
+            merging
+                .frozen
+                .iter()
+                .filter(move |(d_id, _)| !merging.deleted.contains(*d_id))
+        });
+        self.buffer.iter().chain(frozen)
+    }
+
+    /// ends the running merge with its new graph, or puts its vectors back in the buffer
+    /// when it failed
+    fn end_merge(&mut self, merged: Option<Option<DiskGraph>>) {
+        let merging = match self.merging.take() {
+            Some(merging) => merging,
+            None => return,
+        };
+        match merged {
+            Some(graph) => {
+                self.graph = graph.map(Arc::new);
+                self.deleted = merging.deleted;
+            }
+            None => {
+                for (d_id, v) in merging.frozen.iter() {
+                    if !merging.deleted.contains(d_id) {
+                        self.buffer.entry(*d_id).or_insert_with(|| v.clone());
+                    }
+                }
+                for d_id in merging.deleted {
+                    if self.on_graph(d_id) {
+                        self.deleted.insert(d_id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A merge of the live graph nodes and the frozen vectors into a new graph file
+struct MergeJob<D> {
+    dist_f: Arc<D>,
+    params: DiskParams,
+    path: PathBuf,
+    dimension: usize,
+    graph: Option<Arc<DiskGraph>>,
+    /// deleted graph nodes when the merge started
+    deleted: HashSet<DataId>,
+    frozen: Arc<HashMap<DataId, Vec<f32>>>,
+    state: Arc<RwLock<DiskState>>,
+}
+
+impl<D: Distance<f32> + Send + Sync> MergeJob<D> {
+    /// builds the new graph file, None when no point is left
+    fn build(&self) -> Result<Option<DiskGraph>, Box<dyn Error>> {
+        let dim = self.dimension;
+        let mut ids = Vec::new();
+        let mut data = Vec::new();
+        if let Some(graph) = &self.graph {
+            ids.reserve(graph.ids.len() + self.frozen.len());
+            graph.for_each_node(|_, node| {
+                if !self.deleted.contains(&node.d_id) {
+                    ids.push(node.d_id);
+                    data.extend_from_slice(&node.vector);
+                }
+                Ok(())
+            })?;
+        }
+        for (d_id, v) in self.frozen.iter() {
+            ids.push(*d_id);
+            data.extend_from_slice(v);
+        }
+        if ids.is_empty() {
+            return Ok(None);
+        }
+        let dist = |a: &[f32], b: &[f32]| self.dist_f.eval(a, b);
+        let (adjacency, medoid) = build_graph(&data, dim, &self.params, &dist);
+        let sample: Vec<f32> =
+            rand::seq::index::sample(&mut thread_rng(), ids.len(), ids.len().min(PQ_TRAIN_SIZE))
+                .iter()
+                .flat_map(|node| data[node * dim..(node + 1) * dim].iter().copied())
+                .collect();
+        let quantization = Quantization::Product {
+            nb_subspace: self.params.subspaces(dim),
+        };
+        let quantizer = Quantizer::train(quantization, &sample, dim)?;
+        let graph = DiskGraph::write(
+            &self.path,
+            &ids,
+            &data,
+            &adjacency,
+            medoid,
+            self.params.max_degree,
+            &quantizer,
+        )
+        .and_then(|_| DiskGraph::open(&self.path, self.params.cache_size));
+        match graph {
+            Ok(graph) => {
+                log::info!(
+                    "disk index merged {} vectors into {}",
+                    ids.len(),
+                    self.path.display()
+                );
+                Ok(Some(graph))
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&self.path);
+                Err(e)
+            }
+        }
+    }
+
+    fn run(self) -> Result<(), Box<dyn Error>> {
+        let built = self.build();
+        let mut state = self.state.write();
+        match built {
+            Ok(graph) => {
+                state.end_merge(Some(graph));
+                Ok(())
+            }
+            Err(e) => {
+                state.end_merge(None);
+                Err(e)
+            }
+        }
+    }
+}
+
+pub struct DiskIndex<D: Distance<f32>> {
+    dist_f: Arc<D>,
+    params: DiskParams,
+    /// directory of the graph files
+    dir: PathBuf,
+    state: Arc<RwLock<DiskState>>,
+    /// thread of the last merge started in the background
+    merger: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl<D: Distance<f32> + Send + Sync + 'static> DiskIndex<D> {
+    pub fn new(params: DiskParams, dir: &Path, dist_f: D) -> Self {
+        DiskIndex {
+            dist_f: Arc::new(dist_f),
+            params,
+            dir: dir.to_path_buf(),
+            state: Arc::new(RwLock::new(DiskState {
+                dimension: 0,
+                graph: None,
+                buffer: HashMap::new(),
+                deleted: HashSet::new(),
+                merging: None,
+            })),
+            merger: Mutex::new(None),
+        }
+    }
+
+    pub fn get_params(&self) -> &DiskParams {
+        &self.params
+    }
+
+    /// number of vectors waiting for the next merge
+    pub fn get_nb_buffered(&self) -> usize {
+        self.state.read().buffer.len()
+    }
+
+    fn new_graph_path(&self) -> Result<PathBuf, Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        Ok(self.dir.join(format!("{:016x}.dann", random::<u64>())))
+    }
+
+    /// rebuilds the graph file with the live nodes of the current one and the buffered vectors,
+    /// after the merge running in the background if any
+    pub fn merge(&self) -> Result<(), Box<dyn Error>> {
+        loop {
+            self.wait_merge();
+            let mut state = self.state.write();
+            if state.merging.is_some() {
+                // a merge started after the wait
+                drop(state);
+                thread::yield_now();
+                continue;
+            }
+            let job = self.start_merge(&mut state)?;
+            drop(state);
+            return job.run();
+        }
+    }
+
+    /// waits for the merge running in the background
+    pub fn wait_merge(&self) {
+        let merger = self.merger.lock().take();
+        if let Some(merger) = merger {
+            if merger.join().is_err() {
+                log::error!("disk index merge panicked");
+            }
+        }
+    }
+
+    /// freezes the buffer for a merge, which must not be running
+    fn start_merge(&self, state: &mut DiskState) -> Result<MergeJob<D>, Box<dyn Error>> {
+        let path = self.new_graph_path()?;
+        let frozen = Arc::new(std::mem::take(&mut state.buffer));
+        state.merging = Some(Merging {
+            frozen: frozen.clone(),
+            deleted: HashSet::new(),
+        });
+        Ok(MergeJob {
+            dist_f: self.dist_f.clone(),
+            params: self.params,
+            path,
+            dimension: state.dimension,
+            graph: state.graph.clone(),
+            deleted: state.deleted.clone(),
+            frozen,
+            state: self.state.clone(),
+        })
+    }
+
+    /// beam search of the graph file, codes rank the candidates and full vectors the results.
+    /// Nodes rejected by filter are traversed but not returned.
+    fn search_graph(
+        &self,
+        state: &DiskState,
+        query: &[f32],
+        list_size: usize,
+        filter: &IdFilter,
+    ) -> io::Result<Vec<Neighbour>> {
+        let graph = match &state.graph {
+            Some(graph) if graph.header.nb_node > 0 => graph,
+            _ => return Ok(Vec::new()),
+        };
+        let mut decoded = vec![0.; state.dimension];
+        let mut approx = |node: u32| {
+            graph.quantizer.decode(graph.code(node), &mut decoded);
+            self.dist_f.eval(query, &decoded)
+        };
+        let medoid = graph.header.medoid;
+        let mut candidates = vec![(approx(medoid), medoid, false)];
+        let mut seen = HashSet::from([medoid]);
+        let mut found = Vec::new();
+        loop {
+            let hop: Vec<u32> = candidates
+                .iter_mut()
+                .filter(|c| !c.2)
+                .take(self.params.beam_width.max(1))
+                .map(|c| {
+                    c.2 = true;
+                    c.1
+                })
+                .collect();
+            if hop.is_empty() {
+                break;
+            }
+            for (node, content) in hop.iter().zip(graph.read_nodes(&hop)?) {
+                if !state.is_deleted(content.d_id) && filter(content.d_id) {
+                    found.push(Neighbour::new(
+                        content.d_id,
+                        self.dist_f.eval(query, &content.vector),
+                        PointId(0, *node as i32),
+                    ));
+                }
+                for neighbour in content.neighbours {
+                    if seen.insert(neighbour) {
+                        let candidate = (approx(neighbour), neighbour, false);
+                        let at = candidates.partition_point(|c| c.0 <= candidate.0);
+                        candidates.insert(at, candidate);
+                    }
+                }
+            }
+            candidates.truncate(list_size);
+        }
+        Ok(found)
+    }
+
+    fn buffered(&self, state: &DiskState, query: &[f32], filter: &IdFilter) -> Vec<Neighbour> {
+        state
+            .pending()
+            .filter(|(d_id, _)| filter(**d_id))
+            .map(|(d_id, v)| Neighbour::new(*d_id, self.dist_f.eval(query, v), PointId(1, -1)))
+            .collect()
+    }
+}
+
+impl<D: Distance<f32> + Send + Sync + 'static> AnnIndex for DiskIndex<D> {
+    fn kind(&self) -> IndexKind {
+        IndexKind::Disk
+    }
+
+    /// inserting an id already present replaces its vector. Filling the buffer starts a merge
+    /// in the background, unless one is running.
+    fn insert(&self, data: &[(&[f32], DataId)]) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.write();
+        if state.nb_point() == 0 && state.graph.is_none() && state.merging.is_none() {
+            if let Some((v, _)) = data.first() {
+                state.dimension = v.len();
+            }
+        }
+        let dim = state.dimension;
+        if let Some((v, d_id)) = data.iter().find(|(v, _)| v.len() != dim) {
+            return Err(format!(
+                "vector {} has dimension {}, expected {}",
+                d_id,
+                v.len(),
+                dim
+            )
+            .into());
+        }
+        for (v, d_id) in data {
+            state.put(*d_id, v.to_vec());
+        }
+        if state.buffer.len() >= self.params.max_buffer && state.merging.is_none() {
+            let job = self.start_merge(&mut state)?;
+            let merger = thread::spawn(move || {
+                if let Err(e) = job.run() {
+                    log::error!("disk index merge failed: {}", e);
+                }
+            });
+            *self.merger.lock() = Some(merger);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, ids: &[DataId]) -> usize {
+        let mut state = self.state.write();
+        ids.iter().filter(|d_id| state.remove(**d_id)).count()
+    }
+
+    /// ef is the size of the candidate list, widened by the number of deleted nodes
+    fn search(&self, query: &[f32], knbn: usize, ef: usize) -> Vec<Neighbour> {
+        self.filtered_search(query, knbn, ef, &|_| true)
+    }
+
+    fn filtered_search(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        ef: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        let state = self.state.read();
+        let list_size = ef.max(knbn);
+        let list_size = list_size + state.nb_deleted().min(list_size);
+        let mut found = match self.search_graph(&state, query, list_size, filter) {
+            Ok(found) => found,
+            Err(e) => {
+                log::error!("cannot read graph file: {}", e);
+                Vec::new()
+            }
+        };
+        found.extend(self.buffered(&state, query, filter));
+        nearest(found.into_iter(), knbn)
+    }
+
+    fn filtered_exact_search(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        let state = self.state.read();
+        let mut found = self.buffered(&state, query, filter);
+        if let Some(graph) = &state.graph {
+            let scanned = graph.for_each_node(|node, content| {
+                if !state.is_deleted(content.d_id) && filter(content.d_id) {
+                    found.push(Neighbour::new(
+                        content.d_id,
+                        self.dist_f.eval(query, &content.vector),
+                        PointId(0, node as i32),
+                    ));
+                }
+                Ok(())
+            });
+            if let Err(e) = scanned {
+                log::error!("cannot read graph file: {}", e);
+            }
+        }
+        nearest(found.into_iter(), knbn)
+    }
+
+    fn range_search(&self, query: &[f32], radius: f32, ef: usize) -> Vec<Neighbour> {
+        let nb_point = self.state.read().nb_point();
+        let mut knbn = RANGE_FIRST_KNBN;
+        loop {
+            let mut neighbours = self.search(query, knbn, ef.max(knbn));
+            let complete = neighbours.len() < knbn
+                || neighbours.last().is_none_or(|n| n.distance > radius)
+                || knbn >= nb_point;
+            if complete {
+                neighbours.retain(|n| n.distance <= radius);
+                return neighbours;
+            }
+            knbn *= 2;
+        }
+    }
+
+    fn stats(&self) -> IndexStats {
+        let state = self.state.read();
+        IndexStats {
+            kind: IndexKind::Disk,
+            distance: type_name::<D>().to_string(),
+            dimension: state.dimension,
+            nb_point: state.nb_point(),
+            nb_deleted: state.nb_deleted(),
+        }
+    }
+
+    fn scan(&self, visit: &mut Visitor) -> Result<(), Box<dyn Error>> {
+        let state = self.state.read();
+        if let Some(graph) = &state.graph {
+            graph.for_each_node(|_, node| {
+                if state.is_deleted(node.d_id) {
+                    return Ok(());
+                }
+                visit(node.d_id, &node.vector)
+            })?;
+        }
+        for (d_id, v) in state.pending() {
+            visit(*d_id, v)?;
+        }
+        Ok(())
+    }
+
+    /// the running merge is waited for, so that the points it deleted are persisted as
+    /// tombstones of its graph. A merge started since is persisted as if it had not started.
+    fn persist(&self, store: &dyn BlockStore) -> Result<Cid, Box<dyn Error>> {
+        self.wait_merge();
+        let state = self.state.read();
+        let buffered: Vec<(&DataId, &Vec<f32>)> = state.pending().collect();
+        let mut buffer_chunks = Vec::new();
+        for points in buffered.chunks(CHUNK_SIZE) {
+            let block = Block::encode(&BufferChunk {
+                ids: points.iter().map(|(d_id, _)| **d_id).collect(),
+                vectors: points.iter().flat_map(|(_, v)| v.iter().copied()).collect(),
+            })?;
+            buffer_chunks.push(Link(block.cid));
+            store.put(block)?;
+        }
+        let mut deleted: Vec<DataId> = state.deleted.iter().copied().collect();
+        if let Some(merging) = &state.merging {
+            deleted.extend(
+                merging
+                    .deleted
+                    .iter()
+                    .copied()
+                    .filter(|d_id| state.on_graph(*d_id)),
+            );
+        }
+        deleted.sort_unstable();
+        let dimension = state.dimension;
+        let graph = state.graph.clone();
+        // the graph file is read without blocking the writes
+        drop(state);
+        let graph_chunks = match graph {
+            Some(graph) => graph.persist(store)?,
+            None => Vec::new(),
+        };
+        let block = Block::encode(&DiskManifest {
+            format_version: FORMAT_VERSION,
+            distance: type_name::<D>().to_string(),
+            params: self.params,
+            dimension,
+            graph: graph_chunks,
+            buffer: buffer_chunks,
+            deleted,
+        })?;
+        let manifest = block.cid;
+        store.put(block)?;
+        put_root(IndexKind::Disk, manifest, store)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiskManifest {
+    format_version: u32,
+    distance: String,
+    params: DiskParams,
+    dimension: usize,
+    /// raw blocks of the graph file, empty before the first merge
+    graph: Vec<Link>,
+    /// chunks of BufferChunk
+    buffer: Vec<Link>,
+    deleted: Vec<DataId>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BufferChunk {
+    ids: Vec<DataId>,
+    vectors: Vec<f32>,
+}
+
+/// writes the raw blocks of a persisted graph file to path
+fn write_chunks(
+    path: &Path,
+    chunks: &[Link],
+    store: &dyn BlockStore,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for link in chunks {
+        writer.write_all(&store.get_block(&link.0)?.data)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn load_with<D: Distance<f32> + Send + Sync + 'static>(
+    manifest: DiskManifest,
+    store: &dyn BlockStore,
+    dir: &Path,
+    dist_f: D,
+) -> Result<DiskIndex<D>, Box<dyn Error>> {
+    let index = DiskIndex::new(manifest.params, dir, dist_f);
+    {
+        let mut state = index.state.write();
+        state.dimension = manifest.dimension;
+        if !manifest.graph.is_empty() {
+            let path = index.new_graph_path()?;
+            let graph = write_chunks(&path, &manifest.graph, store)
+                .and_then(|_| DiskGraph::open(&path, manifest.params.cache_size));
+            match graph {
+                Ok(graph) => state.graph = Some(Arc::new(graph)),
+                Err(e) => {
+                    let _ = fs::remove_file(&path);
+                    return Err(e);
+                }
+            }
+        }
+        for link in &manifest.buffer {
+            let chunk: BufferChunk = store.get_block(&link.0)?.decode()?;
+            if chunk.vectors.len() != chunk.ids.len() * manifest.dimension {
+                return Err("inconsistent buffer chunk in disk index".into());
+            }
+            for (d_id, v) in chunk
+                .ids
+                .iter()
+                .zip(chunk.vectors.chunks_exact(manifest.dimension.max(1)))
+            {
+                state.buffer.insert(*d_id, v.to_vec());
+            }
+        }
+        state.deleted = manifest.deleted.into_iter().collect();
+    }
+    Ok(index)
+}
+
+/// reloads a persisted disk index with the distance named in its manifest.
+/// Its graph file is written again in dir.
+pub(crate) fn load(
+    manifest: &Cid,
+    store: &dyn BlockStore,
+    dir: &Path,
+) -> Result<Box<dyn AnnIndex>, Box<dyn Error>> {
+    let manifest: DiskManifest = store.get_block(manifest)?.decode()?;
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(format!(
+            "unsupported disk index format version {}",
+            manifest.format_version
+        )
+        .into());
+    }
+    match DistanceKind::from_type_name(&manifest.distance)? {
+        DistanceKind::Cosine => Ok(Box::new(load_with(manifest, store, dir, DistCosine)?)),
+        DistanceKind::Dot => Ok(Box::new(load_with(manifest, store, dir, DistDot)?)),
+        DistanceKind::L2 => Ok(Box::new(load_with(manifest, store, dir, DistL2)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnsw_graph::bench::gen_random_matrix_f32;
+    use crate::index::load_index;
+    use crate::ipfs_storage::block::MemoryBlockStore;
+
+    #[test]
+    fn test_disk_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let params = DiskParams {
+            max_degree: 16,
+            list_size: 50,
+            cache_size: 64,
+            max_buffer: 500,
+            ..DiskParams::default()
+        };
+        let index = DiskIndex::new(params, dir.path(), DistCosine);
+        let data = gen_random_matrix_f32(16, 1100);
+        let data_with_id: Vec<(&[f32], DataId)> = data
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.as_slice(), i))
+            .collect();
+        // the first 1000 vectors are merged in the graph file, the last 100 stay buffered
+        index.insert(&data_with_id[..500]).unwrap();
+        index.wait_merge();
+        assert_eq!(index.get_nb_buffered(), 0);
+        index.insert(&data_with_id[500..1000]).unwrap();
+        index.wait_merge();
+        index.insert(&data_with_id[1000..]).unwrap();
+        assert_eq!(index.get_nb_buffered(), 100);
+        assert_eq!(index.stats().nb_point, 1100);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut nb_common = 0;
+        for query in data.iter().step_by(50) {
+            let exact = index.exact_search(query, 10);
+            let found: Vec<DataId> = index.search(query, 10, 50).iter().map(|n| n.d_id).collect();
+            nb_common += exact.iter().filter(|n| found.contains(&n.d_id)).count();
+        }
+        assert!(nb_common >= 200 * 9 / 10);
+
+        // replacing a node of the graph shadows it
+        index.insert(&[(&data[1][..], 0)]).unwrap();
+        assert_eq!(index.stats().nb_point, 1100);
+        assert_eq!(index.delete(&[0, 1, 1050, 5000]), 3);
+        assert_eq!(index.stats().nb_point, 1097);
+        assert!(index.search(&data[1], 10, 50).iter().all(|n| n.d_id > 1));
+
+        let store = MemoryBlockStore::new();
+        let root = index.persist(&store).unwrap();
+        let reloaded = load_index(&root, &store, dir.path()).unwrap();
+        assert_eq!(reloaded.stats(), index.stats());
+        let query = &data[7];
+        let reloaded_exact: Vec<DataId> = reloaded
+            .exact_search(query, 10)
+            .iter()
+            .map(|n| n.d_id)
+            .collect();
+        let exact: Vec<DataId> = index
+            .exact_search(query, 10)
+            .iter()
+            .map(|n| n.d_id)
+            .collect();
+        assert_eq!(reloaded_exact, exact);
+
+        // dropping an index removes its graph file
+        drop(reloaded);
+        index.merge().unwrap();
+        assert_eq!(index.get_nb_buffered(), 0);
+        assert_eq!(index.stats().nb_point, 1097);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_writes_during_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let params = DiskParams {
+            max_degree: 16,
+            list_size: 50,
+            max_buffer: 500,
+            ..DiskParams::default()
+        };
+        let index = DiskIndex::new(params, dir.path(), DistCosine);
+        let data = gen_random_matrix_f32(16, 700);
+        let data_with_id: Vec<(&[f32], DataId)> = data
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.as_slice(), i))
+            .collect();
+        index.insert(&data_with_id[..500]).unwrap();
+        // whether the merge of the first 500 vectors ended or not, the writes are kept
+        assert_eq!(index.delete(&[0, 1]), 2);
+        index.insert(&[(&data[600][..], 2)]).unwrap();
+        index.insert(&data_with_id[500..550]).unwrap();
+        assert_eq!(index.stats().nb_point, 548);
+        let found = index.exact_search(&data[600], 1);
+        assert_eq!(found[0].d_id, 2);
+        assert!(found[0].distance < 1.0e-5);
+        index.wait_merge();
+        index.merge().unwrap();
+        assert_eq!(index.get_nb_buffered(), 0);
+        assert_eq!(index.stats().nb_point, 548);
+        assert_eq!(index.stats().nb_deleted, 0);
+        assert!(index
+            .search(&data[0], 10, 50)
+            .iter()
+            .all(|n| n.d_id != 0 && n.d_id != 1));
+        assert_eq!(index.search(&data[600], 1, 50)[0].d_id, 2);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_robust_prune() {
+        // points on a line: 1 hides 2 and 3 from 0
+        let position = [0f32, 1., 2., 3., -1.];
+        let dist = |a: u32, b: u32| (position[a as usize] - position[b as usize]).abs();
+        let candidates = (1..5).map(|node| (dist(0, node), node)).collect();
+        assert_eq!(robust_prune(0, candidates, 1., 4, &dist), vec![1, 4]);
+    }
+}
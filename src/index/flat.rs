@@ -0,0 +1,355 @@
+//! Exact index: every search computes the distance to all the points.
+//! It has no construction cost and suits collections of a few thousand vectors.
+
+use std::any::type_name;
+use std::collections::HashMap;
+use std::error::Error;
+
+use cid::Cid;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::hnsw_graph::dist::{DistCosine, DistDot, DistL2, Distance};
+use crate::hnsw_graph::hnsw::{DataId, IdFilter, Neighbour, PointId};
+use crate::index::{nearest, put_root, AnnIndex, DistanceKind, IndexKind, IndexStats, Visitor};
+use crate::ipfs_storage::block::{Block, BlockStore, Link};
+
+/// number of points stored in a block
+const CHUNK_SIZE: usize = 1024;
+
+const FORMAT_VERSION: u32 = 1;
+
+/// vectors stored row after row, the row of a point is its rank in ids
+#[derive(Default)]
+struct FlatPoints {
+    dimension: usize,
+    ids: Vec<DataId>,
+    vectors: Vec<f32>,
+    rows: HashMap<DataId, usize>,
+}
+
+impl FlatPoints {
+    fn vector(&self, row: usize) -> &[f32] {
+        &self.vectors[row * self.dimension..(row + 1) * self.dimension]
+    }
+
+    /// inserts or replaces the vector of d_id
+    fn upsert(&mut self, v: &[f32], d_id: DataId) {
+        match self.rows.get(&d_id) {
+            Some(&row) => {
+                self.vectors[row * self.dimension..(row + 1) * self.dimension].copy_from_slice(v)
+            }
+            None => {
+                self.rows.insert(d_id, self.ids.len());
+                self.ids.push(d_id);
+                self.vectors.extend_from_slice(v);
+            }
+        }
+    }
+
+    /// moves the last row into the removed one
+    fn remove(&mut self, d_id: DataId) -> bool {
+        let row = match self.rows.remove(&d_id) {
+            Some(row) => row,
+            None => return false,
+        };
+        let last = self.ids.len() - 1;
+        if row != last {
+            let moved = self.ids[last];
+            self.ids[row] = moved;
+            self.rows.insert(moved, row);
+            let (head, tail) = self.vectors.split_at_mut(last * self.dimension);
+            head[row * self.dimension..(row + 1) * self.dimension].copy_from_slice(tail);
+        }
+        self.ids.pop();
+        self.vectors.truncate(last * self.dimension);
+        true
+    }
+}
+
+pub struct FlatIndex<D: Distance<f32>> {
+    dist_f: D,
+    points: RwLock<FlatPoints>,
+}
+
+impl<D: Distance<f32> + Send + Sync> FlatIndex<D> {
+    pub fn new(dist_f: D) -> Self {
+        FlatIndex {
+            dist_f,
+            points: RwLock::new(FlatPoints::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.read().ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct FlatManifest {
+    format_version: u32,
+    distance: String,
+    dimension: usize,
+    nb_point: usize,
+    /// chunks of FlatChunk
+    chunks: Vec<Link>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FlatChunk {
+    ids: Vec<DataId>,
+    vectors: Vec<f32>,
+}
+
+impl<D: Distance<f32> + Send + Sync> AnnIndex for FlatIndex<D> {
+    fn kind(&self) -> IndexKind {
+        IndexKind::Flat
+    }
+
+    /// inserting an id already present replaces its vector
+    fn insert(&self, data: &[(&[f32], DataId)]) -> Result<(), Box<dyn Error>> {
+        let mut points = self.points.write();
+        if points.ids.is_empty() {
+            if let Some((v, _)) = data.first() {
+                points.dimension = v.len();
+            }
+        }
+        if let Some((v, d_id)) = data.iter().find(|(v, _)| v.len() != points.dimension) {
+            return Err(format!(
+                "vector {} has dimension {}, expected {}",
+                d_id,
+                v.len(),
+                points.dimension
+            )
+            .into());
+        }
+        for (v, d_id) in data {
+            points.upsert(v, *d_id);
+        }
+        Ok(())
+    }
+
+    fn vector(&self, d_id: DataId) -> Option<Vec<f32>> {
+        let points = self.points.read();
+        points
+            .rows
+            .get(&d_id)
+            .map(|row| points.vector(*row).to_vec())
+    }
+
+    fn delete(&self, ids: &[DataId]) -> usize {
+        let mut points = self.points.write();
+        ids.iter().filter(|d_id| points.remove(**d_id)).count()
+    }
+
+    fn search(&self, query: &[f32], knbn: usize, _ef: usize) -> Vec<Neighbour> {
+        self.exact_search(query, knbn)
+    }
+
+    fn filtered_search(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        _ef: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        self.filtered_exact_search(query, knbn, filter)
+    }
+
+    fn filtered_exact_search(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        let points = self.points.read();
+        let candidates = points
+            .ids
+            .iter()
+            .enumerate()
+            .filter(|(_, d_id)| filter(**d_id))
+            .map(|(row, d_id)| {
+                Neighbour::new(
+                    *d_id,
+                    self.dist_f.eval(query, points.vector(row)),
+                    PointId(0, row as i32),
+                )
+            });
+        nearest(candidates, knbn)
+    }
+
+    fn range_search(&self, query: &[f32], radius: f32, _ef: usize) -> Vec<Neighbour> {
+        let points = self.points.read();
+        let mut neighbours: Vec<Neighbour> = points
+            .ids
+            .iter()
+            .enumerate()
+            .map(|(row, d_id)| {
+                Neighbour::new(
+                    *d_id,
+                    self.dist_f.eval(query, points.vector(row)),
+                    PointId(0, row as i32),
+                )
+            })
+            .filter(|n| n.distance <= radius)
+            .collect();
+        neighbours.sort_unstable_by(|a, b| a.distance.total_cmp(&b.distance));
+        neighbours
+    }
+
+    fn stats(&self) -> IndexStats {
+        let points = self.points.read();
+        IndexStats {
+            kind: IndexKind::Flat,
+            distance: type_name::<D>().to_string(),
+            dimension: points.dimension,
+            nb_point: points.ids.len(),
+            nb_deleted: 0,
+        }
+    }
+
+    fn scan(&self, visit: &mut Visitor) -> Result<(), Box<dyn Error>> {
+        let points = self.points.read();
+        for (row, d_id) in points.ids.iter().enumerate() {
+            visit(*d_id, points.vector(row))?;
+        }
+        Ok(())
+    }
+
+    fn persist(&self, store: &dyn BlockStore) -> Result<Cid, Box<dyn Error>> {
+        let points = self.points.read();
+        let mut chunks = Vec::new();
+        for (rank, ids) in points.ids.chunks(CHUNK_SIZE).enumerate() {
+            let start = rank * CHUNK_SIZE * points.dimension;
+            let chunk = FlatChunk {
+                ids: ids.to_vec(),
+                vectors: points.vectors[start..start + ids.len() * points.dimension].to_vec(),
+            };
+            let block = Block::encode(&chunk)?;
+            chunks.push(Link(block.cid));
+            store.put(block)?;
+        }
+        let block = Block::encode(&FlatManifest {
+            format_version: FORMAT_VERSION,
+            distance: type_name::<D>().to_string(),
+            dimension: points.dimension,
+            nb_point: points.ids.len(),
+            chunks,
+        })?;
+        let manifest = block.cid;
+        store.put(block)?;
+        put_root(IndexKind::Flat, manifest, store)
+    }
+}
+
+fn load_with<D: Distance<f32> + Send + Sync>(
+    manifest: &FlatManifest,
+    store: &dyn BlockStore,
+    dist_f: D,
+) -> Result<FlatIndex<D>, Box<dyn Error>> {
+    let index = FlatIndex::new(dist_f);
+    {
+        let mut points = index.points.write();
+        points.dimension = manifest.dimension;
+        for link in &manifest.chunks {
+            let chunk: FlatChunk = store.get_block(&link.0)?.decode()?;
+            if chunk.vectors.len() != chunk.ids.len() * manifest.dimension {
+                return Err("inconsistent chunk in flat index".into());
+            }
+            for (row, d_id) in chunk.ids.iter().enumerate() {
+                let start = row * manifest.dimension;
+                points.upsert(&chunk.vectors[start..start + manifest.dimension], *d_id);
+            }
+        }
+    }
+    Ok(index)
+}
+
+/// reloads a persisted flat index with the distance named in its manifest
+pub(crate) fn load(
+    manifest: &Cid,
+    store: &dyn BlockStore,
+) -> Result<Box<dyn AnnIndex>, Box<dyn Error>> {
+    let manifest: FlatManifest = store.get_block(manifest)?.decode()?;
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(format!(
+            "unsupported flat index format version {}",
+            manifest.format_version
+        )
+        .into());
+    }
+    match DistanceKind::from_type_name(&manifest.distance)? {
+        DistanceKind::Cosine => Ok(Box::new(load_with(&manifest, store, DistCosine)?)),
+        DistanceKind::Dot => Ok(Box::new(load_with(&manifest, store, DistDot)?)),
+        DistanceKind::L2 => Ok(Box::new(load_with(&manifest, store, DistL2)?)),
+    }
+}
+
+/// the chunks of vectors of a persisted flat index, from its manifest
+pub(crate) fn vector_chunks(manifest: &Block) -> Result<Vec<Cid>, Box<dyn Error>> {
+    let manifest: FlatManifest = manifest.decode()?;
+    Ok(manifest.chunks.into_iter().map(|link| link.0).collect())
+}
+
+/// the distance of a persisted flat index, from its manifest
+pub(crate) fn distance(manifest: &Block) -> Result<DistanceKind, Box<dyn Error>> {
+    DistanceKind::from_type_name(&manifest.decode::<FlatManifest>()?.distance)
+}
+
+/// the points of a chunk of vectors of the flat index of manifest
+pub(crate) fn chunk_points(
+    manifest: &Block,
+    chunk: &Block,
+) -> Result<Vec<(DataId, Vec<f32>)>, Box<dyn Error>> {
+    let dimension = manifest.decode::<FlatManifest>()?.dimension;
+    let chunk: FlatChunk = chunk.decode()?;
+    if dimension == 0 || chunk.vectors.len() != chunk.ids.len() * dimension {
+        return Err("inconsistent chunk in flat index".into());
+    }
+    Ok(chunk
+        .ids
+        .into_iter()
+        .zip(chunk.vectors.chunks(dimension).map(<[f32]>::to_vec))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upsert_and_remove() {
+        let index = FlatIndex::new(DistCosine);
+        let data = vec![vec![1., 0.], vec![0., 1.], vec![1., 1.]];
+        let data_with_id: Vec<(&[f32], DataId)> = data
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.as_slice(), i))
+            .collect();
+        index.insert(&data_with_id).unwrap();
+        assert!(index.insert(&[(&[1., 2., 3.][..], 7)]).is_err());
+
+        // replacing a vector keeps a single point
+        index.insert(&[(&[0., 2.][..], 0)]).unwrap();
+        assert_eq!(index.len(), 3);
+        let found = index.search(&[0., 1.], 2, 0);
+        assert_eq!(found[0].distance, 0.);
+        assert_eq!(found[1].distance, 0.);
+
+        assert_eq!(index.delete(&[0, 0, 9]), 1);
+        assert_eq!(index.len(), 2);
+        let mut scanned = Vec::new();
+        index
+            .scan(&mut |d_id, v| {
+                scanned.push((d_id, v.to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        scanned.sort_by_key(|(d_id, _)| *d_id);
+        assert_eq!(scanned, vec![(1, vec![0., 1.]), (2, vec![1., 1.])]);
+    }
+}
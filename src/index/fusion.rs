@@ -0,0 +1,121 @@
+//! Fusion of the rankings of several searches of the same points, as the dense and the
+//! keyword searches of a hybrid search.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hnsw_graph::hnsw::DataId;
+
+/// constant of reciprocal rank fusion, which damps the weight of the first ranks
+const RRF_K: f32 = 60.;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fusion {
+    /// reciprocal rank fusion, a point scores weight / (60 + rank) in each ranking
+    #[default]
+    Rrf,
+    /// the scores of each ranking are scaled to [0, 1] by min-max normalization, a point
+    /// scores weight * scaled score in each ranking
+    Weighted,
+}
+
+impl fmt::Display for Fusion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fusion::Rrf => write!(f, "rrf"),
+            Fusion::Weighted => write!(f, "weighted"),
+        }
+    }
+}
+
+impl FromStr for Fusion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rrf" => Ok(Fusion::Rrf),
+            "weighted" => Ok(Fusion::Weighted),
+            _ => Err(format!("unknown fusion {}, expected rrf or weighted", s)),
+        }
+    }
+}
+
+/// the points found by a search with their scores, greater is better, best first
+#[derive(Debug, Clone)]
+pub struct Ranking {
+    pub weight: f32,
+    pub hits: Vec<(DataId, f32)>,
+}
+
+/// the knbn points of best fused score over rankings, best first. A point missing from a
+/// ranking scores 0 in it.
+pub fn fuse(fusion: Fusion, rankings: &[Ranking], knbn: usize) -> Vec<(DataId, f32)> {
+    let mut scores: HashMap<DataId, f32> = HashMap::new();
+    for ranking in rankings {
+        let (min, max) = ranking
+            .hits
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), (_, s)| {
+                (min.min(*s), max.max(*s))
+            });
+        for (rank, (d_id, score)) in ranking.hits.iter().enumerate() {
+            let fused = match fusion {
+                Fusion::Rrf => 1. / (RRF_K + rank as f32 + 1.),
+                // all points of a ranking with equal scores are the best of it
+                Fusion::Weighted if max > min => (score - min) / (max - min),
+                Fusion::Weighted => 1.,
+            };
+            *scores.entry(*d_id).or_insert(0.) += ranking.weight * fused;
+        }
+    }
+    let mut fused: Vec<(DataId, f32)> = scores.into_iter().collect();
+    fused.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    fused.truncate(knbn);
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuse() {
+        let dense = Ranking {
+            weight: 1.,
+            hits: vec![(1, -0.1), (2, -0.2), (3, -0.9)],
+        };
+        let keywords = Ranking {
+            weight: 1.,
+            hits: vec![(3, 12.), (2, 11.), (4, 2.)],
+        };
+        let rankings = [dense, keywords];
+        // 3, first and third, is just ahead of 2, second in both rankings
+        assert_eq!(
+            fuse(Fusion::Rrf, &rankings, 2)
+                .iter()
+                .map(|h| h.0)
+                .collect::<Vec<_>>(),
+            vec![3, 2]
+        );
+        let weighted = fuse(Fusion::Weighted, &rankings, 4);
+        assert_eq!(
+            weighted.iter().map(|h| h.0).collect::<Vec<_>>(),
+            vec![2, 1, 3, 4]
+        );
+        assert!((weighted[0].1 - (0.875 + 0.9)).abs() < 1e-5);
+
+        let favour_keywords = [
+            Ranking {
+                weight: 0.05,
+                ..rankings[0].clone()
+            },
+            rankings[1].clone(),
+        ];
+        assert_eq!(fuse(Fusion::Weighted, &favour_keywords, 1)[0].0, 3);
+        assert_eq!("RRF".parse::<Fusion>(), Ok(Fusion::Rrf));
+    }
+}
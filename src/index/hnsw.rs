@@ -0,0 +1,240 @@
+//! AnnIndex implementation of Hnsw. Deleted points are tombstoned, see Hnsw::mark_deleted,
+//! and updated points are superseded by new ones, see Hnsw::supersede.
+//! Saved to a directory, its vectors are memory mapped when opened again.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+
+use cid::Cid;
+
+use crate::hnsw_graph::dist::{DistCosine, DistDot, DistL2, Distance};
+use crate::hnsw_graph::hnsw::{DataId, Hnsw, IdFilter, Neighbour};
+use crate::hnsw_graph::hnswio::{self, IndexManifest};
+use crate::index::{nearest, put_root, AnnIndex, DistanceKind, IndexKind, IndexStats, Visitor};
+use crate::ipfs_storage::block::{Block, BlockStore};
+
+/// number of neighbours asked first by range_search, doubled until the radius is passed
+const RANGE_FIRST_KNBN: usize = 16;
+
+impl<D: Distance<f32> + Send + Sync> AnnIndex for Hnsw<f32, D> {
+    fn kind(&self) -> IndexKind {
+        IndexKind::Hnsw
+    }
+
+    /// the points of the ids already present, deleted or not, are superseded by the new
+    /// ones, see Hnsw::supersede
+    fn insert(&self, data: &[(&[f32], DataId)]) -> Result<(), Box<dyn Error>> {
+        check_dimension(self, data)?;
+        // an id inserted twice keeps its last vector
+        let last: HashMap<DataId, usize> = data
+            .iter()
+            .enumerate()
+            .map(|(i, (_, d_id))| (*d_id, i))
+            .collect();
+        let data: Vec<(&[f32], DataId)> = data
+            .iter()
+            .enumerate()
+            .filter(|(i, (_, d_id))| last[d_id] == *i)
+            .map(|(_, point)| *point)
+            .collect();
+        self.supersede(&last.into_keys().collect());
+        self.parallel_insert_slice(&data);
+        Ok(())
+    }
+
+    fn vector(&self, d_id: DataId) -> Option<Vec<f32>> {
+        self.get_origin_data(d_id)
+    }
+
+    fn delete(&self, ids: &[DataId]) -> usize {
+        let ids: HashSet<DataId> = ids.iter().copied().collect();
+        let found: Vec<DataId> = ids
+            .into_iter()
+            .filter(|d_id| self.get_origin_data(*d_id).is_some())
+            .collect();
+        for d_id in &found {
+            self.mark_deleted(*d_id);
+        }
+        found.len()
+    }
+
+    fn search(&self, query: &[f32], knbn: usize, ef: usize) -> Vec<Neighbour> {
+        Hnsw::search(self, query, knbn, ef)
+    }
+
+    fn filtered_search(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        ef: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        Hnsw::search_filter(self, query, knbn, ef, filter)
+    }
+
+    fn filtered_exact_search(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        let point_indexation = self.get_point_indexation();
+        // the point iterator requires an entry point
+        if point_indexation.get_nb_point() == 0 {
+            return Vec::new();
+        }
+        let deleted = self.deleted.read();
+        let superseded = self.superseded.read();
+        let candidates = point_indexation
+            .into_iter()
+            .filter(|point| {
+                !deleted.contains(&point.get_origin_id())
+                    && !superseded.contains(&point.get_point_id())
+                    && filter(point.get_origin_id())
+            })
+            .map(|point| {
+                Neighbour::new(
+                    point.get_origin_id(),
+                    self.get_distance().eval(query, point.get_v()),
+                    point.get_point_id(),
+                )
+            });
+        nearest(candidates, knbn)
+    }
+
+    fn range_search(&self, query: &[f32], radius: f32, ef: usize) -> Vec<Neighbour> {
+        let mut knbn = RANGE_FIRST_KNBN;
+        loop {
+            let mut neighbours = Hnsw::search(self, query, knbn, ef.max(knbn));
+            let complete = neighbours.len() < knbn
+                || neighbours.last().is_none_or(|n| n.distance > radius)
+                || knbn >= self.get_nb_point();
+            if complete {
+                neighbours.retain(|n| n.distance <= radius);
+                return neighbours;
+            }
+            knbn *= 2;
+        }
+    }
+
+    fn stats(&self) -> IndexStats {
+        IndexStats {
+            kind: IndexKind::Hnsw,
+            distance: self.get_distance_name(),
+            dimension: self.get_point_indexation().get_data_dimension(),
+            nb_point: self.get_nb_point() - self.get_nb_deleted() - self.get_nb_superseded(),
+            nb_deleted: self.get_nb_deleted() + self.get_nb_superseded(),
+        }
+    }
+
+    fn scan(&self, visit: &mut Visitor) -> Result<(), Box<dyn Error>> {
+        let point_indexation = self.get_point_indexation();
+        if point_indexation.get_nb_point() == 0 {
+            return Ok(());
+        }
+        for point in point_indexation {
+            if !self.is_deleted(point.get_origin_id()) && !self.is_superseded(point.get_point_id())
+            {
+                visit(point.get_origin_id(), point.get_v())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_layer(&self, layer: usize, visit: &mut Visitor) -> Result<(), Box<dyn Error>> {
+        if layer >= self.get_max_level() {
+            return Err(format!("layer must be less than {}", self.get_max_level()).into());
+        }
+        let point_indexation = self.get_point_indexation();
+        if point_indexation.get_layer_nb_point(layer) == 0 {
+            return Ok(());
+        }
+        for point in point_indexation.get_layer_iterator(layer) {
+            if !self.is_deleted(point.get_origin_id()) && !self.is_superseded(point.get_point_id())
+            {
+                visit(point.get_origin_id(), point.get_v())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn persist(&self, store: &dyn BlockStore) -> Result<Cid, Box<dyn Error>> {
+        let manifest = hnswio::dump_to_store(self, store)?;
+        put_root(IndexKind::Hnsw, manifest, store)
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        hnswio::save_to_dir(self, dir)
+    }
+}
+
+/// checks that the vectors of data have the dimension of the points of hnsw
+fn check_dimension<D: Distance<f32> + Send + Sync>(
+    hnsw: &Hnsw<f32, D>,
+    data: &[(&[f32], DataId)],
+) -> Result<(), Box<dyn Error>> {
+    let dimension = hnsw.get_point_indexation().get_data_dimension();
+    match data.iter().find(|(v, _)| v.len() != dimension) {
+        Some((v, d_id)) if hnsw.get_nb_point() > 0 => Err(format!(
+            "vector {} has dimension {}, expected {}",
+            d_id,
+            v.len(),
+            dimension
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// reloads a persisted Hnsw with the distance named in its manifest
+pub(crate) fn load(
+    manifest: &Cid,
+    store: &dyn BlockStore,
+) -> Result<Box<dyn AnnIndex>, Box<dyn Error>> {
+    let distance = hnswio::load_manifest(manifest, store)?.distance;
+    match DistanceKind::from_type_name(&distance)? {
+        DistanceKind::Cosine => Ok(Box::new(hnswio::load_from_store::<f32, DistCosine>(
+            manifest, store, DistCosine,
+        )?)),
+        DistanceKind::Dot => Ok(Box::new(hnswio::load_from_store::<f32, DistDot>(
+            manifest, store, DistDot,
+        )?)),
+        DistanceKind::L2 => Ok(Box::new(hnswio::load_from_store::<f32, DistL2>(
+            manifest, store, DistL2,
+        )?)),
+    }
+}
+
+/// the chunks of vectors of a persisted Hnsw, from its manifest
+pub(crate) fn vector_chunks(manifest: &Block) -> Result<Vec<Cid>, Box<dyn Error>> {
+    let manifest: IndexManifest = manifest.decode()?;
+    Ok(manifest.vectors.into_iter().map(|link| link.0).collect())
+}
+
+/// the distance of a persisted Hnsw, from its manifest
+pub(crate) fn distance(manifest: &Block) -> Result<DistanceKind, Box<dyn Error>> {
+    DistanceKind::from_type_name(&manifest.decode::<IndexManifest>()?.distance)
+}
+
+/// the points of a chunk of vectors of the Hnsw of manifest, without the deleted ones
+pub(crate) fn chunk_points(
+    manifest: &Block,
+    chunk: &Block,
+) -> Result<Vec<(DataId, Vec<f32>)>, Box<dyn Error>> {
+    hnswio::chunk_points(&manifest.decode()?, chunk)
+}
+
+/// opens a Hnsw saved to dir with the distance named in its manifest, mapping its vectors
+pub(crate) fn open(dir: &Path) -> Result<Box<dyn AnnIndex>, Box<dyn Error>> {
+    let distance = hnswio::load_dir_manifest(dir)?.distance;
+    match DistanceKind::from_type_name(&distance)? {
+        DistanceKind::Cosine => Ok(Box::new(hnswio::load_from_dir::<f32, DistCosine>(
+            dir, DistCosine,
+        )?)),
+        DistanceKind::Dot => Ok(Box::new(hnswio::load_from_dir::<f32, DistDot>(
+            dir, DistDot,
+        )?)),
+        DistanceKind::L2 => Ok(Box::new(hnswio::load_from_dir::<f32, DistL2>(dir, DistL2)?)),
+    }
+}
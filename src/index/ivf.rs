@@ -0,0 +1,600 @@
+//! Inverted file index: a k-means coarse quantizer splits the vectors in nlist posting
+//! lists and a search only scans the nprobe lists whose centroids are nearest to the query.
+//!
+//! Inserting costs nlist distance evaluations and deleting is constant time, so the index
+//! suits write-heavy collections. Until nlist * TRAIN_POINTS_PER_LIST vectors are inserted,
+//! all of them are kept raw in a single list. The centroids and the quantizer are then
+//! trained on these vectors and kept afterwards.
+
+use std::any::type_name;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use cid::Cid;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::hnsw_graph::dist::{DistCosine, DistDot, DistL2, Distance};
+use crate::hnsw_graph::hnsw::{DataId, IdFilter, Neighbour, PointId};
+use crate::index::kmeans::{kmeans, nearest_centroid, nearest_centroids};
+use crate::index::quantizer::{Quantization, Quantizer};
+use crate::index::{nearest, put_root, AnnIndex, DistanceKind, IndexKind, IndexStats, Visitor};
+use crate::ipfs_storage::block::{Block, BlockStore, Bytes, Link};
+
+/// number of vectors per list needed to train the coarse quantizer
+const TRAIN_POINTS_PER_LIST: usize = 32;
+
+/// number of k-means iterations of the coarse quantizer
+const NB_ITER: usize = 10;
+
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IvfParams {
+    /// number of posting lists
+    pub nlist: usize,
+    /// number of lists scanned by a search
+    pub nprobe: usize,
+    /// compression of the vectors of the posting lists
+    pub quantization: Quantization,
+}
+
+impl IvfParams {
+    /// checks that an index can be built from the params
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.nlist == 0 {
+            return Err("an ivf index needs at least one list".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for IvfParams {
+    fn default() -> Self {
+        IvfParams {
+            nlist: 256,
+            nprobe: 16,
+            quantization: Quantization::None,
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct PostingList {
+    ids: Vec<DataId>,
+    /// codes of the vectors, code_size bytes each
+    codes: Bytes,
+}
+
+struct IvfState {
+    dimension: usize,
+    /// nlist centroids once trained, empty before
+    centroids: Vec<f32>,
+    quantizer: Quantizer,
+    lists: Vec<PostingList>,
+    /// list and rank in list of each id
+    location: HashMap<DataId, (usize, usize)>,
+}
+
+impl IvfState {
+    fn is_trained(&self) -> bool {
+        !self.centroids.is_empty()
+    }
+
+    fn nb_point(&self) -> usize {
+        self.location.len()
+    }
+
+    fn remove(&mut self, d_id: DataId) -> bool {
+        let (list, rank) = match self.location.remove(&d_id) {
+            Some(location) => location,
+            None => return false,
+        };
+        let code_size = self.quantizer.code_size();
+        let posting = &mut self.lists[list];
+        let last = posting.ids.len() - 1;
+        posting.ids.swap_remove(rank);
+        if rank != last {
+            let (head, tail) = posting.codes.0.split_at_mut(last * code_size);
+            head[rank * code_size..(rank + 1) * code_size].copy_from_slice(&tail[..code_size]);
+            self.location.insert(posting.ids[rank], (list, rank));
+        }
+        posting.codes.0.truncate(last * code_size);
+        true
+    }
+
+    fn push(&mut self, list: usize, v: &[f32], d_id: DataId) {
+        let posting = &mut self.lists[list];
+        self.location.insert(d_id, (list, posting.ids.len()));
+        posting.ids.push(d_id);
+        self.quantizer.encode(v, &mut posting.codes.0);
+    }
+
+    /// calls visit with the id, list and decoded vector of the points of the given lists
+    /// decodes the points of lists accepted by filter and calls visit on them
+    fn visit_lists<F: FnMut(DataId, usize, &[f32])>(
+        &self,
+        lists: &[usize],
+        filter: &IdFilter,
+        mut visit: F,
+    ) {
+        let code_size = self.quantizer.code_size();
+        let mut v = vec![0.; self.dimension];
+        for &list in lists {
+            let posting = &self.lists[list];
+            for (d_id, code) in posting
+                .ids
+                .iter()
+                .zip(posting.codes.0.chunks_exact(code_size))
+            {
+                if !filter(*d_id) {
+                    continue;
+                }
+                self.quantizer.decode(code, &mut v);
+                visit(*d_id, list, &v);
+            }
+        }
+    }
+}
+
+pub struct IvfIndex<D: Distance<f32>> {
+    dist_f: D,
+    params: IvfParams,
+    state: RwLock<IvfState>,
+}
+
+impl<D: Distance<f32> + Send + Sync> IvfIndex<D> {
+    pub fn new(params: IvfParams, dist_f: D) -> Self {
+        IvfIndex {
+            dist_f,
+            params,
+            state: RwLock::new(IvfState {
+                dimension: 0,
+                centroids: Vec::new(),
+                quantizer: Quantizer::Raw { dimension: 0 },
+                lists: vec![PostingList::default()],
+                location: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn get_params(&self) -> &IvfParams {
+        &self.params
+    }
+
+    pub fn is_trained(&self) -> bool {
+        self.state.read().is_trained()
+    }
+
+    /// trains the centroids and the quantizer on data, the vectors of ids, then distributes
+    /// them in the posting lists of a new state
+    fn train(&self, dim: usize, data: &[f32], ids: &[DataId]) -> Result<IvfState, Box<dyn Error>> {
+        let quantizer = Quantizer::train(self.params.quantization, data, dim)?;
+        let centroids = kmeans(data, dim, self.params.nlist, NB_ITER, |a, b| {
+            self.dist_f.eval(a, b)
+        });
+        let mut state = IvfState {
+            dimension: dim,
+            lists: (0..centroids.len() / dim)
+                .map(|_| PostingList::default())
+                .collect(),
+            centroids,
+            quantizer,
+            location: HashMap::new(),
+        };
+        for (i, d_id) in ids.iter().enumerate() {
+            let v = &data[i * dim..(i + 1) * dim];
+            let (list, _) = nearest_centroid(&state.centroids, dim, v, &|a: &[f32], b: &[f32]| {
+                self.dist_f.eval(a, b)
+            });
+            state.push(list, v, *d_id);
+        }
+        log::info!(
+            "ivf trained {} lists on {} vectors",
+            state.lists.len(),
+            state.nb_point()
+        );
+        Ok(state)
+    }
+
+    /// the raw vectors of the single list but those of replaced ids, followed by the new
+    /// vectors, the last one of an id inserted twice winning
+    fn training_set(state: &IvfState, data: &[(&[f32], DataId)]) -> (Vec<f32>, Vec<DataId>) {
+        let dim = state.dimension;
+        let last: HashMap<DataId, usize> = data
+            .iter()
+            .enumerate()
+            .map(|(i, (_, d_id))| (*d_id, i))
+            .collect();
+        let raw = &state.lists[0];
+        let raw_data: Vec<f32> = raw
+            .codes
+            .0
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        let mut vectors = Vec::with_capacity(raw_data.len() + data.len() * dim);
+        let mut ids = Vec::with_capacity(raw.ids.len() + last.len());
+        for (i, d_id) in raw.ids.iter().enumerate() {
+            if !last.contains_key(d_id) {
+                vectors.extend_from_slice(&raw_data[i * dim..(i + 1) * dim]);
+                ids.push(*d_id);
+            }
+        }
+        for (i, (v, d_id)) in data.iter().enumerate() {
+            if last[d_id] == i {
+                vectors.extend_from_slice(v);
+                ids.push(*d_id);
+            }
+        }
+        (vectors, ids)
+    }
+
+    /// lists scanned for query: the nprobe nearest, or the single list before training
+    fn probes(&self, state: &IvfState, query: &[f32]) -> Vec<usize> {
+        if state.is_trained() {
+            nearest_centroids(
+                &state.centroids,
+                state.dimension,
+                query,
+                self.params.nprobe.max(1),
+                &|a: &[f32], b: &[f32]| self.dist_f.eval(a, b),
+            )
+        } else {
+            vec![0]
+        }
+    }
+
+    fn scored(
+        &self,
+        state: &IvfState,
+        query: &[f32],
+        lists: &[usize],
+        filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        let mut neighbours = Vec::new();
+        state.visit_lists(lists, filter, |d_id, list, v| {
+            neighbours.push(Neighbour::new(
+                d_id,
+                self.dist_f.eval(query, v),
+                PointId(0, list as i32),
+            ))
+        });
+        neighbours
+    }
+}
+
+impl<D: Distance<f32> + Send + Sync> AnnIndex for IvfIndex<D> {
+    fn kind(&self) -> IndexKind {
+        IndexKind::Ivf
+    }
+
+    /// inserting an id already present replaces its vector
+    fn insert(&self, data: &[(&[f32], DataId)]) -> Result<(), Box<dyn Error>> {
+        self.params.check()?;
+        let mut state = self.state.write();
+        if state.nb_point() == 0 && !state.is_trained() {
+            if let Some((v, _)) = data.first() {
+                if let Quantization::Product { nb_subspace } = self.params.quantization {
+                    if v.len() % nb_subspace != 0 {
+                        return Err(format!(
+                            "dimension {} is not a multiple of the {} subspaces",
+                            v.len(),
+                            nb_subspace
+                        )
+                        .into());
+                    }
+                }
+                state.dimension = v.len();
+                state.quantizer = Quantizer::Raw { dimension: v.len() };
+            }
+        }
+        let dim = state.dimension;
+        if let Some((v, d_id)) = data.iter().find(|(v, _)| v.len() != dim) {
+            return Err(format!(
+                "vector {} has dimension {}, expected {}",
+                d_id,
+                v.len(),
+                dim
+            )
+            .into());
+        }
+        if !state.is_trained() {
+            // trains before writing, so that a training error leaves the index unchanged
+            let ids: HashSet<DataId> = data.iter().map(|(_, d_id)| *d_id).collect();
+            let nb_point = state.nb_point()
+                + ids
+                    .iter()
+                    .filter(|d_id| !state.location.contains_key(*d_id))
+                    .count();
+            if nb_point >= self.params.nlist * TRAIN_POINTS_PER_LIST {
+                let (vectors, ids) = Self::training_set(&state, data);
+                *state = self.train(dim, &vectors, &ids)?;
+                return Ok(());
+            }
+        }
+        for (v, d_id) in data {
+            state.remove(*d_id);
+            let list = if state.is_trained() {
+                nearest_centroid(&state.centroids, dim, v, &|a: &[f32], b: &[f32]| {
+                    self.dist_f.eval(a, b)
+                })
+                .0
+            } else {
+                0
+            };
+            state.push(list, v, *d_id);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, ids: &[DataId]) -> usize {
+        let mut state = self.state.write();
+        ids.iter().filter(|d_id| state.remove(**d_id)).count()
+    }
+
+    fn search(&self, query: &[f32], knbn: usize, _ef: usize) -> Vec<Neighbour> {
+        let state = self.state.read();
+        let probes = self.probes(&state, query);
+        nearest(
+            self.scored(&state, query, &probes, &|_| true).into_iter(),
+            knbn,
+        )
+    }
+
+    fn filtered_search(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        _ef: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        let state = self.state.read();
+        let probes = self.probes(&state, query);
+        nearest(
+            self.scored(&state, query, &probes, filter).into_iter(),
+            knbn,
+        )
+    }
+
+    /// exact over the stored codes, which approximate the vectors when they are quantized
+    fn filtered_exact_search(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        let state = self.state.read();
+        let all: Vec<usize> = (0..state.lists.len()).collect();
+        nearest(self.scored(&state, query, &all, filter).into_iter(), knbn)
+    }
+
+    fn range_search(&self, query: &[f32], radius: f32, _ef: usize) -> Vec<Neighbour> {
+        let state = self.state.read();
+        let probes = self.probes(&state, query);
+        let mut neighbours = self.scored(&state, query, &probes, &|_| true);
+        neighbours.retain(|n| n.distance <= radius);
+        neighbours.sort_unstable_by(|a, b| a.distance.total_cmp(&b.distance));
+        neighbours
+    }
+
+    fn stats(&self) -> IndexStats {
+        let state = self.state.read();
+        IndexStats {
+            kind: IndexKind::Ivf,
+            distance: type_name::<D>().to_string(),
+            dimension: state.dimension,
+            nb_point: state.nb_point(),
+            nb_deleted: 0,
+        }
+    }
+
+    /// quantized vectors are returned decoded
+    fn scan(&self, visit: &mut Visitor) -> Result<(), Box<dyn Error>> {
+        let state = self.state.read();
+        let mut result = Ok(());
+        let all: Vec<usize> = (0..state.lists.len()).collect();
+        state.visit_lists(&all, &|_| true, |d_id, _, v| {
+            if result.is_ok() {
+                result = visit(d_id, v);
+            }
+        });
+        result
+    }
+
+    fn persist(&self, store: &dyn BlockStore) -> Result<Cid, Box<dyn Error>> {
+        let state = self.state.read();
+        let mut lists = Vec::with_capacity(state.lists.len());
+        for posting in &state.lists {
+            let block = Block::encode(posting)?;
+            lists.push(Link(block.cid));
+            store.put(block)?;
+        }
+        let block = Block::encode(&IvfManifest {
+            format_version: FORMAT_VERSION,
+            distance: type_name::<D>().to_string(),
+            params: self.params,
+            dimension: state.dimension,
+            centroids: state.centroids.clone(),
+            quantizer: state.quantizer.clone(),
+            lists,
+        })?;
+        let manifest = block.cid;
+        store.put(block)?;
+        put_root(IndexKind::Ivf, manifest, store)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct IvfManifest {
+    format_version: u32,
+    distance: String,
+    params: IvfParams,
+    dimension: usize,
+    centroids: Vec<f32>,
+    quantizer: Quantizer,
+    /// one PostingList per centroid, a single one before training
+    lists: Vec<Link>,
+}
+
+fn load_with<D: Distance<f32> + Send + Sync>(
+    manifest: IvfManifest,
+    store: &dyn BlockStore,
+    dist_f: D,
+) -> Result<IvfIndex<D>, Box<dyn Error>> {
+    let index = IvfIndex::new(manifest.params, dist_f);
+    {
+        let mut state = index.state.write();
+        state.lists.clear();
+        for (list, link) in manifest.lists.iter().enumerate() {
+            let posting: PostingList = store.get_block(&link.0)?.decode()?;
+            if posting.codes.0.len() != posting.ids.len() * manifest.quantizer.code_size() {
+                return Err("inconsistent posting list in ivf index".into());
+            }
+            for (rank, d_id) in posting.ids.iter().enumerate() {
+                state.location.insert(*d_id, (list, rank));
+            }
+            state.lists.push(posting);
+        }
+        state.dimension = manifest.dimension;
+        state.centroids = manifest.centroids;
+        state.quantizer = manifest.quantizer;
+    }
+    Ok(index)
+}
+
+/// reloads a persisted ivf index with the distance named in its manifest
+pub(crate) fn load(
+    manifest: &Cid,
+    store: &dyn BlockStore,
+) -> Result<Box<dyn AnnIndex>, Box<dyn Error>> {
+    let manifest: IvfManifest = store.get_block(manifest)?.decode()?;
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(format!(
+            "unsupported ivf index format version {}",
+            manifest.format_version
+        )
+        .into());
+    }
+    match DistanceKind::from_type_name(&manifest.distance)? {
+        DistanceKind::Cosine => Ok(Box::new(load_with(manifest, store, DistCosine)?)),
+        DistanceKind::Dot => Ok(Box::new(load_with(manifest, store, DistDot)?)),
+        DistanceKind::L2 => Ok(Box::new(load_with(manifest, store, DistL2)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnsw_graph::bench::gen_random_matrix_f32;
+    use crate::index::load_index;
+    use crate::ipfs_storage::block::MemoryBlockStore;
+
+    #[test]
+    fn test_ivf() {
+        let params = IvfParams {
+            nlist: 8,
+            nprobe: 8,
+            quantization: Quantization::None,
+        };
+        let index = IvfIndex::new(params, DistCosine);
+        let data = gen_random_matrix_f32(10, 1000);
+        let data_with_id: Vec<(&[f32], DataId)> = data
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.as_slice(), i))
+            .collect();
+        index.insert(&data_with_id[..100]).unwrap();
+        assert!(!index.is_trained());
+        index.insert(&data_with_id[100..]).unwrap();
+        assert!(index.is_trained());
+        assert_eq!(index.stats().nb_point, 1000);
+
+        // scanning all the lists is exact
+        let query = &data[3];
+        let found: Vec<DataId> = index.search(query, 10, 0).iter().map(|n| n.d_id).collect();
+        let exact: Vec<DataId> = index
+            .exact_search(query, 10)
+            .iter()
+            .map(|n| n.d_id)
+            .collect();
+        assert_eq!(found, exact);
+        assert_eq!(found[0], 3);
+
+        assert_eq!(index.delete(&[3, 3, 4000]), 1);
+        assert_eq!(index.stats().nb_point, 999);
+        assert!(index.search(query, 10, 0).iter().all(|n| n.d_id != 3));
+
+        let store = MemoryBlockStore::new();
+        let root = index.persist(&store).unwrap();
+        let reloaded = load_index(&root, &store, &std::env::temp_dir()).unwrap();
+        assert_eq!(reloaded.stats(), index.stats());
+        let reloaded_found: Vec<DataId> = reloaded
+            .search(query, 10, 0)
+            .iter()
+            .map(|n| n.d_id)
+            .collect();
+        let found: Vec<DataId> = index.search(query, 10, 0).iter().map(|n| n.d_id).collect();
+        assert_eq!(reloaded_found, found);
+    }
+
+    #[test]
+    fn test_ivf_training() {
+        let data = gen_random_matrix_f32(10, 300);
+        let data_with_id: Vec<(&[f32], DataId)> = data
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.as_slice(), i))
+            .collect();
+        let empty = IvfIndex::new(
+            IvfParams {
+                nlist: 0,
+                ..IvfParams::default()
+            },
+            DistCosine,
+        );
+        assert!(empty.insert(&data_with_id).is_err());
+        assert_eq!(empty.stats().nb_point, 0);
+
+        let index = IvfIndex::new(
+            IvfParams {
+                nlist: 4,
+                nprobe: 4,
+                quantization: Quantization::None,
+            },
+            DistCosine,
+        );
+        index.insert(&data_with_id[..100]).unwrap();
+        // the batch reaching the 128 training points replaces 0 twice
+        let mut batch = data_with_id[100..128].to_vec();
+        batch.push((&data[200], 0));
+        batch.push((&data[201], 0));
+        index.insert(&batch).unwrap();
+        assert!(index.is_trained());
+        assert_eq!(index.stats().nb_point, 128);
+        assert_eq!(index.search(&data[201], 1, 0)[0].d_id, 0);
+        assert!(index.search(&data[0], 1, 0)[0].distance > 0.);
+    }
+
+    #[test]
+    fn test_ivf_quantized() {
+        let params = IvfParams {
+            nlist: 4,
+            nprobe: 2,
+            quantization: Quantization::Scalar,
+        };
+        let index = IvfIndex::new(params, DistCosine);
+        let data = gen_random_matrix_f32(8, 500);
+        let data_with_id: Vec<(&[f32], DataId)> = data
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.as_slice(), i))
+            .collect();
+        index.insert(&data_with_id).unwrap();
+        assert!(index.is_trained());
+        // the query itself is in the nearest list and survives 8 bit quantization
+        let found = index.search(&data[42], 1, 0);
+        assert_eq!(found[0].d_id, 42);
+        assert!(found[0].distance < 1.0e-3);
+    }
+}
@@ -0,0 +1,120 @@
+//! Lloyd's k-means over vectors stored row after row, with any distance.
+
+use rand::prelude::*;
+use rayon::prelude::*;
+
+/// returns the rank of the centroid nearest to v and its distance
+pub(crate) fn nearest_centroid<F>(
+    centroids: &[f32],
+    dim: usize,
+    v: &[f32],
+    dist: &F,
+) -> (usize, f32)
+where
+    F: Fn(&[f32], &[f32]) -> f32,
+{
+    centroids
+        .chunks_exact(dim)
+        .map(|c| dist(v, c))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, f32::MAX))
+}
+
+/// returns the ranks of the n centroids nearest to v, nearest first
+pub(crate) fn nearest_centroids<F>(
+    centroids: &[f32],
+    dim: usize,
+    v: &[f32],
+    n: usize,
+    dist: &F,
+) -> Vec<usize>
+where
+    F: Fn(&[f32], &[f32]) -> f32,
+{
+    let mut distances: Vec<(usize, f32)> = centroids
+        .chunks_exact(dim)
+        .map(|c| dist(v, c))
+        .enumerate()
+        .collect();
+    distances.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+    distances
+        .into_iter()
+        .take(n)
+        .map(|(rank, _)| rank)
+        .collect()
+}
+
+/// computes at most k centroids of data, a matrix of dimension dim, in nb_iter iterations.
+/// Centroids start on distinct points drawn at random, empty clusters are moved to a random point.
+pub(crate) fn kmeans<F>(data: &[f32], dim: usize, k: usize, nb_iter: usize, dist: F) -> Vec<f32>
+where
+    F: Fn(&[f32], &[f32]) -> f32 + Sync,
+{
+    let nb_point = if dim > 0 { data.len() / dim } else { 0 };
+    let k = k.min(nb_point);
+    if k == 0 {
+        return Vec::new();
+    }
+    let mut rng = thread_rng();
+    let mut centroids: Vec<f32> = rand::seq::index::sample(&mut rng, nb_point, k)
+        .iter()
+        .flat_map(|i| data[i * dim..(i + 1) * dim].iter().copied())
+        .collect();
+    for _ in 0..nb_iter {
+        let assignment: Vec<usize> = data
+            .par_chunks_exact(dim)
+            .map(|v| nearest_centroid(&centroids, dim, v, &dist).0)
+            .collect();
+        let mut sums = vec![0f32; k * dim];
+        let mut counts = vec![0usize; k];
+        for (v, &c) in data.chunks_exact(dim).zip(assignment.iter()) {
+            counts[c] += 1;
+            for (sum, x) in sums[c * dim..(c + 1) * dim].iter_mut().zip(v) {
+                *sum += x;
+            }
+        }
+        for c in 0..k {
+            let centroid = &mut centroids[c * dim..(c + 1) * dim];
+            if counts[c] > 0 {
+                for (x, sum) in centroid.iter_mut().zip(&sums[c * dim..(c + 1) * dim]) {
+                    *x = sum / counts[c] as f32;
+                }
+            } else {
+                let i = rng.gen_range(0..nb_point);
+                centroid.copy_from_slice(&data[i * dim..(i + 1) * dim]);
+            }
+        }
+    }
+    centroids
+}
+
+/// squared euclidean distance, used to quantize sub vectors
+pub(crate) fn l2_squared(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kmeans_finds_clusters() {
+        // two well separated groups around (0, 0) and (10, 10)
+        let mut data = Vec::new();
+        for i in 0..50 {
+            let offset = if i % 2 == 0 { 0. } else { 10. };
+            data.extend([offset + (i % 5) as f32 * 0.1, offset - (i % 3) as f32 * 0.1]);
+        }
+        let centroids = kmeans(&data, 2, 2, 10, l2_squared);
+        assert_eq!(centroids.len(), 4);
+        let (near_origin, _) = nearest_centroid(&centroids, 2, &[0., 0.], &l2_squared);
+        let (near_ten, _) = nearest_centroid(&centroids, 2, &[10., 10.], &l2_squared);
+        assert_ne!(near_origin, near_ten);
+        assert!(l2_squared(&centroids[near_ten * 2..near_ten * 2 + 2], &[10., 10.]) < 1.);
+        assert_eq!(
+            nearest_centroids(&centroids, 2, &[9., 9.], 2, &l2_squared)[0],
+            near_ten
+        );
+    }
+}
@@ -0,0 +1,718 @@
+//! Index types behind a common trait.
+//!
+//! A collection holds a `Box<dyn AnnIndex>`, so it can use an Hnsw graph, an exact
+//! flat index, an inverted file index, a disk resident graph, an index of sparse vectors or
+//! an index of documents of several vectors. Every index persists itself under an
+//! `IndexRoot` block that records its kind, so that `load_index` can rebuild it without
+//! knowing it beforehand.
+
+pub mod disk;
+pub mod flat;
+pub mod fusion;
+mod hnsw;
+pub mod ivf;
+pub(crate) mod kmeans;
+pub mod multivector;
+pub mod quantizer;
+pub mod sparse;
+
+use std::any::type_name;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use cid::Cid;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::hnsw_graph::dist::{DistCosine, DistDot, DistL2, Distance};
+use crate::hnsw_graph::hnsw::{DataId, Hnsw, IdFilter, Neighbour};
+use crate::ipfs_storage::block::{Block, BlockStore, Link};
+
+use self::disk::{DiskIndex, DiskParams};
+use self::flat::FlatIndex;
+use self::ivf::{IvfIndex, IvfParams};
+use self::multivector::{GraphParams, MultiVectorIndex};
+use self::sparse::{SparseIndex, SparseVector};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    Hnsw,
+    Flat,
+    Ivf,
+    Disk,
+    Sparse,
+    /// documents of several token vectors, ranked by MaxSim
+    MultiVector,
+}
+
+impl fmt::Display for IndexKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexKind::Hnsw => write!(f, "hnsw"),
+            IndexKind::Flat => write!(f, "flat"),
+            IndexKind::Ivf => write!(f, "ivf"),
+            IndexKind::Disk => write!(f, "disk"),
+            IndexKind::Sparse => write!(f, "sparse"),
+            IndexKind::MultiVector => write!(f, "multivector"),
+        }
+    }
+}
+
+impl FromStr for IndexKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hnsw" => Ok(IndexKind::Hnsw),
+            "flat" => Ok(IndexKind::Flat),
+            "ivf" => Ok(IndexKind::Ivf),
+            "disk" => Ok(IndexKind::Disk),
+            "sparse" => Ok(IndexKind::Sparse),
+            "multivector" => Ok(IndexKind::MultiVector),
+            _ => Err(format!(
+                "unknown index type {}, expected hnsw, flat, ivf, disk, sparse or multivector",
+                s
+            )),
+        }
+    }
+}
+
+/// distance between the vectors of an index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceKind {
+    #[default]
+    Cosine,
+    /// 1 - dot product, for normalized vectors
+    Dot,
+    /// Euclidean distance
+    L2,
+}
+
+impl fmt::Display for DistanceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DistanceKind::Cosine => write!(f, "cosine"),
+            DistanceKind::Dot => write!(f, "dot"),
+            DistanceKind::L2 => write!(f, "l2"),
+        }
+    }
+}
+
+impl DistanceKind {
+    /// the type name of the distance, kept in the manifests of the indexes
+    pub fn type_name(self) -> &'static str {
+        match self {
+            DistanceKind::Cosine => type_name::<DistCosine>(),
+            DistanceKind::Dot => type_name::<DistDot>(),
+            DistanceKind::L2 => type_name::<DistL2>(),
+        }
+    }
+
+    /// the distance between va and vb, as the indexes compute it
+    pub fn eval(self, va: &[f32], vb: &[f32]) -> f32 {
+        match self {
+            DistanceKind::Cosine => DistCosine.eval(va, vb),
+            DistanceKind::Dot => DistDot.eval(va, vb),
+            DistanceKind::L2 => DistL2.eval(va, vb),
+        }
+    }
+
+    /// the distance of a type name read from a manifest
+    pub fn from_type_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        [DistanceKind::Cosine, DistanceKind::Dot, DistanceKind::L2]
+            .into_iter()
+            .find(|kind| kind.type_name() == name)
+            .ok_or_else(|| format!("unknown distance {}", name).into())
+    }
+}
+
+impl FromStr for DistanceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cosine" => Ok(DistanceKind::Cosine),
+            "dot" => Ok(DistanceKind::Dot),
+            "l2" => Ok(DistanceKind::L2),
+            _ => Err(format!(
+                "unknown distance {}, expected cosine, dot or l2",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexStats {
+    pub kind: IndexKind,
+    /// type name of the distance
+    pub distance: String,
+    /// 0 until the first insertion
+    pub dimension: usize,
+    /// number of points that can be returned by a search
+    pub nb_point: usize,
+    /// number of deleted points still held by the index
+    pub nb_deleted: usize,
+}
+
+/// callback receiving the id and the vector of points
+pub type Visitor<'a> = dyn FnMut(DataId, &[f32]) -> Result<(), Box<dyn Error>> + 'a;
+
+/// A nearest neighbour index over f32 vectors.
+/// All methods take &self, implementations synchronize internally.
+pub trait AnnIndex: Send + Sync {
+    fn kind(&self) -> IndexKind;
+
+    /// inserts (vector, id) pairs. The point of an id already present, deleted or not, is
+    /// replaced.
+    fn insert(&self, data: &[(&[f32], DataId)]) -> Result<(), Box<dyn Error>>;
+
+    /// the vector of the point of an id, for the kinds of index that can return them
+    fn vector(&self, _d_id: DataId) -> Option<Vec<f32>> {
+        None
+    }
+
+    /// deletes the points with these ids, returns the number of ids found
+    fn delete(&self, ids: &[DataId]) -> usize;
+
+    /// the knbn nearest neighbours found, by increasing distance.
+    /// ef is the width of the search for approximate indexes.
+    fn search(&self, query: &[f32], knbn: usize, ef: usize) -> Vec<Neighbour>;
+
+    /// one search per query, run in parallel
+    fn parallel_search(&self, queries: &[Vec<f32>], knbn: usize, ef: usize) -> Vec<Vec<Neighbour>> {
+        queries
+            .par_iter()
+            .map(|query| self.search(query, knbn, ef))
+            .collect()
+    }
+
+    /// the knbn nearest neighbours among the points accepted by filter. The filter is
+    /// evaluated during the search, points it rejects are never candidates to the results.
+    fn filtered_search(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        ef: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour>;
+
+    /// the exact knbn nearest neighbours, by brute force
+    fn exact_search(&self, query: &[f32], knbn: usize) -> Vec<Neighbour> {
+        self.filtered_exact_search(query, knbn, &|_| true)
+    }
+
+    /// the exact knbn nearest neighbours among the points accepted by filter, computing
+    /// distances only to those points
+    fn filtered_exact_search(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        filter: &IdFilter,
+    ) -> Vec<Neighbour>;
+
+    /// the points at distance at most radius from query, by increasing distance
+    fn range_search(&self, query: &[f32], radius: f32, ef: usize) -> Vec<Neighbour>;
+
+    /// inserts (sparse vector, id) pairs, for the kinds of index that store them
+    fn sparse_insert(&self, _data: &[(SparseVector, DataId)]) -> Result<(), Box<dyn Error>> {
+        Err(format!("a {} index does not store sparse vectors", self.kind()).into())
+    }
+
+    /// the knbn points accepted by filter of greatest dot product with a sparse query,
+    /// with their dot products, greatest first
+    fn sparse_search(
+        &self,
+        _query: &SparseVector,
+        _knbn: usize,
+        _filter: &IdFilter,
+    ) -> Result<Vec<(DataId, f32)>, Box<dyn Error>> {
+        Err(format!("a {} index does not store sparse vectors", self.kind()).into())
+    }
+
+    /// inserts documents of several vectors with their ids, for the kinds of index that
+    /// store them
+    fn multi_insert(&self, _data: &[(&[Vec<f32>], DataId)]) -> Result<(), Box<dyn Error>> {
+        Err(format!(
+            "a {} index does not store documents of several vectors",
+            self.kind()
+        )
+        .into())
+    }
+
+    /// the knbn documents accepted by filter of greatest MaxSim score with a query of several
+    /// vectors, with their scores, greatest first. ef is the width of the search of each
+    /// query vector.
+    fn multi_search(
+        &self,
+        _query: &[Vec<f32>],
+        _knbn: usize,
+        _ef: usize,
+        _filter: &IdFilter,
+    ) -> Result<Vec<(DataId, f32)>, Box<dyn Error>> {
+        Err(format!(
+            "a {} index does not store documents of several vectors",
+            self.kind()
+        )
+        .into())
+    }
+
+    fn stats(&self) -> IndexStats;
+
+    /// calls visit on every point that is not deleted, stopping at the first error
+    fn scan(&self, visit: &mut Visitor) -> Result<(), Box<dyn Error>>;
+
+    /// as scan, restricted to the points of a layer for indexes that have layers
+    fn scan_layer(&self, _layer: usize, _visit: &mut Visitor) -> Result<(), Box<dyn Error>> {
+        Err(format!("a {} index has no layers", self.kind()).into())
+    }
+
+    /// writes the index to store, returns the CID of its IndexRoot
+    fn persist(&self, store: &dyn BlockStore) -> Result<Cid, Box<dyn Error>>;
+
+    /// writes the index to a local directory, to be opened by open_index without
+    /// reading its vectors, for the kinds of index that support it
+    fn save(&self, _dir: &Path) -> Result<(), Box<dyn Error>> {
+        Err(format!("a {} index cannot be saved to a directory", self.kind()).into())
+    }
+}
+
+/// file recording the kind of an index saved to a directory
+const KIND_FILE: &str = "kind";
+
+/// saves index to dir, see AnnIndex::save
+pub fn save_index(index: &dyn AnnIndex, dir: &Path) -> Result<(), Box<dyn Error>> {
+    index.save(dir)?;
+    std::fs::write(dir.join(KIND_FILE), index.kind().to_string())?;
+    Ok(())
+}
+
+/// opens an index saved to dir by save_index
+pub fn open_index(dir: &Path) -> Result<Box<dyn AnnIndex>, Box<dyn Error>> {
+    let kind: IndexKind = std::fs::read_to_string(dir.join(KIND_FILE))?
+        .trim()
+        .parse()?;
+    match kind {
+        IndexKind::Hnsw => hnsw::open(dir),
+        _ => Err(format!("a {} index cannot be opened from a directory", kind).into()),
+    }
+}
+
+/// The root block of a persisted index
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexRoot {
+    pub kind: IndexKind,
+    /// root of the index specific blocks
+    pub index: Link,
+}
+
+pub(crate) fn put_root(
+    kind: IndexKind,
+    index: Cid,
+    store: &dyn BlockStore,
+) -> Result<Cid, Box<dyn Error>> {
+    let block = Block::encode(&IndexRoot {
+        kind,
+        index: Link(index),
+    })?;
+    let root = block.cid;
+    store.put(block)?;
+    Ok(root)
+}
+
+/// rebuilds the index persisted under root, whatever its kind.
+/// Indexes kept on disk write their files in data_dir.
+pub fn load_index(
+    root: &Cid,
+    store: &dyn BlockStore,
+    data_dir: &Path,
+) -> Result<Box<dyn AnnIndex>, Box<dyn Error>> {
+    let index_root: IndexRoot = store.get_block(root)?.decode()?;
+    match index_root.kind {
+        IndexKind::Hnsw => hnsw::load(&index_root.index.0, store),
+        IndexKind::Flat => flat::load(&index_root.index.0, store),
+        IndexKind::Ivf => ivf::load(&index_root.index.0, store),
+        IndexKind::Disk => disk::load(&index_root.index.0, store, data_dir),
+        IndexKind::Sparse => sparse::load(&index_root.index.0, store),
+        IndexKind::MultiVector => multivector::load(&index_root.index.0, store),
+    }
+}
+
+/// the chunks of vectors of an index persisted under manifest, the block its IndexRoot links
+/// to, for the kinds of index whose points can be proven, see ipfs_storage::proof
+pub(crate) fn vector_chunks(kind: IndexKind, manifest: &Block) -> Result<Vec<Cid>, Box<dyn Error>> {
+    match kind {
+        IndexKind::Hnsw => hnsw::vector_chunks(manifest),
+        IndexKind::Flat => flat::vector_chunks(manifest),
+        _ => Err(format!("the points of a {} index cannot be proven", kind).into()),
+    }
+}
+
+/// the points of a chunk of vectors of an index persisted under manifest, see vector_chunks
+pub(crate) fn chunk_points(
+    kind: IndexKind,
+    manifest: &Block,
+    chunk: &Block,
+) -> Result<Vec<(DataId, Vec<f32>)>, Box<dyn Error>> {
+    match kind {
+        IndexKind::Hnsw => hnsw::chunk_points(manifest, chunk),
+        IndexKind::Flat => flat::chunk_points(manifest, chunk),
+        _ => Err(format!("the points of a {} index cannot be proven", kind).into()),
+    }
+}
+
+/// the distance of an index persisted under manifest, see vector_chunks
+pub(crate) fn index_distance(
+    kind: IndexKind,
+    manifest: &Block,
+) -> Result<DistanceKind, Box<dyn Error>> {
+    match kind {
+        IndexKind::Hnsw => hnsw::distance(manifest),
+        IndexKind::Flat => flat::distance(manifest),
+        _ => Err(format!("the points of a {} index cannot be proven", kind).into()),
+    }
+}
+
+/// parameters of the indexes created for new collections
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    pub kind: IndexKind,
+    pub distance: DistanceKind,
+    pub max_nb_connection: usize,
+    pub max_elements: usize,
+    pub max_layer: usize,
+    pub ef_construction: usize,
+    pub ivf: IvfParams,
+    pub disk: DiskParams,
+    /// directory of the files of disk indexes
+    pub data_dir: PathBuf,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig {
+            kind: IndexKind::Hnsw,
+            distance: DistanceKind::Cosine,
+            max_nb_connection: 16,
+            max_elements: 10000,
+            max_layer: 16,
+            ef_construction: 200,
+            ivf: IvfParams::default(),
+            disk: DiskParams::default(),
+            data_dir: std::env::temp_dir().join("d_celestica"),
+        }
+    }
+}
+
+impl IndexConfig {
+    /// checks that an index can be built from the config
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        match (self.kind, self.distance) {
+            (IndexKind::Ivf, _) => self.ivf.check(),
+            // MaxSim adds similarities, 1 - distance, which only makes sense for cosine and dot
+            (IndexKind::MultiVector, DistanceKind::L2) => {
+                Err("a multivector index needs a cosine or dot distance".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// creates an empty index with the distance of the config
+    pub fn build(&self) -> Box<dyn AnnIndex> {
+        match self.distance {
+            DistanceKind::Cosine => self.build_with(DistCosine),
+            DistanceKind::Dot => self.build_with(DistDot),
+            DistanceKind::L2 => self.build_with(DistL2),
+        }
+    }
+
+    fn build_with<D: Distance<f32> + Send + Sync + 'static>(&self, dist_f: D) -> Box<dyn AnnIndex> {
+        match self.kind {
+            IndexKind::Hnsw => Box::new(Hnsw::<f32, D>::new(
+                self.max_nb_connection,
+                self.max_elements,
+                self.max_layer,
+                self.ef_construction,
+                dist_f,
+            )),
+            IndexKind::Flat => Box::new(FlatIndex::new(dist_f)),
+            IndexKind::Ivf => Box::new(IvfIndex::new(self.ivf, dist_f)),
+            IndexKind::Disk => Box::new(DiskIndex::new(self.disk, &self.data_dir, dist_f)),
+            IndexKind::Sparse => Box::new(SparseIndex::new()),
+            IndexKind::MultiVector => Box::new(MultiVectorIndex::new(
+                GraphParams {
+                    max_nb_connection: self.max_nb_connection,
+                    max_elements: self.max_elements,
+                    max_layer: self.max_layer,
+                    ef_construction: self.ef_construction,
+                },
+                dist_f,
+            )),
+        }
+    }
+}
+
+/// neighbour ordered by distance, the farthest on top of a BinaryHeap
+struct Farthest(Neighbour);
+
+impl PartialEq for Farthest {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Farthest {}
+
+impl PartialOrd for Farthest {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Farthest {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.distance.total_cmp(&other.0.distance)
+    }
+}
+
+/// keeps the knbn nearest of candidates, returned by increasing distance
+pub(crate) fn nearest<I: Iterator<Item = Neighbour>>(candidates: I, knbn: usize) -> Vec<Neighbour> {
+    let mut heap = BinaryHeap::with_capacity(knbn + 1);
+    if knbn == 0 {
+        return Vec::new();
+    }
+    for candidate in candidates {
+        if heap.len() < knbn {
+            heap.push(Farthest(candidate));
+        } else if heap
+            .peek()
+            .is_some_and(|farthest| candidate.distance < farthest.0.distance)
+        {
+            heap.pop();
+            heap.push(Farthest(candidate));
+        }
+    }
+    heap.into_sorted_vec().into_iter().map(|n| n.0).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::hnsw_graph::bench::gen_random_matrix_f32;
+    use crate::ipfs_storage::block::MemoryBlockStore;
+
+    #[test]
+    fn test_indexes_agree_with_exact_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = gen_random_matrix_f32(10, 1000);
+        let data_with_id: Vec<(&[f32], DataId)> = data
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.as_slice(), i))
+            .collect();
+        for kind in [
+            IndexKind::Hnsw,
+            IndexKind::Flat,
+            IndexKind::Ivf,
+            IndexKind::Disk,
+        ] {
+            let config = IndexConfig {
+                kind,
+                // probing every list, the ivf search is exact
+                ivf: IvfParams {
+                    nlist: 4,
+                    nprobe: 4,
+                    ..IvfParams::default()
+                },
+                // the disk index merges its buffer into a graph file in the background, the
+                // points deleted meanwhile are counted as deleted once the merge ends
+                disk: DiskParams {
+                    max_degree: 16,
+                    list_size: 50,
+                    max_buffer: 500,
+                    ..DiskParams::default()
+                },
+                data_dir: dir.path().to_path_buf(),
+                ..IndexConfig::default()
+            };
+            let index = config.build();
+            index.insert(&data_with_id).unwrap();
+            assert_eq!(index.delete(&[0, 1, 2, 5000]), 3);
+            let stats = index.stats();
+            assert_eq!(stats.kind, kind);
+            assert_eq!(stats.dimension, 10);
+            assert_eq!(stats.nb_point, 997);
+
+            let query = &data[0];
+            let exact = index.exact_search(query, 10);
+            assert_eq!(exact.len(), 10);
+            assert!(exact.iter().all(|n| n.d_id > 2));
+            let found: Vec<DataId> = index
+                .search(query, 10, 100)
+                .iter()
+                .map(|n| n.d_id)
+                .collect();
+            let nb_common = exact.iter().filter(|n| found.contains(&n.d_id)).count();
+            assert!(nb_common >= 8);
+
+            let radius = exact[4].distance;
+            let in_range = index.range_search(query, radius, 100);
+            assert!(in_range.iter().all(|n| n.distance <= radius));
+            assert!(in_range.len() >= 4);
+
+            // the filter keeps one point in three
+            let filter = |d_id: DataId| d_id % 3 == 0;
+            let exact_filtered = index.filtered_exact_search(query, 10, &filter);
+            assert_eq!(exact_filtered.len(), 10);
+            assert!(exact_filtered.iter().all(|n| n.d_id % 3 == 0 && n.d_id > 2));
+            let found: Vec<DataId> = index
+                .filtered_search(query, 10, 100, &filter)
+                .iter()
+                .map(|n| n.d_id)
+                .collect();
+            assert!(found.iter().all(|d_id| d_id % 3 == 0 && *d_id > 2));
+            let nb_common = exact_filtered
+                .iter()
+                .filter(|n| found.contains(&n.d_id))
+                .count();
+            assert!(nb_common >= 8);
+
+            let mut nb_scanned = 0;
+            index
+                .scan(&mut |_, v| {
+                    assert_eq!(v.len(), 10);
+                    nb_scanned += 1;
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(nb_scanned, 997);
+
+            let store = MemoryBlockStore::new();
+            let root = index.persist(&store).unwrap();
+            let reloaded = load_index(&root, &store, dir.path()).unwrap();
+            assert_eq!(reloaded.stats(), stats);
+            let reloaded_exact: Vec<DataId> = reloaded
+                .exact_search(query, 10)
+                .iter()
+                .map(|n| n.d_id)
+                .collect();
+            let exact: Vec<DataId> = exact.iter().map(|n| n.d_id).collect();
+            assert_eq!(reloaded_exact, exact);
+        }
+    }
+
+    #[test]
+    fn test_insert_replaces_points() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = gen_random_matrix_f32(10, 200);
+        let data_with_id: Vec<(&[f32], DataId)> = data
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.as_slice(), i))
+            .collect();
+        let nearest_ids = |index: &dyn AnnIndex, query: &[f32]| -> Vec<DataId> {
+            index
+                .exact_search(query, 2)
+                .iter()
+                .map(|n| n.d_id)
+                .collect()
+        };
+        for kind in [
+            IndexKind::Hnsw,
+            IndexKind::Flat,
+            IndexKind::Ivf,
+            IndexKind::Disk,
+        ] {
+            let index = IndexConfig {
+                kind,
+                ivf: IvfParams {
+                    nlist: 4,
+                    nprobe: 4,
+                    ..IvfParams::default()
+                },
+                data_dir: dir.path().to_path_buf(),
+                ..IndexConfig::default()
+            }
+            .build();
+            index.insert(&data_with_id).unwrap();
+            index.delete(&[1]);
+            // 0 moves onto 100, 1 comes back as 101, 2 is inserted twice and keeps 103
+            index
+                .insert(&[
+                    (&data[100], 0),
+                    (&data[101], 1),
+                    (&data[102], 2),
+                    (&data[103], 2),
+                ])
+                .unwrap();
+            assert_eq!(index.stats().nb_point, 200, "{}", kind);
+            // the former vector of 0 is no longer found
+            assert!(index.exact_search(&data[0], 1)[0].distance > 0., "{}", kind);
+            assert!(nearest_ids(&*index, &data[100]).contains(&0), "{}", kind);
+            assert!(nearest_ids(&*index, &data[101]).contains(&1), "{}", kind);
+            assert!(nearest_ids(&*index, &data[103]).contains(&2), "{}", kind);
+            // and its first vector is gone
+            assert!(
+                index.exact_search(&data[102], 2)[1].distance > 0.,
+                "{}",
+                kind
+            );
+            if matches!(kind, IndexKind::Hnsw | IndexKind::Flat) {
+                assert_eq!(index.vector(0), Some(data[100].clone()));
+                assert_eq!(index.vector(1), Some(data[101].clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_repeated_updates() {
+        let data = gen_random_matrix_f32(10, 100);
+        let data_with_id: Vec<(&[f32], DataId)> = data
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.as_slice(), i))
+            .collect();
+        let index = IndexConfig {
+            kind: IndexKind::Hnsw,
+            ..IndexConfig::default()
+        }
+        .build();
+        index.insert(&data_with_id).unwrap();
+        // 0 is updated again and again around data[50], leaving superseded points there
+        let query = data[50].clone();
+        let mut last = query.clone();
+        for i in 0..500 {
+            last = query.iter().map(|x| x + (i % 7) as f32 * 1e-4).collect();
+            index.insert(&[(&last, 0)]).unwrap();
+        }
+        assert_eq!(index.stats().nb_point, 100);
+        assert_eq!(index.vector(0), Some(last));
+        // the search still finds as many live points as asked for, each once
+        let found: Vec<DataId> = index
+            .search(&query, 10, 10)
+            .iter()
+            .map(|n| n.d_id)
+            .collect();
+        assert_eq!(found.len(), 10);
+        assert_eq!(found.iter().collect::<HashSet<_>>().len(), 10);
+        assert!(found.contains(&0) && found.contains(&50));
+    }
+
+    #[test]
+    fn test_nearest() {
+        let candidates = [0.5f32, 0.1, 0.9, 0.3]
+            .iter()
+            .enumerate()
+            .map(|(i, d)| Neighbour::new(i, *d, Default::default()));
+        let kept: Vec<DataId> = nearest(candidates, 2).iter().map(|n| n.d_id).collect();
+        assert_eq!(kept, vec![1, 3]);
+    }
+}
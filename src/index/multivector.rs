@@ -0,0 +1,424 @@
+//! Index of documents made of a variable number of token vectors, as the late interaction
+//! embeddings of ColBERT. The token vectors of all documents are indexed in one Hnsw graph,
+//! each token mapped to its document. A search gathers as candidates the documents of the
+//! tokens nearest to each query vector, then ranks them by their exact MaxSim score: the sum
+//! over the query vectors of their greatest similarity to a vector of the document, the
+//! similarity being 1 - distance: the cosine similarity or the dot product, so that the index
+//! takes no L2 distance, see IndexConfig::check.
+//!
+//! The tokens of deleted or replaced documents stay in the graph as tombstones, counted in
+//! the deleted points of the index.
+
+use std::any::type_name;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use cid::Cid;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::hnsw_graph::dist::{DistCosine, DistDot, DistL2, Distance};
+use crate::hnsw_graph::hnsw::{DataId, Hnsw, IdFilter, Neighbour};
+use crate::index::{put_root, AnnIndex, DistanceKind, IndexKind, IndexStats, Visitor};
+use crate::ipfs_storage::block::{Block, BlockStore, Link};
+
+/// number of documents stored in a block
+const CHUNK_SIZE: usize = 256;
+
+const FORMAT_VERSION: u32 = 1;
+
+/// parameters of the graph of the token vectors, see Hnsw::new
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GraphParams {
+    pub max_nb_connection: usize,
+    pub max_elements: usize,
+    pub max_layer: usize,
+    pub ef_construction: usize,
+}
+
+/// the token vectors of a document, row after row, and their ids in the graph
+struct Document {
+    tokens: Vec<DataId>,
+    vectors: Vec<f32>,
+}
+
+#[derive(Default)]
+struct MultiState {
+    dimension: usize,
+    docs: HashMap<DataId, Document>,
+    /// document of each token of the graph, the tokens of deleted documents excluded
+    token_docs: HashMap<DataId, DataId>,
+    /// graph ids are never reused, deleted tokens stay in the graph as tombstones
+    next_token: DataId,
+}
+
+pub struct MultiVectorIndex<D: Distance<f32> + Send + Sync> {
+    params: GraphParams,
+    tokens: Hnsw<f32, D>,
+    state: RwLock<MultiState>,
+}
+
+impl<D: Distance<f32> + Send + Sync> MultiVectorIndex<D> {
+    pub fn new(params: GraphParams, dist_f: D) -> Self {
+        MultiVectorIndex {
+            params,
+            tokens: Hnsw::new(
+                params.max_nb_connection,
+                params.max_elements,
+                params.max_layer,
+                params.ef_construction,
+                dist_f,
+            ),
+            state: RwLock::new(MultiState::default()),
+        }
+    }
+
+    /// removes a document from the mapping and tombstones its tokens
+    fn remove(&self, state: &mut MultiState, d_id: DataId) -> bool {
+        match state.docs.remove(&d_id) {
+            Some(doc) => {
+                for token in doc.tokens {
+                    state.token_docs.remove(&token);
+                    self.tokens.mark_deleted(token);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// sum over the query vectors of their greatest similarity to a vector of doc
+    fn max_sim(&self, state: &MultiState, query: &[Vec<f32>], doc: &Document) -> f32 {
+        let dist_f = self.tokens.get_distance();
+        query
+            .iter()
+            .map(|q| {
+                doc.vectors
+                    .chunks(state.dimension)
+                    .map(|v| 1. - dist_f.eval(q, v))
+                    .fold(f32::MIN, f32::max)
+            })
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MultiManifest {
+    format_version: u32,
+    distance: String,
+    params: GraphParams,
+    dimension: usize,
+    nb_point: usize,
+    chunks: Vec<Link>,
+}
+
+/// documents with their token vectors, row after row
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MultiChunk {
+    docs: Vec<(DataId, Vec<f32>)>,
+}
+
+impl<D: Distance<f32> + Send + Sync> AnnIndex for MultiVectorIndex<D> {
+    fn kind(&self) -> IndexKind {
+        IndexKind::MultiVector
+    }
+
+    fn insert(&self, _data: &[(&[f32], DataId)]) -> Result<(), Box<dyn Error>> {
+        Err("a multivector index stores documents of several vectors".into())
+    }
+
+    fn delete(&self, ids: &[DataId]) -> usize {
+        let mut state = self.state.write();
+        ids.iter()
+            .filter(|d_id| self.remove(&mut state, **d_id))
+            .count()
+    }
+
+    /// finds no point, a multivector index is searched with queries of several vectors
+    fn search(&self, _query: &[f32], _knbn: usize, _ef: usize) -> Vec<Neighbour> {
+        Vec::new()
+    }
+
+    fn filtered_search(
+        &self,
+        _query: &[f32],
+        _knbn: usize,
+        _ef: usize,
+        _filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        Vec::new()
+    }
+
+    fn filtered_exact_search(
+        &self,
+        _query: &[f32],
+        _knbn: usize,
+        _filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        Vec::new()
+    }
+
+    fn range_search(&self, _query: &[f32], _radius: f32, _ef: usize) -> Vec<Neighbour> {
+        Vec::new()
+    }
+
+    /// inserts or replaces documents, whose vectors all have the dimension of the index
+    fn multi_insert(&self, data: &[(&[Vec<f32>], DataId)]) -> Result<(), Box<dyn Error>> {
+        // the write lock keeps searches out until the tokens are mapped to their documents
+        let mut state = self.state.write();
+        let mut dimension = state.dimension;
+        for (vectors, d_id) in data {
+            if vectors.is_empty() {
+                return Err(format!("document {} has no vector", d_id).into());
+            }
+            for v in vectors.iter() {
+                if dimension == 0 {
+                    dimension = v.len();
+                }
+                if v.len() != dimension {
+                    return Err(format!(
+                        "a vector of document {} has dimension {}, expected {}",
+                        d_id,
+                        v.len(),
+                        dimension
+                    )
+                    .into());
+                }
+            }
+        }
+        state.dimension = dimension;
+        let mut points: Vec<(&[f32], DataId)> = Vec::new();
+        for (vectors, d_id) in data {
+            self.remove(&mut state, *d_id);
+            let first = state.next_token;
+            state.next_token += vectors.len();
+            let tokens: Vec<DataId> = (first..state.next_token).collect();
+            for (v, token) in vectors.iter().zip(&tokens) {
+                state.token_docs.insert(*token, *d_id);
+                points.push((v.as_slice(), *token));
+            }
+            let doc = Document {
+                tokens,
+                vectors: vectors.concat(),
+            };
+            state.docs.insert(*d_id, doc);
+        }
+        self.tokens.parallel_insert_slice(&points);
+        Ok(())
+    }
+
+    fn multi_search(
+        &self,
+        query: &[Vec<f32>],
+        knbn: usize,
+        ef: usize,
+        filter: &IdFilter,
+    ) -> Result<Vec<(DataId, f32)>, Box<dyn Error>> {
+        let state = self.state.read();
+        if let Some(q) = query
+            .iter()
+            .find(|q| state.dimension > 0 && q.len() != state.dimension)
+        {
+            return Err(format!(
+                "query vector of dimension {}, expected {}",
+                q.len(),
+                state.dimension
+            )
+            .into());
+        }
+        let accepts = |token: DataId| {
+            state
+                .token_docs
+                .get(&token)
+                .is_some_and(|d_id| filter(*d_id))
+        };
+        let depth = ef.max(knbn);
+        let candidates: HashSet<DataId> = query
+            .iter()
+            .flat_map(|q| self.tokens.search_filter(q, depth, depth, &accepts))
+            .map(|n| state.token_docs[&n.d_id])
+            .collect();
+        let mut scored: Vec<(DataId, f32)> = candidates
+            .into_iter()
+            .map(|d_id| (d_id, self.max_sim(&state, query, &state.docs[&d_id])))
+            .collect();
+        scored.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scored.truncate(knbn);
+        Ok(scored)
+    }
+
+    fn stats(&self) -> IndexStats {
+        let state = self.state.read();
+        IndexStats {
+            kind: IndexKind::MultiVector,
+            distance: type_name::<D>().to_string(),
+            dimension: state.dimension,
+            nb_point: state.docs.len(),
+            nb_deleted: state.next_token - state.token_docs.len(),
+        }
+    }
+
+    /// visits no point, the points of a multivector index have several vectors
+    fn scan(&self, _visit: &mut Visitor) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// writes the token vectors of the documents, the graph is rebuilt when loaded
+    fn persist(&self, store: &dyn BlockStore) -> Result<Cid, Box<dyn Error>> {
+        let state = self.state.read();
+        let mut ids: Vec<DataId> = state.docs.keys().copied().collect();
+        ids.sort_unstable();
+        let mut chunks = Vec::new();
+        for ids in ids.chunks(CHUNK_SIZE) {
+            let block = Block::encode(&MultiChunk {
+                docs: ids
+                    .iter()
+                    .map(|d_id| (*d_id, state.docs[d_id].vectors.clone()))
+                    .collect(),
+            })?;
+            chunks.push(Link(block.cid));
+            store.put(block)?;
+        }
+        let block = Block::encode(&MultiManifest {
+            format_version: FORMAT_VERSION,
+            distance: type_name::<D>().to_string(),
+            params: self.params,
+            dimension: state.dimension,
+            nb_point: ids.len(),
+            chunks,
+        })?;
+        let manifest = block.cid;
+        store.put(block)?;
+        put_root(IndexKind::MultiVector, manifest, store)
+    }
+}
+
+fn load_with<D: Distance<f32> + Send + Sync>(
+    manifest: &MultiManifest,
+    store: &dyn BlockStore,
+    dist_f: D,
+) -> Result<MultiVectorIndex<D>, Box<dyn Error>> {
+    let index = MultiVectorIndex::new(manifest.params, dist_f);
+    for link in &manifest.chunks {
+        let chunk: MultiChunk = store.get_block(&link.0)?.decode()?;
+        let mut docs = Vec::with_capacity(chunk.docs.len());
+        for (d_id, vectors) in chunk.docs {
+            if manifest.dimension == 0 || vectors.len() % manifest.dimension != 0 {
+                return Err("inconsistent chunk in multivector index".into());
+            }
+            docs.push((
+                vectors
+                    .chunks(manifest.dimension)
+                    .map(<[f32]>::to_vec)
+                    .collect::<Vec<_>>(),
+                d_id,
+            ));
+        }
+        let docs: Vec<(&[Vec<f32>], DataId)> =
+            docs.iter().map(|(v, d_id)| (v.as_slice(), *d_id)).collect();
+        index.multi_insert(&docs)?;
+    }
+    Ok(index)
+}
+
+/// reloads a persisted multivector index with the distance named in its manifest
+pub(crate) fn load(
+    manifest: &Cid,
+    store: &dyn BlockStore,
+) -> Result<Box<dyn AnnIndex>, Box<dyn Error>> {
+    let manifest: MultiManifest = store.get_block(manifest)?.decode()?;
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(format!(
+            "unsupported multivector index format version {}",
+            manifest.format_version
+        )
+        .into());
+    }
+    match DistanceKind::from_type_name(&manifest.distance)? {
+        DistanceKind::Cosine => Ok(Box::new(load_with(&manifest, store, DistCosine)?)),
+        DistanceKind::Dot => Ok(Box::new(load_with(&manifest, store, DistDot)?)),
+        DistanceKind::L2 => Ok(Box::new(load_with(&manifest, store, DistL2)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnsw_graph::bench::gen_random_matrix_f32;
+    use crate::index::IndexConfig;
+    use crate::ipfs_storage::block::MemoryBlockStore;
+
+    #[test]
+    fn test_max_sim_ranking() {
+        let params = GraphParams {
+            max_nb_connection: 16,
+            max_elements: 5000,
+            max_layer: 16,
+            ef_construction: 100,
+        };
+        let index = MultiVectorIndex::new(params, DistCosine);
+        let vectors = gen_random_matrix_f32(8, 3000);
+        // documents of 1 to 10 tokens
+        let mut docs: Vec<(Vec<Vec<f32>>, DataId)> = Vec::new();
+        let mut rows = vectors.into_iter();
+        for d_id in 0..500 {
+            let doc: Vec<Vec<f32>> = rows.by_ref().take(1 + d_id % 10).collect();
+            if doc.is_empty() {
+                break;
+            }
+            docs.push((doc, d_id));
+        }
+        let data: Vec<(&[Vec<f32>], DataId)> =
+            docs.iter().map(|(v, d_id)| (v.as_slice(), *d_id)).collect();
+        index.multi_insert(&data).unwrap();
+        assert!(index.multi_insert(&[(&[vec![1., 2.]][..], 0)]).is_err());
+        assert_eq!(index.delete(&[0, 0, 9999]), 1);
+        // the single token of document 0 is a tombstone
+        assert_eq!(index.stats().nb_deleted, 1);
+
+        // a query made of tokens of a document finds it first
+        let target = &docs[42].0;
+        let query = vec![target[0].clone(), target[2].clone()];
+        let found = index.multi_search(&query, 5, 64, &|_| true).unwrap();
+        assert_eq!(found[0].0, 42);
+        assert!((found[0].1 - 2.).abs() < 1e-4);
+        // candidates are ranked by their exact score
+        let state = index.state.read();
+        for (d_id, score) in &found {
+            assert!((index.max_sim(&state, &query, &state.docs[d_id]) - score).abs() < 1e-5);
+        }
+        drop(state);
+        assert!(found.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        let filtered = index
+            .multi_search(&query, 5, 64, &|d_id| d_id != 42)
+            .unwrap();
+        assert!(filtered.iter().all(|(d_id, _)| *d_id != 42));
+
+        // replacing a document tombstones its former tokens
+        index.multi_insert(&[(&[vec![1.; 8]][..], 42)]).unwrap();
+        assert_eq!(index.stats().nb_deleted, 4);
+        assert_ne!(
+            index.multi_search(&query, 1, 64, &|_| true).unwrap()[0].0,
+            42
+        );
+
+        let store = MemoryBlockStore::new();
+        let root = index.persist(&store).unwrap();
+        let loaded = crate::index::load_index(&root, &store, &std::env::temp_dir()).unwrap();
+        assert_eq!(loaded.stats().nb_point, index.stats().nb_point);
+        assert_eq!(loaded.stats().nb_deleted, 0);
+        let query = vec![vec![1.; 8]];
+        assert_eq!(
+            loaded.multi_search(&query, 1, 64, &|_| true).unwrap()[0].0,
+            42
+        );
+
+        // the similarities of L2 distances cannot be added
+        let config = IndexConfig {
+            kind: IndexKind::MultiVector,
+            distance: DistanceKind::L2,
+            ..IndexConfig::default()
+        };
+        assert!(config.check().is_err());
+    }
+}
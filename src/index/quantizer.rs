@@ -0,0 +1,256 @@
+//! Compression of vectors into byte codes: raw f32, 8 bit scalar or product quantization.
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::index::kmeans::{kmeans, l2_squared, nearest_centroid};
+
+/// number of k-means iterations when training product quantizers
+const PQ_NB_ITER: usize = 10;
+
+/// how vectors are compressed, as asked when creating an index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Quantization {
+    None,
+    /// 8 bits per component
+    Scalar,
+    /// one byte per subspace, the dimension must be a multiple of nb_subspace
+    Product {
+        nb_subspace: usize,
+    },
+}
+
+impl fmt::Display for Quantization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Quantization::None => write!(f, "none"),
+            Quantization::Scalar => write!(f, "sq8"),
+            Quantization::Product { nb_subspace } => write!(f, "pq{}", nb_subspace),
+        }
+    }
+}
+
+/// parses none, sq8, or pq followed by the number of subspaces as in pq16
+impl FromStr for Quantization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        match s.as_str() {
+            "" | "none" => Ok(Quantization::None),
+            "sq8" => Ok(Quantization::Scalar),
+            _ => match s.strip_prefix("pq").map(|m| m.parse::<usize>()) {
+                Some(Ok(nb_subspace)) if nb_subspace > 0 => {
+                    Ok(Quantization::Product { nb_subspace })
+                }
+                _ => Err(format!(
+                    "unknown quantization {}, expected none, sq8 or pq<m>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// A trained quantizer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Quantizer {
+    /// little endian f32, no loss
+    Raw { dimension: usize },
+    /// each component scaled to a byte between the bounds seen in training
+    Scalar { min: Vec<f32>, max: Vec<f32> },
+    /// each subvector replaced by the rank of its nearest sub centroid
+    Product {
+        dimension: usize,
+        nb_subspace: usize,
+        nb_centroid: usize,
+        /// nb_subspace blocks of nb_centroid sub centroids
+        centroids: Vec<f32>,
+    },
+}
+
+impl Quantizer {
+    /// trains a quantizer on data, a matrix of dimension dimension
+    pub fn train(
+        quantization: Quantization,
+        data: &[f32],
+        dimension: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        match quantization {
+            Quantization::None => Ok(Quantizer::Raw { dimension }),
+            Quantization::Scalar => {
+                let mut min = vec![f32::MAX; dimension];
+                let mut max = vec![f32::MIN; dimension];
+                for v in data.chunks_exact(dimension) {
+                    for (d, x) in v.iter().enumerate() {
+                        min[d] = min[d].min(*x);
+                        max[d] = max[d].max(*x);
+                    }
+                }
+                if data.is_empty() {
+                    min.fill(0.);
+                    max.fill(0.);
+                }
+                Ok(Quantizer::Scalar { min, max })
+            }
+            Quantization::Product { nb_subspace } => {
+                if dimension % nb_subspace != 0 {
+                    return Err(format!(
+                        "dimension {} is not a multiple of the {} subspaces",
+                        dimension, nb_subspace
+                    )
+                    .into());
+                }
+                let dsub = dimension / nb_subspace;
+                let nb_point = data.len() / dimension;
+                let nb_centroid = nb_point.min(256);
+                if nb_centroid == 0 {
+                    return Err("product quantization needs training vectors".into());
+                }
+                let mut centroids = Vec::with_capacity(nb_subspace * nb_centroid * dsub);
+                for m in 0..nb_subspace {
+                    let sub: Vec<f32> = data
+                        .chunks_exact(dimension)
+                        .flat_map(|v| v[m * dsub..(m + 1) * dsub].iter().copied())
+                        .collect();
+                    centroids.extend(kmeans(&sub, dsub, nb_centroid, PQ_NB_ITER, l2_squared));
+                }
+                Ok(Quantizer::Product {
+                    dimension,
+                    nb_subspace,
+                    nb_centroid,
+                    centroids,
+                })
+            }
+        }
+    }
+
+    pub fn dimension(&self) -> usize {
+        match self {
+            Quantizer::Raw { dimension } | Quantizer::Product { dimension, .. } => *dimension,
+            Quantizer::Scalar { min, .. } => min.len(),
+        }
+    }
+
+    /// number of bytes of a code
+    pub fn code_size(&self) -> usize {
+        match self {
+            Quantizer::Raw { dimension } => 4 * dimension,
+            Quantizer::Scalar { min, .. } => min.len(),
+            Quantizer::Product { nb_subspace, .. } => *nb_subspace,
+        }
+    }
+
+    /// appends the code of v to codes
+    pub fn encode(&self, v: &[f32], codes: &mut Vec<u8>) {
+        match self {
+            Quantizer::Raw { .. } => {
+                for x in v {
+                    codes.extend(x.to_le_bytes());
+                }
+            }
+            Quantizer::Scalar { min, max } => {
+                for (d, x) in v.iter().enumerate() {
+                    let range = max[d] - min[d];
+                    let scaled = if range > 0. {
+                        ((x - min[d]) / range * 255.).round()
+                    } else {
+                        0.
+                    };
+                    codes.push(scaled.clamp(0., 255.) as u8);
+                }
+            }
+            Quantizer::Product {
+                dimension,
+                nb_subspace,
+                nb_centroid,
+                centroids,
+            } => {
+                let dsub = dimension / nb_subspace;
+                for m in 0..*nb_subspace {
+                    let sub_centroids =
+                        &centroids[m * nb_centroid * dsub..(m + 1) * nb_centroid * dsub];
+                    let (rank, _) = nearest_centroid(
+                        sub_centroids,
+                        dsub,
+                        &v[m * dsub..(m + 1) * dsub],
+                        &l2_squared,
+                    );
+                    codes.push(rank as u8);
+                }
+            }
+        }
+    }
+
+    /// writes the vector represented by code into v
+    pub fn decode(&self, code: &[u8], v: &mut [f32]) {
+        match self {
+            Quantizer::Raw { .. } => {
+                for (x, bytes) in v.iter_mut().zip(code.chunks_exact(4)) {
+                    *x = f32::from_le_bytes(bytes.try_into().unwrap());
+                }
+            }
+            Quantizer::Scalar { min, max } => {
+                for (d, (x, byte)) in v.iter_mut().zip(code).enumerate() {
+                    *x = min[d] + (max[d] - min[d]) * (*byte as f32) / 255.;
+                }
+            }
+            Quantizer::Product {
+                dimension,
+                nb_subspace,
+                nb_centroid,
+                centroids,
+            } => {
+                let dsub = dimension / nb_subspace;
+                for (m, rank) in code.iter().enumerate() {
+                    let start = (m * nb_centroid + *rank as usize) * dsub;
+                    v[m * dsub..(m + 1) * dsub].copy_from_slice(&centroids[start..start + dsub]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnsw_graph::bench::gen_random_matrix_f32;
+
+    fn reconstruction_error(quantizer: &Quantizer, data: &[f32], dim: usize) -> f32 {
+        let mut code = Vec::new();
+        let mut decoded = vec![0.; dim];
+        let mut error = 0.;
+        for v in data.chunks_exact(dim) {
+            code.clear();
+            quantizer.encode(v, &mut code);
+            assert_eq!(code.len(), quantizer.code_size());
+            quantizer.decode(&code, &mut decoded);
+            error += l2_squared(v, &decoded);
+        }
+        error / (data.len() / dim) as f32
+    }
+
+    #[test]
+    fn test_quantizers() {
+        let dim = 16;
+        let data: Vec<f32> = gen_random_matrix_f32(dim, 2000).concat();
+
+        let raw = Quantizer::train(Quantization::None, &data, dim).unwrap();
+        assert_eq!(reconstruction_error(&raw, &data, dim), 0.);
+
+        let scalar = Quantizer::train(Quantization::Scalar, &data, dim).unwrap();
+        // a step of 1/255 on each of 16 components
+        assert!(reconstruction_error(&scalar, &data, dim) < 1.0e-3);
+
+        let product = Quantizer::train("pq4".parse().unwrap(), &data, dim).unwrap();
+        assert_eq!(product.code_size(), 4);
+        // uniform vectors have a mean squared distance of 16 / 6 to each other
+        assert!(reconstruction_error(&product, &data, dim) < 1.);
+
+        assert!(Quantizer::train("pq5".parse().unwrap(), &data, dim).is_err());
+        assert!("pq".parse::<Quantization>().is_err());
+    }
+}
@@ -0,0 +1,457 @@
+//! Index of sparse vectors, such as the learned sparse embeddings of SPLADE, scored by dot
+//! product. Each dimension has a posting list of the points with a value in it, sorted by
+//! id, and searches skip the points that cannot enter the results with WAND: a point is
+//! scored only when the sum of the greatest values of the lists it may appear in beats the
+//! worst score kept.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::error::Error;
+
+use cid::Cid;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::hnsw_graph::hnsw::{DataId, IdFilter, Neighbour};
+use crate::index::{put_root, AnnIndex, IndexKind, IndexStats, Visitor};
+use crate::ipfs_storage::block::{Block, BlockStore, Link};
+
+/// number of points stored in a block
+const CHUNK_SIZE: usize = 1024;
+
+const FORMAT_VERSION: u32 = 1;
+
+/// the values of a vector at some indices, the other components being 0
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SparseVector {
+    pub indices: Vec<u32>,
+    pub values: Vec<f32>,
+}
+
+impl SparseVector {
+    /// sorts the pairs by index, checking that indices are distinct and values are finite
+    /// and non-negative
+    pub fn new(indices: Vec<u32>, values: Vec<f32>) -> Result<Self, Box<dyn Error>> {
+        if indices.len() != values.len() {
+            return Err(format!("{} indices for {} values", indices.len(), values.len()).into());
+        }
+        if let Some(x) = values.iter().find(|x| !x.is_finite() || **x < 0.) {
+            return Err(format!(
+                "sparse vector value {} is not a finite non-negative number",
+                x
+            )
+            .into());
+        }
+        let mut pairs: Vec<(u32, f32)> = indices.into_iter().zip(values).collect();
+        pairs.sort_unstable_by_key(|(i, _)| *i);
+        if let Some(pair) = pairs.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(format!("index {} appears twice in a sparse vector", pair[0].0).into());
+        }
+        let (indices, values) = pairs.into_iter().unzip();
+        Ok(SparseVector { indices, values })
+    }
+
+    pub fn pairs(&self) -> impl Iterator<Item = (u32, f32)> + '_ {
+        self.indices
+            .iter()
+            .copied()
+            .zip(self.values.iter().copied())
+    }
+
+    pub fn dot(&self, other: &SparseVector) -> f32 {
+        let (mut i, mut j, mut dot) = (0, 0, 0.);
+        while i < self.indices.len() && j < other.indices.len() {
+            match self.indices[i].cmp(&other.indices[j]) {
+                Ordering::Less => i += 1,
+                Ordering::Greater => j += 1,
+                Ordering::Equal => {
+                    dot += self.values[i] * other.values[j];
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        dot
+    }
+}
+
+/// the points with a value in a dimension, sorted by id
+#[derive(Default)]
+struct PostingList {
+    points: Vec<(DataId, f32)>,
+    /// greatest value of the list, not lowered by removals so that it stays an upper bound
+    max: f32,
+}
+
+#[derive(Default)]
+struct SparseState {
+    postings: HashMap<u32, PostingList>,
+    vectors: HashMap<DataId, SparseVector>,
+}
+
+impl SparseState {
+    /// inserts or replaces the vector of d_id
+    fn upsert(&mut self, v: SparseVector, d_id: DataId) {
+        self.remove(d_id);
+        for (i, x) in v.pairs() {
+            let list = self.postings.entry(i).or_default();
+            let pos = list.points.partition_point(|(id, _)| *id < d_id);
+            list.points.insert(pos, (d_id, x));
+            list.max = list.max.max(x);
+        }
+        self.vectors.insert(d_id, v);
+    }
+
+    fn remove(&mut self, d_id: DataId) -> bool {
+        let v = match self.vectors.remove(&d_id) {
+            Some(v) => v,
+            None => return false,
+        };
+        for i in &v.indices {
+            if let Some(list) = self.postings.get_mut(i) {
+                if let Ok(pos) = list.points.binary_search_by_key(&d_id, |(id, _)| *id) {
+                    list.points.remove(pos);
+                }
+                if list.points.is_empty() {
+                    self.postings.remove(i);
+                }
+            }
+        }
+        true
+    }
+}
+
+/// position in the posting list of a query dimension
+struct Cursor<'a> {
+    points: &'a [(DataId, f32)],
+    pos: usize,
+    /// value of the query in the dimension
+    weight: f32,
+    /// greatest contribution of the list to a score
+    bound: f32,
+}
+
+impl<'a> Cursor<'a> {
+    fn current(&self) -> Option<DataId> {
+        self.points.get(self.pos).map(|(d_id, _)| *d_id)
+    }
+
+    /// moves to the first point whose id is at least d_id
+    fn seek(&mut self, d_id: DataId) {
+        self.pos += self.points[self.pos..].partition_point(|(id, _)| *id < d_id);
+    }
+}
+
+/// a point and its score, the worst score on top of a BinaryHeap
+struct Worst(DataId, f32);
+
+impl PartialEq for Worst {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Worst {}
+
+impl PartialOrd for Worst {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Worst {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.1.total_cmp(&self.1).then(self.0.cmp(&other.0))
+    }
+}
+
+pub struct SparseIndex {
+    state: RwLock<SparseState>,
+}
+
+impl Default for SparseIndex {
+    fn default() -> Self {
+        SparseIndex::new()
+    }
+}
+
+impl SparseIndex {
+    pub fn new() -> Self {
+        SparseIndex {
+            state: RwLock::new(SparseState::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().vectors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SparseManifest {
+    format_version: u32,
+    nb_point: usize,
+    /// chunks of SparseChunk
+    chunks: Vec<Link>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SparseChunk {
+    entries: Vec<(DataId, SparseVector)>,
+}
+
+/// Dense vectors are not stored, dense searches find nothing.
+impl AnnIndex for SparseIndex {
+    fn kind(&self) -> IndexKind {
+        IndexKind::Sparse
+    }
+
+    fn insert(&self, _data: &[(&[f32], DataId)]) -> Result<(), Box<dyn Error>> {
+        Err("a sparse index stores sparse vectors only".into())
+    }
+
+    fn delete(&self, ids: &[DataId]) -> usize {
+        let mut state = self.state.write();
+        ids.iter().filter(|d_id| state.remove(**d_id)).count()
+    }
+
+    fn search(&self, _query: &[f32], _knbn: usize, _ef: usize) -> Vec<Neighbour> {
+        Vec::new()
+    }
+
+    fn filtered_search(
+        &self,
+        _query: &[f32],
+        _knbn: usize,
+        _ef: usize,
+        _filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        Vec::new()
+    }
+
+    fn filtered_exact_search(
+        &self,
+        _query: &[f32],
+        _knbn: usize,
+        _filter: &IdFilter,
+    ) -> Vec<Neighbour> {
+        Vec::new()
+    }
+
+    fn range_search(&self, _query: &[f32], _radius: f32, _ef: usize) -> Vec<Neighbour> {
+        Vec::new()
+    }
+
+    /// inserting an id already present replaces its vector
+    fn sparse_insert(&self, data: &[(SparseVector, DataId)]) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.write();
+        for (v, d_id) in data {
+            state.upsert(v.clone(), *d_id);
+        }
+        Ok(())
+    }
+
+    fn sparse_search(
+        &self,
+        query: &SparseVector,
+        knbn: usize,
+        filter: &IdFilter,
+    ) -> Result<Vec<(DataId, f32)>, Box<dyn Error>> {
+        if knbn == 0 {
+            return Ok(Vec::new());
+        }
+        let state = self.state.read();
+        let mut cursors: Vec<Cursor> = query
+            .pairs()
+            .filter(|(_, weight)| *weight > 0.)
+            .filter_map(|(i, weight)| {
+                state.postings.get(&i).map(|list| Cursor {
+                    points: &list.points,
+                    pos: 0,
+                    weight,
+                    bound: weight * list.max,
+                })
+            })
+            .collect();
+        let mut heap: BinaryHeap<Worst> = BinaryHeap::with_capacity(knbn + 1);
+        loop {
+            cursors.retain(|c| c.current().is_some());
+            cursors.sort_unstable_by_key(|c| c.current());
+            // the score a point must beat once knbn points are kept
+            let threshold = if heap.len() < knbn {
+                None
+            } else {
+                heap.peek().map(|worst| worst.1)
+            };
+            // the pivot is the first cursor whose bound, summed with those of the cursors
+            // on smaller ids, beats the threshold. No point before its id can.
+            let mut sum_bound = 0.;
+            let pivot = cursors.iter().position(|c| {
+                sum_bound += c.bound;
+                threshold.is_none_or(|t| sum_bound > t)
+            });
+            let pivot = match pivot {
+                Some(pivot) => pivot,
+                None => break,
+            };
+            let pivot_id = cursors[pivot].points[cursors[pivot].pos].0;
+            if cursors[0].current() == Some(pivot_id) {
+                let mut score = 0.;
+                for c in cursors
+                    .iter_mut()
+                    .take_while(|c| c.current() == Some(pivot_id))
+                {
+                    score += c.weight * c.points[c.pos].1;
+                    c.pos += 1;
+                }
+                if threshold.is_none_or(|t| score > t) && filter(pivot_id) {
+                    heap.push(Worst(pivot_id, score));
+                    if heap.len() > knbn {
+                        heap.pop();
+                    }
+                }
+            } else {
+                for c in &mut cursors[..pivot] {
+                    c.seek(pivot_id);
+                }
+            }
+        }
+        Ok(heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|w| (w.0, w.1))
+            .collect())
+    }
+
+    fn stats(&self) -> IndexStats {
+        IndexStats {
+            kind: IndexKind::Sparse,
+            distance: "dot product".to_string(),
+            dimension: 0,
+            nb_point: self.len(),
+            nb_deleted: 0,
+        }
+    }
+
+    /// visits no point, a sparse index has no dense vectors
+    fn scan(&self, _visit: &mut Visitor) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn persist(&self, store: &dyn BlockStore) -> Result<Cid, Box<dyn Error>> {
+        let state = self.state.read();
+        let mut entries: Vec<(DataId, SparseVector)> = state
+            .vectors
+            .iter()
+            .map(|(d_id, v)| (*d_id, v.clone()))
+            .collect();
+        entries.sort_unstable_by_key(|(d_id, _)| *d_id);
+        let mut chunks = Vec::new();
+        for entries in entries.chunks(CHUNK_SIZE) {
+            let block = Block::encode(&SparseChunk {
+                entries: entries.to_vec(),
+            })?;
+            chunks.push(Link(block.cid));
+            store.put(block)?;
+        }
+        let block = Block::encode(&SparseManifest {
+            format_version: FORMAT_VERSION,
+            nb_point: entries.len(),
+            chunks,
+        })?;
+        let manifest = block.cid;
+        store.put(block)?;
+        put_root(IndexKind::Sparse, manifest, store)
+    }
+}
+
+/// reloads a persisted sparse index
+pub(crate) fn load(
+    manifest: &Cid,
+    store: &dyn BlockStore,
+) -> Result<Box<dyn AnnIndex>, Box<dyn Error>> {
+    let manifest: SparseManifest = store.get_block(manifest)?.decode()?;
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(format!(
+            "unsupported sparse index format version {}",
+            manifest.format_version
+        )
+        .into());
+    }
+    let index = SparseIndex::new();
+    {
+        let mut state = index.state.write();
+        for link in &manifest.chunks {
+            let chunk: SparseChunk = store.get_block(&link.0)?.decode()?;
+            for (d_id, v) in chunk.entries {
+                state.upsert(v, d_id);
+            }
+        }
+    }
+    Ok(Box::new(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipfs_storage::block::MemoryBlockStore;
+    use rand::distributions::{Distribution, Uniform};
+    use rand::Rng;
+
+    fn random_sparse(rng: &mut impl Rng, nb_dimension: u32, nb_value: usize) -> SparseVector {
+        let dimensions = Uniform::new(0, nb_dimension);
+        let mut indices: Vec<u32> = (0..nb_value).map(|_| dimensions.sample(rng)).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        let values = indices.iter().map(|_| rng.gen_range(0.0..1.0)).collect();
+        SparseVector::new(indices, values).unwrap()
+    }
+
+    #[test]
+    fn test_wand_agrees_with_exhaustive_scoring() {
+        assert!(SparseVector::new(vec![1, 1], vec![0.5, 0.5]).is_err());
+        assert!(SparseVector::new(vec![1], vec![-0.5]).is_err());
+        let v = SparseVector::new(vec![7, 2], vec![1., 2.]).unwrap();
+        assert_eq!(v.indices, vec![2, 7]);
+        assert_eq!(
+            v.dot(&SparseVector::new(vec![7, 3], vec![3., 1.]).unwrap()),
+            3.
+        );
+
+        let mut rng = rand::thread_rng();
+        let index = SparseIndex::new();
+        let vectors: Vec<(SparseVector, DataId)> = (0..2000)
+            .map(|d_id| (random_sparse(&mut rng, 500, 40), d_id))
+            .collect();
+        index.sparse_insert(&vectors).unwrap();
+        assert_eq!(index.delete(&[0, 1, 1]), 2);
+        assert!(index.insert(&[(&[1.][..], 0)]).is_err());
+
+        let filter = |d_id: DataId| d_id % 2 == 0;
+        for _ in 0..10 {
+            let query = random_sparse(&mut rng, 500, 20);
+            let mut exact: Vec<(DataId, f32)> = vectors[2..]
+                .iter()
+                .filter(|(_, d_id)| filter(*d_id))
+                .map(|(v, d_id)| (*d_id, v.dot(&query)))
+                .collect();
+            exact.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+            let found = index.sparse_search(&query, 10, &filter).unwrap();
+            assert_eq!(found.len(), 10);
+            for (f, e) in found.iter().zip(&exact) {
+                assert!((f.1 - e.1).abs() < 1e-5, "{:?} {:?}", f, e);
+            }
+        }
+
+        let store = MemoryBlockStore::new();
+        let root = index.persist(&store).unwrap();
+        let loaded = crate::index::load_index(&root, &store, &std::env::temp_dir()).unwrap();
+        assert_eq!(loaded.stats().nb_point, 1998);
+        let query = &vectors[5].0;
+        assert_eq!(loaded.sparse_search(query, 1, &|_| true).unwrap()[0].0, 5);
+    }
+}
@@ -1,25 +1,2183 @@
-use crate::hnsw_graph::dist;
-use crate::hnsw_graph::hnsw::{Hnsw, Neighbour};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cid::Cid;
+use ed25519_dalek::SigningKey;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::cluster::crdt::{Change, Clock, PointSet};
+use crate::cluster::replication::{WriteLog, WriteOp};
+use crate::dataset::export::VectorWriter;
+use crate::dataset::VectorFormat;
+use crate::hnsw_graph::bench::{self, RecallEstimate};
+use crate::hnsw_graph::hnsw::{DataId, Neighbour};
+use crate::index::fusion::{self, Fusion, Ranking};
+use crate::index::sparse::SparseVector;
+use crate::index::{self, AnnIndex, IndexConfig, IndexKind, IndexStats};
+use crate::ipfs_storage::block::{Block, BlockStore, Link, MemoryBlockStore};
+use crate::ipfs_storage::car;
+use crate::ipfs_storage::fs::FsBlockStore;
+use crate::ipfs_storage::manifest::{self, Manifest, TrustList};
+use crate::ipfs_storage::proof::{Prover, SearchProofs};
+use crate::ipfs_storage::source::BlockSource;
+use crate::payload::filter::{self, Filter, SearchPlan};
+use crate::payload::index::PayloadIndexType;
+use crate::payload::PayloadStore;
+
+/// name of the collection used by requests that do not name one
+pub const DEFAULT_COLLECTION: &str = "default";
+
+/// directory of data_dir where collections are saved
+const COLLECTIONS_DIR: &str = "collections";
+
+/// directory of data_dir holding the blocks of the published collections
+const BLOCKS_DIR: &str = "blocks";
+
+/// file of a saved collection directory holding its payloads
+const PAYLOADS_FILE: &str = "payloads.cbor";
+
+/// file of a saved collection directory listing its payload indexes
+const PAYLOAD_INDEXES_FILE: &str = "payload_indexes.json";
+
+/// directory of a saved collection directory holding the indexes of its named vectors
+const VECTORS_DIR: &str = "vectors";
+
+/// file of a saved collection directory holding the stamps of its points
+const STAMPS_FILE: &str = "stamps.cbor";
+
+/// file of data_dir holding the root each collection was last published or checked out at
+const ROOTS_FILE: &str = "roots.cbor";
+
+/// file of the collections directory listing the attached collections with their roots and
+/// sources
+const ATTACHMENTS_FILE: &str = "attachments.cbor";
+
+/// The root block of a persisted collection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CollectionRoot {
+    /// the IndexRoot of the index
+    pub(crate) index: Link,
+    pub(crate) payloads: Vec<Link>,
+    /// indexed payload fields, rebuilt from the payloads when loaded
+    #[serde(default)]
+    pub(crate) payload_indexes: Vec<(String, PayloadIndexType)>,
+    /// the IndexRoot of the index of each named vector
+    #[serde(default)]
+    pub(crate) vectors: Vec<(String, Link)>,
+    /// the stamps of the last writes of the points, see PointSet
+    #[serde(default)]
+    pub(crate) stamps: Vec<Link>,
+}
+
+/// what a search returns beyond the neighbours of its queries
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// only points whose payload matches the filter are returned
+    pub filter: Option<Filter>,
+    /// payloads are returned when set, reduced to the fields at these paths if there are some
+    pub payload_fields: Option<Vec<String>>,
+    /// name of the vector searched, the default one when empty
+    pub vector: String,
+}
+
+/// the vectors of one name of the points of an insertion, one per point. An empty name
+/// designates the default vector.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NamedVectors {
+    pub name: String,
+    pub vectors: Vec<Vec<f32>>,
+}
+
+/// the keywords of a hybrid search and how their ranking is fused with the dense one
+#[derive(Debug, Clone)]
+pub struct HybridQuery {
+    /// dotted path of a payload field with a text index
+    pub text_field: String,
+    /// the keywords of each query vector
+    pub texts: Vec<String>,
+    pub fusion: Fusion,
+    pub dense_weight: f32,
+    pub text_weight: f32,
+}
+
+/// a point found by a hybrid search with its fused score, by a sparse search with its dot
+/// product or by a multivector search with its MaxSim score, greater is better
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoredPoint {
+    pub d_id: DataId,
+    pub score: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Value>,
+}
+
+/// A named set of vectors, the index searching them and their payloads. A point may also
+/// have named vectors, each name with its own index, dimension and distance.
+pub struct Collection {
+    index: RwLock<Box<dyn AnnIndex>>,
+    /// the index of each named vector, fixed when the collection is created
+    vectors: HashMap<String, RwLock<Box<dyn AnnIndex>>>,
+    payloads: PayloadStore,
+    /// the writes, appended under the default index lock like the payloads
+    log: WriteLog,
+    /// held by each write from its application to its append to the log, so that the log
+    /// numbers the writes in the order they were applied. Taken before the index locks.
+    writes: Mutex<()>,
+    /// refuses the writes while a shard of the collection moves to another node, set under
+    /// the write lock
+    frozen: AtomicBool,
+    /// the stamps of the writes of the points, recorded under the default index lock
+    points: PointSet,
+    /// the root of an attached collection, which refuses writes
+    attached: Option<Cid>,
+    /// where an attached collection reads its blocks from
+    source: Option<BlockSource>,
+    /// proves the points of an attached collection, when they can be proven
+    prover: Option<Prover>,
+}
+
+impl Collection {
+    pub fn new(index: Box<dyn AnnIndex>) -> Self {
+        Collection::with_payloads(index, PayloadStore::new())
+    }
+
+    pub fn with_payloads(index: Box<dyn AnnIndex>, payloads: PayloadStore) -> Self {
+        Collection::with_vectors(index, Vec::new(), payloads)
+    }
+
+    pub fn with_vectors(
+        index: Box<dyn AnnIndex>,
+        vectors: Vec<(String, Box<dyn AnnIndex>)>,
+        payloads: PayloadStore,
+    ) -> Self {
+        Collection {
+            index: RwLock::new(index),
+            vectors: vectors
+                .into_iter()
+                .map(|(name, index)| (name, RwLock::new(index)))
+                .collect(),
+            payloads,
+            log: WriteLog::default(),
+            writes: Mutex::new(()),
+            frozen: AtomicBool::new(false),
+            points: PointSet::default(),
+            attached: None,
+            source: None,
+            prover: None,
+        }
+    }
+
+    pub fn stats(&self) -> IndexStats {
+        self.index.read().stats()
+    }
+
+    pub fn log(&self) -> &WriteLog {
+        &self.log
+    }
+
+    pub fn points(&self) -> &PointSet {
+        &self.points
+    }
+
+    /// the root the collection is attached at, None when it accepts writes
+    pub fn attached(&self) -> Option<Cid> {
+        self.attached
+    }
+
+    /// takes the write lock, failing while the collection is frozen
+    fn lock_writes(&self) -> Result<MutexGuard<'_, ()>, Box<dyn Error>> {
+        let writes = self.writes.lock();
+        if self.frozen.load(Ordering::Relaxed) {
+            return Err("the collection is frozen while it moves to another node".into());
+        }
+        Ok(writes)
+    }
+
+    /// the index of the vector of this name, the default one for an empty name
+    fn vector_index(&self, name: &str) -> Result<&RwLock<Box<dyn AnnIndex>>, Box<dyn Error>> {
+        if name.is_empty() {
+            return Ok(&self.index);
+        }
+        self.vectors
+            .get(name)
+            .ok_or_else(|| format!("no vector named {}", name).into())
+    }
+
+    /// names of the named vectors, sorted, which is the order their indexes are locked in
+    fn vector_names(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self.vectors.keys().collect();
+        names.sort();
+        names
+    }
+
+    /// fails unless the points of the collection can be merged with those of peers, which
+    /// takes a single vector of an index that can replace points
+    fn check_mergeable(&self, name: &str, index: &dyn AnnIndex) -> Result<(), Box<dyn Error>> {
+        if !self.vectors.is_empty() {
+            return Err(
+                format!("collection {} has named vectors and cannot be merged", name).into(),
+            );
+        }
+        match index.kind() {
+            IndexKind::Hnsw | IndexKind::Flat => Ok(()),
+            kind => Err(format!(
+                "collection {} has a {} index and cannot be merged",
+                name, kind
+            )
+            .into()),
+        }
+    }
+
+    /// the payload of d_id when options ask for payloads
+    fn payload(&self, d_id: DataId, options: &SearchOptions) -> Option<Value> {
+        options
+            .payload_fields
+            .as_ref()
+            .and_then(|paths| self.payloads.get_projected(d_id, paths))
+    }
+
+    /// writes the index and the payloads to store, returns the CID of their CollectionRoot
+    fn persist(&self, store: &dyn BlockStore) -> Result<Cid, Box<dyn Error>> {
+        self.persist_at(store).map(|(root, _)| root)
+    }
+
+    /// persists the collection, also returns the seq of the last write it contains
+    fn persist_at(&self, store: &dyn BlockStore) -> Result<(Cid, u64), Box<dyn Error>> {
+        // the write lock keeps insertions out during the dump
+        let index = self.index.write();
+        let seq = self.log.last_seq();
+        let mut vectors = Vec::new();
+        for name in self.vector_names() {
+            vectors.push((
+                name.clone(),
+                Link(self.vectors[name].write().persist(store)?),
+            ));
+        }
+        let block = Block::encode(&CollectionRoot {
+            index: Link(index.persist(store)?),
+            payloads: self.payloads.persist(store)?,
+            payload_indexes: self.payloads.index_schema(),
+            vectors,
+            stamps: self.points.persist(store)?,
+        })?;
+        let root = block.cid;
+        store.put(block)?;
+        Ok((root, seq))
+    }
+}
+
+/// rebuilds the collection persisted under root, which is the CollectionRoot of a
+/// collection or the IndexRoot of an index without payloads
+fn load_collection(
+    root: &Cid,
+    store: &dyn BlockStore,
+    data_dir: &Path,
+) -> Result<Collection, Box<dyn Error>> {
+    match store.get_block(root)?.decode::<CollectionRoot>() {
+        Ok(collection_root) => {
+            let payloads = PayloadStore::load(&collection_root.payloads, store)?;
+            for (path, index_type) in collection_root.payload_indexes {
+                payloads.create_index(&path, index_type);
+            }
+            let vectors = collection_root
+                .vectors
+                .into_iter()
+                .map(|(name, link)| Ok((name, index::load_index(&link.0, store, data_dir)?)))
+                .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+            let mut collection = Collection::with_vectors(
+                index::load_index(&collection_root.index.0, store, data_dir)?,
+                vectors,
+                payloads,
+            );
+            collection.points = PointSet::load(&collection_root.stamps, store)?;
+            Ok(collection)
+        }
+        Err(_) => Ok(Collection::new(index::load_index(root, store, data_dir)?)),
+    }
+}
+
+/// the name of a collection, the default one for an empty name
+pub(crate) fn default_name(collection: &str) -> &str {
+    if collection.is_empty() {
+        DEFAULT_COLLECTION
+    } else {
+        collection
+    }
+}
+
+/// reads the roots written by write_roots, none when the file does not exist
+fn read_roots(path: &Path) -> Result<HashMap<String, (Cid, Option<Cid>)>, Box<dyn Error>> {
+    if !path.is_file() {
+        return Ok(HashMap::new());
+    }
+    let saved: Vec<(String, String, Option<String>)> =
+        serde_cbor::from_reader(BufReader::new(File::open(path)?))?;
+    saved
+        .into_iter()
+        .map(|(collection, root, manifest)| {
+            let manifest = manifest
+                .map(|cid| Cid::try_from(cid.as_str()))
+                .transpose()?;
+            Ok((collection, (Cid::try_from(root.as_str())?, manifest)))
+        })
+        .collect()
+}
+
+/// writes the root and the manifest of each collection to a CBOR file
+fn write_roots(
+    path: &Path,
+    roots: &HashMap<String, (Cid, Option<Cid>)>,
+) -> Result<(), Box<dyn Error>> {
+    let saved: Vec<(String, String, Option<String>)> = roots
+        .iter()
+        .map(|(collection, (root, manifest))| {
+            (
+                collection.clone(),
+                root.to_string(),
+                manifest.map(|cid| cid.to_string()),
+            )
+        })
+        .collect();
+    write_cbor(path, &saved)
+}
+
+/// reads the attachments written by save_collections as (name, root, source), none when the
+/// file does not exist
+fn read_attachments(path: &Path) -> Result<Vec<(String, Cid, BlockSource)>, Box<dyn Error>> {
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let saved: Vec<(String, String, String)> =
+        serde_cbor::from_reader(BufReader::new(File::open(path)?))?;
+    saved
+        .into_iter()
+        .map(|(collection, root, source)| {
+            let source = source.parse::<BlockSource>()?;
+            Ok((collection, Cid::try_from(root.as_str())?, source))
+        })
+        .collect()
+}
+
+/// writes value to a CBOR file, aside then renamed so that it is never seen partly written
+fn write_cbor<T: Serialize>(path: &Path, value: &T) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension("partial");
+    let mut writer = BufWriter::new(File::create(&partial)?);
+    serde_cbor::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// whether name is a single normal component of a path, so that it can name a directory
+fn is_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
 
 pub struct VectorAPI {
-    hnsw: Hnsw<f32, dist::DistCosine>,
+    /// parameters of the indexes of new collections
+    config: IndexConfig,
+    collections: RwLock<HashMap<String, Arc<Collection>>>,
+    /// the blocks of the published collections, which peers fetch
+    blocks: FsBlockStore,
+    /// the root each collection was last published or checked out at, with its signed
+    /// manifest if it has one
+    roots: RwLock<HashMap<String, (Cid, Option<Cid>)>>,
+    /// where attached collections read their blocks from by default
+    block_source: BlockSource,
+    /// the other directories and IPFS nodes attached collections may read their blocks from
+    attach_sources: Vec<BlockSource>,
+    /// signs the manifests of the published versions when set
+    signing_key: Option<SigningKey>,
+    /// the signers whose versions are attached and synced, any version when None
+    trust_list: Option<TrustList>,
+    /// stamps the writes of the points, see crdt
+    clock: Clock,
+    /// the leader of each collection following one, which only takes the writes it replays
+    leaders: RwLock<HashMap<String, String>>,
 }
 
 impl VectorAPI {
-    pub fn new(hnsw: Hnsw<f32, dist::DistCosine>) -> Self {
-        VectorAPI { hnsw }
+    /// creates the API with an empty default collection
+    pub fn new(config: IndexConfig) -> Self {
+        let mut collections = HashMap::new();
+        collections.insert(
+            DEFAULT_COLLECTION.to_string(),
+            Arc::new(Collection::new(config.build())),
+        );
+        // the published roots are kept with the blocks, whether collections are saved or not
+        let roots = match read_roots(&config.data_dir.join(ROOTS_FILE)) {
+            Ok(roots) => roots,
+            Err(e) => {
+                log::warn!("published roots not read: {}", e);
+                HashMap::new()
+            }
+        };
+        VectorAPI {
+            blocks: FsBlockStore::new(config.data_dir.join(BLOCKS_DIR)),
+            block_source: BlockSource::Dir(config.data_dir.join(BLOCKS_DIR)),
+            attach_sources: Vec::new(),
+            config,
+            collections: RwLock::new(collections),
+            roots: RwLock::new(roots),
+            signing_key: None,
+            trust_list: None,
+            clock: Clock::new(rand::random()),
+            leaders: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// reads attached collections from source rather than from the block store of the node
+    pub fn with_block_source(mut self, source: BlockSource) -> Self {
+        self.block_source = source;
+        self
+    }
+
+    pub fn block_source(&self) -> &BlockSource {
+        &self.block_source
+    }
+
+    /// lets attached collections also read their blocks from sources
+    pub fn with_attach_sources(mut self, sources: Vec<BlockSource>) -> Self {
+        self.attach_sources = sources;
+        self
+    }
+
+    /// fails for a directory or an IPFS node other than the block source, the blocks
+    /// directory of data_dir and the attach sources, which attached collections cannot read
+    pub fn check_source(&self, source: &BlockSource) -> Result<(), Box<dyn Error>> {
+        match source {
+            BlockSource::Peers(_) => Ok(()),
+            _ if *source == self.block_source
+                || *source == BlockSource::Dir(self.config.data_dir.join(BLOCKS_DIR))
+                || self.attach_sources.contains(source) =>
+            {
+                Ok(())
+            }
+            _ => Err(format!("block source {} is not allowed", source).into()),
+        }
+    }
+
+    /// signs a manifest of each version published with key
+    pub fn with_signing_key(mut self, key: SigningKey) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// attaches and syncs only the versions whose manifest is signed by a key of trust_list
+    pub fn with_trust_list(mut self, trust_list: TrustList) -> Self {
+        self.trust_list = Some(trust_list);
+        self
+    }
+
+    pub fn trust_list(&self) -> Option<&TrustList> {
+        self.trust_list.as_ref()
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// makes the clock read after the stamps of the points of collection, loaded from elsewhere
+    fn observe(&self, collection: &Collection) {
+        if let Some(stamp) = collection.points.max_stamp() {
+            self.clock.observe(stamp);
+        }
+    }
+
+    /// returns the collection of this name, the default one for an empty name
+    pub fn collection(&self, name: &str) -> Result<Arc<Collection>, Box<dyn Error>> {
+        let name = default_name(name);
+        self.collections
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("no collection named {}", name).into())
+    }
+
+    /// records that collection follows leader, or no longer follows one for None
+    pub fn set_leader(&self, collection: &str, leader: Option<&str>) {
+        let name = default_name(collection).to_string();
+        match leader {
+            Some(leader) => self.leaders.write().insert(name, leader.to_string()),
+            None => self.leaders.write().remove(&name),
+        };
+    }
+
+    /// refuses the writes to collection, or accepts them again. The writes under way when
+    /// it is frozen are over when this returns.
+    pub fn freeze(&self, collection: &str, frozen: bool) -> Result<(), Box<dyn Error>> {
+        let collection = self.collection(collection)?;
+        let _writes = collection.writes.lock();
+        collection.frozen.store(frozen, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// fails for a collection following a leader, its writes come from the leader only
+    pub fn check_writable(&self, collection: &str) -> Result<(), Box<dyn Error>> {
+        let name = default_name(collection);
+        match self.leaders.read().get(name) {
+            Some(leader) => Err(format!(
+                "collection {} follows {}, write to the leader",
+                name, leader
+            )
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// the collection of this name to write to, failing when it follows a leader or is
+    /// attached
+    fn writable(&self, name: &str) -> Result<Arc<Collection>, Box<dyn Error>> {
+        self.check_writable(name)?;
+        self.replica(name)
+    }
+
+    /// the collection of this name to replay the writes of its leader on, failing when it
+    /// is attached
+    fn replica(&self, name: &str) -> Result<Arc<Collection>, Box<dyn Error>> {
+        let collection = self.collection(name)?;
+        match collection.attached {
+            Some(root) => Err(format!(
+                "collection {} is attached read-only at {}",
+                default_name(name),
+                root
+            )
+            .into()),
+            None => Ok(collection),
+        }
+    }
+
+    /// parameters of the index of the default collection
+    pub fn index_config(&self) -> &IndexConfig {
+        &self.config
+    }
+
+    /// creates an empty collection with an index built from config
+    pub fn create_collection(&self, name: &str, config: IndexConfig) -> Result<(), Box<dyn Error>> {
+        self.create_collection_with_vectors(name, config, Vec::new())
+    }
+
+    /// creates an empty collection whose points also have the named vectors, each indexed
+    /// by an index built from its own config
+    pub fn create_collection_with_vectors(
+        &self,
+        name: &str,
+        config: IndexConfig,
+        vectors: Vec<(String, IndexConfig)>,
+    ) -> Result<(), Box<dyn Error>> {
+        if name.is_empty() {
+            return Err("collection name cannot be empty".into());
+        }
+        config.check()?;
+        let mut names = std::collections::HashSet::new();
+        for (vector, vector_config) in &vectors {
+            vector_config.check()?;
+            // vector names become directory names of saved collections
+            if !is_file_name(vector) {
+                return Err(format!("invalid vector name {:?}", vector).into());
+            }
+            if !names.insert(vector) {
+                return Err(format!("vector {} named twice", vector).into());
+            }
+            if vector_config.kind == IndexKind::Sparse {
+                return Err(format!("vector {} cannot have a sparse index", vector).into());
+            }
+        }
+        let mut collections = self.collections.write();
+        if collections.contains_key(name) {
+            return Err(format!("collection {} already exists", name).into());
+        }
+        let vectors = vectors
+            .into_iter()
+            .map(|(vector, vector_config)| (vector, vector_config.build()))
+            .collect();
+        collections.insert(
+            name.to_string(),
+            Arc::new(Collection::with_vectors(
+                config.build(),
+                vectors,
+                PayloadStore::new(),
+            )),
+        );
+        Ok(())
+    }
+
+    pub fn drop_collection(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        if name == DEFAULT_COLLECTION {
+            return Err("the default collection cannot be dropped".into());
+        }
+        if self.collections.write().remove(name).is_none() {
+            return Err(format!("no collection named {}", name).into());
+        }
+        // a saved collection must not be opened again at the next start
+        let dir = self.collections_dir().join(name);
+        if is_file_name(name) && dir.is_dir() {
+            std::fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    /// names and statistics of the collections, sorted by name
+    pub fn list_collections(&self) -> Vec<(String, IndexStats)> {
+        let mut collections: Vec<(String, IndexStats)> = self
+            .collections
+            .read()
+            .iter()
+            .map(|(name, collection)| (name.clone(), collection.stats()))
+            .collect();
+        collections.sort_by(|a, b| a.0.cmp(&b.0));
+        collections
+    }
+
+    pub fn parallel_insert(
+        &self,
+        collection: &str,
+        data: &Vec<(&Vec<f32>, usize)>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert_with_payloads(collection, data, Vec::new())
+    }
+
+    /// inserts points with their payloads, one per point or none at all. A null payload
+    /// removes the payload of its point, no payloads leave those of the points unchanged.
+    pub fn insert_with_payloads(
+        &self,
+        collection: &str,
+        data: &Vec<(&Vec<f32>, usize)>,
+        payloads: Vec<Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert_points(&*self.writable(collection)?, data, payloads)
+    }
+
+    fn insert_points(
+        &self,
+        collection: &Collection,
+        data: &Vec<(&Vec<f32>, usize)>,
+        payloads: Vec<Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        if !payloads.is_empty() && payloads.len() != data.len() {
+            return Err(format!("{} payloads for {} vectors", payloads.len(), data.len()).into());
+        }
+        let points: Vec<(&[f32], DataId)> =
+            data.iter().map(|(v, id)| (v.as_slice(), *id)).collect();
+        let _writes = collection.lock_writes()?;
+        // payloads are set under the index lock, so that a dump sees them with their points
+        let index = collection.index.read();
+        index.insert(&points)?;
+        let ids: Vec<DataId> = data.iter().map(|(_, d_id)| *d_id).collect();
+        collection
+            .points
+            .record_write(&ids, self.clock.now(), false);
+        collection.log.append(|| WriteOp::Insert {
+            ids: ids.clone(),
+            vectors: data.iter().map(|(v, _)| v.to_vec()).collect(),
+            payloads: payloads.clone(),
+        });
+        for ((_, d_id), payload) in data.iter().zip(payloads) {
+            collection.payloads.set(*d_id, payload);
+        }
+        Ok(())
+    }
+
+    /// inserts points with several vectors and their payloads, see insert_with_payloads.
+    /// Each named vectors hold one vector per id, a point may lack the vectors of some names.
+    pub fn insert_named(
+        &self,
+        collection: &str,
+        ids: &[DataId],
+        vectors: &[NamedVectors],
+        payloads: Vec<Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert_named_points(&*self.writable(collection)?, ids, vectors, payloads)
+    }
+
+    fn insert_named_points(
+        &self,
+        collection: &Collection,
+        ids: &[DataId],
+        vectors: &[NamedVectors],
+        payloads: Vec<Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        if !payloads.is_empty() && payloads.len() != ids.len() {
+            return Err(format!("{} payloads for {} ids", payloads.len(), ids.len()).into());
+        }
+        if let Some(named) = vectors
+            .iter()
+            .find(|named| named.vectors.len() != ids.len())
+        {
+            return Err(format!(
+                "{} vectors {} for {} ids",
+                named.vectors.len(),
+                named.name,
+                ids.len()
+            )
+            .into());
+        }
+        let _writes = collection.lock_writes()?;
+        // the default index lock comes first, it keeps dumps out of the whole insertion
+        let index = collection.index.read();
+        // all names and dimensions are checked before any insertion
+        for named in vectors {
+            let dimension = if named.name.is_empty() {
+                index.stats().dimension
+            } else {
+                collection
+                    .vector_index(&named.name)?
+                    .read()
+                    .stats()
+                    .dimension
+            };
+            let dimension = match (dimension, named.vectors.first()) {
+                (0, Some(v)) => v.len(),
+                _ => dimension,
+            };
+            if let Some(v) = named.vectors.iter().find(|v| v.len() != dimension) {
+                return Err(format!(
+                    "vector {} has dimension {}, expected {}",
+                    named.name,
+                    v.len(),
+                    dimension
+                )
+                .into());
+            }
+        }
+        for named in vectors {
+            let points: Vec<(&[f32], DataId)> = named
+                .vectors
+                .iter()
+                .map(Vec::as_slice)
+                .zip(ids.iter().copied())
+                .collect();
+            if named.name.is_empty() {
+                index.insert(&points)?;
+            } else {
+                collection.vectors[&named.name].read().insert(&points)?;
+            }
+        }
+        collection.points.record_write(ids, self.clock.now(), false);
+        collection.log.append(|| WriteOp::InsertNamed {
+            ids: ids.to_vec(),
+            vectors: vectors.to_vec(),
+            payloads: payloads.clone(),
+        });
+        for (d_id, payload) in ids.iter().zip(payloads) {
+            collection.payloads.set(*d_id, payload);
+        }
+        Ok(())
+    }
+
+    /// inserts sparse vectors with their payloads in a collection whose index stores them,
+    /// see insert_with_payloads
+    pub fn insert_sparse(
+        &self,
+        collection: &str,
+        data: Vec<(SparseVector, DataId)>,
+        payloads: Vec<Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert_sparse_points(&*self.writable(collection)?, data, payloads)
+    }
+
+    fn insert_sparse_points(
+        &self,
+        collection: &Collection,
+        data: Vec<(SparseVector, DataId)>,
+        payloads: Vec<Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        if !payloads.is_empty() && payloads.len() != data.len() {
+            return Err(format!("{} payloads for {} vectors", payloads.len(), data.len()).into());
+        }
+        let points = data
+            .into_iter()
+            .map(|(v, d_id)| Ok((SparseVector::new(v.indices, v.values)?, d_id)))
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        let _writes = collection.lock_writes()?;
+        let index = collection.index.read();
+        index.sparse_insert(&points)?;
+        let ids: Vec<DataId> = points.iter().map(|(_, d_id)| *d_id).collect();
+        collection
+            .points
+            .record_write(&ids, self.clock.now(), false);
+        collection.log.append(|| WriteOp::InsertSparse {
+            data: points.clone(),
+            payloads: payloads.clone(),
+        });
+        for ((_, d_id), payload) in points.iter().zip(payloads) {
+            collection.payloads.set(*d_id, payload);
+        }
+        Ok(())
+    }
+
+    /// the knbn points of greatest dot product with each sparse query, among those matching
+    /// the filter of options, with their payloads when options ask for them
+    pub fn sparse_search(
+        &self,
+        collection: &str,
+        queries: Vec<SparseVector>,
+        knbn: usize,
+        options: &SearchOptions,
+    ) -> Result<Vec<Vec<ScoredPoint>>, Box<dyn Error>> {
+        let queries = queries
+            .into_iter()
+            .map(|q| SparseVector::new(q.indices, q.values))
+            .collect::<Result<Vec<_>, _>>()?;
+        let collection = self.collection(collection)?;
+        let index = collection.index.read();
+        let prepared = options
+            .filter
+            .as_ref()
+            .map(|f| collection.payloads.prepare(f));
+        let accepts = |d_id: DataId| prepared.as_ref().is_none_or(|p| p.accepts(d_id));
+        let results = queries
+            .par_iter()
+            // errors cross threads as strings
+            .map(|query| {
+                index
+                    .sparse_search(query, knbn, &accepts)
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        // the payloads are read again below
+        drop(prepared);
+        Ok(results
+            .into_iter()
+            .map(|hits| {
+                hits.into_iter()
+                    .map(|(d_id, score)| ScoredPoint {
+                        d_id,
+                        score,
+                        payload: collection.payload(d_id, options),
+                    })
+                    .collect()
+            })
+            .collect())
     }
 
-    pub fn parallel_insert(&self, data: &Vec<(&Vec<f32>, usize)>) {
-        self.hnsw.parallel_insert(data);
+    /// inserts documents of several vectors with their payloads in the vector of this name,
+    /// empty for the default one, whose index stores them. See insert_with_payloads.
+    pub fn insert_multi(
+        &self,
+        collection: &str,
+        vector: &str,
+        data: &[(Vec<Vec<f32>>, DataId)],
+        payloads: Vec<Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.insert_multi_points(&*self.writable(collection)?, vector, data, payloads)
+    }
+
+    fn insert_multi_points(
+        &self,
+        collection: &Collection,
+        vector: &str,
+        data: &[(Vec<Vec<f32>>, DataId)],
+        payloads: Vec<Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        if !payloads.is_empty() && payloads.len() != data.len() {
+            return Err(format!("{} payloads for {} documents", payloads.len(), data.len()).into());
+        }
+        let docs: Vec<(&[Vec<f32>], DataId)> =
+            data.iter().map(|(v, d_id)| (v.as_slice(), *d_id)).collect();
+        let _writes = collection.lock_writes()?;
+        // the default index lock comes first, see insert_named
+        let index = collection.index.read();
+        if vector.is_empty() {
+            index.multi_insert(&docs)?;
+        } else {
+            collection
+                .vector_index(vector)?
+                .read()
+                .multi_insert(&docs)?;
+        }
+        let ids: Vec<DataId> = data.iter().map(|(_, d_id)| *d_id).collect();
+        collection
+            .points
+            .record_write(&ids, self.clock.now(), false);
+        collection.log.append(|| WriteOp::InsertMulti {
+            vector: vector.to_string(),
+            data: data.to_vec(),
+            payloads: payloads.clone(),
+        });
+        for ((_, d_id), payload) in data.iter().zip(payloads) {
+            collection.payloads.set(*d_id, payload);
+        }
+        Ok(())
+    }
+
+    /// the knbn documents of greatest MaxSim score with each query of several vectors, among
+    /// the documents of the vector named by options and matching its filter. The documents
+    /// of the ef tokens nearest to each query vector are ranked by their exact score.
+    pub fn multi_search(
+        &self,
+        collection: &str,
+        queries: &[Vec<Vec<f32>>],
+        knbn: usize,
+        ef: usize,
+        options: &SearchOptions,
+    ) -> Result<Vec<Vec<ScoredPoint>>, Box<dyn Error>> {
+        let collection = self.collection(collection)?;
+        let index = collection.vector_index(&options.vector)?.read();
+        let prepared = options
+            .filter
+            .as_ref()
+            .map(|f| collection.payloads.prepare(f));
+        let accepts = |d_id: DataId| prepared.as_ref().is_none_or(|p| p.accepts(d_id));
+        let results = queries
+            .par_iter()
+            // errors cross threads as strings
+            .map(|query| {
+                index
+                    .multi_search(query, knbn, ef, &accepts)
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        // the payloads are read again below
+        drop(prepared);
+        Ok(results
+            .into_iter()
+            .map(|hits| {
+                hits.into_iter()
+                    .map(|(d_id, score)| ScoredPoint {
+                        d_id,
+                        score,
+                        payload: collection.payload(d_id, options),
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// deletes points, their named vectors and their payloads by id, returns the number of
+    /// ids found by the index, default or named, which found most
+    pub fn delete(&self, collection: &str, ids: &[DataId]) -> Result<usize, Box<dyn Error>> {
+        self.delete_points(&*self.writable(collection)?, ids)
+    }
+
+    fn delete_points(
+        &self,
+        collection: &Collection,
+        ids: &[DataId],
+    ) -> Result<usize, Box<dyn Error>> {
+        let _writes = collection.lock_writes()?;
+        let index = collection.index.read();
+        let nb_deleted = if collection.vectors.is_empty() {
+            index.delete(ids)
+        } else {
+            // an id counts once, whichever of its vectors it had
+            let vectors: Vec<_> = collection
+                .vector_names()
+                .into_iter()
+                .map(|name| collection.vectors[name].read())
+                .collect();
+            ids.iter()
+                .filter(|d_id| {
+                    let mut deleted = index.delete(&[**d_id]) > 0;
+                    for vector in &vectors {
+                        deleted |= vector.delete(&[**d_id]) > 0;
+                    }
+                    deleted
+                })
+                .count()
+        };
+        collection.payloads.remove(ids);
+        collection.points.record_write(ids, self.clock.now(), true);
+        collection
+            .log
+            .append(|| WriteOp::Delete { ids: ids.to_vec() });
+        Ok(nb_deleted)
+    }
+
+    /// the points of a collection changed after seq since, up to max of them, but those last
+    /// written by the node skipped, see PointSet::changed. All points are changed for an epoch
+    /// other than the one of the point set of the collection. Returns that epoch, the seq to
+    /// ask from next time and the changes.
+    pub fn changes(
+        &self,
+        collection: &str,
+        epoch: u64,
+        since: u64,
+        max: usize,
+        skipped: Option<u64>,
+    ) -> Result<(u64, u64, Vec<Change>), Box<dyn Error>> {
+        let name = default_name(collection);
+        let collection = self.collection(collection)?;
+        let index = collection.index.read();
+        collection.check_mergeable(name, index.as_ref())?;
+        let points = &collection.points;
+        let since = if epoch == points.epoch() { since } else { 0 };
+        let (stamps, next) = points.changed(since, max, skipped);
+        let changes = stamps
+            .into_iter()
+            .map(|stamp| {
+                let point = if stamp.deleted {
+                    None
+                } else {
+                    let vector = index.vector(stamp.d_id).ok_or_else(|| {
+                        format!("point {} of collection {} has no vector", stamp.d_id, name)
+                    })?;
+                    Some((
+                        vector,
+                        collection.payloads.get(stamp.d_id).unwrap_or(Value::Null),
+                    ))
+                };
+                Ok(Change {
+                    d_id: stamp.d_id,
+                    stamp: stamp.stamp,
+                    point,
+                })
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        Ok((points.epoch(), next, changes))
+    }
+
+    /// applies to a collection the changes of peers newer than its points, the last one of
+    /// each point: its vector replaces the one of the index and its payload the one of the
+    /// point, or the point is deleted. Returns the number of changes applied.
+    pub fn merge(&self, collection: &str, changes: Vec<Change>) -> Result<usize, Box<dyn Error>> {
+        let name = default_name(collection);
+        self.apply_changes(name, &*self.writable(collection)?, changes, true)
+    }
+
+    /// applies a write of its leader to a collection following it. The changes the leader
+    /// merged won there whatever the stamps of the points of the follower.
+    pub fn replay(&self, collection: &str, op: WriteOp) -> Result<(), Box<dyn Error>> {
+        let name = default_name(collection);
+        let replica = self.replica(collection)?;
+        match op {
+            WriteOp::Insert {
+                ids,
+                vectors,
+                payloads,
+            } => {
+                let data: Vec<(&Vec<f32>, DataId)> = vectors.iter().zip(ids).collect();
+                self.insert_points(&replica, &data, payloads)
+            }
+            WriteOp::InsertNamed {
+                ids,
+                vectors,
+                payloads,
+            } => self.insert_named_points(&replica, &ids, &vectors, payloads),
+            WriteOp::InsertSparse { data, payloads } => {
+                self.insert_sparse_points(&replica, data, payloads)
+            }
+            WriteOp::InsertMulti {
+                vector,
+                data,
+                payloads,
+            } => self.insert_multi_points(&replica, &vector, &data, payloads),
+            WriteOp::Delete { ids } => self.delete_points(&replica, &ids).map(|_| ()),
+            WriteOp::Merge { changes } => self
+                .apply_changes(name, &replica, changes, false)
+                .map(|_| ()),
+        }
+    }
+
+    /// applies changes to the collection of this name, those newer than its points only
+    /// when newer_only is set
+    fn apply_changes(
+        &self,
+        name: &str,
+        collection: &Collection,
+        changes: Vec<Change>,
+        newer_only: bool,
+    ) -> Result<usize, Box<dyn Error>> {
+        if let Some(stamp) = changes.iter().map(|change| change.stamp).max() {
+            self.clock.observe(stamp);
+        }
+        let _writes = collection.lock_writes()?;
+        // the write lock keeps out the local writes, which record their stamps under the
+        // read lock, between the comparison of the stamps and the update of the points
+        let index = collection.index.write();
+        collection.check_mergeable(name, index.as_ref())?;
+        let newer = if newer_only {
+            collection.points.newer(changes)
+        } else {
+            changes
+        };
+        if newer.is_empty() {
+            return Ok(0);
+        }
+        let points: Vec<(&[f32], DataId)> = newer
+            .iter()
+            .filter_map(|change| {
+                change
+                    .point
+                    .as_ref()
+                    .map(|(v, _)| (v.as_slice(), change.d_id))
+            })
+            .collect();
+        if !points.is_empty() {
+            index.insert(&points)?;
+        }
+        let deleted: Vec<DataId> = newer
+            .iter()
+            .filter(|change| change.point.is_none())
+            .map(|change| change.d_id)
+            .collect();
+        index.delete(&deleted);
+        collection.payloads.remove(&deleted);
+        for change in &newer {
+            if let Some((_, payload)) = &change.point {
+                collection.payloads.set(change.d_id, payload.clone());
+            }
+        }
+        collection.log.append(|| WriteOp::Merge {
+            changes: newer.clone(),
+        });
+        collection
+            .points
+            .record(newer.iter().map(Change::point_stamp));
+        Ok(newer.len())
     }
 
     pub fn parallel_search(
         &self,
+        collection: &str,
+        data: &Vec<Vec<f32>>,
+        knbn: usize,
+        ef: usize,
+    ) -> Result<Vec<Vec<Neighbour>>, Box<dyn Error>> {
+        Ok(self
+            .collection(collection)?
+            .index
+            .read()
+            .parallel_search(data, knbn, ef))
+    }
+
+    /// searches the neighbours of each query among the vectors named by options, with their
+    /// payloads when options ask for them. A filtered search traverses the index skipping rejected points, or computes the
+    /// distances to all accepted points when they are few, see filter::plan.
+    pub fn search(
+        &self,
+        collection: &str,
         data: &Vec<Vec<f32>>,
         knbn: usize,
         ef: usize,
+        options: &SearchOptions,
+    ) -> Result<Vec<Vec<(Neighbour, Option<Value>)>>, Box<dyn Error>> {
+        let collection = self.collection(collection)?;
+        let index = collection.vector_index(&options.vector)?.read();
+        let results = Self::neighbours(
+            &collection,
+            index.as_ref(),
+            data,
+            knbn,
+            ef,
+            options.filter.as_ref(),
+        );
+        Ok(results
+            .into_iter()
+            .map(|neighbours| {
+                neighbours
+                    .into_iter()
+                    .map(|n| {
+                        let payload = collection.payload(n.d_id, options);
+                        (n, payload)
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// the neighbours of each query among the points accepted by filter
+    fn neighbours(
+        collection: &Collection,
+        index: &dyn AnnIndex,
+        data: &Vec<Vec<f32>>,
+        knbn: usize,
+        ef: usize,
+        filter: Option<&Filter>,
     ) -> Vec<Vec<Neighbour>> {
-        self.hnsw.parallel_search(data, knbn, ef)
+        match filter {
+            None => index.parallel_search(data, knbn, ef),
+            Some(payload_filter) => {
+                let prepared = collection.payloads.prepare(payload_filter);
+                let nb_point = index.stats().nb_point;
+                let accepts = |d_id: DataId| prepared.accepts(d_id);
+                let plan = filter::plan(
+                    prepared.estimate_nb_accepted(nb_point),
+                    nb_point,
+                    ef.max(knbn),
+                );
+                log::debug!("filtered search planned as {:?}", plan);
+                data.par_iter()
+                    .map(|query| match plan {
+                        SearchPlan::Traverse => index.filtered_search(query, knbn, ef, &accepts),
+                        SearchPlan::BruteForce => {
+                            index.filtered_exact_search(query, knbn, &accepts)
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// proves that the points found by a search of collection among the vectors of this name,
+    /// and their payloads when returned, belong to the root the collection is attached at
+    pub fn prove(
+        &self,
+        collection: &str,
+        vector: &str,
+        results: &[Vec<(Neighbour, Option<Value>)>],
+    ) -> Result<SearchProofs, Box<dyn Error>> {
+        let name = default_name(collection);
+        let collection = self.collection(collection)?;
+        let prover = match (&collection.prover, collection.attached) {
+            (Some(prover), _) => prover,
+            (None, Some(root)) => {
+                return Err(
+                    format!("points of collection {} at {} cannot be proven", name, root).into(),
+                );
+            }
+            (None, None) => {
+                return Err(format!(
+                    "collection {} is not attached, its points are not proven",
+                    name
+                )
+                .into());
+            }
+        };
+        let mut blocks = HashMap::new();
+        let proofs = results
+            .iter()
+            .map(|neighbours| {
+                neighbours
+                    .iter()
+                    .map(|(n, payload)| {
+                        prover.prove(n.d_id, vector, payload.is_some(), &mut blocks)
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SearchProofs {
+            root: prover.root(),
+            proofs,
+            blocks,
+        })
+    }
+
+    /// searches each query vector and its keywords, and fuses the two rankings. Each search
+    /// returns up to ef points, dense ones being scored by their opposite distance.
+    pub fn hybrid_search(
+        &self,
+        collection: &str,
+        data: &Vec<Vec<f32>>,
+        knbn: usize,
+        ef: usize,
+        query: &HybridQuery,
+        options: &SearchOptions,
+    ) -> Result<Vec<Vec<ScoredPoint>>, Box<dyn Error>> {
+        if query.texts.len() != data.len() {
+            return Err(format!(
+                "{} texts for {} query vectors",
+                query.texts.len(),
+                data.len()
+            )
+            .into());
+        }
+        let collection = self.collection(collection)?;
+        let index = collection.vector_index(&options.vector)?.read();
+        let depth = ef.max(knbn);
+        let dense = Self::neighbours(
+            &collection,
+            index.as_ref(),
+            data,
+            depth,
+            ef,
+            options.filter.as_ref(),
+        );
+        dense
+            .into_par_iter()
+            .zip(query.texts.par_iter())
+            .map(|(neighbours, text)| {
+                let keywords = collection
+                    .payloads
+                    .text_search(&query.text_field, text, depth, options.filter.as_ref())
+                    // errors cross threads as strings
+                    .map_err(|e| e.to_string())?;
+                let rankings = [
+                    Ranking {
+                        weight: query.dense_weight,
+                        hits: neighbours.iter().map(|n| (n.d_id, -n.distance)).collect(),
+                    },
+                    Ranking {
+                        weight: query.text_weight,
+                        hits: keywords,
+                    },
+                ];
+                Ok(fusion::fuse(query.fusion, &rankings, knbn)
+                    .into_iter()
+                    .map(|(d_id, score)| ScoredPoint {
+                        d_id,
+                        score,
+                        payload: collection.payload(d_id, options),
+                    })
+                    .collect())
+            })
+            .collect::<Result<_, String>>()
+            .map_err(|e| e.into())
+    }
+
+    /// indexes a payload field of a collection, so that filters on it read fewer payloads
+    pub fn create_payload_index(
+        &self,
+        collection: &str,
+        path: &str,
+        index_type: PayloadIndexType,
+    ) -> Result<(), Box<dyn Error>> {
+        if path.is_empty() {
+            return Err("payload field cannot be empty".into());
+        }
+        self.collection(collection)?
+            .payloads
+            .create_index(path, index_type);
+        Ok(())
+    }
+
+    /// persists the index and the payloads of a collection and writes all their blocks to a
+    /// CAR file at path. Returns the root CID and the number of blocks written.
+    pub fn export_car(&self, collection: &str, path: &str) -> Result<(Cid, usize), Box<dyn Error>> {
+        let collection = self.collection(collection)?;
+        let store = MemoryBlockStore::new();
+        let root = collection.persist(&store)?;
+        let mut writer = BufWriter::new(File::create(path)?);
+        let nb_block = car::export_car(&mut writer, &root, &store)?;
+        Ok((root, nb_block))
+    }
+
+    /// replaces the index and the payloads of a collection, created if needed, by those stored
+    /// in the CAR file at path. Returns the root CID and the number of points of the imported index.
+    pub fn import_car(&self, collection: &str, path: &str) -> Result<(Cid, usize), Box<dyn Error>> {
+        self.import(collection, &mut BufReader::new(File::open(path)?))
+    }
+
+    /// writes a collection to a CAR held in memory, see export_car. Also returns the seq of
+    /// the last write it contains and the epoch of its write log, which keeps the writes
+    /// following it.
+    pub fn snapshot(&self, collection: &str) -> Result<(Vec<u8>, u64, u64), Box<dyn Error>> {
+        let collection = self.collection(collection)?;
+        collection.log.enable();
+        let store = MemoryBlockStore::new();
+        let (root, seq) = collection.persist_at(&store)?;
+        let mut car = Vec::new();
+        car::export_car(&mut car, &root, &store)?;
+        Ok((car, seq, collection.log.epoch()))
+    }
+
+    /// replaces a collection by the one of a CAR held in memory, see import_car
+    pub fn restore(&self, collection: &str, car: &[u8]) -> Result<(Cid, usize), Box<dyn Error>> {
+        self.import(collection, &mut &car[..])
+    }
+
+    pub fn blocks(&self) -> &FsBlockStore {
+        &self.blocks
+    }
+
+    /// persists a collection to the block store of data_dir, where peers can fetch its
+    /// blocks, with a signed manifest of the version when the API has a signing key. Returns
+    /// the root of the collection.
+    pub fn publish(&self, collection: &str) -> Result<Cid, Box<dyn Error>> {
+        let name = default_name(collection).to_string();
+        let published = self.collection(collection)?;
+        let root = published.persist(&self.blocks)?;
+        let signed = match &self.signing_key {
+            Some(key) => {
+                let parent = self.manifest(&name);
+                let version = match parent {
+                    Some(parent) => {
+                        manifest::decode(&self.blocks.get_block(&parent)?)?
+                            .manifest
+                            .version
+                            + 1
+                    }
+                    None => 1,
+                };
+                let stats = published.stats();
+                let block = manifest::sign(
+                    Manifest {
+                        collection: name.clone(),
+                        root: Link(root),
+                        version,
+                        parent: parent.map(|parent| parent.to_string()),
+                        kind: stats.kind,
+                        distance: stats.distance,
+                        dimension: stats.dimension,
+                        nb_point: stats.nb_point,
+                        vectors: published.vector_names().into_iter().cloned().collect(),
+                        published_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                    },
+                    key,
+                )?;
+                let cid = block.cid;
+                self.blocks.put(block)?;
+                Some(cid)
+            }
+            None => None,
+        };
+        self.set_version(name, root, signed)?;
+        Ok(root)
+    }
+
+    /// the root a collection was last published, checked out or attached at
+    pub fn published(&self, collection: &str) -> Option<Cid> {
+        self.version(collection).map(|(root, _)| root)
+    }
+
+    /// the signed manifest of the root a collection was last published, checked out or
+    /// attached at
+    pub fn manifest(&self, collection: &str) -> Option<Cid> {
+        self.version(collection).and_then(|(_, manifest)| manifest)
+    }
+
+    /// the root a collection was last published, checked out or attached at and its signed
+    /// manifest
+    pub fn version(&self, collection: &str) -> Option<(Cid, Option<Cid>)> {
+        self.roots.read().get(default_name(collection)).copied()
+    }
+
+    /// records the version of collection and saves the roots to data_dir
+    fn set_version(
+        &self,
+        collection: String,
+        root: Cid,
+        manifest: Option<Cid>,
+    ) -> Result<(), Box<dyn Error>> {
+        // the lock orders the saves, the last one writes the latest roots
+        let mut roots = self.roots.write();
+        roots.insert(collection, (root, manifest));
+        write_roots(&self.config.data_dir.join(ROOTS_FILE), &roots)
+    }
+
+    /// checks that collection may be replaced by the version of root with manifest, its
+    /// signed manifest read from the block store. With a trust list, only the versions whose
+    /// manifest is signed by a trusted key are accepted. A manifest must be the one of root and
+    /// collection, and its version not older than the one of collection held.
+    pub fn check_version(
+        &self,
+        collection: &str,
+        root: &Cid,
+        manifest: Option<&Cid>,
+    ) -> Result<(), Box<dyn Error>> {
+        let name = default_name(collection);
+        let manifest_cid = match manifest {
+            Some(manifest) => manifest,
+            None if self.trust_list.is_some() => {
+                return Err(
+                    "only versions with a manifest signed by a trusted key are accepted".into(),
+                )
+            }
+            None => return Ok(()),
+        };
+        let block = self.blocks.get_block(manifest_cid)?;
+        let manifest = match &self.trust_list {
+            Some(trust_list) => trust_list.verify(&block)?,
+            None if block.verify() => manifest::decode(&block)?.manifest,
+            None => return Err(format!("manifest {} does not match its CID", manifest_cid).into()),
+        };
+        if manifest.root.0 != *root {
+            return Err(
+                format!("manifest {} is not the one of root {}", manifest_cid, root).into(),
+            );
+        }
+        if manifest.collection != name {
+            return Err(format!(
+                "manifest {} is the one of collection {}, not {}",
+                manifest_cid, manifest.collection, name
+            )
+            .into());
+        }
+        // an older version would roll back the collection
+        if let Some(held) = self.manifest(name) {
+            let held = manifest::decode(&self.blocks.get_block(&held)?)?.manifest;
+            if manifest.version < held.version {
+                return Err(format!(
+                    "version {} of collection {} is older than the version {} held",
+                    manifest.version, name, held.version
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// replaces a collection, created if needed, by the one persisted under root in the block
+    /// store, see publish. manifest is the signed manifest of root, if it has one, see
+    /// check_version. Returns the number of points of the collection.
+    pub fn checkout(
+        &self,
+        collection: &str,
+        root: &Cid,
+        manifest: Option<Cid>,
+    ) -> Result<usize, Box<dyn Error>> {
+        self.check_version(collection, root, manifest.as_ref())?;
+        let checked_out = load_collection(root, &self.blocks, &self.config.data_dir)?;
+        self.observe(&checked_out);
+        let nb_point = checked_out.stats().nb_point;
+        let name = default_name(collection).to_string();
+        self.collections
+            .write()
+            .insert(name.clone(), Arc::new(checked_out));
+        self.set_version(name, *root, manifest)?;
+        Ok(nb_point)
+    }
+
+    /// serves the collection persisted under root as a read-only collection, which replaces
+    /// an attached collection of this name but no other. manifest is the signed manifest of
+    /// root, if it has one, see check_version. Its blocks are read from a directory source, or
+    /// from the block store of the node where those of other sources are fetched, see
+    /// check_source. The collection stays as of root, whatever is written to the collection it
+    /// was published from since, and its points are proven from its blocks, see prove.
+    /// Returns the number of points of the collection.
+    pub fn attach(
+        &self,
+        collection: &str,
+        root: &Cid,
+        manifest: Option<Cid>,
+        source: &BlockSource,
+    ) -> Result<usize, Box<dyn Error>> {
+        let name = default_name(collection);
+        self.check_source(source)?;
+        self.check_version(collection, root, manifest.as_ref())?;
+        let store: Arc<dyn BlockStore> = match source {
+            BlockSource::Dir(dir) => Arc::new(FsBlockStore::new(dir)),
+            _ => Arc::new(self.blocks.clone()),
+        };
+        let mut attached = load_collection(root, store.as_ref(), &self.config.data_dir)?;
+        attached.attached = Some(*root);
+        attached.source = Some(source.clone());
+        attached.prover = match Prover::new(*root, store) {
+            Ok(prover) => Some(prover),
+            Err(e) => {
+                log::warn!(
+                    "points of collection {} at {} cannot be proven: {}",
+                    name,
+                    root,
+                    e
+                );
+                None
+            }
+        };
+        let nb_point = attached.stats().nb_point;
+        let mut collections = self.collections.write();
+        if collections
+            .get(name)
+            .is_some_and(|existing| existing.attached.is_none())
+        {
+            return Err(format!("collection {} exists and is not attached", name).into());
+        }
+        collections.insert(name.to_string(), Arc::new(attached));
+        drop(collections);
+        self.set_version(name.to_string(), *root, manifest)?;
+        Ok(nb_point)
+    }
+
+    fn import(
+        &self,
+        collection: &str,
+        reader: &mut impl Read,
+    ) -> Result<(Cid, usize), Box<dyn Error>> {
+        let store = MemoryBlockStore::new();
+        let root = car::import_car(reader, &store)?;
+        let imported = load_collection(&root, &store, &self.config.data_dir)?;
+        self.observe(&imported);
+        let nb_point = imported.stats().nb_point;
+        let name = default_name(collection);
+        // a new collection replaces the existing one, so that searches see the index and
+        // the payloads imported together
+        self.collections
+            .write()
+            .insert(name.to_string(), Arc::new(imported));
+        Ok((root, nb_point))
+    }
+
+    /// writes the (id, vector) pairs of a collection to path, all of them or only those of a layer.
+    /// Returns the number of vectors written.
+    pub fn export_vectors(
+        &self,
+        collection: &str,
+        path: &str,
+        format: VectorFormat,
+        layer: Option<usize>,
+    ) -> Result<usize, Box<dyn Error>> {
+        let collection = self.collection(collection)?;
+        // the write lock keeps insertions out so that the count written in headers stays exact
+        let index = collection.index.write();
+        let stats = index.stats();
+        let nb_vector = match layer {
+            Some(layer) => {
+                let mut nb_vector = 0;
+                index.scan_layer(layer, &mut |_, _| {
+                    nb_vector += 1;
+                    Ok(())
+                })?;
+                nb_vector
+            }
+            None => stats.nb_point,
+        };
+        let mut writer = VectorWriter::create(Path::new(path), format, stats.dimension, nb_vector)?;
+        let mut write = |id: DataId, v: &[f32]| writer.write(id, v);
+        match layer {
+            Some(layer) => index.scan_layer(layer, &mut write)?,
+            None => index.scan(&mut write)?,
+        }
+        writer.finish()
+    }
+
+    /// estimates the recall@knbn of searches at ef in a collection, with queries or else
+    /// with nb_sample vectors sampled from it. Exact neighbours are computed by brute force.
+    pub fn estimate_recall(
+        &self,
+        collection: &str,
+        queries: Vec<Vec<f32>>,
+        nb_sample: usize,
+        knbn: usize,
+        ef: usize,
+    ) -> Result<RecallEstimate, Box<dyn Error>> {
+        if knbn == 0 {
+            return Err("knbn must be positive".into());
+        }
+        let collection = self.collection(collection)?;
+        let index = collection.index.read();
+        let queries = if queries.is_empty() {
+            bench::sample_vectors(index.as_ref(), nb_sample)?
+        } else {
+            queries
+        };
+        let stats = index.stats();
+        if stats.nb_point > 0 && queries.iter().any(|q| q.len() != stats.dimension) {
+            return Err(format!("queries must have dimension {}", stats.dimension).into());
+        }
+        Ok(bench::estimate_recall(
+            index.as_ref(),
+            &queries,
+            knbn,
+            ef.max(knbn),
+        ))
+    }
+
+    fn collections_dir(&self) -> PathBuf {
+        self.config.data_dir.join(COLLECTIONS_DIR)
+    }
+
+    /// saves every collection whose index supports it to its own directory of data_dir,
+    /// returns the number of collections saved
+    pub fn save_collections(&self) -> Result<usize, Box<dyn Error>> {
+        let collections: Vec<(String, Arc<Collection>)> = self
+            .collections
+            .read()
+            .iter()
+            .map(|(name, collection)| (name.clone(), Arc::clone(collection)))
+            .collect();
+        let mut nb_saved = 0;
+        let mut attachments = Vec::new();
+        for (name, collection) in collections {
+            // attached collections are attached again at their root
+            if let (Some(root), Some(source)) = (collection.attached, &collection.source) {
+                attachments.push((name, root.to_string(), source.to_string()));
+                continue;
+            }
+            // collection names become directory names
+            if !is_file_name(&name) {
+                log::warn!("collection {} not saved, its name is not a file name", name);
+                continue;
+            }
+            // the write lock keeps insertions out during the save
+            let index = collection.index.write();
+            let dir = self.collections_dir().join(&name);
+            let saved = index::save_index(index.as_ref(), &dir)
+                .and_then(|_| {
+                    // vectors of an earlier collection of this name must not be opened
+                    if dir.join(VECTORS_DIR).is_dir() {
+                        std::fs::remove_dir_all(dir.join(VECTORS_DIR))?;
+                    }
+                    for name in collection.vector_names() {
+                        let vector_index = collection.vectors[name].write();
+                        index::save_index(
+                            vector_index.as_ref(),
+                            &dir.join(VECTORS_DIR).join(name),
+                        )?;
+                    }
+                    Ok(())
+                })
+                .and_then(|_| collection.payloads.save(&dir.join(PAYLOADS_FILE)))
+                .and_then(|_| collection.points.save(&dir.join(STAMPS_FILE)))
+                .and_then(|_| {
+                    let schema = serde_json::to_string(&collection.payloads.index_schema())?;
+                    Ok(std::fs::write(dir.join(PAYLOAD_INDEXES_FILE), schema)?)
+                });
+            match saved {
+                Ok(()) => nb_saved += 1,
+                Err(e) => log::warn!("collection {} not saved: {}", name, e),
+            }
+        }
+        write_cbor(&self.collections_dir().join(ATTACHMENTS_FILE), &attachments)?;
+        Ok(nb_saved + attachments.len())
+    }
+
+    /// opens the collections saved in data_dir, replacing those of the same name. A collection
+    /// that cannot be opened is skipped with a warning.
+    /// Returns the number of collections opened.
+    pub fn open_collections(&self) -> Result<usize, Box<dyn Error>> {
+        let dir = self.collections_dir();
+        if !dir.is_dir() {
+            return Ok(0);
+        }
+        let mut nb_opened = 0;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if path.is_dir() => name.to_string(),
+                _ => continue,
+            };
+            match self.open_collection(&path) {
+                Ok(collection) => {
+                    self.collections.write().insert(name, Arc::new(collection));
+                    nb_opened += 1;
+                }
+                Err(e) => log::warn!("collection {} not opened: {}", name, e),
+            }
+        }
+        for (name, root, source) in read_attachments(&dir.join(ATTACHMENTS_FILE))? {
+            // the manifest of the root was saved with the roots
+            let manifest = self
+                .version(&name)
+                .filter(|(published, _)| *published == root)
+                .and_then(|(_, manifest)| manifest);
+            match self.attach(&name, &root, manifest, &source) {
+                Ok(_) => nb_opened += 1,
+                Err(e) => log::warn!("collection {} not attached: {}", name, e),
+            }
+        }
+        Ok(nb_opened)
+    }
+
+    /// opens the collection saved in path
+    fn open_collection(&self, path: &Path) -> Result<Collection, Box<dyn Error>> {
+        let index = index::open_index(path)?;
+        let mut vectors = Vec::new();
+        let vectors_dir = path.join(VECTORS_DIR);
+        if vectors_dir.is_dir() {
+            for entry in std::fs::read_dir(vectors_dir)? {
+                let vector_path = entry?.path();
+                if let Some(vector) = vector_path.file_name().and_then(|name| name.to_str()) {
+                    vectors.push((vector.to_string(), index::open_index(&vector_path)?));
+                }
+            }
+        }
+        let payloads_path = path.join(PAYLOADS_FILE);
+        let payloads = if payloads_path.is_file() {
+            PayloadStore::open(&payloads_path)?
+        } else {
+            PayloadStore::new()
+        };
+        let indexes_path = path.join(PAYLOAD_INDEXES_FILE);
+        if indexes_path.is_file() {
+            let schema: Vec<(String, PayloadIndexType)> =
+                serde_json::from_str(&std::fs::read_to_string(&indexes_path)?)?;
+            for (field, index_type) in schema {
+                payloads.create_index(&field, index_type);
+            }
+        }
+        let mut collection = Collection::with_vectors(index, vectors, payloads);
+        let stamps_path = path.join(STAMPS_FILE);
+        if stamps_path.is_file() {
+            collection.points = PointSet::open(&stamps_path)?;
+            self.observe(&collection);
+        }
+        Ok(collection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{DistanceKind, IndexKind};
+
+    #[test]
+    fn test_drop_collection() {
+        let api = VectorAPI::new(IndexConfig::default());
+        api.create_collection("docs", IndexConfig::default())
+            .unwrap();
+        assert!(api
+            .create_collection("docs", IndexConfig::default())
+            .is_err());
+        api.drop_collection("docs").unwrap();
+        assert!(api.drop_collection("docs").is_err());
+        assert!(api.drop_collection(DEFAULT_COLLECTION).is_err());
+        assert!(api.collection(DEFAULT_COLLECTION).is_ok());
+    }
+
+    #[test]
+    fn test_freeze() {
+        let config = IndexConfig {
+            kind: IndexKind::Flat,
+            ..IndexConfig::default()
+        };
+        let api = VectorAPI::new(config.clone());
+        api.create_collection("docs", config).unwrap();
+        let v = vec![1., 0.];
+        api.parallel_insert("docs", &vec![(&v, 1)]).unwrap();
+        api.freeze("docs", true).unwrap();
+        assert!(api.parallel_insert("docs", &vec![(&v, 2)]).is_err());
+        assert!(api.delete("docs", &[1]).is_err());
+        // the other collections take writes
+        api.parallel_insert("", &vec![(&v, 2)]).unwrap();
+        api.freeze("docs", false).unwrap();
+        assert_eq!(api.delete("docs", &[1]).unwrap(), 1);
+        assert!(api.freeze("images", true).is_err());
+    }
+
+    #[test]
+    fn test_open_collections() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = IndexConfig {
+            kind: IndexKind::Hnsw,
+            data_dir: dir.path().to_path_buf(),
+            ..IndexConfig::default()
+        };
+        let api = VectorAPI::new(config.clone());
+        api.create_collection("docs", config.clone()).unwrap();
+        let v = vec![1., 0.];
+        api.parallel_insert("docs", &vec![(&v, 7)]).unwrap();
+        assert_eq!(api.save_collections().unwrap(), 2);
+        // a directory without an index does not keep the others from opening
+        std::fs::create_dir(dir.path().join(COLLECTIONS_DIR).join("broken")).unwrap();
+
+        let reopened = VectorAPI::new(config.clone());
+        assert_eq!(reopened.open_collections().unwrap(), 2);
+        assert!(reopened.collection("broken").is_err());
+        assert_eq!(reopened.collection("docs").unwrap().stats().nb_point, 1);
+
+        // a dropped collection is not opened again
+        reopened.drop_collection("docs").unwrap();
+        assert_eq!(reopened.save_collections().unwrap(), 1);
+        let restarted = VectorAPI::new(config);
+        assert_eq!(restarted.open_collections().unwrap(), 1);
+        assert!(restarted.collection("docs").is_err());
+    }
+
+    #[test]
+    fn test_named_vectors() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = IndexConfig {
+            kind: IndexKind::Flat,
+            data_dir: dir.path().to_path_buf(),
+            ..IndexConfig::default()
+        };
+        let api = VectorAPI::new(config.clone());
+        let image = IndexConfig {
+            distance: DistanceKind::L2,
+            ..config.clone()
+        };
+        let vectors = vec![
+            ("image".to_string(), image),
+            ("text".to_string(), config.clone()),
+        ];
+        api.create_collection_with_vectors("docs", config.clone(), vectors)
+            .unwrap();
+        assert!(api
+            .create_collection_with_vectors(
+                "bad",
+                config.clone(),
+                vec![("../x".to_string(), config)]
+            )
+            .is_err());
+
+        let named = [
+            NamedVectors {
+                name: "image".to_string(),
+                vectors: vec![vec![0., 0.], vec![10., 10.], vec![1., 1.]],
+            },
+            NamedVectors {
+                name: "text".to_string(),
+                vectors: vec![vec![1., 0., 0.], vec![0., 1., 0.], vec![0., 0., 1.]],
+            },
+        ];
+        let payloads = vec![
+            serde_json::json!({"n": 0}),
+            serde_json::json!({"n": 1}),
+            Value::Null,
+        ];
+        api.insert_named("docs", &[0, 1, 2], &named, payloads)
+            .unwrap();
+        assert!(api.insert_named("docs", &[3], &named, Vec::new()).is_err());
+
+        let search = |vector: &str, query: Vec<f32>| {
+            let options = SearchOptions {
+                payload_fields: Some(Vec::new()),
+                vector: vector.to_string(),
+                ..SearchOptions::default()
+            };
+            api.search("docs", &vec![query], 1, 10, &options)
+                .unwrap()
+                .remove(0)
+                .remove(0)
+        };
+        let (neighbour, payload) = search("image", vec![9., 9.]);
+        assert_eq!(neighbour.d_id, 1);
+        assert!((neighbour.distance - 2f32.sqrt()).abs() < 1e-5);
+        assert_eq!(payload, Some(serde_json::json!({"n": 1})));
+        assert_eq!(search("text", vec![0.9, 0.1, 0.]).0.d_id, 0);
+        let options = SearchOptions {
+            vector: "audio".to_string(),
+            ..SearchOptions::default()
+        };
+        assert!(api
+            .search("docs", &vec![vec![1., 0.]], 1, 10, &options)
+            .is_err());
+
+        // the named vectors go through a CAR file with their points
+        let car = dir.path().join("docs.car");
+        api.export_car("docs", car.to_str().unwrap()).unwrap();
+        api.import_car("copy", car.to_str().unwrap()).unwrap();
+        assert_eq!(api.delete("copy", &[1]).unwrap(), 1);
+        assert_eq!(
+            api.collection("copy").unwrap().vectors["image"]
+                .read()
+                .stats()
+                .nb_point,
+            2
+        );
+        // the exported collection keeps its points
+        assert_eq!(search("image", vec![9., 9.]).0.d_id, 1);
+
+        // a vector of a wrong dimension fails the insertion into every index
+        let named = [
+            NamedVectors {
+                name: "image".to_string(),
+                vectors: vec![vec![5., 5.]],
+            },
+            NamedVectors {
+                name: "text".to_string(),
+                vectors: vec![vec![1., 0.]],
+            },
+        ];
+        assert!(api.insert_named("docs", &[5], &named, Vec::new()).is_err());
+        let nb_image = |api: &VectorAPI| {
+            api.collection("docs").unwrap().vectors["image"]
+                .read()
+                .stats()
+                .nb_point
+        };
+        assert_eq!(nb_image(&api), 3);
+        // a point counts once when deleted, whatever vectors it had
+        api.insert_named("docs", &[5], &named[..1], Vec::new())
+            .unwrap();
+        assert_eq!(nb_image(&api), 4);
+        assert_eq!(api.delete("docs", &[5, 0, 42]).unwrap(), 2);
+        assert_eq!(nb_image(&api), 2);
+    }
+
+    #[test]
+    fn test_attach_read_only() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let config = |dir: &tempfile::TempDir| IndexConfig {
+            kind: IndexKind::Flat,
+            data_dir: dir.path().to_path_buf(),
+            ..IndexConfig::default()
+        };
+        let publisher = VectorAPI::new(config(&dirs[0]));
+        let data: Vec<Vec<f32>> = (0..20).map(|i| vec![1., i as f32]).collect();
+        let points: Vec<(&Vec<f32>, DataId)> = data.iter().zip(0..).collect();
+        publisher
+            .parallel_insert("", &points[..10].to_vec())
+            .unwrap();
+        let root = publisher.publish("").unwrap();
+
+        // the blocks are read where the publisher wrote them, once the node is configured with it
+        let shared = BlockSource::Dir(dirs[0].path().join(BLOCKS_DIR));
+        let api = VectorAPI::new(config(&dirs[1]));
+        assert!(api.attach("shared", &root, None, &shared).is_err());
+        let api = api.with_attach_sources(vec![shared.clone()]);
+        assert!(api.attach("", &root, None, &shared).is_err());
+        assert_eq!(api.attach("shared", &root, None, &shared).unwrap(), 10);
+        assert_eq!(api.collection("shared").unwrap().attached(), Some(root));
+        assert!(api
+            .parallel_insert("shared", &points[10..].to_vec())
+            .is_err());
+        assert!(api.delete("shared", &[0]).is_err());
+
+        // later writes of the publisher are not seen until attached at their root
+        publisher
+            .parallel_insert("", &points[10..].to_vec())
+            .unwrap();
+        let found = api
+            .search(
+                "shared",
+                &vec![vec![1., 19.]],
+                1,
+                10,
+                &SearchOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(found[0][0].0.d_id, 9);
+        let root = publisher.publish("").unwrap();
+        assert_eq!(api.attach("shared", &root, None, &shared).unwrap(), 20);
+        // every block of the root must be in the store
+        assert!(api
+            .attach("other", &root, None, api.block_source())
+            .is_err());
+
+        // the attachments are saved with the collections, and attached again when opened
+        assert_eq!(api.save_collections().unwrap(), 1);
+        let reopened = VectorAPI::new(config(&dirs[1])).with_attach_sources(vec![shared.clone()]);
+        assert_eq!(reopened.open_collections().unwrap(), 1);
+        assert_eq!(
+            reopened.collection("shared").unwrap().attached(),
+            Some(root)
+        );
+        assert_eq!(reopened.collection("shared").unwrap().stats().nb_point, 20);
+        assert!(reopened.delete("shared", &[0]).is_err());
+        // but not from a source the node is no longer configured with
+        let reopened = VectorAPI::new(config(&dirs[1]));
+        assert_eq!(reopened.open_collections().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_roots_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = IndexConfig {
+            kind: IndexKind::Flat,
+            data_dir: dir.path().to_path_buf(),
+            ..IndexConfig::default()
+        };
+        let api = VectorAPI::new(config.clone()).with_signing_key(SigningKey::from_bytes(&[3; 32]));
+        let v = vec![1., 0.];
+        api.parallel_insert("", &vec![(&v, 1)]).unwrap();
+        let root = api.publish("").unwrap();
+        api.checkout("copy", &root, None).unwrap();
+        let manifest = api.manifest("").unwrap();
+
+        // the roots outlive the API, whether the collections are saved or not
+        let reopened = VectorAPI::new(config.clone());
+        assert_eq!(reopened.version(""), Some((root, Some(manifest))));
+        assert_eq!(reopened.version("copy"), Some((root, None)));
+        assert_eq!(reopened.version("other"), None);
+
+        // and the versions published next follow the latest manifest
+        let reopened = VectorAPI::new(config).with_signing_key(SigningKey::from_bytes(&[3; 32]));
+        reopened.parallel_insert("", &vec![(&v, 2)]).unwrap();
+        reopened.publish("").unwrap();
+        let latest = reopened.manifest("").unwrap();
+        let latest = manifest::decode(&reopened.blocks().get_block(&latest).unwrap())
+            .unwrap()
+            .manifest;
+        assert_eq!(
+            (latest.version, latest.parent),
+            (2, Some(manifest.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_trusted_versions() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let config = |dir: &tempfile::TempDir| IndexConfig {
+            kind: IndexKind::Flat,
+            data_dir: dir.path().to_path_buf(),
+            ..IndexConfig::default()
+        };
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let publisher = VectorAPI::new(config(&dirs[0])).with_signing_key(key.clone());
+        publisher
+            .create_collection("docs", config(&dirs[0]))
+            .unwrap();
+        let v = vec![1., 0.];
+        publisher.parallel_insert("docs", &vec![(&v, 1)]).unwrap();
+        let first = publisher.publish("docs").unwrap();
+        let first_manifest = publisher.manifest("docs").unwrap();
+        publisher.parallel_insert("docs", &vec![(&v, 2)]).unwrap();
+        let second = publisher.publish("docs").unwrap();
+        let second_manifest = publisher.manifest("docs").unwrap();
+
+        let source = BlockSource::Dir(dirs[0].path().join(BLOCKS_DIR));
+        let mut trust_list = TrustList::default();
+        trust_list.add(&key.verifying_key(), "publisher");
+        let api = VectorAPI::new(config(&dirs[1]))
+            .with_attach_sources(vec![source.clone()])
+            .with_trust_list(trust_list);
+        for manifest in [first_manifest, second_manifest] {
+            let block = publisher.blocks().get_block(&manifest).unwrap();
+            api.blocks().put(block).unwrap();
+        }
+        // only a version with a trusted manifest, the one of its root and collection
+        assert!(api.attach("docs", &second, None, &source).is_err());
+        assert!(api
+            .attach("docs", &first, Some(second_manifest), &source)
+            .is_err());
+        assert!(api
+            .attach("copy", &second, Some(second_manifest), &source)
+            .is_err());
+        assert_eq!(
+            api.attach("docs", &second, Some(second_manifest), &source)
+                .unwrap(),
+            2
+        );
+        // and never an older one
+        assert!(api
+            .attach("docs", &first, Some(first_manifest), &source)
+            .is_err());
+        assert_eq!(api.version("docs"), Some((second, Some(second_manifest))));
+        assert!(publisher
+            .checkout("docs", &first, Some(first_manifest))
+            .is_err());
+        assert_eq!(
+            publisher
+                .checkout("docs", &second, Some(second_manifest))
+                .unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_signed_publish() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let api = VectorAPI::new(IndexConfig {
+            kind: IndexKind::Flat,
+            data_dir: dir.path().to_path_buf(),
+            ..IndexConfig::default()
+        })
+        .with_signing_key(key.clone());
+        let data: Vec<Vec<f32>> = (0..4).map(|i| vec![1., i as f32]).collect();
+        let points: Vec<(&Vec<f32>, DataId)> = data.iter().zip(0..).collect();
+        api.parallel_insert("", &points[..2].to_vec()).unwrap();
+        let first = api.publish("").unwrap();
+        api.parallel_insert("", &points[2..].to_vec()).unwrap();
+        let second = api.publish("").unwrap();
+
+        let mut trust_list = TrustList::default();
+        trust_list.add(&key.verifying_key(), "publisher");
+        let manifest = |cid: &Cid| {
+            trust_list
+                .verify(&api.blocks().get_block(cid).unwrap())
+                .unwrap()
+        };
+        let (root, signed) = api.version("").unwrap();
+        assert_eq!(root, second);
+        let latest = manifest(&signed.unwrap());
+        assert_eq!(
+            (latest.root.0, latest.version, latest.nb_point),
+            (second, 2, 4)
+        );
+        // each version names the manifest of the one it follows
+        let parent: Cid = latest.parent.unwrap().parse().unwrap();
+        let previous = manifest(&parent);
+        assert_eq!(
+            (previous.root.0, previous.version, previous.parent),
+            (first, 1, None)
+        );
     }
 }
@@ -5,7 +5,7 @@ use prettytable::{format, row, Table};
 use tokio::runtime::Runtime;
 
 use crate::hnsw_graph::hnsw::Neighbour;
-use crate::interfaces::api::VectorAPI;
+use crate::interfaces::api::{VectorAPI, DEFAULT_COLLECTION};
 
 pub struct CLIInterface {
     api: Arc<VectorAPI>,
@@ -93,10 +93,13 @@ impl CLIInterface {
                     .unwrap();
 
                 let insert_task = async move {
-                    self.api.parallel_insert(&vec![(&data, cid)]);
+                    self.api
+                        .parallel_insert(DEFAULT_COLLECTION, &vec![(&data, cid)])
                 };
 
-                rt.block_on(insert_task);
+                if let Err(e) = rt.block_on(insert_task) {
+                    println!("Insert failed: {}", e);
+                }
             } else if let Some(search_matches) = matches.subcommand_matches("search") {
                 let query_str = search_matches.value_of("query").unwrap();
                 let query: Vec<f32> = query_str
@@ -117,10 +120,17 @@ impl CLIInterface {
 
                 let search_task = async move {
                     //self.api.parallel_search(&query, knbn, ef)
-                    self.api.parallel_search(&vec![query], knbn, ef)
+                    self.api
+                        .parallel_search(DEFAULT_COLLECTION, &vec![query], knbn, ef)
                 };
 
-                let search_results: Vec<Vec<Neighbour>> = rt.block_on(search_task);
+                let search_results: Vec<Vec<Neighbour>> = match rt.block_on(search_task) {
+                    Ok(search_results) => search_results,
+                    Err(e) => {
+                        println!("Search failed: {}", e);
+                        continue;
+                    }
+                };
                 let flattened_search_results: Vec<Neighbour> = search_results
                     .into_iter()
                     .flat_map(|v| v.into_iter())
@@ -4,11 +4,30 @@ use rustyline::Editor;
 use tonic::transport::Channel;
 use tonic::Response;
 
+use cid::Cid;
 use colored::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::dataset::{self, IdSource, LoadOptions, VectorFormat};
+use crate::hnsw_graph::hnsw::{self, PointId};
+use crate::ipfs_storage::block::MAX_BLOCK_BYTES;
+use crate::ipfs_storage::proof::{self, PointProof, ProvenPoint};
+use crate::payload;
 
 use vector_service::{
-    vector_service_client::VectorServiceClient, FloatArray, InsertRequest, Neighbours,
-    SearchRequest,
+    admin_service_client::AdminServiceClient, block_service_client::BlockServiceClient,
+    cluster_service_client::ClusterServiceClient,
+    replication_service_client::ReplicationServiceClient,
+    vector_service_client::VectorServiceClient, AttachRequest, AttachResponse, ClusterView,
+    CollectionInfo, CreateCollectionRequest, CreatePayloadIndexRequest, DeleteRequest,
+    DropCollectionRequest, ExportRequest, ExportVectorsRequest, FloatArray, FollowRequest,
+    HybridSearchRequest, ImportRequest, InsertMultiRequest, InsertRequest, InsertSparseRequest,
+    MultiSearchRequest, MultiVector, NamedVectors, Neighbour, PublishRequest, RebalanceRequest,
+    RecallRequest, RecallResponse, ReconcileRequest, ReconcileResponse, ReplicaStatus, ScoredPoint,
+    SearchRequest, ShardMove, SparseSearchRequest, SparseVector, SyncRequest, SyncResponse,
+    UnfollowRequest, VectorConfig,
 };
 
 use crate::interfaces::cli_grpc::vector_service::SearchResult;
@@ -19,6 +38,12 @@ pub mod vector_service {
 
 pub struct GrpcCli {
     client: VectorServiceClient<Channel>,
+    admin_client: AdminServiceClient<Channel>,
+    replication_client: ReplicationServiceClient<Channel>,
+    cluster_client: ClusterServiceClient<Channel>,
+    block_client: BlockServiceClient<Channel>,
+    /// collection of the commands, empty for the default one
+    collection: String,
 }
 
 impl GrpcCli {
@@ -27,20 +52,73 @@ impl GrpcCli {
             .connect()
             .await?;
 
-        let client = VectorServiceClient::new(channel);
+        let client = VectorServiceClient::new(channel.clone());
+        let admin_client = AdminServiceClient::new(channel.clone());
+        let replication_client = ReplicationServiceClient::new(channel.clone());
+        let cluster_client = ClusterServiceClient::new(channel.clone());
+        let block_client = BlockServiceClient::new(channel);
 
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            admin_client,
+            replication_client,
+            cluster_client,
+            block_client,
+            collection: String::new(),
+        })
     }
 
+    /// inserts a point with its vectors and an optional JSON payload, the vector of an
+    /// empty name being the default one
     pub async fn insert(
         &mut self,
         key: usize,
-        vector: Vec<f32>,
+        vectors: Vec<(String, Vec<f32>)>,
+        payload: Option<String>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let float_array = FloatArray { values: vector };
+        let mut data = Vec::new();
+        let mut named_vectors = Vec::new();
+        for (name, vector) in vectors {
+            let float_array = FloatArray { values: vector };
+            if name.is_empty() {
+                data.push(float_array);
+            } else {
+                named_vectors.push(NamedVectors {
+                    name,
+                    data: vec![float_array],
+                });
+            }
+        }
         let request = tonic::Request::new(InsertRequest {
             ids: vec![key as u32],
-            data: vec![float_array],
+            data,
+            collection: self.collection.clone(),
+            payloads: payload.into_iter().collect(),
+            named_vectors,
+        });
+
+        let _response = self.client.insert(request).await?;
+
+        Ok(())
+    }
+
+    /// inserts a batch of (id, vector) in one request
+    pub async fn insert_batch(
+        &mut self,
+        batch: Vec<(usize, Vec<f32>)>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut ids = Vec::with_capacity(batch.len());
+        let mut data = Vec::with_capacity(batch.len());
+        for (id, vector) in batch {
+            ids.push(u32::try_from(id).map_err(|_| format!("id {} does not fit in 32 bits", id))?);
+            data.push(FloatArray { values: vector });
+        }
+        let request = tonic::Request::new(InsertRequest {
+            ids,
+            data,
+            collection: self.collection.clone(),
+            payloads: Vec::new(),
+            named_vectors: Vec::new(),
         });
 
         let _response = self.client.insert(request).await?;
@@ -48,23 +126,536 @@ impl GrpcCli {
         Ok(())
     }
 
+    /// streams the vectors of a local file to the server in batches, printing progress.
+    /// Returns the number of vectors inserted.
+    pub async fn load(
+        &mut self,
+        path: &Path,
+        options: &LoadOptions,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let start = std::time::Instant::now();
+        let mut nb_loaded = 0;
+        for batch in dataset::read_batches(path, options)? {
+            let batch = batch?;
+            nb_loaded += batch.len();
+            self.insert_batch(batch).await?;
+            let progress = dataset::LoadProgress {
+                nb_loaded,
+                elapsed: start.elapsed(),
+            };
+            println!(
+                "loaded {} vectors ({:.0} vectors/s)",
+                progress.nb_loaded,
+                progress.rate()
+            );
+        }
+
+        Ok(nb_loaded)
+    }
+
+    /// searches the neighbours of query among the vectors of vector_name, empty for the
+    /// default vector, and among the points matching filter, a JSON filter expression or
+    /// empty, with their payloads reduced to payload_fields when payload_fields is given
     pub async fn search(
         &mut self,
         query: Vec<f32>,
+        vector_name: &str,
+        knbn: usize,
+        ef: usize,
+        payload_fields: Option<Vec<String>>,
+        filter: &str,
+    ) -> Result<SearchResult, Box<dyn std::error::Error>> {
+        let request = self.search_request(query, vector_name, knbn, ef, payload_fields, filter);
+        let response: Response<SearchResult> = self.client.search(request).await?;
+
+        Ok(response.into_inner())
+    }
+
+    /// the request of search
+    fn search_request(
+        &self,
+        query: Vec<f32>,
+        vector_name: &str,
+        knbn: usize,
+        ef: usize,
+        payload_fields: Option<Vec<String>>,
+        filter: &str,
+    ) -> SearchRequest {
+        SearchRequest {
+            data: vec![FloatArray { values: query }],
+            knbn: knbn as u32,
+            ef: ef as u32,
+            collection: self.collection.clone(),
+            with_payload: payload_fields.is_some(),
+            payload_fields: payload_fields.unwrap_or_default(),
+            filter: filter.to_string(),
+            vector_name: vector_name.to_string(),
+            with_proof: false,
+        }
+    }
+
+    /// sends request asking for proofs and checks that the points found, and their payloads,
+    /// belong to the collection persisted under root. Returns them as proven, by query.
+    pub async fn verified_search(
+        &mut self,
+        mut request: SearchRequest,
+        root: &Cid,
+    ) -> Result<(SearchResult, Vec<Vec<ProvenPoint>>), Box<dyn std::error::Error>> {
+        request.with_proof = true;
+        // the proofs of a search carry chunks of vectors
+        let result = self
+            .client
+            .clone()
+            .max_decoding_message_size(max_proven_result_bytes(&request))
+            .search(request.clone())
+            .await?
+            .into_inner();
+        let proven = verify_search(root, &request, &result)?;
+
+        Ok((result, proven))
+    }
+
+    /// searches query and the keywords of text in the text indexed payload field, and fuses
+    /// the two rankings. The points are returned with their payloads.
+    pub async fn hybrid_search(
+        &mut self,
+        query: Vec<f32>,
+        text: String,
+        text_field: &str,
         knbn: usize,
         ef: usize,
-    ) -> Result<Vec<Neighbours>, Box<dyn std::error::Error>> {
-        let float_array = FloatArray { values: query };
-        let request = tonic::Request::new(SearchRequest {
-            data: vec![float_array],
+        fusion: &str,
+    ) -> Result<Vec<ScoredPoint>, Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(HybridSearchRequest {
+            data: vec![FloatArray { values: query }],
+            texts: vec![text],
+            text_field: text_field.to_string(),
             knbn: knbn as u32,
             ef: ef as u32,
+            collection: self.collection.clone(),
+            with_payload: true,
+            payload_fields: Vec::new(),
+            filter: String::new(),
+            fusion: fusion.to_string(),
+            dense_weight: 0.,
+            text_weight: 0.,
+            vector_name: String::new(),
         });
 
-        let response: Response<SearchResult> = self.client.search(request).await?;
-        let neighbours = response.into_inner().neighbours;
+        let response = self.client.hybrid_search(request).await?.into_inner();
+
+        Ok(response.results.into_iter().flat_map(|r| r.hits).collect())
+    }
+
+    /// inserts a sparse vector with an optional JSON payload
+    pub async fn insert_sparse(
+        &mut self,
+        key: usize,
+        vector: SparseVector,
+        payload: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(InsertSparseRequest {
+            data: vec![vector],
+            ids: vec![key as u32],
+            collection: self.collection.clone(),
+            payloads: payload.into_iter().collect(),
+        });
+
+        let _response = self.client.insert_sparse(request).await?;
+
+        Ok(())
+    }
+
+    /// searches the points of greatest dot product with a sparse query among the points
+    /// matching filter, a JSON filter expression or empty
+    pub async fn sparse_search(
+        &mut self,
+        query: SparseVector,
+        knbn: usize,
+        with_payload: bool,
+        filter: &str,
+    ) -> Result<Vec<ScoredPoint>, Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(SparseSearchRequest {
+            data: vec![query],
+            knbn: knbn as u32,
+            collection: self.collection.clone(),
+            with_payload,
+            payload_fields: Vec::new(),
+            filter: filter.to_string(),
+        });
+
+        let response = self.client.sparse_search(request).await?.into_inner();
+
+        Ok(response.results.into_iter().flat_map(|r| r.hits).collect())
+    }
+
+    /// inserts a document of several vectors in the vector of vector_name, empty for the
+    /// default vector, with an optional JSON payload
+    pub async fn insert_multi(
+        &mut self,
+        key: usize,
+        vectors: Vec<Vec<f32>>,
+        vector_name: &str,
+        payload: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(InsertMultiRequest {
+            data: vec![to_pb_multi(vectors)],
+            ids: vec![key as u32],
+            collection: self.collection.clone(),
+            payloads: payload.into_iter().collect(),
+            vector_name: vector_name.to_string(),
+        });
+
+        let _response = self.client.insert_multi(request).await?;
+
+        Ok(())
+    }
+
+    /// searches the documents of greatest MaxSim score with a query of several vectors
+    /// among the documents matching filter, a JSON filter expression or empty
+    pub async fn multi_search(
+        &mut self,
+        query: Vec<Vec<f32>>,
+        vector_name: &str,
+        knbn: usize,
+        ef: usize,
+        with_payload: bool,
+        filter: &str,
+    ) -> Result<Vec<ScoredPoint>, Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(MultiSearchRequest {
+            data: vec![to_pb_multi(query)],
+            knbn: knbn as u32,
+            ef: ef as u32,
+            collection: self.collection.clone(),
+            with_payload,
+            payload_fields: Vec::new(),
+            filter: filter.to_string(),
+            vector_name: vector_name.to_string(),
+        });
+
+        let response = self.client.multi_search(request).await?.into_inner();
+
+        Ok(response.results.into_iter().flat_map(|r| r.hits).collect())
+    }
+
+    /// asks the server to write its index to a CAR file, returns the root CID and the number of blocks
+    pub async fn export_car(
+        &mut self,
+        path: &str,
+    ) -> Result<(String, u64), Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(ExportRequest {
+            path: path.to_string(),
+            collection: self.collection.clone(),
+        });
+
+        let response = self.admin_client.export_car(request).await?.into_inner();
+
+        Ok((response.root_cid, response.nb_block))
+    }
+
+    /// asks the server to replace its index by a CAR file, returns the root CID and the number of points
+    pub async fn import_car(
+        &mut self,
+        path: &str,
+    ) -> Result<(String, u64), Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(ImportRequest {
+            path: path.to_string(),
+            collection: self.collection.clone(),
+        });
+
+        let response = self.admin_client.import_car(request).await?.into_inner();
+
+        Ok((response.root_cid, response.nb_point))
+    }
+
+    /// asks the server to serve the collection persisted under a root read-only, its blocks
+    /// being read from source or the block source of the server when empty. The root may be
+    /// given by a signed manifest, required by servers having a trust list.
+    pub async fn attach(
+        &mut self,
+        collection: &str,
+        root_cid: &str,
+        manifest_cid: &str,
+        source: &str,
+    ) -> Result<AttachResponse, Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(AttachRequest {
+            collection: collection.to_string(),
+            root_cid: root_cid.to_string(),
+            source: source.to_string(),
+            manifest_cid: manifest_cid.to_string(),
+        });
+
+        let response = self.admin_client.attach(request).await?.into_inner();
+
+        Ok(response)
+    }
+
+    /// asks the server to persist the collection to its block store, returns the root CID
+    /// and the CID of the signed manifest, empty when the server has no signing key
+    pub async fn publish(&mut self) -> Result<(String, String), Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(PublishRequest {
+            collection: self.collection.clone(),
+        });
+
+        let response = self.block_client.publish(request).await?.into_inner();
+
+        Ok((response.root_cid, response.manifest_cid))
+    }
+
+    /// asks the server to fetch the blocks of a root it misses from peers and to replace the
+    /// collection by the one of the root, the root of the signed manifest, or the root
+    /// published by the first peer when both are empty
+    pub async fn sync(
+        &mut self,
+        root_cid: &str,
+        manifest_cid: &str,
+        peers: Vec<String>,
+    ) -> Result<SyncResponse, Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(SyncRequest {
+            collection: self.collection.clone(),
+            root_cid: root_cid.to_string(),
+            peers,
+            manifest_cid: manifest_cid.to_string(),
+        });
+
+        let response = self.block_client.sync(request).await?.into_inner();
+
+        Ok(response)
+    }
+
+    /// asks the server to write its vectors to a file, all of them or those of one layer.
+    /// Returns the number of vectors written.
+    pub async fn export_vectors(
+        &mut self,
+        path: &str,
+        format: &str,
+        layer: Option<u32>,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(ExportVectorsRequest {
+            path: path.to_string(),
+            format: format.to_string(),
+            by_layer: layer.is_some(),
+            layer: layer.unwrap_or(0),
+            collection: self.collection.clone(),
+        });
+
+        let response = self
+            .admin_client
+            .export_vectors(request)
+            .await?
+            .into_inner();
+
+        Ok(response.nb_vector)
+    }
+
+    /// asks the server to estimate its recall@knbn at ef on nb_sample of its own vectors
+    pub async fn estimate_recall(
+        &mut self,
+        nb_sample: usize,
+        knbn: usize,
+        ef: usize,
+    ) -> Result<RecallResponse, Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(RecallRequest {
+            nb_sample: nb_sample as u32,
+            queries: Vec::new(),
+            knbn: knbn as u32,
+            ef: ef as u32,
+            collection: self.collection.clone(),
+        });
+
+        let response = self
+            .admin_client
+            .estimate_recall(request)
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    /// deletes points of the current collection, returns the number of ids found
+    pub async fn delete(&mut self, ids: Vec<u32>) -> Result<u64, Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(DeleteRequest {
+            ids,
+            collection: self.collection.clone(),
+        });
+
+        let response = self.client.delete(request).await?.into_inner();
 
-        Ok(neighbours)
+        Ok(response.nb_deleted)
+    }
+
+    pub async fn create_collection(
+        &mut self,
+        request: CreateCollectionRequest,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.admin_client
+            .create_collection(tonic::Request::new(request))
+            .await?;
+
+        Ok(())
+    }
+
+    /// indexes a payload field of the current collection
+    pub async fn create_payload_index(
+        &mut self,
+        field: &str,
+        index_type: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(CreatePayloadIndexRequest {
+            collection: self.collection.clone(),
+            field: field.to_string(),
+            index_type: index_type.to_string(),
+        });
+
+        self.admin_client.create_payload_index(request).await?;
+
+        Ok(())
+    }
+
+    pub async fn drop_collection(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(DropCollectionRequest {
+            name: name.to_string(),
+        });
+
+        self.admin_client.drop_collection(request).await?;
+
+        Ok(())
+    }
+
+    pub async fn list_collections(
+        &mut self,
+    ) -> Result<Vec<CollectionInfo>, Box<dyn std::error::Error>> {
+        let response = self
+            .admin_client
+            .list_collections(tonic::Request::new(()))
+            .await?
+            .into_inner();
+
+        Ok(response.collections)
+    }
+
+    /// makes the collection a read-only copy of the collection of the same name on leader
+    pub async fn follow(&mut self, leader: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(FollowRequest {
+            collection: self.collection.clone(),
+            leader: leader.to_string(),
+        });
+
+        self.replication_client.follow(request).await?;
+
+        Ok(())
+    }
+
+    /// asks the server to merge the collection with the one of the same name on each of peers,
+    /// the last write of each point winning
+    pub async fn merge(
+        &mut self,
+        peers: Vec<String>,
+    ) -> Result<ReconcileResponse, Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(ReconcileRequest {
+            collection: self.collection.clone(),
+            peers,
+        });
+
+        let response = self
+            .replication_client
+            .reconcile(request)
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn unfollow(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(UnfollowRequest {
+            collection: self.collection.clone(),
+        });
+
+        self.replication_client.unfollow(request).await?;
+
+        Ok(())
+    }
+
+    /// moves the shards of a sharded collection of the server to their owners among the
+    /// live nodes. Returns the moves made.
+    pub async fn rebalance(
+        &mut self,
+        collection: &str,
+    ) -> Result<Vec<ShardMove>, Box<dyn std::error::Error>> {
+        let request = tonic::Request::new(RebalanceRequest {
+            collection: collection.to_string(),
+        });
+
+        let response = self.admin_client.rebalance(request).await?.into_inner();
+
+        Ok(response.moves)
+    }
+
+    /// the nodes of the cluster as the server knows them, and the last membership events
+    pub async fn list_nodes(&mut self) -> Result<ClusterView, Box<dyn std::error::Error>> {
+        let response = self
+            .cluster_client
+            .list_nodes(tonic::Request::new(()))
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    /// where the collections following a leader stand
+    pub async fn list_replicas(
+        &mut self,
+    ) -> Result<Vec<ReplicaStatus>, Box<dyn std::error::Error>> {
+        let response = self
+            .replication_client
+            .list_replicas(tonic::Request::new(()))
+            .await?
+            .into_inner();
+
+        Ok(response.replicas)
+    }
+
+    /// searches the neighbours of every vector of a local query file and writes them
+    /// to a local .ivecs or .jsonl file. Returns the number of queries.
+    pub async fn batch_search(
+        &mut self,
+        queries: &Path,
+        output: &Path,
+        knbn: usize,
+        ef: usize,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let options = LoadOptions {
+            batch_size: 100,
+            ..LoadOptions::default()
+        };
+        let mut results: Vec<Vec<hnsw::Neighbour>> = Vec::new();
+        for batch in dataset::read_batches(queries, &options)? {
+            let float_arrays = batch?
+                .into_iter()
+                .map(|(_, vector)| FloatArray { values: vector })
+                .collect();
+            let request = tonic::Request::new(SearchRequest {
+                data: float_arrays,
+                knbn: knbn as u32,
+                ef: ef as u32,
+                collection: self.collection.clone(),
+                with_payload: false,
+                payload_fields: Vec::new(),
+                filter: String::new(),
+                vector_name: String::new(),
+                with_proof: false,
+            });
+            let response = self.client.search(request).await?.into_inner();
+            results.extend(
+                response
+                    .neighbours
+                    .into_iter()
+                    .map(|n| n.neighbour.into_iter().map(from_pb_neighbour).collect()),
+            );
+        }
+        dataset::export::write_search_results(output, &results)?;
+
+        Ok(results.len())
     }
 
     pub async fn start(&mut self) {
@@ -97,8 +688,17 @@ impl GrpcCli {
                                     Arg::with_name("vector")
                                         .short('v')
                                         .long("vector")
+                                        .help("Comma separated values, prefixed by name= for a named vector")
                                         .takes_value(true)
+                                        .multiple_occurrences(true)
                                         .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("payload")
+                                        .short('p')
+                                        .long("payload")
+                                        .help("JSON payload of the vector")
+                                        .takes_value(true),
                                 ),
                         )
                         .subcommand(
@@ -111,6 +711,13 @@ impl GrpcCli {
                                         .takes_value(true)
                                         .required(true),
                                 )
+                                .arg(
+                                    Arg::with_name("vector_name")
+                                        .short('n')
+                                        .long("vector_name")
+                                        .help("Named vector searched, the default vector when absent")
+                                        .takes_value(true),
+                                )
                                 .arg(
                                     Arg::with_name("k")
                                         .short('k')
@@ -124,65 +731,1173 @@ impl GrpcCli {
                                         .long("ef")
                                         .takes_value(true)
                                         .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("with_payload")
+                                        .long("with_payload")
+                                        .help("Print the payloads of the neighbours"),
+                                )
+                                .arg(
+                                    Arg::with_name("fields")
+                                        .long("fields")
+                                        .help("Comma separated payload fields to print, dotted for nested fields")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("filter")
+                                        .short('f')
+                                        .long("filter")
+                                        .help("JSON filter on payloads, without spaces")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("proof")
+                                        .long("proof")
+                                        .help("Root CID the neighbours are proven to belong to, the collection being attached at it")
+                                        .takes_value(true),
                                 ),
                         )
-                        .subcommand(SubCommand::with_name("exit").about("Exit the application"))
-                        .setting(clap::AppSettings::NoBinaryName)
-                        .try_get_matches_from(line.split_whitespace());
-
-                    match matches {
-                        Ok(matches) => {
-                            if let Some(matches) = matches.subcommand_matches("insert") {
-                                let key =
-                                    matches.value_of("key").unwrap().parse::<usize>().unwrap();
-                                let vector_str = matches.value_of("vector").unwrap();
-                                let vector: Vec<f32> = vector_str
-                                    .split(',')
-                                    .map(|s| s.parse::<f32>().unwrap())
-                                    .collect();
-
-                                match self.insert(key, vector).await {
-                                    Ok(_) => {
-                                        println!("{}", "Vector inserted successfully.".green())
-                                    }
-                                    Err(err) => println!("Error inserting vector: {:?}", err),
-                                }
-                            } else if let Some(matches) = matches.subcommand_matches("search") {
-                                let vector_str = matches.value_of("vector").unwrap();
-                                let vector: Vec<f32> = vector_str
-                                    .split(',')
-                                    .map(|s| s.parse::<f32>().unwrap())
-                                    .collect();
-                                let k = matches.value_of("k").unwrap().parse::<usize>().unwrap();
-                                let ef = matches.value_of("ef").unwrap().parse::<usize>().unwrap();
-
-                                match self.search(vector, k, ef).await {
-                                    Ok(neighbours) => {
-                                        println!("{}", "Neighbours found:".green());
-                                        for neighbour in
-                                            neighbours.into_iter().flat_map(|n| n.neighbour)
-                                        {
-                                            println!(
-                                                "ID: {}, Distance: {}",
-                                                format!("{}", neighbour.d_id).blue(),
-                                                format!("{:.2}", neighbour.distance).blue()
-                                            );
-                                        }
-                                    }
-                                    Err(err) => {
-                                        println!("Error searching for neighbours: {:?}", err)
-                                    }
-                                }
-                            } else if matches.subcommand_matches("exit").is_some() {
-                                println!("{}", "Exiting...".red());
-                                break;
-                            } else {
-                                println!("Invalid command. Please enter a valid command.");
-                            }
-                        }
-                        Err(err) => {
-                            eprintln!("Error: {}", err);
-                            eprintln!("Type 'help' for more information.");
+                        .subcommand(
+                            SubCommand::with_name("insert_sparse")
+                                .about("Insert a sparse vector in a sparse collection")
+                                .arg(
+                                    Arg::with_name("key")
+                                        .short('k')
+                                        .long("key")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("vector")
+                                        .short('s')
+                                        .long("sparse")
+                                        .help("Comma separated index:value pairs")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("payload")
+                                        .short('p')
+                                        .long("payload")
+                                        .help("JSON payload of the vector, without spaces")
+                                        .takes_value(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("sparse_search")
+                                .about("Search the points of greatest dot product with a sparse vector")
+                                .arg(
+                                    Arg::with_name("vector")
+                                        .short('s')
+                                        .long("sparse")
+                                        .help("Comma separated index:value pairs")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("k")
+                                        .short('k')
+                                        .long("knbn")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("with_payload")
+                                        .long("with_payload")
+                                        .help("Print the payloads of the points found"),
+                                )
+                                .arg(
+                                    Arg::with_name("filter")
+                                        .short('f')
+                                        .long("filter")
+                                        .help("JSON filter on payloads, without spaces")
+                                        .takes_value(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("insert_multi")
+                                .about("Insert a document of several vectors in a multivector index")
+                                .arg(
+                                    Arg::with_name("key")
+                                        .short('k')
+                                        .long("key")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("vector")
+                                        .short('v')
+                                        .long("vector")
+                                        .help("Comma separated values of a vector of the document, once per vector")
+                                        .takes_value(true)
+                                        .multiple_occurrences(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("vector_name")
+                                        .short('n')
+                                        .long("vector_name")
+                                        .help("Named vector of the document, the default vector when absent")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("payload")
+                                        .short('p')
+                                        .long("payload")
+                                        .help("JSON payload of the document")
+                                        .takes_value(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("multi_search")
+                                .about("Search the documents of greatest MaxSim score with a query of several vectors")
+                                .arg(
+                                    Arg::with_name("vector")
+                                        .short('v')
+                                        .long("vector")
+                                        .help("Comma separated values of a vector of the query, once per vector")
+                                        .takes_value(true)
+                                        .multiple_occurrences(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("vector_name")
+                                        .short('n')
+                                        .long("vector_name")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("k")
+                                        .short('k')
+                                        .long("knbn")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("ef")
+                                        .short('e')
+                                        .long("ef")
+                                        .help("Number of tokens found for each query vector")
+                                        .takes_value(true)
+                                        .default_value("64"),
+                                )
+                                .arg(
+                                    Arg::with_name("with_payload")
+                                        .long("with_payload")
+                                        .help("Print the payloads of the documents found"),
+                                )
+                                .arg(
+                                    Arg::with_name("filter")
+                                        .short('f')
+                                        .long("filter")
+                                        .help("JSON filter on payloads, without spaces")
+                                        .takes_value(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("hybrid")
+                                .about("Search for neighbours and keywords, fusing both rankings")
+                                .arg(
+                                    Arg::with_name("vector")
+                                        .short('v')
+                                        .long("vector")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("text")
+                                        .short('t')
+                                        .long("text")
+                                        .help("Keywords searched in the text field")
+                                        .takes_value(true)
+                                        .multiple_values(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("field")
+                                        .long("field")
+                                        .help("Payload field with a text index")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("k")
+                                        .short('k')
+                                        .long("knbn")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("ef")
+                                        .short('e')
+                                        .long("ef")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("fusion")
+                                        .long("fusion")
+                                        .help("rrf or weighted")
+                                        .takes_value(true)
+                                        .default_value("rrf"),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("load")
+                                .about("Insert the vectors of a local fvecs, ivecs, bvecs, npy, jsonl or csv file")
+                                .arg(
+                                    Arg::with_name("file")
+                                        .short('f')
+                                        .long("file")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("format")
+                                        .long("format")
+                                        .help("Format of the file, guessed from its extension by default")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("id_column")
+                                        .long("id-column")
+                                        .help("Field or CSV column holding the ids")
+                                        .takes_value(true)
+                                        .conflicts_with("ids"),
+                                )
+                                .arg(
+                                    Arg::with_name("ids")
+                                        .long("ids")
+                                        .help("File with one id per line, in the order of the vectors")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("first_id")
+                                        .long("first-id")
+                                        .help("Id of the first vector when ids are row numbers")
+                                        .takes_value(true)
+                                        .default_value("0"),
+                                )
+                                .arg(
+                                    Arg::with_name("batch")
+                                        .short('b')
+                                        .long("batch")
+                                        .takes_value(true)
+                                        .default_value("1000"),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("export")
+                                .about("Export the index to a CAR file on the server")
+                                .arg(
+                                    Arg::with_name("file")
+                                        .short('f')
+                                        .long("file")
+                                        .takes_value(true)
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("import")
+                                .about("Replace the index by a CAR file on the server")
+                                .arg(
+                                    Arg::with_name("file")
+                                        .short('f')
+                                        .long("file")
+                                        .takes_value(true)
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("attach")
+                                .about("Serve a collection persisted under a root CID read-only")
+                                .arg(Arg::with_name("collection").index(1).required(true))
+                                .arg(
+                                    Arg::with_name("root")
+                                        .index(2)
+                                        .required_unless_present("manifest"),
+                                )
+                                .arg(
+                                    Arg::with_name("manifest")
+                                        .long("manifest")
+                                        .help("CID of the signed manifest of the version, read before its root")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("source")
+                                        .long("source")
+                                        .help("dir:<path>, ipfs:<url> or peers:<url>,<url>, the block source of the server when absent")
+                                        .takes_value(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("publish")
+                                .about("Persist the collection to the block store of the server, where peers can sync it"),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("sync")
+                                .about("Fetch the blocks of a root the server misses from peers and load its collection")
+                                .arg(
+                                    Arg::with_name("peers")
+                                        .long("peers")
+                                        .help("Comma separated gRPC addresses of the peers, as http://host:port")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("root")
+                                        .long("root")
+                                        .help("Root CID, the one the first peer published the collection at when absent")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("manifest")
+                                        .long("manifest")
+                                        .help("CID of the signed manifest of the version, read before its root")
+                                        .takes_value(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("export_vectors")
+                                .about("Write the vectors of the index to a npy, fvecs or jsonl file on the server")
+                                .arg(
+                                    Arg::with_name("file")
+                                        .short('f')
+                                        .long("file")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("format")
+                                        .long("format")
+                                        .takes_value(true)
+                                        .default_value("npy"),
+                                )
+                                .arg(
+                                    Arg::with_name("layer")
+                                        .short('l')
+                                        .long("layer")
+                                        .help("Only export the points of this layer")
+                                        .takes_value(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("batch_search")
+                                .about("Search the neighbours of every vector of a local file")
+                                .arg(
+                                    Arg::with_name("file")
+                                        .short('f')
+                                        .long("file")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("output")
+                                        .short('o')
+                                        .long("output")
+                                        .help("Local .ivecs or .jsonl file receiving the results")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("k")
+                                        .short('k')
+                                        .long("knbn")
+                                        .takes_value(true)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("ef")
+                                        .short('e')
+                                        .long("ef")
+                                        .takes_value(true)
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("recall")
+                                .about("Estimate the recall of the index on a sample of its vectors")
+                                .arg(
+                                    Arg::with_name("nb_sample")
+                                        .short('n')
+                                        .long("nb_sample")
+                                        .takes_value(true)
+                                        .default_value("100"),
+                                )
+                                .arg(
+                                    Arg::with_name("k")
+                                        .short('k')
+                                        .long("knbn")
+                                        .takes_value(true)
+                                        .default_value("10"),
+                                )
+                                .arg(
+                                    Arg::with_name("ef")
+                                        .short('e')
+                                        .long("ef")
+                                        .takes_value(true)
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("delete")
+                                .about("Delete vectors by id")
+                                .arg(
+                                    Arg::with_name("keys")
+                                        .short('k')
+                                        .long("keys")
+                                        .help("Comma separated ids")
+                                        .takes_value(true)
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("use")
+                                .about("Send the next commands to a collection, empty for the default one")
+                                .arg(Arg::with_name("name").index(1)),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("create_collection")
+                                .about("Create a collection")
+                                .arg(Arg::with_name("name").index(1).required(true))
+                                .arg(
+                                    Arg::with_name("type")
+                                        .short('t')
+                                        .long("type")
+                                        .help("hnsw, flat for exact search, ivf, disk, sparse for sparse vectors, or multivector for documents of several vectors")
+                                        .takes_value(true)
+                                        .default_value("hnsw"),
+                                )
+                                .arg(
+                                    Arg::with_name("nlist")
+                                        .long("nlist")
+                                        .help("Number of posting lists of an ivf index, 0 for the server default")
+                                        .takes_value(true)
+                                        .default_value("0"),
+                                )
+                                .arg(
+                                    Arg::with_name("nprobe")
+                                        .long("nprobe")
+                                        .help("Number of posting lists scanned by ivf searches, 0 for the server default")
+                                        .takes_value(true)
+                                        .default_value("0"),
+                                )
+                                .arg(
+                                    Arg::with_name("quantization")
+                                        .long("quantization")
+                                        .help("Compression of ivf posting lists: none, sq8 or pq<m>")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("distance")
+                                        .long("distance")
+                                        .help("cosine, dot or l2, the server default when absent")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("vectors")
+                                        .long("vectors")
+                                        .help("Comma separated named vectors as name[:type[:distance]]")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("shards")
+                                        .long("shards")
+                                        .help("Comma separated gRPC addresses of the nodes of the shards, as http://host:port")
+                                        .takes_value(true),
+                                )
+                                .arg(
+                                    Arg::with_name("shard_count")
+                                        .long("shard_count")
+                                        .help("Number of shards placed on the nodes, 0 for one per node")
+                                        .takes_value(true)
+                                        .default_value("0"),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("create_payload_index")
+                                .about("Index a payload field of the collection to speed up filtered searches")
+                                .arg(Arg::with_name("field").index(1).required(true))
+                                .arg(
+                                    Arg::with_name("type")
+                                        .short('t')
+                                        .long("type")
+                                        .help("keyword, numeric, bool, timestamp, geo or text")
+                                        .takes_value(true)
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("drop_collection")
+                                .about("Drop a collection and its vectors")
+                                .arg(Arg::with_name("name").index(1).required(true)),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("collections").about("List the collections"),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("follow")
+                                .about("Make the collection a read-only copy of the one of a leader")
+                                .arg(
+                                    Arg::with_name("leader")
+                                        .index(1)
+                                        .help("gRPC endpoint of the leader, as http://host:port")
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("unfollow")
+                                .about("Stop following the leader, the collection accepts writes again"),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("replication")
+                                .about("Show the collections following a leader and their lag"),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("merge")
+                                .about("Merge the collection with the one written on peers, the last write of each point winning")
+                                .arg(
+                                    Arg::with_name("peers")
+                                        .long("peers")
+                                        .help("Comma separated gRPC addresses of the peers, as http://host:port")
+                                        .takes_value(true)
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("rebalance")
+                                .about("Move the shards of a sharded collection to their owners among the live nodes")
+                                .arg(Arg::with_name("collection").index(1).required(true)),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("list")
+                                .about("List the members of the cluster")
+                                .subcommand(
+                                    SubCommand::with_name("nodes")
+                                        .about("List the nodes of the cluster, their collections and shards"),
+                                ),
+                        )
+                        .subcommand(SubCommand::with_name("exit").about("Exit the application"))
+                        .setting(clap::AppSettings::NoBinaryName)
+                        .try_get_matches_from(line.split_whitespace());
+
+                    match matches {
+                        Ok(matches) => {
+                            if let Some(matches) = matches.subcommand_matches("insert") {
+                                let key =
+                                    matches.value_of("key").unwrap().parse::<usize>().unwrap();
+                                let vectors: Vec<(String, Vec<f32>)> = matches
+                                    .values_of("vector")
+                                    .unwrap()
+                                    .map(|vector_str| {
+                                        let (name, values) =
+                                            vector_str.split_once('=').unwrap_or(("", vector_str));
+                                        let vector = values
+                                            .split(',')
+                                            .map(|s| s.parse::<f32>().unwrap())
+                                            .collect();
+                                        (name.to_string(), vector)
+                                    })
+                                    .collect();
+                                let payload = matches.value_of("payload").map(String::from);
+
+                                match self.insert(key, vectors, payload).await {
+                                    Ok(_) => {
+                                        println!("{}", "Vector inserted successfully.".green())
+                                    }
+                                    Err(err) => println!("Error inserting vector: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("search") {
+                                let vector_str = matches.value_of("vector").unwrap();
+                                let vector: Vec<f32> = vector_str
+                                    .split(',')
+                                    .map(|s| s.parse::<f32>().unwrap())
+                                    .collect();
+                                let k = matches.value_of("k").unwrap().parse::<usize>().unwrap();
+                                let ef = matches.value_of("ef").unwrap().parse::<usize>().unwrap();
+                                let payload_fields = match matches.value_of("fields") {
+                                    Some(fields) => {
+                                        Some(fields.split(',').map(String::from).collect())
+                                    }
+                                    None if matches.is_present("with_payload") => Some(Vec::new()),
+                                    None => None,
+                                };
+
+                                let filter = matches.value_of("filter").unwrap_or("");
+                                let vector_name = matches.value_of("vector_name").unwrap_or("");
+
+                                let searched = match matches.value_of("proof") {
+                                    Some(root) => match Cid::try_from(root) {
+                                        Ok(root) => {
+                                            let request = self.search_request(
+                                                vector,
+                                                vector_name,
+                                                k,
+                                                ef,
+                                                payload_fields,
+                                                filter,
+                                            );
+                                            self.verified_search(request, &root).await.map(
+                                                |(result, _)| {
+                                                    println!(
+                                                        "{} {}",
+                                                        "Neighbours proven to belong to".green(),
+                                                        root
+                                                    );
+                                                    result
+                                                },
+                                            )
+                                        }
+                                        Err(err) => {
+                                            Err(format!("invalid root {}: {}", root, err).into())
+                                        }
+                                    },
+                                    None => {
+                                        self.search(
+                                            vector,
+                                            vector_name,
+                                            k,
+                                            ef,
+                                            payload_fields,
+                                            filter,
+                                        )
+                                        .await
+                                    }
+                                };
+                                match searched {
+                                    Ok(result) => {
+                                        println!("{}", "Neighbours found:".green());
+                                        if result.partial {
+                                            println!(
+                                                "{} {:?}",
+                                                "Partial results, shards not answering:".yellow(),
+                                                result.missing_shards
+                                            );
+                                        }
+                                        for neighbour in
+                                            result.neighbours.into_iter().flat_map(|n| n.neighbour)
+                                        {
+                                            println!(
+                                                "ID: {}, Distance: {}",
+                                                format!("{}", neighbour.d_id).blue(),
+                                                format!("{:.2}", neighbour.distance).blue()
+                                            );
+                                            if !neighbour.payload.is_empty() {
+                                                println!("  Payload: {}", neighbour.payload);
+                                            }
+                                        }
+                                    }
+                                    Err(err) => {
+                                        println!("Error searching for neighbours: {:?}", err)
+                                    }
+                                }
+                            } else if let Some(matches) =
+                                matches.subcommand_matches("insert_sparse")
+                            {
+                                let key =
+                                    matches.value_of("key").unwrap().parse::<usize>().unwrap();
+                                let payload = matches.value_of("payload").map(String::from);
+                                match parse_sparse(matches.value_of("vector").unwrap()) {
+                                    Ok(vector) => match self
+                                        .insert_sparse(key, vector, payload)
+                                        .await
+                                    {
+                                        Ok(_) => {
+                                            println!("{}", "Vector inserted successfully.".green())
+                                        }
+                                        Err(err) => println!("Error inserting vector: {:?}", err),
+                                    },
+                                    Err(err) => println!("Invalid sparse vector: {}", err),
+                                }
+                            } else if let Some(matches) =
+                                matches.subcommand_matches("sparse_search")
+                            {
+                                let k = matches.value_of("k").unwrap().parse::<usize>().unwrap();
+                                let with_payload = matches.is_present("with_payload");
+                                let filter = matches.value_of("filter").unwrap_or("");
+                                let vector = match parse_sparse(matches.value_of("vector").unwrap())
+                                {
+                                    Ok(vector) => vector,
+                                    Err(err) => {
+                                        println!("Invalid sparse vector: {}", err);
+                                        continue;
+                                    }
+                                };
+                                match self.sparse_search(vector, k, with_payload, filter).await {
+                                    Ok(hits) => {
+                                        println!("{}", "Points found:".green());
+                                        for hit in hits {
+                                            println!(
+                                                "ID: {}, Score: {}",
+                                                format!("{}", hit.d_id).blue(),
+                                                format!("{:.4}", hit.score).blue()
+                                            );
+                                            if !hit.payload.is_empty() {
+                                                println!("  Payload: {}", hit.payload);
+                                            }
+                                        }
+                                    }
+                                    Err(err) => println!("Error in sparse search: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("insert_multi")
+                            {
+                                let key =
+                                    matches.value_of("key").unwrap().parse::<usize>().unwrap();
+                                let vectors = parse_vectors(matches.values_of("vector").unwrap());
+                                let vector_name = matches.value_of("vector_name").unwrap_or("");
+                                let payload = matches.value_of("payload").map(String::from);
+                                match self.insert_multi(key, vectors, vector_name, payload).await {
+                                    Ok(_) => {
+                                        println!("{}", "Document inserted successfully.".green())
+                                    }
+                                    Err(err) => println!("Error inserting document: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("multi_search")
+                            {
+                                let query = parse_vectors(matches.values_of("vector").unwrap());
+                                let vector_name = matches.value_of("vector_name").unwrap_or("");
+                                let k = matches.value_of("k").unwrap().parse::<usize>().unwrap();
+                                let ef = matches.value_of("ef").unwrap().parse::<usize>().unwrap();
+                                let with_payload = matches.is_present("with_payload");
+                                let filter = matches.value_of("filter").unwrap_or("");
+                                match self
+                                    .multi_search(query, vector_name, k, ef, with_payload, filter)
+                                    .await
+                                {
+                                    Ok(hits) => {
+                                        println!("{}", "Documents found:".green());
+                                        for hit in hits {
+                                            println!(
+                                                "ID: {}, Score: {}",
+                                                format!("{}", hit.d_id).blue(),
+                                                format!("{:.4}", hit.score).blue()
+                                            );
+                                            if !hit.payload.is_empty() {
+                                                println!("  Payload: {}", hit.payload);
+                                            }
+                                        }
+                                    }
+                                    Err(err) => println!("Error in multivector search: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("hybrid") {
+                                let vector: Vec<f32> = matches
+                                    .value_of("vector")
+                                    .unwrap()
+                                    .split(',')
+                                    .map(|s| s.parse::<f32>().unwrap())
+                                    .collect();
+                                let text = matches
+                                    .values_of("text")
+                                    .unwrap()
+                                    .collect::<Vec<_>>()
+                                    .join(" ");
+                                let field = matches.value_of("field").unwrap();
+                                let k = matches.value_of("k").unwrap().parse::<usize>().unwrap();
+                                let ef = matches.value_of("ef").unwrap().parse::<usize>().unwrap();
+                                let fusion = matches.value_of("fusion").unwrap();
+
+                                match self.hybrid_search(vector, text, field, k, ef, fusion).await {
+                                    Ok(hits) => {
+                                        println!("{}", "Points found:".green());
+                                        for hit in hits {
+                                            println!(
+                                                "ID: {}, Score: {}",
+                                                format!("{}", hit.d_id).blue(),
+                                                format!("{:.4}", hit.score).blue()
+                                            );
+                                            if !hit.payload.is_empty() {
+                                                println!("  Payload: {}", hit.payload);
+                                            }
+                                        }
+                                    }
+                                    Err(err) => println!("Error in hybrid search: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("load") {
+                                match load_options(matches) {
+                                    Ok(options) => {
+                                        let file = Path::new(matches.value_of("file").unwrap());
+                                        match self.load(file, &options).await {
+                                            Ok(nb_loaded) => println!(
+                                                "{} {} vectors inserted.",
+                                                "Load finished.".green(),
+                                                nb_loaded
+                                            ),
+                                            Err(err) => {
+                                                println!("Error loading vectors: {:?}", err)
+                                            }
+                                        }
+                                    }
+                                    Err(err) => println!("Error: {}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("export") {
+                                let file = matches.value_of("file").unwrap();
+
+                                match self.export_car(file).await {
+                                    Ok((root_cid, nb_block)) => println!(
+                                        "{} root: {}, blocks: {}",
+                                        "Index exported.".green(),
+                                        root_cid.blue(),
+                                        nb_block
+                                    ),
+                                    Err(err) => println!("Error exporting index: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("import") {
+                                let file = matches.value_of("file").unwrap();
+
+                                match self.import_car(file).await {
+                                    Ok((root_cid, nb_point)) => println!(
+                                        "{} root: {}, points: {}",
+                                        "Index imported.".green(),
+                                        root_cid.blue(),
+                                        nb_point
+                                    ),
+                                    Err(err) => println!("Error importing index: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("attach") {
+                                let collection = matches.value_of("collection").unwrap();
+                                let root = matches.value_of("root").unwrap_or("");
+                                let manifest = matches.value_of("manifest").unwrap_or("");
+                                let source = matches.value_of("source").unwrap_or("");
+
+                                match self.attach(collection, root, manifest, source).await {
+                                    Ok(response) => println!(
+                                        "{} root: {}, points: {}, blocks fetched: {}",
+                                        "Collection attached.".green(),
+                                        response.root_cid.blue(),
+                                        response.nb_point,
+                                        response.nb_fetched
+                                    ),
+                                    Err(err) => {
+                                        println!("Error attaching {}: {:?}", collection, err)
+                                    }
+                                }
+                            } else if matches.subcommand_matches("publish").is_some() {
+                                match self.publish().await {
+                                    Ok((root_cid, manifest_cid)) if manifest_cid.is_empty() => {
+                                        println!(
+                                            "{} root: {}",
+                                            "Collection published.".green(),
+                                            root_cid.blue()
+                                        )
+                                    }
+                                    Ok((root_cid, manifest_cid)) => println!(
+                                        "{} root: {}, manifest: {}",
+                                        "Collection published.".green(),
+                                        root_cid.blue(),
+                                        manifest_cid.blue()
+                                    ),
+                                    Err(err) => println!("Error publishing collection: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("sync") {
+                                let peers = matches
+                                    .value_of("peers")
+                                    .unwrap()
+                                    .split(',')
+                                    .map(String::from)
+                                    .collect();
+                                let root = matches.value_of("root").unwrap_or("");
+                                let manifest = matches.value_of("manifest").unwrap_or("");
+
+                                match self.sync(root, manifest, peers).await {
+                                    Ok(response) => println!(
+                                        "{} root: {}, blocks: {}, fetched: {} ({} bytes), points: {}",
+                                        "Collection synced.".green(),
+                                        response.root_cid.blue(),
+                                        response.nb_block,
+                                        response.nb_fetched,
+                                        response.nb_byte,
+                                        response.nb_point
+                                    ),
+                                    Err(err) => println!("Error syncing collection: {:?}", err),
+                                }
+                            } else if let Some(matches) =
+                                matches.subcommand_matches("export_vectors")
+                            {
+                                let file = matches.value_of("file").unwrap();
+                                let format = matches.value_of("format").unwrap();
+                                let layer =
+                                    matches.value_of("layer").map(|l| l.parse::<u32>().unwrap());
+
+                                match self.export_vectors(file, format, layer).await {
+                                    Ok(nb_vector) => println!(
+                                        "{} {} vectors written.",
+                                        "Vectors exported.".green(),
+                                        nb_vector
+                                    ),
+                                    Err(err) => println!("Error exporting vectors: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("batch_search")
+                            {
+                                let file = Path::new(matches.value_of("file").unwrap());
+                                let output = Path::new(matches.value_of("output").unwrap());
+                                let k = matches.value_of("k").unwrap().parse::<usize>().unwrap();
+                                let ef = matches.value_of("ef").unwrap().parse::<usize>().unwrap();
+
+                                match self.batch_search(file, output, k, ef).await {
+                                    Ok(nb_query) => println!(
+                                        "{} results of {} queries written.",
+                                        "Search finished.".green(),
+                                        nb_query
+                                    ),
+                                    Err(err) => println!("Error searching: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("recall") {
+                                let nb_sample = matches
+                                    .value_of("nb_sample")
+                                    .unwrap()
+                                    .parse::<usize>()
+                                    .unwrap();
+                                let k = matches.value_of("k").unwrap().parse::<usize>().unwrap();
+                                let ef = matches.value_of("ef").unwrap().parse::<usize>().unwrap();
+
+                                match self.estimate_recall(nb_sample, k, ef).await {
+                                    Ok(estimate) => {
+                                        println!(
+                                            "{} recall@{}: {}, queries: {}",
+                                            "Recall estimated.".green(),
+                                            k,
+                                            format!("{:.4}", estimate.recall).blue(),
+                                            estimate.nb_query
+                                        );
+                                        for (rank, nb_missed) in
+                                            estimate.missed_by_rank.iter().enumerate()
+                                        {
+                                            println!("rank {}: missed {} times", rank, nb_missed);
+                                        }
+                                    }
+                                    Err(err) => println!("Error estimating recall: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("delete") {
+                                let ids: Vec<u32> = matches
+                                    .value_of("keys")
+                                    .unwrap()
+                                    .split(',')
+                                    .map(|s| s.parse::<u32>().unwrap())
+                                    .collect();
+
+                                match self.delete(ids).await {
+                                    Ok(nb_deleted) => println!(
+                                        "{} {} vectors deleted.",
+                                        "Delete finished.".green(),
+                                        nb_deleted
+                                    ),
+                                    Err(err) => println!("Error deleting vectors: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("use") {
+                                self.collection =
+                                    matches.value_of("name").unwrap_or("").to_string();
+                                let name = if self.collection.is_empty() {
+                                    "default"
+                                } else {
+                                    self.collection.as_str()
+                                };
+                                println!("Using collection {}.", name.blue());
+                            } else if let Some(matches) =
+                                matches.subcommand_matches("create_collection")
+                            {
+                                let vectors = matches
+                                    .value_of("vectors")
+                                    .map(parse_vector_configs)
+                                    .unwrap_or_default();
+                                let request = CreateCollectionRequest {
+                                    name: matches.value_of("name").unwrap().to_string(),
+                                    index_type: matches.value_of("type").unwrap().to_string(),
+                                    nlist: matches
+                                        .value_of("nlist")
+                                        .unwrap()
+                                        .parse::<u32>()
+                                        .unwrap(),
+                                    nprobe: matches
+                                        .value_of("nprobe")
+                                        .unwrap()
+                                        .parse::<u32>()
+                                        .unwrap(),
+                                    quantization: matches
+                                        .value_of("quantization")
+                                        .unwrap_or("")
+                                        .to_string(),
+                                    distance: matches
+                                        .value_of("distance")
+                                        .unwrap_or("")
+                                        .to_string(),
+                                    vectors,
+                                    shard_addresses: matches
+                                        .value_of("shards")
+                                        .map(|shards| shards.split(',').map(String::from).collect())
+                                        .unwrap_or_default(),
+                                    shard_count: matches
+                                        .value_of("shard_count")
+                                        .unwrap()
+                                        .parse::<u32>()
+                                        .unwrap(),
+                                };
+
+                                match self.create_collection(request).await {
+                                    Ok(()) => println!("{}", "Collection created.".green()),
+                                    Err(err) => println!("Error creating collection: {:?}", err),
+                                }
+                            } else if let Some(matches) =
+                                matches.subcommand_matches("create_payload_index")
+                            {
+                                let field = matches.value_of("field").unwrap();
+                                let index_type = matches.value_of("type").unwrap();
+
+                                match self.create_payload_index(field, index_type).await {
+                                    Ok(()) => println!("{}", "Payload index created.".green()),
+                                    Err(err) => println!("Error creating payload index: {:?}", err),
+                                }
+                            } else if let Some(matches) =
+                                matches.subcommand_matches("drop_collection")
+                            {
+                                let name = matches.value_of("name").unwrap();
+
+                                match self.drop_collection(name).await {
+                                    Ok(()) => println!("{}", "Collection dropped.".green()),
+                                    Err(err) => println!("Error dropping collection: {:?}", err),
+                                }
+                            } else if matches.subcommand_matches("collections").is_some() {
+                                match self.list_collections().await {
+                                    Ok(collections) => {
+                                        for info in collections {
+                                            let attached = if info.attached_root.is_empty() {
+                                                String::new()
+                                            } else {
+                                                format!(", attached at {}", info.attached_root)
+                                            };
+                                            println!(
+                                                "{} type: {}, dimension: {}, points: {}, deleted: {}{}",
+                                                info.name.blue(),
+                                                info.index_type,
+                                                info.dimension,
+                                                info.nb_point,
+                                                info.nb_deleted,
+                                                attached
+                                            );
+                                        }
+                                    }
+                                    Err(err) => println!("Error listing collections: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("follow") {
+                                let leader = matches.value_of("leader").unwrap();
+
+                                match self.follow(leader).await {
+                                    Ok(()) => {
+                                        println!("{}", format!("Following {}.", leader).green())
+                                    }
+                                    Err(err) => println!("Error following {}: {:?}", leader, err),
+                                }
+                            } else if matches.subcommand_matches("unfollow").is_some() {
+                                match self.unfollow().await {
+                                    Ok(()) => println!("{}", "Leader unfollowed.".green()),
+                                    Err(err) => println!("Error unfollowing: {:?}", err),
+                                }
+                            } else if matches.subcommand_matches("replication").is_some() {
+                                match self.list_replicas().await {
+                                    Ok(replicas) if replicas.is_empty() => {
+                                        println!("No collection follows a leader.")
+                                    }
+                                    Ok(replicas) => {
+                                        for replica in replicas {
+                                            let lag = format!("lag: {}", replica.lag);
+                                            println!(
+                                                "{} leader: {}, {}, applied: {}, leader seq: {}, {}",
+                                                replica.collection.blue(),
+                                                replica.leader,
+                                                replica.phase,
+                                                replica.applied_seq,
+                                                replica.leader_seq,
+                                                if replica.lag == 0 { lag.green() } else { lag.yellow() }
+                                            );
+                                        }
+                                    }
+                                    Err(err) => println!("Error listing replicas: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("merge") {
+                                let peers = matches
+                                    .value_of("peers")
+                                    .unwrap()
+                                    .split(',')
+                                    .map(String::from)
+                                    .collect();
+
+                                match self.merge(peers).await {
+                                    Ok(response) => println!(
+                                        "{} sent: {}, received: {}, applied: {}",
+                                        "Collection merged.".green(),
+                                        response.nb_sent,
+                                        response.nb_received,
+                                        response.nb_applied
+                                    ),
+                                    Err(err) => println!("Error merging collection: {:?}", err),
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("rebalance") {
+                                let collection = matches.value_of("collection").unwrap();
+
+                                match self.rebalance(collection).await {
+                                    Ok(moves) if moves.is_empty() => {
+                                        println!("{}", "The shards are on their nodes.".green())
+                                    }
+                                    Ok(moves) => {
+                                        for shard_move in moves {
+                                            println!(
+                                                "shard {} moved from {} to {}",
+                                                shard_move.shard, shard_move.from, shard_move.to
+                                            );
+                                        }
+                                    }
+                                    Err(err) => {
+                                        println!("Error rebalancing {}: {:?}", collection, err)
+                                    }
+                                }
+                            } else if let Some(matches) = matches.subcommand_matches("list") {
+                                if matches.subcommand_matches("nodes").is_none() {
+                                    println!("Usage: list nodes");
+                                    continue;
+                                }
+                                match self.list_nodes().await {
+                                    Ok(view) => {
+                                        for node in view.nodes {
+                                            let status = match node.status.as_str() {
+                                                "alive" => node.status.green(),
+                                                "suspect" => node.status.yellow(),
+                                                _ => node.status.red(),
+                                            };
+                                            let local = if node.address == view.address {
+                                                " (this node)"
+                                            } else {
+                                                ""
+                                            };
+                                            println!(
+                                                "{}{} {}, last seen {} ms ago, collections: {}",
+                                                node.address.blue(),
+                                                local,
+                                                status,
+                                                node.last_seen_ms,
+                                                node.collections.join(",")
+                                            );
+                                            for shard in node.shards {
+                                                println!(
+                                                    "    coordinates shard {} of {} on {}",
+                                                    shard.id, shard.collection, shard.address
+                                                );
+                                            }
+                                        }
+                                        for event in view.events {
+                                            println!("{}", event.dimmed());
+                                        }
+                                    }
+                                    Err(err) => println!("Error listing nodes: {:?}", err),
+                                }
+                            } else if matches.subcommand_matches("exit").is_some() {
+                                println!("{}", "Exiting...".red());
+                                break;
+                            } else {
+                                println!("Invalid command. Please enter a valid command.");
+                            }
+                        }
+                        Err(err) => {
+                            eprintln!("Error: {}", err);
+                            eprintln!("Type 'help' for more information.");
                         }
                     }
                 }
@@ -203,3 +1918,246 @@ impl GrpcCli {
         rl.save_history("history.txt").unwrap();
     }
 }
+
+fn load_options(matches: &clap::ArgMatches) -> Result<LoadOptions, String> {
+    let format = match matches.value_of("format") {
+        Some(format) => Some(format.parse::<VectorFormat>()?),
+        None => None,
+    };
+    let ids = if let Some(column) = matches.value_of("id_column") {
+        IdSource::Column(column.to_string())
+    } else if let Some(ids) = matches.value_of("ids") {
+        IdSource::Sidecar(PathBuf::from(ids))
+    } else {
+        let first_id = matches.value_of("first_id").unwrap();
+        IdSource::RowNumber(
+            first_id
+                .parse::<usize>()
+                .map_err(|e| format!("invalid first id {}: {}", first_id, e))?,
+        )
+    };
+    let batch = matches.value_of("batch").unwrap();
+    let batch_size = batch
+        .parse::<usize>()
+        .map_err(|e| format!("invalid batch size {}: {}", batch, e))?;
+
+    Ok(LoadOptions {
+        format,
+        ids,
+        batch_size,
+        ..LoadOptions::default()
+    })
+}
+
+/// parses a sparse vector written as comma separated index:value pairs, as 3:0.5,17:1.2
+fn parse_sparse(s: &str) -> Result<SparseVector, String> {
+    let mut vector = SparseVector::default();
+    for pair in s.split(',') {
+        let (index, value) = pair
+            .split_once(':')
+            .ok_or_else(|| format!("{} is not an index:value pair", pair))?;
+        vector.indices.push(
+            index
+                .parse()
+                .map_err(|e| format!("index {}: {}", index, e))?,
+        );
+        vector.values.push(
+            value
+                .parse()
+                .map_err(|e| format!("value {}: {}", value, e))?,
+        );
+    }
+    Ok(vector)
+}
+
+/// parses vectors written as comma separated values
+fn parse_vectors<'a>(values: impl Iterator<Item = &'a str>) -> Vec<Vec<f32>> {
+    values
+        .map(|vector_str| {
+            vector_str
+                .split(',')
+                .map(|s| s.parse::<f32>().unwrap())
+                .collect()
+        })
+        .collect()
+}
+
+fn to_pb_multi(vectors: Vec<Vec<f32>>) -> MultiVector {
+    MultiVector {
+        vectors: vectors
+            .into_iter()
+            .map(|values| FloatArray { values })
+            .collect(),
+    }
+}
+
+/// parses named vectors written as comma separated name[:type[:distance]], as
+/// image:hnsw:l2,text, the missing parts being the defaults of the collection
+fn parse_vector_configs(s: &str) -> Vec<VectorConfig> {
+    s.split(',')
+        .map(|vector| {
+            let mut parts = vector.splitn(3, ':');
+            VectorConfig {
+                name: parts.next().unwrap_or_default().to_string(),
+                index_type: parts.next().unwrap_or_default().to_string(),
+                distance: parts.next().unwrap_or_default().to_string(),
+            }
+        })
+        .collect()
+}
+
+/// the largest result of request asking for proofs: each point found brings at most a chunk
+/// of vectors and a chunk of payloads besides the blocks shared by all the paths, and the
+/// neighbours themselves fit in the default message size
+fn max_proven_result_bytes(request: &SearchRequest) -> usize {
+    let nb_found = request.data.len().saturating_mul(request.knbn as usize);
+    MAX_BLOCK_BYTES
+        .saturating_mul(nb_found.saturating_mul(2).saturating_add(3))
+        .saturating_add(4 << 20)
+}
+
+/// checks the proofs of the result of a search of request against root, see proof::verify.
+/// The distances returned must be those of the query to the persisted vectors, and the
+/// payloads returned those persisted, reduced to the fields asked for.
+pub fn verify_search(
+    root: &Cid,
+    request: &SearchRequest,
+    result: &SearchResult,
+) -> Result<Vec<Vec<ProvenPoint>>, Box<dyn std::error::Error>> {
+    if result.root_cid != root.to_string() {
+        return Err(format!("proofs lead from {:?}, not from {}", result.root_cid, root).into());
+    }
+    let mut blocks = HashMap::new();
+    for block in &result.proof_blocks {
+        blocks.insert(Cid::try_from(block.cid.as_slice())?, block.data.clone());
+    }
+    let parse_path = |path: &[Vec<u8>]| -> Result<Vec<Cid>, cid::Error> {
+        path.iter()
+            .map(|cid| Cid::try_from(cid.as_slice()))
+            .collect()
+    };
+    if result.neighbours.len() > request.data.len() {
+        return Err("more results than queries".into());
+    }
+    let mut proven = Vec::new();
+    for (neighbours, query) in result.neighbours.iter().zip(&request.data) {
+        let mut points = Vec::new();
+        for neighbour in &neighbours.neighbour {
+            let pb_proof = neighbour
+                .proof
+                .as_ref()
+                .ok_or_else(|| format!("point {} has no proof", neighbour.d_id))?;
+            let proof = PointProof {
+                vector_path: parse_path(&pb_proof.vector_path)?,
+                payload_path: parse_path(&pb_proof.payload_path)?,
+            };
+            let point = proof::verify(
+                root,
+                neighbour.d_id as usize,
+                &request.vector_name,
+                &proof,
+                &blocks,
+            )?;
+            let distance = point.distance.eval(&query.values, &point.vector);
+            if (distance - neighbour.distance).abs() > 1e-4 * distance.abs().max(1.) {
+                return Err(format!(
+                    "distance {} of point {} is not {}, its distance to the query",
+                    neighbour.distance, neighbour.d_id, distance
+                )
+                .into());
+            }
+            if !neighbour.payload.is_empty() {
+                let returned: Value = serde_json::from_str(&neighbour.payload)?;
+                let persisted = point
+                    .payload
+                    .as_ref()
+                    .map(|payload| payload::project(payload, &request.payload_fields));
+                if persisted.as_ref() != Some(&returned) {
+                    return Err(format!(
+                        "payload of point {} is not the one persisted",
+                        neighbour.d_id
+                    )
+                    .into());
+                }
+            }
+            points.push(point);
+        }
+        proven.push(points);
+    }
+
+    Ok(proven)
+}
+
+fn from_pb_neighbour(neighbour: Neighbour) -> hnsw::Neighbour {
+    let p_id = neighbour
+        .point_id
+        .map(|p| PointId(p.layer as u8, p.index))
+        .unwrap_or_default();
+    hnsw::Neighbour::new(neighbour.d_id as usize, neighbour.distance, p_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use crate::cluster::coordinator::Coordinator;
+    use crate::cluster::membership::{GossipConfig, Membership};
+    use crate::cluster::replication::Replication;
+    use crate::hnsw_graph::hnsw::DataId;
+    use crate::index::{IndexConfig, IndexKind};
+    use crate::interfaces::api::VectorAPI;
+    use crate::interfaces::grpc::{local_listener, serve_grpc};
+
+    #[tokio::test]
+    async fn test_verified_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let api = VectorAPI::new(IndexConfig {
+            kind: IndexKind::Flat,
+            data_dir: dir.path().to_path_buf(),
+            ..IndexConfig::default()
+        });
+        let data: Vec<Vec<f32>> = (0..50).map(|i| vec![1., i as f32]).collect();
+        let points: Vec<(&Vec<f32>, DataId)> = data.iter().zip(0..).collect();
+        let payloads: Vec<Value> = (0..50).map(|i| json!({ "rank": i })).collect();
+        api.insert_with_payloads("", &points, payloads).unwrap();
+        let root = api.publish("").unwrap();
+        api.attach("attached", &root, None, api.block_source())
+            .unwrap();
+
+        let (listener, address) = local_listener().await;
+        let membership = Arc::new(Membership::new(
+            &address,
+            Vec::new(),
+            GossipConfig::default(),
+        ));
+        tokio::spawn(async move {
+            serve_grpc(
+                Arc::new(api),
+                Arc::new(Coordinator::default()),
+                Arc::new(Replication::default()),
+                membership,
+                listener,
+            )
+            .await
+            .unwrap()
+        });
+        let mut cli = GrpcCli::new(&address).await.unwrap();
+        let mut request = cli.search_request(vec![1., 7.], "", 3, 16, Some(Vec::new()), "");
+        request.collection = "attached".to_string();
+        let (result, proven) = cli.verified_search(request.clone(), &root).await.unwrap();
+        assert_eq!(proven[0].len(), 3);
+        assert_eq!(proven[0][0].vector, data[7]);
+
+        // a point proven with a distance it is not at is refused
+        let mut tampered = result.clone();
+        tampered.neighbours[0].neighbour[0].distance += 0.5;
+        assert!(verify_search(&root, &request, &tampered).is_err());
+        let mut other = request.clone();
+        other.data[0].values = vec![1., 30.];
+        assert!(verify_search(&root, &other, &result).is_err());
+    }
+}
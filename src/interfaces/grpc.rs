@@ -1,15 +1,59 @@
+use std::error::Error;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
+use cid::Cid;
+use serde_json::Value;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{transport::Server, Request, Response, Status};
 
 use vector_service::{
+    admin_service_server::{AdminService, AdminServiceServer},
+    block_service_server::{BlockService, BlockServiceServer},
+    cluster_service_server::{ClusterService, ClusterServiceServer},
+    replication_service_server::{ReplicationService, ReplicationServiceServer},
     vector_service_server::{VectorService, VectorServiceServer},
-    InsertRequest, Neighbour as PbNeighbour, Neighbours, PointId, SearchRequest, SearchResult,
+    AttachRequest, AttachResponse, BlockBatch, BlockData, ClusterView, CollectionInfo,
+    CollectionList, CreateCollectionRequest, CreatePayloadIndexRequest, DeleteRequest,
+    DeleteResponse, DropCollectionRequest, ExportRequest, ExportResponse, ExportVectorsRequest,
+    ExportVectorsResponse, FollowRequest, FreezeRequest, GossipMessage, HybridSearchRequest,
+    HybridSearchResult, ImportRequest, ImportResponse, InsertMultiRequest, InsertRequest,
+    InsertSparseRequest, LogEntry, MergeRequest, MergeResponse, MultiSearchRequest,
+    MultiSearchResult, MultiVector, NamedVectors as PbNamedVectors, Neighbour as PbNeighbour,
+    Neighbours, PointId, PointProof as PbPointProof, PublishRequest, PublishResponse,
+    RebalanceRequest, RebalanceResponse, RecallRequest, RecallResponse, ReconcileRequest,
+    ReconcileResponse, ReplicaList, ReplicaStatus, ReplicateRequest, ResolveRequest,
+    ResolveResponse, ScoredPoint as PbScoredPoint, ScoredPoints, SearchRequest, SearchResult,
+    ShardMove as PbShardMove, SnapshotRequest, SnapshotResponse, SparseSearchRequest,
+    SparseSearchResult, SparseVector as PbSparseVector, SyncRequest, SyncResponse, UnfollowRequest,
+    WantList,
 };
 
+use crate::cluster::coordinator::Coordinator;
+use crate::cluster::crdt::{self, Change, MERGE_BATCH};
+use crate::cluster::exchange;
+use crate::cluster::membership::{self, Membership};
+use crate::cluster::replication::{self, Replication};
+use crate::dataset::VectorFormat;
 use crate::hnsw_graph::hnsw::Neighbour;
-use crate::interfaces::api::VectorAPI;
+use crate::index::fusion::Fusion;
+use crate::index::quantizer::Quantization;
+use crate::index::sparse::SparseVector;
+use crate::index::{DistanceKind, IndexKind};
+use crate::interfaces::api::{
+    default_name, HybridQuery, NamedVectors, ScoredPoint, SearchOptions, VectorAPI,
+    DEFAULT_COLLECTION,
+};
+use crate::ipfs_storage::block::{Block, BlockStore};
+use crate::ipfs_storage::fs::FsBlockStore;
+use crate::ipfs_storage::ipfs;
+use crate::ipfs_storage::manifest;
+use crate::ipfs_storage::proof::PointProof;
+use crate::ipfs_storage::source::BlockSource;
+use crate::payload::filter::Filter;
+use crate::payload::index::PayloadIndexType;
 
 // Import the generated Rust code
 pub mod vector_service {
@@ -18,11 +62,27 @@ pub mod vector_service {
 
 pub struct GRPCServer {
     api: Arc<VectorAPI>,
+    /// routes the requests on sharded collections to their shards
+    coordinator: Arc<Coordinator>,
+    /// the collections following a leader, which refuse writes
+    replication: Arc<Replication>,
+    /// the nodes of the cluster known to this node
+    membership: Arc<Membership>,
 }
 
 impl GRPCServer {
-    pub fn new(api: Arc<VectorAPI>) -> Self {
-        GRPCServer { api }
+    pub fn new(
+        api: Arc<VectorAPI>,
+        coordinator: Arc<Coordinator>,
+        replication: Arc<Replication>,
+        membership: Arc<Membership>,
+    ) -> Self {
+        GRPCServer {
+            api,
+            coordinator,
+            replication,
+            membership,
+        }
     }
 }
 
@@ -30,6 +90,17 @@ impl GRPCServer {
 impl VectorService for GRPCServer {
     async fn insert(&self, request: Request<InsertRequest>) -> Result<Response<()>, Status> {
         let request_data = request.into_inner();
+        self.api
+            .check_writable(&request_data.collection)
+            .map_err(failed_precondition)?;
+        if self.coordinator.shards(&request_data.collection).is_some() {
+            self.coordinator.insert(request_data).await?;
+            return Ok(Response::new(()));
+        }
+        if !request_data.named_vectors.is_empty() {
+            self.insert_named(request_data).map_err(invalid_argument)?;
+            return Ok(Response::new(()));
+        }
         let data: Vec<(Vec<f32>, usize)> = request_data
             .data
             .into_iter()
@@ -37,44 +108,111 @@ impl VectorService for GRPCServer {
             .zip(request_data.ids)
             .map(|(data, id)| (data, id as usize))
             .collect();
+        let payloads = parse_payloads(&request_data.payloads).map_err(invalid_argument)?;
 
-        self.api.parallel_insert(
-            &data
-                .iter()
-                .map(|(vec, idx)| (vec as &Vec<f32>, *idx))
-                .collect::<Vec<_>>(),
-        );
+        self.api
+            .insert_with_payloads(
+                &request_data.collection,
+                &data
+                    .iter()
+                    .map(|(vec, idx)| (vec as &Vec<f32>, *idx))
+                    .collect::<Vec<_>>(),
+                payloads,
+            )
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
 
         Ok(Response::new(()))
     }
 
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let request_data = request.into_inner();
+        self.api
+            .check_writable(&request_data.collection)
+            .map_err(failed_precondition)?;
+        if self.coordinator.shards(&request_data.collection).is_some() {
+            return Ok(Response::new(self.coordinator.delete(request_data).await?));
+        }
+        let ids: Vec<usize> = request_data.ids.iter().map(|&id| id as usize).collect();
+        let nb_deleted = self
+            .api
+            .delete(&request_data.collection, &ids)
+            .map_err(|e| Status::not_found(e.to_string()))?;
+
+        Ok(Response::new(DeleteResponse {
+            nb_deleted: nb_deleted as u64,
+        }))
+    }
+
     async fn search(
         &self,
         request: Request<SearchRequest>,
     ) -> Result<Response<SearchResult>, Status> {
         let request_data = request.into_inner();
+        if let Some(shards) = self.coordinator.shards(&request_data.collection) {
+            if request_data.with_proof {
+                return Err(Status::invalid_argument(
+                    "the points of sharded collections are not proven",
+                ));
+            }
+            return Ok(Response::new(
+                self.coordinator.search(request_data, &shards).await?,
+            ));
+        }
         let data: Vec<Vec<f32>> = request_data
             .data
             .into_iter()
             .map(|float_array| float_array.values)
             .collect();
 
-        let results: Vec<Vec<Neighbour>> =
-            self.api
-                .parallel_search(&data, request_data.knbn as usize, request_data.ef as usize);
+        let options = SearchOptions {
+            filter: parse_filter(&request_data.filter).map_err(invalid_argument)?,
+            payload_fields: request_data
+                .with_payload
+                .then_some(request_data.payload_fields),
+            vector: request_data.vector_name,
+        };
+
+        let results: Vec<Vec<(Neighbour, Option<Value>)>> = self
+            .api
+            .search(
+                &request_data.collection,
+                &data,
+                request_data.knbn as usize,
+                request_data.ef as usize,
+                &options,
+            )
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        let proofs = if request_data.with_proof {
+            let proofs = self
+                .api
+                .prove(&request_data.collection, &options.vector, &results)
+                .map_err(failed_precondition)?;
+            Some(proofs)
+        } else {
+            None
+        };
 
         let neighbours_message: Vec<Neighbours> = results
             .into_iter()
-            .map(|neighbours| {
+            .enumerate()
+            .map(|(query, neighbours)| {
                 let neighbour_message: Vec<PbNeighbour> = neighbours
                     .into_iter()
-                    .map(|neighbour| PbNeighbour {
+                    .enumerate()
+                    .map(|(rank, (neighbour, payload))| PbNeighbour {
                         d_id: neighbour.d_id as u32,
                         distance: neighbour.distance,
                         point_id: Some(PointId {
                             layer: neighbour.p_id.0 as u32,
                             index: neighbour.p_id.1,
                         }),
+                        payload: payload.map(|p| p.to_string()).unwrap_or_default(),
+                        proof: proofs
+                            .as_ref()
+                            .map(|proofs| to_pb_proof(&proofs.proofs[query][rank])),
                     })
                     .collect();
                 Neighbours {
@@ -83,19 +221,976 @@ impl VectorService for GRPCServer {
             })
             .collect();
 
+        let (root_cid, proof_blocks) = match proofs {
+            Some(proofs) => (
+                proofs.root.to_string(),
+                proofs
+                    .blocks
+                    .into_iter()
+                    .map(|(cid, data)| BlockData {
+                        cid: cid.to_bytes(),
+                        data,
+                    })
+                    .collect(),
+            ),
+            None => (String::new(), Vec::new()),
+        };
         Ok(Response::new(SearchResult {
             neighbours: neighbours_message,
+            root_cid,
+            proof_blocks,
+            ..SearchResult::default()
+        }))
+    }
+
+    async fn hybrid_search(
+        &self,
+        request: Request<HybridSearchRequest>,
+    ) -> Result<Response<HybridSearchResult>, Status> {
+        let request_data = request.into_inner();
+        let data: Vec<Vec<f32>> = request_data
+            .data
+            .into_iter()
+            .map(|float_array| float_array.values)
+            .collect();
+        let fusion = match request_data.fusion.as_str() {
+            "" => Fusion::default(),
+            fusion => fusion.parse::<Fusion>().map_err(Status::invalid_argument)?,
+        };
+        let (dense_weight, text_weight) =
+            match (request_data.dense_weight, request_data.text_weight) {
+                (w1, w2) if w1 == 0. && w2 == 0. => (1., 1.),
+                weights => weights,
+            };
+        let query = HybridQuery {
+            text_field: request_data.text_field,
+            texts: request_data.texts,
+            fusion,
+            dense_weight,
+            text_weight,
+        };
+        let options = SearchOptions {
+            filter: parse_filter(&request_data.filter).map_err(invalid_argument)?,
+            payload_fields: request_data
+                .with_payload
+                .then_some(request_data.payload_fields),
+            vector: request_data.vector_name,
+        };
+
+        let results = self
+            .api
+            .hybrid_search(
+                &request_data.collection,
+                &data,
+                request_data.knbn as usize,
+                request_data.ef as usize,
+                &query,
+                &options,
+            )
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(HybridSearchResult {
+            results: to_pb_scored_points(results),
+        }))
+    }
+
+    async fn insert_sparse(
+        &self,
+        request: Request<InsertSparseRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request_data = request.into_inner();
+        self.api
+            .check_writable(&request_data.collection)
+            .map_err(failed_precondition)?;
+        let data: Vec<(SparseVector, usize)> = request_data
+            .data
+            .into_iter()
+            .map(from_pb_sparse)
+            .zip(request_data.ids.into_iter().map(|id| id as usize))
+            .collect();
+        let payloads = parse_payloads(&request_data.payloads).map_err(invalid_argument)?;
+
+        self.api
+            .insert_sparse(&request_data.collection, data, payloads)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn sparse_search(
+        &self,
+        request: Request<SparseSearchRequest>,
+    ) -> Result<Response<SparseSearchResult>, Status> {
+        let request_data = request.into_inner();
+        let queries: Vec<SparseVector> =
+            request_data.data.into_iter().map(from_pb_sparse).collect();
+        let options = SearchOptions {
+            filter: parse_filter(&request_data.filter).map_err(invalid_argument)?,
+            payload_fields: request_data
+                .with_payload
+                .then_some(request_data.payload_fields),
+            ..SearchOptions::default()
+        };
+
+        let results = self
+            .api
+            .sparse_search(
+                &request_data.collection,
+                queries,
+                request_data.knbn as usize,
+                &options,
+            )
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(SparseSearchResult {
+            results: to_pb_scored_points(results),
+        }))
+    }
+
+    async fn insert_multi(
+        &self,
+        request: Request<InsertMultiRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request_data = request.into_inner();
+        self.api
+            .check_writable(&request_data.collection)
+            .map_err(failed_precondition)?;
+        let data: Vec<(Vec<Vec<f32>>, usize)> = request_data
+            .data
+            .into_iter()
+            .map(from_pb_multi)
+            .zip(request_data.ids.into_iter().map(|id| id as usize))
+            .collect();
+        let payloads = parse_payloads(&request_data.payloads).map_err(invalid_argument)?;
+
+        self.api
+            .insert_multi(
+                &request_data.collection,
+                &request_data.vector_name,
+                &data,
+                payloads,
+            )
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn multi_search(
+        &self,
+        request: Request<MultiSearchRequest>,
+    ) -> Result<Response<MultiSearchResult>, Status> {
+        let request_data = request.into_inner();
+        let queries: Vec<Vec<Vec<f32>>> =
+            request_data.data.into_iter().map(from_pb_multi).collect();
+        let options = SearchOptions {
+            filter: parse_filter(&request_data.filter).map_err(invalid_argument)?,
+            payload_fields: request_data
+                .with_payload
+                .then_some(request_data.payload_fields),
+            vector: request_data.vector_name,
+        };
+
+        let results = self
+            .api
+            .multi_search(
+                &request_data.collection,
+                &queries,
+                request_data.knbn as usize,
+                request_data.ef as usize,
+                &options,
+            )
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(MultiSearchResult {
+            results: to_pb_scored_points(results),
+        }))
+    }
+}
+
+impl GRPCServer {
+    /// fails unless collection is written on this node only, so that it can be merged with peers
+    fn check_mergeable(&self, collection: &str) -> Result<(), Box<dyn Error>> {
+        self.api.check_writable(collection)?;
+        let name = default_name(collection);
+        match self.coordinator.shards(name) {
+            Some(_) => Err(format!("collection {} is sharded and cannot be merged", name).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// the root to attach or sync collection at: root_cid, or the root of the signed manifest
+    /// of manifest_cid, read from source before any block of the root and checked by
+    /// VectorAPI::check_version. Also returns the manifest.
+    async fn trusted_root(
+        &self,
+        collection: &str,
+        root_cid: &str,
+        manifest_cid: &str,
+        source: &BlockSource,
+    ) -> Result<(Cid, Option<Cid>), Status> {
+        let parse =
+            |cid: &str| Cid::try_from(cid).map_err(|e| format!("invalid CID {}: {}", cid, e));
+        let root = match root_cid {
+            "" => None,
+            root => Some(parse(root).map_err(Status::invalid_argument)?),
+        };
+        if manifest_cid.is_empty() {
+            let root =
+                root.ok_or_else(|| Status::invalid_argument("a root or a manifest is required"))?;
+            self.api
+                .check_version(collection, &root, None)
+                .map_err(|e| Status::permission_denied(e.to_string()))?;
+            return Ok((root, None));
+        }
+        let manifest_cid = parse(manifest_cid).map_err(Status::invalid_argument)?;
+        let block = fetch_block(source, manifest_cid).await?;
+        if !block.verify() {
+            return Err(Status::permission_denied(format!(
+                "manifest {} does not match its CID",
+                manifest_cid
+            )));
+        }
+        let manifest = manifest::decode(&block)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?
+            .manifest;
+        let root = root.unwrap_or(manifest.root.0);
+        // kept so that the node serves the manifest with the version
+        self.api
+            .blocks()
+            .put(block)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        self.api
+            .check_version(collection, &root, Some(&manifest_cid))
+            .map_err(|e| Status::permission_denied(e.to_string()))?;
+        Ok((root, Some(manifest_cid)))
+    }
+
+    /// inserts points having named vectors, and default vectors when data is not empty
+    fn insert_named(&self, request_data: InsertRequest) -> Result<(), Box<dyn Error>> {
+        let ids: Vec<usize> = request_data.ids.iter().map(|&id| id as usize).collect();
+        let payloads = parse_payloads(&request_data.payloads)?;
+        let default_vectors = PbNamedVectors {
+            name: String::new(),
+            data: request_data.data,
+        };
+        let vectors: Vec<NamedVectors> = Some(default_vectors)
+            .filter(|named| !named.data.is_empty())
+            .into_iter()
+            .chain(request_data.named_vectors)
+            .map(|named| NamedVectors {
+                name: named.name,
+                vectors: named
+                    .data
+                    .into_iter()
+                    .map(|float_array| float_array.values)
+                    .collect(),
+            })
+            .collect();
+
+        self.api
+            .insert_named(&request_data.collection, &ids, &vectors, payloads)
+    }
+}
+
+/// parses JSON payloads, an empty string standing for a null payload
+fn parse_payloads(payloads: &[String]) -> Result<Vec<Value>, Box<dyn Error>> {
+    payloads
+        .iter()
+        .map(|payload| match payload.as_str() {
+            "" => Ok(Value::Null),
+            json => serde_json::from_str(json),
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("invalid payload: {}", e).into())
+}
+
+/// the sparse vector of a message, checked when inserted or searched
+fn from_pb_sparse(v: PbSparseVector) -> SparseVector {
+    SparseVector {
+        indices: v.indices,
+        values: v.values,
+    }
+}
+
+fn from_pb_multi(v: MultiVector) -> Vec<Vec<f32>> {
+    v.vectors
+        .into_iter()
+        .map(|float_array| float_array.values)
+        .collect()
+}
+
+fn to_pb_scored_points(results: Vec<Vec<ScoredPoint>>) -> Vec<ScoredPoints> {
+    results
+        .into_iter()
+        .map(|hits| ScoredPoints {
+            hits: hits
+                .into_iter()
+                .map(|hit| PbScoredPoint {
+                    d_id: hit.d_id as u32,
+                    score: hit.score,
+                    payload: hit.payload.map(|p| p.to_string()).unwrap_or_default(),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn to_pb_proof(proof: &PointProof) -> PbPointProof {
+    PbPointProof {
+        vector_path: proof.vector_path.iter().map(Cid::to_bytes).collect(),
+        payload_path: proof.payload_path.iter().map(Cid::to_bytes).collect(),
+    }
+}
+
+/// the block of cid read from source, checked against its CID when fetched
+async fn fetch_block(source: &BlockSource, cid: Cid) -> Result<Block, Status> {
+    match source {
+        BlockSource::Dir(dir) => FsBlockStore::new(dir)
+            .get_block(&cid)
+            .map_err(|e| Status::not_found(e.to_string())),
+        BlockSource::Ipfs(url) => ipfs::fetch_block(url, cid)
+            .await
+            .map_err(|e| Status::unavailable(e.to_string())),
+        BlockSource::Peers(peers) => exchange::fetch_block(cid, peers).await,
+    }
+}
+
+/// parses a JSON filter expression, None when empty
+fn parse_filter(json: &str) -> Result<Option<Filter>, Box<dyn Error>> {
+    match json {
+        "" => Ok(None),
+        json => Filter::parse(json)
+            .map(Some)
+            .map_err(|e| format!("invalid filter: {}", e).into()),
+    }
+}
+
+/// the status of a request which cannot be parsed or applied as is
+fn invalid_argument(e: Box<dyn Error>) -> Status {
+    Status::invalid_argument(e.to_string())
+}
+
+/// the status of a write the collection does not take in its state
+fn failed_precondition(e: Box<dyn Error>) -> Status {
+    Status::failed_precondition(e.to_string())
+}
+
+#[tonic::async_trait]
+impl AdminService for GRPCServer {
+    async fn export_car(
+        &self,
+        request: Request<ExportRequest>,
+    ) -> Result<Response<ExportResponse>, Status> {
+        let request_data = request.into_inner();
+        let path = request_data.path;
+        let (root, nb_block) = self
+            .api
+            .export_car(&request_data.collection, &path)
+            .map_err(|e| Status::internal(format!("export to {} failed: {}", path, e)))?;
+
+        Ok(Response::new(ExportResponse {
+            root_cid: root.to_string(),
+            nb_block: nb_block as u64,
+        }))
+    }
+
+    async fn import_car(
+        &self,
+        request: Request<ImportRequest>,
+    ) -> Result<Response<ImportResponse>, Status> {
+        let request_data = request.into_inner();
+        let path = request_data.path;
+        let (root, nb_point) = self
+            .api
+            .import_car(&request_data.collection, &path)
+            .map_err(|e| Status::invalid_argument(format!("import of {} failed: {}", path, e)))?;
+
+        Ok(Response::new(ImportResponse {
+            root_cid: root.to_string(),
+            nb_point: nb_point as u64,
+        }))
+    }
+
+    async fn export_vectors(
+        &self,
+        request: Request<ExportVectorsRequest>,
+    ) -> Result<Response<ExportVectorsResponse>, Status> {
+        let request_data = request.into_inner();
+        let format = request_data
+            .format
+            .parse::<VectorFormat>()
+            .map_err(Status::invalid_argument)?;
+        let layer = if request_data.by_layer {
+            Some(request_data.layer as usize)
+        } else {
+            None
+        };
+        let nb_vector = self
+            .api
+            .export_vectors(&request_data.collection, &request_data.path, format, layer)
+            .map_err(|e| {
+                Status::internal(format!("export to {} failed: {}", request_data.path, e))
+            })?;
+
+        Ok(Response::new(ExportVectorsResponse {
+            nb_vector: nb_vector as u64,
+        }))
+    }
+
+    async fn estimate_recall(
+        &self,
+        request: Request<RecallRequest>,
+    ) -> Result<Response<RecallResponse>, Status> {
+        let request_data = request.into_inner();
+        let queries: Vec<Vec<f32>> = request_data
+            .queries
+            .into_iter()
+            .map(|float_array| float_array.values)
+            .collect();
+        let api = Arc::clone(&self.api);
+        // brute force is long, keep it off the async workers
+        let estimate = tokio::task::spawn_blocking(move || {
+            api.estimate_recall(
+                &request_data.collection,
+                queries,
+                request_data.nb_sample as usize,
+                request_data.knbn as usize,
+                request_data.ef as usize,
+            )
+            .map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(Status::invalid_argument)?;
+
+        Ok(Response::new(RecallResponse {
+            nb_query: estimate.nb_query as u64,
+            recall: estimate.recall,
+            missed_by_rank: estimate.missed_by_rank.iter().map(|&n| n as u64).collect(),
+        }))
+    }
+
+    async fn create_collection(
+        &self,
+        request: Request<CreateCollectionRequest>,
+    ) -> Result<Response<()>, Status> {
+        let mut request_data = request.into_inner();
+        if !request_data.shard_addresses.is_empty() {
+            let addresses = std::mem::take(&mut request_data.shard_addresses);
+            self.coordinator
+                .create_collection(request_data, &addresses)
+                .await?;
+            return Ok(Response::new(()));
+        }
+        let mut config = self.api.index_config().clone();
+        config.kind = request_data
+            .index_type
+            .parse::<IndexKind>()
+            .map_err(Status::invalid_argument)?;
+        if !request_data.distance.is_empty() {
+            config.distance = request_data
+                .distance
+                .parse::<DistanceKind>()
+                .map_err(Status::invalid_argument)?;
+        }
+        if request_data.nlist > 0 {
+            config.ivf.nlist = request_data.nlist as usize;
+        }
+        if request_data.nprobe > 0 {
+            config.ivf.nprobe = request_data.nprobe as usize;
+        }
+        if !request_data.quantization.is_empty() {
+            config.ivf.quantization = request_data
+                .quantization
+                .parse::<Quantization>()
+                .map_err(Status::invalid_argument)?;
+        }
+        let vectors = request_data
+            .vectors
+            .into_iter()
+            .map(|vector| {
+                let mut vector_config = config.clone();
+                if !vector.index_type.is_empty() {
+                    vector_config.kind = vector.index_type.parse::<IndexKind>()?;
+                }
+                if !vector.distance.is_empty() {
+                    vector_config.distance = vector.distance.parse::<DistanceKind>()?;
+                }
+                Ok((vector.name, vector_config))
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(Status::invalid_argument)?;
+        if self.api.collection(&request_data.name).is_ok() {
+            return Err(Status::already_exists(format!(
+                "collection {} already exists",
+                request_data.name
+            )));
+        }
+        self.api
+            .create_collection_with_vectors(&request_data.name, config, vectors)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn drop_collection(
+        &self,
+        request: Request<DropCollectionRequest>,
+    ) -> Result<Response<()>, Status> {
+        let name = request.into_inner().name;
+        if self.coordinator.shards(&name).is_some() {
+            self.coordinator.drop_collection(&name).await?;
+            return Ok(Response::new(()));
+        }
+        if name == DEFAULT_COLLECTION {
+            return Err(Status::failed_precondition(
+                "the default collection cannot be dropped",
+            ));
+        }
+        self.api
+            .drop_collection(&name)
+            .map_err(|e| Status::not_found(e.to_string()))?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn list_collections(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<CollectionList>, Status> {
+        let collections = self
+            .api
+            .list_collections()
+            .into_iter()
+            .map(|(name, stats)| {
+                let collection = self.api.collection(&name).ok();
+                CollectionInfo {
+                    last_seq: collection
+                        .as_ref()
+                        .map_or(0, |collection| collection.log().last_seq()),
+                    attached_root: collection
+                        .and_then(|collection| collection.attached())
+                        .map(|root| root.to_string())
+                        .unwrap_or_default(),
+                    name,
+                    index_type: stats.kind.to_string(),
+                    dimension: stats.dimension as u32,
+                    nb_point: stats.nb_point as u64,
+                    nb_deleted: stats.nb_deleted as u64,
+                }
+            })
+            .collect();
+
+        Ok(Response::new(CollectionList { collections }))
+    }
+
+    async fn create_payload_index(
+        &self,
+        request: Request<CreatePayloadIndexRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request_data = request.into_inner();
+        let index_type = request_data
+            .index_type
+            .parse::<PayloadIndexType>()
+            .map_err(Status::invalid_argument)?;
+        self.api
+            .create_payload_index(&request_data.collection, &request_data.field, index_type)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn rebalance(
+        &self,
+        request: Request<RebalanceRequest>,
+    ) -> Result<Response<RebalanceResponse>, Status> {
+        let collection = request.into_inner().collection;
+        let nodes = self.membership.live_nodes();
+        let moves = self
+            .coordinator
+            .rebalance(&collection, &nodes)
+            .await?
+            .into_iter()
+            .map(|shard_move| PbShardMove {
+                shard: shard_move.shard,
+                from: shard_move.from,
+                to: shard_move.to,
+            })
+            .collect();
+
+        Ok(Response::new(RebalanceResponse { moves }))
+    }
+
+    async fn attach(
+        &self,
+        request: Request<AttachRequest>,
+    ) -> Result<Response<AttachResponse>, Status> {
+        let request_data = request.into_inner();
+        self.api
+            .check_writable(&request_data.collection)
+            .map_err(failed_precondition)?;
+        let source = if request_data.source.is_empty() {
+            self.api.block_source().clone()
+        } else {
+            request_data
+                .source
+                .parse::<BlockSource>()
+                .map_err(Status::invalid_argument)?
+        };
+        // nothing is read from a source the node is not configured with
+        self.api
+            .check_source(&source)
+            .map_err(|e| Status::permission_denied(e.to_string()))?;
+        let (root, manifest) = self
+            .trusted_root(
+                &request_data.collection,
+                &request_data.root_cid,
+                &request_data.manifest_cid,
+                &source,
+            )
+            .await?;
+        // blocks fetched from elsewhere are kept in the block store of the node
+        let nb_fetched = match &source {
+            BlockSource::Dir(_) => 0,
+            BlockSource::Ipfs(url) => ipfs::fetch_dag(url, root, self.api.blocks())
+                .await
+                .map_err(|e| Status::unavailable(e.to_string()))?,
+            BlockSource::Peers(peers) => {
+                exchange::sync(root, self.api.blocks(), peers)
+                    .await?
+                    .nb_fetched
+            }
+        };
+        let nb_point = self
+            .api
+            .attach(&request_data.collection, &root, manifest, &source)
+            .map_err(|e| Status::failed_precondition(format!("{} not attached: {}", root, e)))?;
+
+        Ok(Response::new(AttachResponse {
+            nb_point: nb_point as u64,
+            nb_fetched: nb_fetched as u64,
+            root_cid: root.to_string(),
+        }))
+    }
+
+    async fn freeze(&self, request: Request<FreezeRequest>) -> Result<Response<()>, Status> {
+        let request_data = request.into_inner();
+        self.api
+            .freeze(&request_data.collection, request_data.frozen)
+            .map_err(|e| Status::not_found(e.to_string()))?;
+
+        Ok(Response::new(()))
+    }
+}
+
+#[tonic::async_trait]
+impl ReplicationService for GRPCServer {
+    type ReplicateStream = ReceiverStream<Result<LogEntry, Status>>;
+
+    async fn replicate(
+        &self,
+        request: Request<ReplicateRequest>,
+    ) -> Result<Response<Self::ReplicateStream>, Status> {
+        let request_data = request.into_inner();
+        let collection = self
+            .api
+            .collection(&request_data.collection)
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(replication::stream_log(
+            collection,
+            request_data.epoch,
+            request_data.from_seq,
+            tx,
+        ));
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn snapshot(
+        &self,
+        request: Request<SnapshotRequest>,
+    ) -> Result<Response<SnapshotResponse>, Status> {
+        let name = request.into_inner().collection;
+        let api = Arc::clone(&self.api);
+        // persisting is long, keep it off the async workers
+        let (car, seq, epoch) =
+            tokio::task::spawn_blocking(move || api.snapshot(&name).map_err(|e| e.to_string()))
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .map_err(Status::not_found)?;
+
+        Ok(Response::new(SnapshotResponse { car, seq, epoch }))
+    }
+
+    async fn follow(&self, request: Request<FollowRequest>) -> Result<Response<()>, Status> {
+        let request_data = request.into_inner();
+        if request_data.leader.is_empty() {
+            return Err(Status::invalid_argument("a leader is needed"));
+        }
+        self.replication
+            .follow(
+                Arc::clone(&self.api),
+                default_name(&request_data.collection),
+                &request_data.leader,
+            )
+            .map_err(|e| Status::already_exists(e.to_string()))?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn unfollow(&self, request: Request<UnfollowRequest>) -> Result<Response<()>, Status> {
+        self.replication
+            .unfollow(&self.api, default_name(&request.into_inner().collection))
+            .map_err(|e| Status::not_found(e.to_string()))?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn list_replicas(&self, _request: Request<()>) -> Result<Response<ReplicaList>, Status> {
+        let replicas = self
+            .replication
+            .status()
+            .into_iter()
+            .map(|status| ReplicaStatus {
+                lag: status.lag(),
+                phase: status.phase.to_string(),
+                collection: status.collection,
+                leader: status.leader,
+                applied_seq: status.applied_seq,
+                leader_seq: status.leader_seq,
+                copied: status.copied,
+            })
+            .collect();
+
+        Ok(Response::new(ReplicaList { replicas }))
+    }
+
+    async fn merge(
+        &self,
+        request: Request<MergeRequest>,
+    ) -> Result<Response<MergeResponse>, Status> {
+        let request_data = request.into_inner();
+        self.check_mergeable(&request_data.collection)
+            .map_err(failed_precondition)?;
+        let dimension = self
+            .api
+            .collection(&request_data.collection)
+            .map_err(|e| Status::not_found(e.to_string()))?
+            .stats()
+            .dimension;
+        if request_data.changes.len() > crdt::max_batch_bytes(dimension) {
+            return Err(Status::resource_exhausted(format!(
+                "changes of {} bytes for points of dimension {}",
+                request_data.changes.len(),
+                dimension
+            )));
+        }
+        let incoming: Vec<Change> = serde_cbor::from_slice(&request_data.changes)
+            .map_err(|e| Status::invalid_argument(format!("invalid changes: {}", e)))?;
+        // the changes sent back are read before those of the caller are applied
+        let (epoch, seq, outgoing) = self
+            .api
+            .changes(
+                &request_data.collection,
+                request_data.epoch,
+                request_data.since_seq,
+                MERGE_BATCH,
+                Some(request_data.node),
+            )
+            .map_err(failed_precondition)?;
+        let nb_applied = self
+            .api
+            .merge(&request_data.collection, incoming)
+            .map_err(failed_precondition)?;
+        let changes = serde_cbor::to_vec(&outgoing).map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(MergeResponse {
+            changes,
+            epoch,
+            seq,
+            node: self.api.clock().node(),
+            nb_applied: nb_applied as u64,
+        }))
+    }
+
+    async fn reconcile(
+        &self,
+        request: Request<ReconcileRequest>,
+    ) -> Result<Response<ReconcileResponse>, Status> {
+        let request_data = request.into_inner();
+        self.check_mergeable(&request_data.collection)
+            .map_err(failed_precondition)?;
+        let stats = crdt::merge(&self.api, &request_data.collection, &request_data.peers).await?;
+
+        Ok(Response::new(ReconcileResponse {
+            nb_sent: stats.nb_sent as u64,
+            nb_received: stats.nb_received as u64,
+            nb_applied: stats.nb_applied as u64,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl ClusterService for GRPCServer {
+    async fn gossip(
+        &self,
+        request: Request<GossipMessage>,
+    ) -> Result<Response<GossipMessage>, Status> {
+        let states = membership::parse_states(request.into_inner().nodes)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        self.membership.merge(states);
+        let nodes = self.membership.nodes().iter().map(Into::into).collect();
+
+        Ok(Response::new(GossipMessage { nodes }))
+    }
+
+    async fn list_nodes(&self, _request: Request<()>) -> Result<Response<ClusterView>, Status> {
+        Ok(Response::new(self.membership.view().into()))
+    }
+}
+
+#[tonic::async_trait]
+impl BlockService for GRPCServer {
+    async fn publish(
+        &self,
+        request: Request<PublishRequest>,
+    ) -> Result<Response<PublishResponse>, Status> {
+        let collection = request.into_inner().collection;
+        self.api
+            .publish(&collection)
+            .map_err(|e| Status::internal(format!("{} not published: {}", collection, e)))?;
+        let (root, manifest) = self.api.version(&collection).ok_or_else(|| {
+            Status::internal(format!("collection {} is not published", collection))
+        })?;
+
+        Ok(Response::new(PublishResponse {
+            root_cid: root.to_string(),
+            manifest_cid: manifest
+                .map(|manifest| manifest.to_string())
+                .unwrap_or_default(),
+        }))
+    }
+
+    async fn resolve(
+        &self,
+        request: Request<ResolveRequest>,
+    ) -> Result<Response<ResolveResponse>, Status> {
+        let collection = request.into_inner().collection;
+        let (root, manifest) = self.api.version(&collection).ok_or_else(|| {
+            Status::not_found(format!("collection {} is not published", collection))
+        })?;
+
+        Ok(Response::new(ResolveResponse {
+            root_cid: root.to_string(),
+            manifest_cid: manifest
+                .map(|manifest| manifest.to_string())
+                .unwrap_or_default(),
+        }))
+    }
+
+    async fn exchange(&self, request: Request<WantList>) -> Result<Response<BlockBatch>, Status> {
+        let wants = request
+            .into_inner()
+            .cids
+            .iter()
+            .map(|bytes| exchange::parse_cid(bytes))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let batch = exchange::answer(self.api.blocks(), &wants)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(batch))
+    }
+
+    async fn sync(&self, request: Request<SyncRequest>) -> Result<Response<SyncResponse>, Status> {
+        let request_data = request.into_inner();
+        self.api
+            .check_writable(&request_data.collection)
+            .map_err(failed_precondition)?;
+        let (root_cid, manifest_cid) =
+            if request_data.root_cid.is_empty() && request_data.manifest_cid.is_empty() {
+                let collection = default_name(&request_data.collection);
+                let (root, manifest) = exchange::resolve(collection, &request_data.peers).await?;
+                (
+                    root.to_string(),
+                    manifest.map(|m| m.to_string()).unwrap_or_default(),
+                )
+            } else {
+                (request_data.root_cid, request_data.manifest_cid)
+            };
+        let peers = BlockSource::Peers(request_data.peers.clone());
+        let (root, manifest) = self
+            .trusted_root(&request_data.collection, &root_cid, &manifest_cid, &peers)
+            .await?;
+        let stats = exchange::sync(root, self.api.blocks(), &request_data.peers).await?;
+        let nb_point = self
+            .api
+            .checkout(&request_data.collection, &root, manifest)
+            .map_err(|e| Status::internal(format!("{} not loaded: {}", root, e)))?;
+
+        Ok(Response::new(SyncResponse {
+            root_cid: root.to_string(),
+            nb_block: stats.nb_block as u64,
+            nb_fetched: stats.nb_fetched as u64,
+            nb_byte: stats.nb_byte as u64,
+            nb_point: nb_point as u64,
         }))
     }
 }
 
 pub async fn start_grpc(
     api: Arc<VectorAPI>,
+    coordinator: Arc<Coordinator>,
+    replication: Arc<Replication>,
+    membership: Arc<Membership>,
     address: SocketAddr,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let vector_service = GRPCServer::new(api);
-    let svc = VectorServiceServer::new(vector_service);
-    Server::builder().add_service(svc).serve(address).await?;
+    let listener = TcpListener::bind(address).await?;
+    serve_grpc(api, coordinator, replication, membership, listener).await
+}
+
+/// serves the gRPC services on the connections accepted by listener
+pub async fn serve_grpc(
+    api: Arc<VectorAPI>,
+    coordinator: Arc<Coordinator>,
+    replication: Arc<Replication>,
+    membership: Arc<Membership>,
+    listener: TcpListener,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = || {
+        GRPCServer::new(
+            Arc::clone(&api),
+            Arc::clone(&coordinator),
+            Arc::clone(&replication),
+            Arc::clone(&membership),
+        )
+    };
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+        let accepted = listener.accept().await.map(|(stream, _)| stream);
+        Some((accepted, listener))
+    });
+    Server::builder()
+        .add_service(VectorServiceServer::new(server()))
+        .add_service(AdminServiceServer::new(server()))
+        // merges exchange batches of points, see crdt::max_batch_bytes
+        .add_service(
+            ReplicationServiceServer::new(server())
+                .max_decoding_message_size(crdt::max_batch_bytes(crdt::MAX_MERGE_DIMENSION)),
+        )
+        .add_service(ClusterServiceServer::new(server()))
+        .add_service(BlockServiceServer::new(server()))
+        .serve_with_incoming(incoming)
+        .await?;
 
     Ok(())
 }
+
+/// a listener on a free port of the loopback interface and the address of the node
+/// serving on it
+#[cfg(test)]
+pub(crate) async fn local_listener() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+    (listener, address)
+}
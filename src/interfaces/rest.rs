@@ -1,18 +1,31 @@
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
 use actix_web::rt as actix_rt;
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tokio_compat_02::FutureExt;
 
+use crate::cluster::membership::Membership;
 use crate::hnsw_graph::hnsw::Neighbour;
-use crate::interfaces::api::VectorAPI;
+use crate::index::fusion::Fusion;
+use crate::index::sparse::SparseVector;
+use crate::interfaces::api::{HybridQuery, NamedVectors, ScoredPoint, SearchOptions, VectorAPI};
+use crate::payload::filter::Filter;
+use crate::payload::index::PayloadIndexType;
 
 // Define request and response types
+// An absent or empty collection designates the default collection.
 #[derive(Serialize, Deserialize)]
 pub struct InsertRequest {
     pub data: Vec<(Vec<f32>, usize)>,
+    #[serde(default)]
+    pub collection: String,
+    /// one JSON payload per vector or none, a null payload removes the payload of its point
+    #[serde(default)]
+    pub payloads: Vec<Value>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,11 +33,154 @@ pub struct SearchRequest {
     pub data: Vec<Vec<f32>>,
     pub knbn: usize,
     pub ef: usize,
+    #[serde(default)]
+    pub collection: String,
+    #[serde(default)]
+    pub with_payload: bool,
+    /// dotted paths of the payload fields returned, all fields when empty
+    #[serde(default)]
+    pub payload_fields: Vec<String>,
+    /// only points whose payload matches are returned
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    /// name of the vector searched, the default vector when empty
+    #[serde(default)]
+    pub vector: String,
+}
+
+/// points with several vectors, the vectors of each name holding one vector per id. The
+/// default vector is named by an empty string.
+#[derive(Serialize, Deserialize)]
+pub struct InsertNamedRequest {
+    pub ids: Vec<usize>,
+    pub vectors: BTreeMap<String, Vec<Vec<f32>>>,
+    #[serde(default)]
+    pub collection: String,
+    #[serde(default)]
+    pub payloads: Vec<Value>,
+}
+
+/// a search of query vectors and of keywords in a text indexed payload field, whose
+/// rankings are fused
+#[derive(Serialize, Deserialize)]
+pub struct HybridSearchRequest {
+    pub data: Vec<Vec<f32>>,
+    /// the keywords of each query vector
+    pub texts: Vec<String>,
+    pub text_field: String,
+    pub knbn: usize,
+    /// number of points found by each of the searches fused
+    pub ef: usize,
+    #[serde(default)]
+    pub collection: String,
+    #[serde(default)]
+    pub with_payload: bool,
+    #[serde(default)]
+    pub payload_fields: Vec<String>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    #[serde(default)]
+    pub fusion: Fusion,
+    #[serde(default = "default_weight")]
+    pub dense_weight: f32,
+    #[serde(default = "default_weight")]
+    pub text_weight: f32,
+    #[serde(default)]
+    pub vector: String,
+}
+
+fn default_weight() -> f32 {
+    1.
+}
+
+/// sparse vectors as (indices, values) with their ids, see InsertRequest
+#[derive(Serialize, Deserialize)]
+pub struct InsertSparseRequest {
+    pub data: Vec<(SparseVector, usize)>,
+    #[serde(default)]
+    pub collection: String,
+    #[serde(default)]
+    pub payloads: Vec<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SparseSearchRequest {
+    pub data: Vec<SparseVector>,
+    pub knbn: usize,
+    #[serde(default)]
+    pub collection: String,
+    #[serde(default)]
+    pub with_payload: bool,
+    #[serde(default)]
+    pub payload_fields: Vec<String>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+}
+
+/// documents of several vectors with their ids, in the vector of this name, see InsertRequest
+#[derive(Serialize, Deserialize)]
+pub struct InsertMultiRequest {
+    pub data: Vec<(Vec<Vec<f32>>, usize)>,
+    #[serde(default)]
+    pub collection: String,
+    #[serde(default)]
+    pub payloads: Vec<Value>,
+    #[serde(default)]
+    pub vector: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MultiSearchRequest {
+    pub data: Vec<Vec<Vec<f32>>>,
+    pub knbn: usize,
+    /// number of tokens found for each query vector, whose documents are ranked
+    pub ef: usize,
+    #[serde(default)]
+    pub collection: String,
+    #[serde(default)]
+    pub with_payload: bool,
+    #[serde(default)]
+    pub payload_fields: Vec<String>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    #[serde(default)]
+    pub vector: String,
+}
+
+/// the points found by hybrid, sparse or multivector searches
+#[derive(Serialize, Deserialize)]
+pub struct ScoredResult {
+    pub results: Vec<Vec<ScoredPoint>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayloadIndexRequest {
+    /// dotted path of the payload field
+    pub field: String,
+    pub index_type: PayloadIndexType,
+    #[serde(default)]
+    pub collection: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteRequest {
+    pub ids: Vec<usize>,
+    #[serde(default)]
+    pub collection: String,
+}
+
+/// a neighbour and, when asked for, its payload
+#[derive(Serialize, Deserialize)]
+pub struct ScoredNeighbour {
+    #[serde(flatten)]
+    pub neighbour: Neighbour,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Value>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SearchResult {
-    pub results: Vec<Vec<Neighbour>>,
+    pub results: Vec<Vec<ScoredNeighbour>>,
 }
 
 // Request handlers
@@ -32,31 +188,207 @@ async fn handle_insert(
     api: web::Data<Arc<VectorAPI>>,
     req: web::Json<InsertRequest>,
 ) -> impl Responder {
-    //TODO double check this
-    api.parallel_insert(
+    let req = req.into_inner();
+    let result = api.insert_with_payloads(
+        &req.collection,
         &req.data
             .iter()
             .map(|(data, idx)| (data as &Vec<f32>, *idx))
             .collect::<Vec<_>>(),
+        req.payloads,
     );
-    HttpResponse::Ok().json("Insert successful")
+    match result {
+        Ok(()) => HttpResponse::Ok().json("Insert successful"),
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
+async fn handle_insert_named(
+    api: web::Data<Arc<VectorAPI>>,
+    req: web::Json<InsertNamedRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    let vectors: Vec<NamedVectors> = req
+        .vectors
+        .into_iter()
+        .map(|(name, vectors)| NamedVectors { name, vectors })
+        .collect();
+    match api.insert_named(&req.collection, &req.ids, &vectors, req.payloads) {
+        Ok(()) => HttpResponse::Ok().json("Insert successful"),
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
 }
 
 async fn handle_search(
     api: web::Data<Arc<VectorAPI>>,
     req: web::Json<SearchRequest>,
 ) -> impl Responder {
-    let results = api.parallel_search(&req.data, req.knbn, req.ef);
-    HttpResponse::Ok().json(SearchResult { results })
+    let req = req.into_inner();
+    if let Some(Err(e)) = req.filter.as_ref().map(Filter::validate) {
+        return HttpResponse::BadRequest().json(e.to_string());
+    }
+    let options = SearchOptions {
+        filter: req.filter,
+        payload_fields: req.with_payload.then_some(req.payload_fields),
+        vector: req.vector,
+    };
+    let results = api.search(&req.collection, &req.data, req.knbn, req.ef, &options);
+    match results {
+        Ok(results) => {
+            let results = results
+                .into_iter()
+                .map(|neighbours| {
+                    neighbours
+                        .into_iter()
+                        .map(|(neighbour, payload)| ScoredNeighbour { neighbour, payload })
+                        .collect()
+                })
+                .collect();
+            HttpResponse::Ok().json(SearchResult { results })
+        }
+        Err(e) => HttpResponse::NotFound().json(e.to_string()),
+    }
+}
+
+async fn handle_hybrid_search(
+    api: web::Data<Arc<VectorAPI>>,
+    req: web::Json<HybridSearchRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    if let Some(Err(e)) = req.filter.as_ref().map(Filter::validate) {
+        return HttpResponse::BadRequest().json(e.to_string());
+    }
+    let query = HybridQuery {
+        text_field: req.text_field,
+        texts: req.texts,
+        fusion: req.fusion,
+        dense_weight: req.dense_weight,
+        text_weight: req.text_weight,
+    };
+    let options = SearchOptions {
+        filter: req.filter,
+        payload_fields: req.with_payload.then_some(req.payload_fields),
+        vector: req.vector,
+    };
+    match api.hybrid_search(
+        &req.collection,
+        &req.data,
+        req.knbn,
+        req.ef,
+        &query,
+        &options,
+    ) {
+        Ok(results) => HttpResponse::Ok().json(ScoredResult { results }),
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
+async fn handle_insert_sparse(
+    api: web::Data<Arc<VectorAPI>>,
+    req: web::Json<InsertSparseRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    match api.insert_sparse(&req.collection, req.data, req.payloads) {
+        Ok(()) => HttpResponse::Ok().json("Insert successful"),
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
+async fn handle_sparse_search(
+    api: web::Data<Arc<VectorAPI>>,
+    req: web::Json<SparseSearchRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    if let Some(Err(e)) = req.filter.as_ref().map(Filter::validate) {
+        return HttpResponse::BadRequest().json(e.to_string());
+    }
+    let options = SearchOptions {
+        filter: req.filter,
+        payload_fields: req.with_payload.then_some(req.payload_fields),
+        ..SearchOptions::default()
+    };
+    match api.sparse_search(&req.collection, req.data, req.knbn, &options) {
+        Ok(results) => HttpResponse::Ok().json(ScoredResult { results }),
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
+async fn handle_insert_multi(
+    api: web::Data<Arc<VectorAPI>>,
+    req: web::Json<InsertMultiRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    match api.insert_multi(&req.collection, &req.vector, &req.data, req.payloads) {
+        Ok(()) => HttpResponse::Ok().json("Insert successful"),
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
+async fn handle_multi_search(
+    api: web::Data<Arc<VectorAPI>>,
+    req: web::Json<MultiSearchRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    if let Some(Err(e)) = req.filter.as_ref().map(Filter::validate) {
+        return HttpResponse::BadRequest().json(e.to_string());
+    }
+    let options = SearchOptions {
+        filter: req.filter,
+        payload_fields: req.with_payload.then_some(req.payload_fields),
+        vector: req.vector,
+    };
+    match api.multi_search(&req.collection, &req.data, req.knbn, req.ef, &options) {
+        Ok(results) => HttpResponse::Ok().json(ScoredResult { results }),
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
+async fn handle_payload_index(
+    api: web::Data<Arc<VectorAPI>>,
+    req: web::Json<PayloadIndexRequest>,
+) -> impl Responder {
+    match api.create_payload_index(&req.collection, &req.field, req.index_type) {
+        Ok(()) => HttpResponse::Ok().json("Payload index created"),
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
+async fn handle_delete(
+    api: web::Data<Arc<VectorAPI>>,
+    req: web::Json<DeleteRequest>,
+) -> impl Responder {
+    match api.delete(&req.collection, &req.ids) {
+        Ok(nb_deleted) => HttpResponse::Ok().json(nb_deleted),
+        Err(e) => HttpResponse::NotFound().json(e.to_string()),
+    }
+}
+
+async fn handle_cluster(membership: web::Data<Arc<Membership>>) -> impl Responder {
+    HttpResponse::Ok().json(membership.view())
 }
 
-pub async fn start_rest_api(api: Arc<VectorAPI>, address: SocketAddr) -> std::io::Result<()> {
+pub async fn start_rest_api(
+    api: Arc<VectorAPI>,
+    membership: Arc<Membership>,
+    address: SocketAddr,
+) -> std::io::Result<()> {
     let api = web::Data::new(api);
+    let membership = web::Data::new(membership);
     HttpServer::new(move || {
         App::new()
             .app_data(api.clone())
+            .app_data(membership.clone())
             .route("/insert", web::post().to(handle_insert))
+            .route("/insert_named", web::post().to(handle_insert_named))
             .route("/search", web::post().to(handle_search))
+            .route("/hybrid_search", web::post().to(handle_hybrid_search))
+            .route("/insert_sparse", web::post().to(handle_insert_sparse))
+            .route("/sparse_search", web::post().to(handle_sparse_search))
+            .route("/insert_multi", web::post().to(handle_insert_multi))
+            .route("/multi_search", web::post().to(handle_multi_search))
+            .route("/delete", web::post().to(handle_delete))
+            .route("/payload_index", web::post().to(handle_payload_index))
+            .route("/cluster", web::get().to(handle_cluster))
     })
     .bind(address)?
     .run()
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use cid::Cid;
+use multihash::{Code, MultihashDigest};
+use parking_lot::RwLock;
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use serde_cbor::tags::Tagged;
+use serde_cbor::Value;
+
+/// multicodec code of DAG-CBOR encoded blocks
+pub const DAG_CBOR: u64 = 0x71;
+
+/// multicodec code of blocks of opaque bytes
+pub const RAW: u64 = 0x55;
+
+/// size of the largest block a node takes from another, that of a chunk of 1024 vectors of
+/// dimension 16384
+pub const MAX_BLOCK_BYTES: usize = 64 << 20;
+
+/// CBOR tag used by DAG-CBOR to mark a link to another block
+const CID_TAG: u64 = 42;
+
+/// A content addressed block: some bytes and the CID computed from them.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub cid: Cid,
+    pub data: Vec<u8>,
+}
+
+impl Block {
+    /// hash data with sha2-256 and build the v1 CID of the block
+    pub fn new(codec: u64, data: Vec<u8>) -> Self {
+        let hash = Code::Sha2_256.digest(&data);
+        Block {
+            cid: Cid::new_v1(codec, hash),
+            data,
+        }
+    }
+
+    /// encode a value as DAG-CBOR. Links must be expressed with the Link type.
+    pub fn encode<S: Serialize>(value: &S) -> Result<Self, Box<dyn Error>> {
+        let data = serde_cbor::to_vec(value)?;
+        Ok(Block::new(DAG_CBOR, data))
+    }
+
+    pub fn decode<S: DeserializeOwned>(&self) -> Result<S, Box<dyn Error>> {
+        Ok(serde_cbor::from_slice(&self.data)?)
+    }
+
+    /// checks that the data of the block hashes to its CID
+    pub fn verify(&self) -> bool {
+        match Code::try_from(self.cid.hash().code()) {
+            Ok(code) => code.digest(&self.data) == *self.cid.hash(),
+            Err(_) => false,
+        }
+    }
+
+    /// returns the CIDs of the blocks this block links to, in encoding order.
+    /// Blocks that are not DAG-CBOR have no links.
+    pub fn links(&self) -> Result<Vec<Cid>, Box<dyn Error>> {
+        let mut links = Vec::new();
+        if self.cid.codec() == DAG_CBOR {
+            let value: Value = serde_cbor::from_slice(&self.data)?;
+            collect_links(&value, &mut links)?;
+        }
+        Ok(links)
+    }
+}
+
+fn collect_links(value: &Value, links: &mut Vec<Cid>) -> Result<(), Box<dyn Error>> {
+    match value {
+        Value::Tag(CID_TAG, inner) => match inner.as_ref() {
+            Value::Bytes(bytes) if !bytes.is_empty() => links.push(Cid::try_from(&bytes[1..])?),
+            _ => return Err("malformed link in DAG-CBOR block".into()),
+        },
+        Value::Tag(_, inner) => collect_links(inner, links)?,
+        Value::Array(values) => {
+            for v in values {
+                collect_links(v, links)?;
+            }
+        }
+        Value::Map(map) => {
+            for v in map.values() {
+                collect_links(v, links)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// A link to another block. It is encoded as a DAG-CBOR link (tag 42) so that
+/// IPFS tools can walk the blocks of a persisted index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Link(pub Cid);
+
+/// Bytes encoded as a CBOR byte string rather than an array of integers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bytes(pub Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Bytes;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a byte string")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+                Ok(Bytes(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+                Ok(Bytes(v))
+            }
+        }
+
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+}
+
+impl Serialize for Link {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // DAG-CBOR prefixes the binary CID with the multibase identity code 0x00
+        let mut bytes = vec![0u8];
+        bytes.extend(self.0.to_bytes());
+        Tagged::new(Some(CID_TAG), Bytes(bytes)).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Link {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tagged = Tagged::<Bytes>::deserialize(deserializer)?;
+        if tagged.tag != Some(CID_TAG) {
+            return Err(de::Error::custom("expected a CID tag"));
+        }
+        let bytes = tagged.value.0;
+        if bytes.first() != Some(&0u8) {
+            return Err(de::Error::custom("expected identity multibase prefix"));
+        }
+        Cid::try_from(&bytes[1..])
+            .map(Link)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A store of content addressed blocks.
+pub trait BlockStore: Send + Sync {
+    /// returns the data of a block, None if the block is not in the store
+    fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+
+    fn put(&self, block: Block) -> Result<(), Box<dyn Error>>;
+
+    fn has(&self, cid: &Cid) -> Result<bool, Box<dyn Error>> {
+        Ok(self.get(cid)?.is_some())
+    }
+
+    /// returns the block, failing if it is missing from the store
+    fn get_block(&self, cid: &Cid) -> Result<Block, Box<dyn Error>> {
+        match self.get(cid)? {
+            Some(data) => Ok(Block { cid: *cid, data }),
+            None => Err(format!("block {} not found", cid).into()),
+        }
+    }
+}
+
+/// A block store keeping blocks in memory
+#[derive(Default)]
+pub struct MemoryBlockStore {
+    blocks: RwLock<HashMap<Cid, Vec<u8>>>,
+}
+
+impl MemoryBlockStore {
+    pub fn new() -> Self {
+        MemoryBlockStore::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.read().is_empty()
+    }
+}
+
+impl BlockStore for MemoryBlockStore {
+    fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.blocks.read().get(cid).cloned())
+    }
+
+    fn put(&self, block: Block) -> Result<(), Box<dyn Error>> {
+        self.blocks.write().insert(block.cid, block.data);
+        Ok(())
+    }
+
+    fn has(&self, cid: &Cid) -> Result<bool, Box<dyn Error>> {
+        Ok(self.blocks.read().contains_key(cid))
+    }
+}
+
+/// returns the CIDs of all blocks reachable from root, root first.
+/// Every block of the DAG must be in the store.
+pub fn walk_dag(root: &Cid, store: &dyn BlockStore) -> Result<Vec<Cid>, Box<dyn Error>> {
+    let mut visited = std::collections::HashSet::new();
+    let mut order = Vec::new();
+    let mut to_visit = vec![*root];
+    while let Some(cid) = to_visit.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        let block = store.get_block(&cid)?;
+        order.push(cid);
+        // push in reverse so that links are visited in encoding order
+        for link in block.links()?.into_iter().rev() {
+            if !visited.contains(&link) {
+                to_visit.push(link);
+            }
+        }
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Node {
+        name: String,
+        children: Vec<Link>,
+    }
+
+    #[test]
+    fn test_links_roundtrip() {
+        let store = MemoryBlockStore::new();
+        let leaf = Block::encode(&Node {
+            name: "leaf".to_string(),
+            children: vec![],
+        })
+        .unwrap();
+        let leaf_cid = leaf.cid;
+        store.put(leaf).unwrap();
+        let root = Block::encode(&Node {
+            name: "root".to_string(),
+            children: vec![Link(leaf_cid)],
+        })
+        .unwrap();
+        assert!(root.verify());
+        assert_eq!(root.links().unwrap(), vec![leaf_cid]);
+        let decoded: Node = root.decode().unwrap();
+        assert_eq!(decoded.children[0].0, leaf_cid);
+        let root_cid = root.cid;
+        store.put(root).unwrap();
+        assert_eq!(
+            walk_dag(&root_cid, &store).unwrap(),
+            vec![root_cid, leaf_cid]
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! Reading and writing of CARv1 archives (<https://ipld.io/specs/transport/car/carv1/>).
+//! An archive is a DAG-CBOR header holding the root CIDs followed by a sequence of
+//! varint length prefixed (CID, data) sections.
+
+use std::error::Error;
+use std::io::{Cursor, ErrorKind, Read, Write};
+
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+
+use crate::ipfs_storage::block::{walk_dag, Block, BlockStore, Link};
+
+const CAR_VERSION: u64 = 1;
+
+/// largest header read, a header only lists the roots
+const MAX_HEADER_LEN: u64 = 1 << 20;
+
+/// largest section read, far above the blocks of a persisted collection
+const MAX_SECTION_LEN: u64 = 1 << 28;
+
+#[derive(Serialize, Deserialize)]
+struct CarHeader {
+    roots: Vec<Link>,
+    version: u64,
+}
+
+fn write_uvarint<W: Write>(writer: &mut W, mut value: u64) -> std::io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// reads an unsigned varint, returns None on a clean end of stream
+fn read_uvarint<R: Read>(reader: &mut R) -> std::io::Result<Option<u64>> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    let mut byte = [0u8; 1];
+    loop {
+        match reader.read_exact(&mut byte) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        if shift > 63 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "varint overflow",
+            ));
+        }
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+        shift += 7;
+    }
+}
+
+fn write_section<W: Write>(writer: &mut W, cid: &Cid, data: &[u8]) -> std::io::Result<()> {
+    let cid_bytes = cid.to_bytes();
+    write_uvarint(writer, (cid_bytes.len() + data.len()) as u64)?;
+    writer.write_all(&cid_bytes)?;
+    writer.write_all(data)
+}
+
+/// reads the len bytes of a header or a section of at most max bytes. The buffer grows
+/// with the bytes read, so that a truncated archive does not allocate its announced length.
+fn read_bytes<R: Read>(
+    reader: &mut R,
+    len: u64,
+    max: u64,
+    what: &str,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if len > max {
+        return Err(format!("CAR {} of {} bytes exceeds {} bytes", what, len, max).into());
+    }
+    let mut bytes = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(format!("CAR {} truncated", what).into());
+    }
+    Ok(bytes)
+}
+
+/// writes the DAG rooted at root as a CARv1 archive, root block first.
+/// Returns the number of blocks written.
+pub fn export_car<W: Write>(
+    writer: &mut W,
+    root: &Cid,
+    store: &dyn BlockStore,
+) -> Result<usize, Box<dyn Error>> {
+    let header = serde_cbor::to_vec(&CarHeader {
+        roots: vec![Link(*root)],
+        version: CAR_VERSION,
+    })?;
+    write_uvarint(writer, header.len() as u64)?;
+    writer.write_all(&header)?;
+    let cids = walk_dag(root, store)?;
+    for cid in &cids {
+        let block = store.get_block(cid)?;
+        write_section(writer, &block.cid, &block.data)?;
+    }
+    writer.flush()?;
+    Ok(cids.len())
+}
+
+/// reads a CARv1 archive into store, verifying each block against its CID.
+/// Returns the first root of the archive.
+pub fn import_car<R: Read>(reader: &mut R, store: &dyn BlockStore) -> Result<Cid, Box<dyn Error>> {
+    let header_len = read_uvarint(reader)?.ok_or("empty CAR archive")?;
+    let header = read_bytes(reader, header_len, MAX_HEADER_LEN, "header")?;
+    let header: CarHeader = serde_cbor::from_slice(&header)?;
+    if header.version != CAR_VERSION {
+        return Err(format!("unsupported CAR version {}", header.version).into());
+    }
+    let root = header.roots.first().ok_or("CAR archive has no root")?.0;
+    while let Some(section_len) = read_uvarint(reader)? {
+        let section = read_bytes(reader, section_len, MAX_SECTION_LEN, "section")?;
+        let mut cursor = Cursor::new(section);
+        let cid = Cid::read_bytes(&mut cursor)?;
+        let offset = cursor.position() as usize;
+        let mut data = cursor.into_inner();
+        data.drain(..offset);
+        let block = Block { cid, data };
+        if !block.verify() {
+            return Err(format!("block {} does not match its CID", cid).into());
+        }
+        store.put(block)?;
+    }
+    if !store.has(&root)? {
+        return Err(format!("CAR archive does not contain its root {}", root).into());
+    }
+    Ok(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipfs_storage::block::MemoryBlockStore;
+
+    #[derive(Serialize, Deserialize)]
+    struct Node {
+        payload: Vec<u32>,
+        children: Vec<Link>,
+    }
+
+    #[test]
+    fn test_uvarint() {
+        for value in [0u64, 1, 127, 128, 300, 1 << 35, u64::MAX] {
+            let mut buf = Vec::new();
+            write_uvarint(&mut buf, value).unwrap();
+            let read = read_uvarint(&mut Cursor::new(buf)).unwrap();
+            assert_eq!(read, Some(value));
+        }
+    }
+
+    #[test]
+    fn test_oversized_lengths_rejected() {
+        let store = MemoryBlockStore::new();
+        // a header announcing 2^60 bytes
+        let mut car = Vec::new();
+        write_uvarint(&mut car, 1 << 60).unwrap();
+        assert!(import_car(&mut Cursor::new(car), &store).is_err());
+        // a section announcing more bytes than the archive holds
+        let mut car = Vec::new();
+        let header = serde_cbor::to_vec(&CarHeader {
+            roots: vec![Link(Block::encode(&1u32).unwrap().cid)],
+            version: CAR_VERSION,
+        })
+        .unwrap();
+        write_uvarint(&mut car, header.len() as u64).unwrap();
+        car.extend_from_slice(&header);
+        write_uvarint(&mut car, MAX_SECTION_LEN).unwrap();
+        car.extend_from_slice(&[0u8; 16]);
+        assert!(import_car(&mut Cursor::new(car), &store).is_err());
+    }
+
+    #[test]
+    fn test_car_roundtrip() {
+        let store = MemoryBlockStore::new();
+        let mut children = Vec::new();
+        for i in 0..10 {
+            let block = Block::encode(&Node {
+                payload: vec![i; 100],
+                children: vec![],
+            })
+            .unwrap();
+            children.push(Link(block.cid));
+            store.put(block).unwrap();
+        }
+        let root = Block::encode(&Node {
+            payload: vec![],
+            children,
+        })
+        .unwrap();
+        let root_cid = root.cid;
+        store.put(root).unwrap();
+
+        let mut car = Vec::new();
+        let nb_block = export_car(&mut car, &root_cid, &store).unwrap();
+        assert_eq!(nb_block, 11);
+
+        let imported = MemoryBlockStore::new();
+        let imported_root = import_car(&mut Cursor::new(car), &imported).unwrap();
+        assert_eq!(imported_root, root_cid);
+        assert_eq!(imported.len(), 11);
+        assert_eq!(walk_dag(&root_cid, &imported).unwrap().len(), 11);
+    }
+}
@@ -0,0 +1,82 @@
+//! A block store keeping each block in a file of a directory, named by its CID.
+
+use std::error::Error;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use cid::Cid;
+
+use crate::ipfs_storage::block::{Block, BlockStore};
+
+#[derive(Debug, Clone)]
+pub struct FsBlockStore {
+    dir: PathBuf,
+}
+
+impl FsBlockStore {
+    /// a store in dir, created with its first block
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FsBlockStore { dir: dir.into() }
+    }
+
+    fn path(&self, cid: &Cid) -> PathBuf {
+        self.dir.join(cid.to_string())
+    }
+}
+
+impl BlockStore for FsBlockStore {
+    fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match std::fs::read(self.path(cid)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn put(&self, block: Block) -> Result<(), Box<dyn Error>> {
+        let path = self.path(&block.cid);
+        // a block never changes once written
+        if path.is_file() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        // written aside then renamed, so that a block is never seen partly written
+        let partial = self
+            .dir
+            .join(format!("{}.{:x}.partial", block.cid, rand::random::<u64>()));
+        std::fs::write(&partial, &block.data)?;
+        std::fs::rename(&partial, &path)?;
+        Ok(())
+    }
+
+    fn has(&self, cid: &Cid) -> Result<bool, Box<dyn Error>> {
+        Ok(self.path(cid).is_file())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipfs_storage::block::RAW;
+
+    #[test]
+    fn test_fs_block_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FsBlockStore::new(dir.path().join("blocks"));
+        let block = Block::new(RAW, b"some bytes".to_vec());
+        let cid = block.cid;
+        assert_eq!(store.get(&cid).unwrap(), None);
+        store.put(block.clone()).unwrap();
+        store.put(block).unwrap();
+        assert!(store.has(&cid).unwrap());
+        let read = store.get_block(&cid).unwrap();
+        assert!(read.verify());
+        assert_eq!(read.data, b"some bytes");
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("blocks"))
+                .unwrap()
+                .count(),
+            1
+        );
+    }
+}
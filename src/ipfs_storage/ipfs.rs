@@ -1 +1,74 @@
+//! Fetching of the blocks of a DAG from the HTTP API of an IPFS node.
 
+use std::collections::HashSet;
+use std::error::Error;
+
+use cid::Cid;
+use futures::TryStreamExt;
+use ipfs_api::{IpfsApi, IpfsClient, TryFromUri};
+use tokio::runtime::Handle;
+
+use crate::ipfs_storage::block::{Block, BlockStore};
+
+/// copies the blocks of the DAG of root missing from store from the IPFS node whose API is at
+/// url, checking each against its CID. Returns the number of blocks fetched.
+pub async fn fetch_dag(
+    url: &str,
+    root: Cid,
+    store: &dyn BlockStore,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let client = connect(url)?;
+    let mut visited = HashSet::new();
+    let mut to_visit = vec![root];
+    let mut nb_fetched = 0;
+    while let Some(cid) = to_visit.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        let stored = store.get(&cid).map_err(|e| e.to_string())?;
+        let block = match stored {
+            Some(data) => Block { cid, data },
+            None => {
+                let block = get(&client, url, cid).await?;
+                store.put(block.clone()).map_err(|e| e.to_string())?;
+                nb_fetched += 1;
+                block
+            }
+        };
+        to_visit.extend(block.links().map_err(|e| e.to_string())?);
+    }
+    Ok(nb_fetched)
+}
+
+/// the block of cid on the IPFS node whose API is at url, checked against its CID
+pub async fn fetch_block(url: &str, cid: Cid) -> Result<Block, Box<dyn Error + Send + Sync>> {
+    get(&connect(url)?, url, cid).await
+}
+
+fn connect(url: &str) -> Result<IpfsClient, Box<dyn Error + Send + Sync>> {
+    IpfsClient::from_str(url).map_err(|e| format!("invalid IPFS API address {}: {}", url, e).into())
+}
+
+async fn get(
+    client: &IpfsClient,
+    url: &str,
+    cid: Cid,
+) -> Result<Block, Box<dyn Error + Send + Sync>> {
+    // the responses of ipfs-api are not Send, they are read off the async workers
+    let client = client.clone();
+    let fetched = tokio::task::spawn_blocking(move || {
+        Handle::current().block_on(
+            client
+                .block_get(&cid.to_string())
+                .map_ok(|chunk| chunk.to_vec())
+                .try_concat(),
+        )
+    })
+    .await?;
+    let data = fetched.map_err(|e| format!("block {} not fetched from {}: {}", cid, url, e))?;
+    let block = Block { cid, data };
+    if !block.verify() {
+        return Err(format!("block {} from {} does not match its CID", cid, url).into());
+    }
+    Ok(block)
+}
@@ -0,0 +1,202 @@
+//! Signed manifests of the published versions of collections.
+//!
+//! Content addressing tells that the blocks of a collection were not altered, not who
+//! produced them. A node holding a signing key describes each version of a collection it
+//! publishes in a manifest: the root of the version, the parameters of its index and the
+//! manifest of the version it follows, signed with ed25519. A node configured with a trust
+//! list, the public keys it accepts, attaches and syncs only the versions whose manifest is
+//! signed by one of them, and checks the manifest before fetching the blocks of its root.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::index::IndexKind;
+use crate::ipfs_storage::block::{Block, Bytes, Link};
+
+/// A published version of a collection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub collection: String,
+    /// the CollectionRoot of the version
+    pub root: Link,
+    /// 1 for the first version, then the version of the parent plus one
+    pub version: u64,
+    /// the manifest of the previous version, as a CID rather than a link so that fetching a
+    /// version does not fetch its history
+    pub parent: Option<String>,
+    pub kind: IndexKind,
+    /// type name of the distance
+    pub distance: String,
+    pub dimension: usize,
+    pub nb_point: usize,
+    /// names of the named vectors of the points
+    pub vectors: Vec<String>,
+    /// seconds since the epoch
+    pub published_at: u64,
+}
+
+/// A manifest, the public key of its signer and the signature of its DAG-CBOR encoding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedManifest {
+    pub manifest: Manifest,
+    pub public_key: Bytes,
+    pub signature: Bytes,
+}
+
+/// signs manifest with key, returns the block of the SignedManifest
+pub fn sign(manifest: Manifest, key: &SigningKey) -> Result<Block, Box<dyn Error>> {
+    let signature = key.sign(&serde_cbor::to_vec(&manifest)?);
+    Block::encode(&SignedManifest {
+        manifest,
+        public_key: Bytes(key.verifying_key().to_bytes().to_vec()),
+        signature: Bytes(signature.to_bytes().to_vec()),
+    })
+}
+
+/// the SignedManifest of block, whose signature is not checked, see TrustList::verify
+pub fn decode(block: &Block) -> Result<SignedManifest, Box<dyn Error>> {
+    block.decode()
+}
+
+/// reads a signing key written by write_signing_key, in hex
+pub fn read_signing_key(path: &Path) -> Result<SigningKey, Box<dyn Error>> {
+    let bytes: [u8; 32] = hex::decode(fs::read_to_string(path)?.trim())?
+        .try_into()
+        .map_err(|_| format!("{} does not hold a 32 byte ed25519 key", path.display()))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// writes a new signing key to path, returns its public key
+pub fn write_signing_key(path: &Path) -> Result<VerifyingKey, Box<dyn Error>> {
+    let key = SigningKey::from_bytes(&rand::random::<[u8; 32]>());
+    fs::write(path, hex::encode(key.to_bytes()))?;
+    Ok(key.verifying_key())
+}
+
+/// The public keys whose manifests a node accepts, each with a name. Written one key per
+/// line, in hex, followed by its name; lines starting with # are comments.
+#[derive(Debug, Clone, Default)]
+pub struct TrustList {
+    keys: HashMap<[u8; 32], String>,
+}
+
+impl TrustList {
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(fs::read_to_string(path)?.parse()?)
+    }
+
+    pub fn add(&mut self, key: &VerifyingKey, name: &str) {
+        self.keys.insert(key.to_bytes(), name.to_string());
+    }
+
+    /// the manifest of block when it is signed by a trusted key
+    pub fn verify(&self, block: &Block) -> Result<Manifest, Box<dyn Error>> {
+        if !block.verify() {
+            return Err(format!("manifest {} does not match its CID", block.cid).into());
+        }
+        let signed = decode(block)?;
+        let public_key: [u8; 32] = signed
+            .public_key
+            .0
+            .try_into()
+            .map_err(|_| format!("invalid public key in manifest {}", block.cid))?;
+        let signer = self.keys.get(&public_key).ok_or_else(|| {
+            format!(
+                "manifest {} is signed by {}, which is not trusted",
+                block.cid,
+                hex::encode(public_key)
+            )
+        })?;
+        let signature = Signature::from_slice(&signed.signature.0)?;
+        VerifyingKey::from_bytes(&public_key)?
+            .verify_strict(&serde_cbor::to_vec(&signed.manifest)?, &signature)
+            .map_err(|_| format!("invalid signature of manifest {}", block.cid))?;
+        log::info!(
+            "manifest {} of version {} of {} signed by {}",
+            block.cid,
+            signed.manifest.version,
+            signed.manifest.collection,
+            signer
+        );
+        Ok(signed.manifest)
+    }
+}
+
+impl FromStr for TrustList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = HashMap::new();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let key: [u8; 32] = hex::decode(key)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| format!("invalid public key {}, expected 64 hex digits", key))?;
+            keys.insert(key, name.trim().to_string());
+        }
+        Ok(TrustList { keys })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipfs_storage::block::RAW;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("publisher.key");
+        let public_key = write_signing_key(&path).unwrap();
+        let key = read_signing_key(&path).unwrap();
+        assert_eq!(key.verifying_key(), public_key);
+
+        let manifest = Manifest {
+            collection: "docs".to_string(),
+            root: Link(Block::new(RAW, vec![1, 2, 3]).cid),
+            version: 1,
+            parent: None,
+            kind: IndexKind::Hnsw,
+            distance: "DistCosine".to_string(),
+            dimension: 3,
+            nb_point: 1,
+            vectors: Vec::new(),
+            published_at: 0,
+        };
+        let block = sign(manifest.clone(), &key).unwrap();
+        // the manifest links to its root
+        assert_eq!(block.links().unwrap(), vec![manifest.root.0]);
+
+        let trust: TrustList = format!(
+            "# publishers\n\n{} publisher\n",
+            hex::encode(public_key.to_bytes())
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(trust.verify(&block).unwrap(), manifest);
+        assert!(TrustList::default().verify(&block).is_err());
+        assert!("0123 short".parse::<TrustList>().is_err());
+
+        // a manifest signed by another key, or altered, is refused
+        let other = SigningKey::from_bytes(&[7; 32]);
+        assert!(trust
+            .verify(&sign(manifest.clone(), &other).unwrap())
+            .is_err());
+        let mut signed = decode(&block).unwrap();
+        signed.manifest.nb_point = 2;
+        assert!(trust.verify(&Block::encode(&signed).unwrap()).is_err());
+        let mut trust = trust;
+        trust.add(&other.verifying_key(), "other");
+        assert!(trust.verify(&sign(manifest, &other).unwrap()).is_ok());
+    }
+}
@@ -1 +1,7 @@
+pub mod block;
+pub mod car;
+pub mod fs;
 pub mod ipfs;
+pub mod manifest;
+pub mod proof;
+pub mod source;
@@ -0,0 +1,346 @@
+//! Merkle proofs that points belong to a persisted collection.
+//!
+//! A persisted collection is a DAG of blocks named by their hashes, so a client holding only
+//! its root CID can check the points an untrusted node returns. With each point the node
+//! gives the path of blocks leading from the root to the chunk holding its vector: the
+//! CollectionRoot, the IndexRoot and the manifest of the index searched, then the chunk of
+//! vectors, and the path to the chunk of payloads holding its payload. The verifier checks
+//! that every block hashes to its CID, that each one links to the next through the field
+//! leading to the vector searched and that the chunk holds the point. The leaves are the
+//! chunks of 1024 points a collection is persisted in, so a proof weighs about a chunk of
+//! vectors; the blocks shared by the proofs of a search are sent once.
+//!
+//! The points of hnsw and flat indexes can be proven.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use cid::Cid;
+use serde_json::Value;
+
+use crate::hnsw_graph::hnsw::DataId;
+use crate::index::{self, DistanceKind, IndexRoot};
+use crate::interfaces::api::CollectionRoot;
+use crate::ipfs_storage::block::{Block, BlockStore};
+use crate::payload::PayloadStore;
+
+/// the CIDs of the blocks leading from the root of a collection to a point
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PointProof {
+    /// the CollectionRoot, the IndexRoot, the manifest of the index and the chunk of vectors
+    pub vector_path: Vec<Cid>,
+    /// the CollectionRoot and the chunk of payloads, empty when the payload is not proven
+    pub payload_path: Vec<Cid>,
+}
+
+/// a point as persisted under a root
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProvenPoint {
+    pub d_id: DataId,
+    pub vector: Vec<f32>,
+    /// distance of the index the vector is persisted in
+    pub distance: DistanceKind,
+    /// None when the proof has no payload path
+    pub payload: Option<Value>,
+}
+
+/// the proofs of the points found by a search, by query, and the blocks of their paths
+#[derive(Debug, Clone)]
+pub struct SearchProofs {
+    pub root: Cid,
+    pub proofs: Vec<Vec<PointProof>>,
+    pub blocks: HashMap<Cid, Vec<u8>>,
+}
+
+/// chunks of points and the chunk of each point
+#[derive(Default)]
+struct Chunks {
+    cids: Vec<Cid>,
+    /// rank in cids of the chunk of each point
+    chunk_of: HashMap<DataId, u32>,
+}
+
+impl Chunks {
+    fn add(&mut self, cid: Cid, ids: impl Iterator<Item = DataId>) {
+        let rank = self.cids.len() as u32;
+        self.cids.push(cid);
+        for d_id in ids {
+            self.chunk_of.insert(d_id, rank);
+        }
+    }
+
+    fn get(&self, d_id: DataId) -> Option<Cid> {
+        self.chunk_of
+            .get(&d_id)
+            .map(|rank| self.cids[*rank as usize])
+    }
+}
+
+/// the blocks of an index leading to its chunks of vectors
+struct IndexPath {
+    index_root: Cid,
+    manifest: Cid,
+    chunks: Chunks,
+}
+
+impl IndexPath {
+    fn new(index_root: Cid, store: &dyn BlockStore) -> Result<Self, Box<dyn Error>> {
+        let root: IndexRoot = store.get_block(&index_root)?.decode()?;
+        let manifest = store.get_block(&root.index.0)?;
+        let mut chunks = Chunks::default();
+        for cid in index::vector_chunks(root.kind, &manifest)? {
+            let points = index::chunk_points(root.kind, &manifest, &store.get_block(&cid)?)?;
+            chunks.add(cid, points.into_iter().map(|(d_id, _)| d_id));
+        }
+        Ok(IndexPath {
+            index_root,
+            manifest: manifest.cid,
+            chunks,
+        })
+    }
+}
+
+/// Proves the points of the collection persisted under a root, reading the blocks of the
+/// proofs from a store.
+pub struct Prover {
+    root: Cid,
+    store: Arc<dyn BlockStore>,
+    /// the path of the index of each vector, by name, empty for the default vector. An error
+    /// when the points of the index cannot be proven.
+    indexes: HashMap<String, Result<IndexPath, String>>,
+    payloads: Chunks,
+}
+
+impl Prover {
+    /// reads the chunks of the collection persisted under root to find the chunk of each point
+    pub fn new(root: Cid, store: Arc<dyn BlockStore>) -> Result<Self, Box<dyn Error>> {
+        let collection_root: CollectionRoot = store.get_block(&root)?.decode()?;
+        let named = collection_root
+            .vectors
+            .iter()
+            .map(|(name, link)| (name.clone(), link.0));
+        let indexes = std::iter::once((String::new(), collection_root.index.0))
+            .chain(named)
+            .map(|(name, index_root)| {
+                let path = IndexPath::new(index_root, store.as_ref()).map_err(|e| e.to_string());
+                (name, path)
+            })
+            .collect();
+        let mut payloads = Chunks::default();
+        for link in &collection_root.payloads {
+            let entries = PayloadStore::chunk_entries(&store.get_block(&link.0)?)?;
+            payloads.add(link.0, entries.into_iter().map(|(d_id, _)| d_id));
+        }
+        Ok(Prover {
+            root,
+            store,
+            indexes,
+            payloads,
+        })
+    }
+
+    pub fn root(&self) -> Cid {
+        self.root
+    }
+
+    /// the proof of the point d_id of the vector of this name, empty for the default one,
+    /// and of its payload when with_payload. Adds the blocks of its paths to blocks.
+    pub fn prove(
+        &self,
+        d_id: DataId,
+        vector: &str,
+        with_payload: bool,
+        blocks: &mut HashMap<Cid, Vec<u8>>,
+    ) -> Result<PointProof, Box<dyn Error>> {
+        let path = match self.indexes.get(vector) {
+            Some(Ok(path)) => path,
+            Some(Err(e)) => return Err(e.clone().into()),
+            None => return Err(format!("no vector named {}", vector).into()),
+        };
+        let chunk = path
+            .chunks
+            .get(d_id)
+            .ok_or_else(|| format!("point {} is not persisted under {}", d_id, self.root))?;
+        let mut proof = PointProof {
+            vector_path: vec![self.root, path.index_root, path.manifest, chunk],
+            payload_path: Vec::new(),
+        };
+        if with_payload {
+            if let Some(chunk) = self.payloads.get(d_id) {
+                proof.payload_path = vec![self.root, chunk];
+            }
+        }
+        for cid in proof.vector_path.iter().chain(&proof.payload_path) {
+            if !blocks.contains_key(cid) {
+                blocks.insert(*cid, self.store.get_block(cid)?.data);
+            }
+        }
+        Ok(proof)
+    }
+}
+
+/// checks that proof leads from root to the point d_id of the vector of this name, empty for
+/// the default one, through blocks. Returns the point as persisted under root.
+pub fn verify(
+    root: &Cid,
+    d_id: DataId,
+    vector: &str,
+    proof: &PointProof,
+    blocks: &HashMap<Cid, Vec<u8>>,
+) -> Result<ProvenPoint, Box<dyn Error>> {
+    let path = path_blocks(root, &proof.vector_path, 4, blocks)?;
+    let collection_root: CollectionRoot = path[0].decode()?;
+    let index_root = if vector.is_empty() {
+        Some(collection_root.index.0)
+    } else {
+        collection_root
+            .vectors
+            .iter()
+            .find(|(name, _)| name == vector)
+            .map(|(_, link)| link.0)
+    };
+    if index_root != Some(path[1].cid) {
+        return Err(format!(
+            "{} is not the index of vector {} under {}",
+            path[1].cid, vector, root
+        )
+        .into());
+    }
+    let index_root: IndexRoot = path[1].decode()?;
+    if index_root.index.0 != path[2].cid
+        || !index::vector_chunks(index_root.kind, &path[2])?.contains(&path[3].cid)
+    {
+        return Err(format!(
+            "proof of point {} does not follow the links of its index",
+            d_id
+        )
+        .into());
+    }
+    let distance = index::index_distance(index_root.kind, &path[2])?;
+    let vector = index::chunk_points(index_root.kind, &path[2], &path[3])?
+        .into_iter()
+        .find(|(id, _)| *id == d_id)
+        .map(|(_, v)| v)
+        .ok_or_else(|| format!("point {} is not in chunk {}", d_id, path[3].cid))?;
+    let payload = if proof.payload_path.is_empty() {
+        None
+    } else {
+        let path = path_blocks(root, &proof.payload_path, 2, blocks)?;
+        if !collection_root
+            .payloads
+            .iter()
+            .any(|link| link.0 == path[1].cid)
+        {
+            return Err(
+                format!("{} is not a chunk of payloads under {}", path[1].cid, root).into(),
+            );
+        }
+        let payload = PayloadStore::chunk_entries(&path[1])?
+            .into_iter()
+            .find(|(id, _)| *id == d_id)
+            .map(|(_, payload)| payload)
+            .ok_or_else(|| format!("payload of point {} is not in chunk {}", d_id, path[1].cid))?;
+        Some(payload)
+    };
+    Ok(ProvenPoint {
+        d_id,
+        vector,
+        distance,
+        payload,
+    })
+}
+
+/// the blocks of a path of len CIDs starting at root, checked against their CIDs
+fn path_blocks(
+    root: &Cid,
+    path: &[Cid],
+    len: usize,
+    blocks: &HashMap<Cid, Vec<u8>>,
+) -> Result<Vec<Block>, Box<dyn Error>> {
+    if path.len() != len || path[0] != *root {
+        return Err(format!("proof path does not start at {}", root).into());
+    }
+    path.iter()
+        .map(|cid| {
+            let data = blocks
+                .get(cid)
+                .cloned()
+                .ok_or_else(|| format!("block {} is missing from the proof", cid))?;
+            let block = Block { cid: *cid, data };
+            if !block.verify() {
+                return Err(format!("block {} does not match its CID", cid).into());
+            }
+            Ok(block)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::index::{IndexConfig, IndexKind};
+    use crate::interfaces::api::{SearchOptions, VectorAPI};
+
+    #[test]
+    fn test_prove_and_verify() {
+        for kind in [IndexKind::Hnsw, IndexKind::Flat] {
+            let dir = tempfile::tempdir().unwrap();
+            let api = VectorAPI::new(IndexConfig {
+                kind,
+                data_dir: dir.path().to_path_buf(),
+                ..IndexConfig::default()
+            });
+            let data: Vec<Vec<f32>> = (0..1500).map(|i| vec![i as f32, 1.]).collect();
+            let points: Vec<(&Vec<f32>, DataId)> = data.iter().zip(0..).collect();
+            let payloads: Vec<Value> = (0..1500).map(|i| json!({ "rank": i })).collect();
+            api.insert_with_payloads("", &points, payloads).unwrap();
+            let root = api.publish("").unwrap();
+            let store: Arc<dyn BlockStore> = Arc::new(api.blocks().clone());
+            let prover = Prover::new(root, Arc::clone(&store)).unwrap();
+
+            let mut blocks = HashMap::new();
+            let proof = prover.prove(1200, "", true, &mut blocks).unwrap();
+            assert_eq!(blocks.len(), 5);
+            let point = verify(&root, 1200, "", &proof, &blocks).unwrap();
+            assert_eq!(point.vector, vec![1200., 1.]);
+            assert_eq!(point.payload, Some(json!({ "rank": 1200 })));
+            // the collection root, the index root and the manifest are shared
+            let other_proof = prover.prove(3, "", false, &mut blocks).unwrap();
+            assert_eq!(other_proof.vector_path[..3], proof.vector_path[..3]);
+            assert!(other_proof.payload_path.is_empty());
+            assert!(prover.prove(2000, "", false, &mut blocks).is_err());
+
+            // a path proves neither another point nor the point under another root
+            assert!(verify(&root, 2000, "", &proof, &blocks).is_err());
+            api.delete("", &[5]).unwrap();
+            let other = api.publish("").unwrap();
+            assert!(verify(&other, 1200, "", &proof, &blocks).is_err());
+            // nor with a block not matching its CID
+            let mut tampered = blocks.clone();
+            tampered.get_mut(&proof.vector_path[3]).unwrap()[20] ^= 1;
+            assert!(verify(&root, 1200, "", &proof, &tampered).is_err());
+
+            // only the points of attached collections are proven
+            let query = vec![vec![7., 1.]];
+            let options = SearchOptions {
+                payload_fields: Some(Vec::new()),
+                ..SearchOptions::default()
+            };
+            let results = api.search("", &query, 3, 16, &options).unwrap();
+            assert!(api.prove("", "", &results).is_err());
+            api.attach("attached", &root, None, api.block_source())
+                .unwrap();
+            let results = api.search("attached", &query, 3, 16, &options).unwrap();
+            let proven = api.prove("attached", "", &results).unwrap();
+            assert_eq!(proven.root, root);
+            for ((neighbour, payload), proof) in results[0].iter().zip(&proven.proofs[0]) {
+                let point = verify(&root, neighbour.d_id, "", proof, &proven.blocks).unwrap();
+                assert_eq!(point.vector, data[neighbour.d_id]);
+                assert_eq!(&point.payload, payload);
+            }
+        }
+    }
+}
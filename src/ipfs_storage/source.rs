@@ -0,0 +1,68 @@
+//! Where the blocks of an attached collection are read from.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A store of blocks outside of the node: a directory of blocks named by CID, such as the
+/// block store of another node on a shared volume, the HTTP API of an IPFS node or nodes
+/// serving their blocks. Written dir:<path>, ipfs:<url> or peers:<url>,<url>.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockSource {
+    Dir(PathBuf),
+    Ipfs(String),
+    Peers(Vec<String>),
+}
+
+impl FromStr for BlockSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("dir", path)) if !path.is_empty() => Ok(BlockSource::Dir(PathBuf::from(path))),
+            Some(("ipfs", url)) if !url.is_empty() => Ok(BlockSource::Ipfs(url.to_string())),
+            Some(("peers", peers)) if !peers.is_empty() => Ok(BlockSource::Peers(
+                peers.split(',').map(String::from).collect(),
+            )),
+            _ => Err(format!(
+                "invalid block source {}, expected dir:<path>, ipfs:<url> or peers:<url>,<url>",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BlockSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockSource::Dir(path) => write!(f, "dir:{}", path.display()),
+            BlockSource::Ipfs(url) => write!(f, "ipfs:{}", url),
+            BlockSource::Peers(peers) => write!(f, "peers:{}", peers.join(",")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_block_source() {
+        for s in [
+            "dir:/mnt/shared/blocks",
+            "ipfs:http://127.0.0.1:5001",
+            "peers:http://10.0.0.1:50051,http://10.0.0.2:50051",
+        ] {
+            assert_eq!(s.parse::<BlockSource>().unwrap().to_string(), s);
+        }
+        assert_eq!(
+            "peers:http://a:1,http://b:2".parse::<BlockSource>(),
+            Ok(BlockSource::Peers(vec![
+                "http://a:1".to_string(),
+                "http://b:2".to_string()
+            ]))
+        );
+        assert!("dir:".parse::<BlockSource>().is_err());
+        assert!("/mnt/shared/blocks".parse::<BlockSource>().is_err());
+    }
+}
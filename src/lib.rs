@@ -1,3 +1,7 @@
+pub mod cluster;
+pub mod dataset;
 pub mod hnsw_graph;
+pub mod index;
 pub mod interfaces;
 pub mod ipfs_storage;
+pub mod payload;
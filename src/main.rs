@@ -1,29 +1,39 @@
 use std::net::AddrParseError;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 #[cfg(test)]
 use std::{println as info, println as warn, println as debug, println as trace};
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use log::{info, warn};
 use tokio::select;
 use tokio::signal;
 use tokio::try_join;
 
+use d_celestica::cluster::coordinator::{Coordinator, SHARDS_FILE};
+use d_celestica::cluster::membership::{GossipConfig, Membership};
+use d_celestica::cluster::replication::Replication;
+use d_celestica::hnsw_graph::bench::{self, BenchConfig};
 use d_celestica::hnsw_graph::dist;
-use d_celestica::hnsw_graph::hnsw::Hnsw;
+use d_celestica::index::disk::DiskParams;
+use d_celestica::index::ivf::IvfParams;
+use d_celestica::index::quantizer::Quantization;
+use d_celestica::index::{DistanceKind, IndexConfig, IndexKind};
 use d_celestica::interfaces::api::VectorAPI;
 use d_celestica::interfaces::cli_grpc::GrpcCli;
 use d_celestica::interfaces::grpc::*;
 use d_celestica::interfaces::rest::*;
+use d_celestica::ipfs_storage::manifest::{self, TrustList};
+use d_celestica::ipfs_storage::source::BlockSource;
 
 #[actix_rt::main]
 async fn main() {
     info!("Starting Celestica");
     // async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    use clap::{App, Arg};
 
     env_logger::init();
 
@@ -72,6 +82,7 @@ async fn main() {
                 .help("Maximum number of connections per element")
                 .takes_value(true)
                 .env("MAX_NB_CONNECTION")
+                .global(true)
                 .default_value("16"),
         )
         .arg(
@@ -90,6 +101,7 @@ async fn main() {
                 .help("Maximum number of layers")
                 .takes_value(true)
                 .env("MAX_LAYER")
+                .global(true)
                 .default_value("16"),
         )
         .arg(
@@ -99,10 +111,266 @@ async fn main() {
                 .help("Size of the dynamic candidate list during construction")
                 .takes_value(true)
                 .env("EF_CONSTRUCTION")
+                .global(true)
                 .default_value("200"),
         )
+        .arg(
+            Arg::with_name("index_type")
+                .long("index_type")
+                .value_name("INDEX_TYPE")
+                .help("Index of the collections: hnsw, flat for exact search, ivf, disk, sparse for sparse vectors, or multivector for documents of several vectors")
+                .takes_value(true)
+                .env("INDEX_TYPE")
+                .possible_values(&["hnsw", "flat", "ivf", "disk", "sparse", "multivector"])
+                .default_value("hnsw"),
+        )
+        .arg(
+            Arg::with_name("distance")
+                .long("distance")
+                .value_name("DISTANCE")
+                .help("Distance of the dense indexes of the collections: cosine, dot or l2")
+                .takes_value(true)
+                .env("DISTANCE")
+                .possible_values(&["cosine", "dot", "l2"])
+                .default_value("cosine"),
+        )
+        .arg(
+            Arg::with_name("nlist")
+                .long("nlist")
+                .value_name("NLIST")
+                .help("Number of posting lists of ivf indexes")
+                .takes_value(true)
+                .env("NLIST")
+                .default_value("256"),
+        )
+        .arg(
+            Arg::with_name("nprobe")
+                .long("nprobe")
+                .value_name("NPROBE")
+                .help("Number of posting lists scanned by ivf searches")
+                .takes_value(true)
+                .env("NPROBE")
+                .default_value("16"),
+        )
+        .arg(
+            Arg::with_name("quantization")
+                .long("quantization")
+                .value_name("QUANTIZATION")
+                .help("Compression of ivf posting lists: none, sq8 or pq<m>")
+                .takes_value(true)
+                .env("QUANTIZATION")
+                .default_value("none"),
+        )
+        .arg(
+            Arg::with_name("shard_timeout")
+                .long("shard_timeout")
+                .value_name("SHARD_TIMEOUT")
+                .help("Milliseconds after which a shard that has not answered a search is left out of its results")
+                .takes_value(true)
+                .env("SHARD_TIMEOUT")
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::with_name("node_address")
+                .long("node_address")
+                .value_name("NODE_ADDRESS")
+                .help("gRPC endpoint other nodes reach this node at, http://127.0.0.1:<grpc_port> by default")
+                .takes_value(true)
+                .env("NODE_ADDRESS"),
+        )
+        .arg(
+            Arg::with_name("seeds")
+                .long("seeds")
+                .value_name("SEEDS")
+                .help("Comma separated gRPC endpoints of nodes of the cluster to join")
+                .takes_value(true)
+                .env("SEEDS"),
+        )
+        .arg(
+            Arg::with_name("gossip_interval")
+                .long("gossip_interval")
+                .value_name("GOSSIP_INTERVAL")
+                .help("Milliseconds between two exchanges of the view of the cluster with another node")
+                .takes_value(true)
+                .env("GOSSIP_INTERVAL")
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::with_name("follow")
+                .long("follow")
+                .value_name("COLLECTION=LEADER")
+                .help("Makes a collection a read-only copy of the collection of a leader, as collection=http://host:port")
+                .takes_value(true)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::with_name("data_dir")
+                .long("data_dir")
+                .value_name("DATA_DIR")
+                .help("Directory of the graph files of disk indexes")
+                .takes_value(true)
+                .env("DATA_DIR")
+                .default_value("data"),
+        )
+        .arg(
+            Arg::with_name("block_source")
+                .long("block_source")
+                .value_name("BLOCK_SOURCE")
+                .help("Where attached collections read their blocks: dir:<path>, ipfs:<url> or peers:<url>,<url>. The blocks directory of the data directory by default")
+                .takes_value(true)
+                .env("BLOCK_SOURCE"),
+        )
+        .arg(
+            Arg::with_name("attach_sources")
+                .long("attach_sources")
+                .value_name("ATTACH_SOURCES")
+                .help("Other dir:<path> or ipfs:<url> sources collections may be attached from, separated by semicolons")
+                .takes_value(true)
+                .env("ATTACH_SOURCES"),
+        )
+        .arg(
+            Arg::with_name("signing_key")
+                .long("signing_key")
+                .value_name("SIGNING_KEY")
+                .help("File of the ed25519 key signing the manifests of published versions, see keygen")
+                .takes_value(true)
+                .env("SIGNING_KEY"),
+        )
+        .arg(
+            Arg::with_name("trusted_keys")
+                .long("trusted_keys")
+                .value_name("TRUSTED_KEYS")
+                .help("File of the public keys, one per line in hex followed by a name, whose signed manifests attach and sync accept. Any version is accepted by default")
+                .takes_value(true)
+                .env("TRUSTED_KEYS"),
+        )
+        .arg(
+            Arg::with_name("beam_width")
+                .long("beam_width")
+                .value_name("BEAM_WIDTH")
+                .help("Number of graph nodes read per hop by disk index searches")
+                .takes_value(true)
+                .env("BEAM_WIDTH")
+                .default_value("4"),
+        )
+        .arg(
+            Arg::with_name("cache_size")
+                .long("cache_size")
+                .value_name("CACHE_SIZE")
+                .help("Number of graph nodes of disk indexes cached in RAM")
+                .takes_value(true)
+                .env("CACHE_SIZE")
+                .default_value("4096"),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .help("Open the collections saved in the data directory at startup and save them at shutdown. Vectors of hnsw indexes are memory mapped")
+                .env("SNAPSHOT"),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("Writes a new ed25519 key signing manifests and prints its public key")
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .value_name("PATH")
+                        .help("File of the key")
+                        .takes_value(true)
+                        .default_value("signing.key"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Builds an index and reports recall, QPS, build time and memory")
+                .arg(
+                    Arg::with_name("dataset")
+                        .long("dataset")
+                        .value_name("FILE")
+                        .help("Vectors to index (fvecs, ivecs, bvecs, npy, jsonl or csv); random if absent")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("queries")
+                        .long("queries")
+                        .value_name("FILE")
+                        .help("Query vectors; by default held out from the dataset or random")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("nb_elem")
+                        .long("nb_elem")
+                        .help("Number of random vectors to index")
+                        .takes_value(true)
+                        .default_value("10000"),
+                )
+                .arg(
+                    Arg::with_name("dim")
+                        .long("dim")
+                        .help("Dimension of random vectors")
+                        .takes_value(true)
+                        .default_value("25"),
+                )
+                .arg(
+                    Arg::with_name("nb_query")
+                        .long("nb_query")
+                        .help("Number of queries")
+                        .takes_value(true)
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::with_name("knbn")
+                        .short('k')
+                        .long("knbn")
+                        .help("Number of neighbours searched, the k of recall@k")
+                        .takes_value(true)
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::with_name("ef")
+                        .short('e')
+                        .long("ef")
+                        .help("Comma separated ef values to search with")
+                        .takes_value(true)
+                        .default_value("10,20,40,80,160"),
+                )
+                .arg(
+                    Arg::with_name("distance")
+                        .long("distance")
+                        .help("cosine or dot, dot normalizes the vectors")
+                        .takes_value(true)
+                        .possible_values(&["cosine", "dot"])
+                        .default_value("cosine"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the report as JSON")
+                        .takes_value(false),
+                ),
+        )
         .get_matches();
 
+    if let Some(bench_matches) = matches.subcommand_matches("bench") {
+        if let Err(e) = run_bench(bench_matches) {
+            eprintln!("bench failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(keygen_matches) = matches.subcommand_matches("keygen") {
+        let path = PathBuf::from(keygen_matches.value_of("out").unwrap());
+        match manifest::write_signing_key(&path) {
+            Ok(public_key) => println!("{}", hex::encode(public_key.to_bytes())),
+            Err(e) => {
+                eprintln!("keygen failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let grpc_port = matches
         .value_of("grpc_port")
         .unwrap()
@@ -150,35 +418,175 @@ async fn main() {
             .parse::<usize>()
             .unwrap();
 
-        //TODO get Distance from command line
-        // Create an instance of Hnsw with your desired parameters
-        let hnsw = Hnsw::new(
+        let kind = matches
+            .value_of("index_type")
+            .unwrap()
+            .parse::<IndexKind>()
+            .unwrap();
+        let ivf = IvfParams {
+            nlist: matches.value_of("nlist").unwrap().parse::<usize>().unwrap(),
+            nprobe: matches
+                .value_of("nprobe")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap(),
+            quantization: matches
+                .value_of("quantization")
+                .unwrap()
+                .parse::<Quantization>()
+                .unwrap(),
+        };
+        let disk = DiskParams {
+            beam_width: matches
+                .value_of("beam_width")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap(),
+            cache_size: matches
+                .value_of("cache_size")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap(),
+            ..DiskParams::default()
+        };
+        let data_dir = PathBuf::from(matches.value_of("data_dir").unwrap());
+        let shards_file = data_dir.join(SHARDS_FILE);
+
+        let distance = matches
+            .value_of("distance")
+            .unwrap()
+            .parse::<DistanceKind>()
+            .unwrap();
+
+        // Parameters of the index of each collection
+        let config = IndexConfig {
+            kind,
+            distance,
             max_nb_connection,
             max_elements,
             max_layer,
             ef_construction,
-            dist::DistCosine,
-        );
+            ivf,
+            disk,
+            data_dir,
+        };
+        if let Err(e) = config.check() {
+            eprintln!("invalid index parameters: {}", e);
+            std::process::exit(1);
+        }
 
-        // Initialize the unified VectorAPI with the Hnsw instance
-        let vector_api = Arc::new(VectorAPI::new(hnsw));
+        // Initialize the unified VectorAPI with an empty default collection
+        let mut vector_api = VectorAPI::new(config);
+        if let Some(source) = matches.value_of("block_source") {
+            vector_api = vector_api.with_block_source(source.parse::<BlockSource>().unwrap());
+        }
+        if let Some(sources) = matches.value_of("attach_sources") {
+            let sources = sources
+                .split(';')
+                .map(|source| source.parse::<BlockSource>().unwrap())
+                .collect();
+            vector_api = vector_api.with_attach_sources(sources);
+        }
+        if let Some(path) = matches.value_of("signing_key") {
+            vector_api =
+                vector_api.with_signing_key(manifest::read_signing_key(path.as_ref()).unwrap());
+        }
+        if let Some(path) = matches.value_of("trusted_keys") {
+            vector_api = vector_api.with_trust_list(TrustList::read(path.as_ref()).unwrap());
+        }
+        let vector_api = Arc::new(vector_api);
+        let snapshot = matches.is_present("snapshot");
+        if snapshot {
+            match vector_api.open_collections() {
+                Ok(nb_opened) => info!("Opened {} saved collections", nb_opened),
+                Err(e) => warn!("Cannot open saved collections: {}", e),
+            }
+        }
 
         let rest_addr = create_socket_addr(host, rest_port).unwrap();
         let grpc_addr = create_socket_addr(host, grpc_port).unwrap();
 
         let rest_api = Arc::clone(&vector_api);
         let grpc_api = Arc::clone(&vector_api);
+        let shard_timeout = matches
+            .value_of("shard_timeout")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        let coordinator =
+            match Coordinator::open(Duration::from_millis(shard_timeout), &shards_file) {
+                Ok(coordinator) => Arc::new(coordinator),
+                Err(e) => {
+                    eprintln!("cannot open the shard map {}: {}", shards_file.display(), e);
+                    std::process::exit(1);
+                }
+            };
+        let replication = Arc::new(Replication::default());
+        let node_address = matches
+            .value_of("node_address")
+            .map(String::from)
+            .unwrap_or_else(|| format!("http://127.0.0.1:{}", grpc_port));
+        let seeds: Vec<String> = matches
+            .value_of("seeds")
+            .map(|seeds| {
+                seeds
+                    .split(',')
+                    .filter(|seed| !seed.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let gossip_interval = matches
+            .value_of("gossip_interval")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        let membership = Arc::new(Membership::new(
+            &node_address,
+            seeds,
+            GossipConfig::new(Duration::from_millis(gossip_interval)),
+        ));
+        for follow in matches.values_of("follow").into_iter().flatten() {
+            let followed = follow
+                .split_once('=')
+                .ok_or_else(|| "expected collection=leader".into())
+                .and_then(|(collection, leader)| {
+                    replication.follow(Arc::clone(&vector_api), collection, leader)
+                });
+            match followed {
+                Ok(()) => info!("Following {}", follow),
+                Err(e) => warn!("Cannot follow {}: {}", follow, e),
+            }
+        }
 
         info!("Starting REST API on {}", rest_addr);
+        let rest_membership = Arc::clone(&membership);
         let rest_server = actix_web::rt::spawn(async move {
-            start_rest_api(rest_api, rest_addr).await.unwrap();
+            start_rest_api(rest_api, rest_membership, rest_addr)
+                .await
+                .unwrap();
         });
 
         info!("Starting gRPC server on {}", grpc_addr);
+        let grpc_membership = Arc::clone(&membership);
+        let gossip_coordinator = Arc::clone(&coordinator);
         let grpc_server = actix_web::rt::spawn(async move {
-            start_grpc(grpc_api, grpc_addr).await.unwrap();
+            start_grpc(
+                grpc_api,
+                coordinator,
+                replication,
+                grpc_membership,
+                grpc_addr,
+            )
+            .await
+            .unwrap();
         });
 
+        info!("Joining the cluster as {}", node_address);
+        actix_web::rt::spawn(
+            Arc::clone(&membership).run(Arc::clone(&vector_api), gossip_coordinator),
+        );
+
         let ctrl_c = signal::ctrl_c();
 
         select! {
@@ -192,6 +600,77 @@ async fn main() {
                 warn!("Ctrl+C received, shutting down...");
             }
         }
+        membership.leave().await;
+
+        if snapshot {
+            match vector_api.save_collections() {
+                Ok(nb_saved) => info!("Saved {} collections", nb_saved),
+                Err(e) => warn!("Cannot save collections: {}", e),
+            }
+        }
+    }
+
+    fn run_bench(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+        let parse = |name: &str| -> Result<usize, Box<dyn std::error::Error>> {
+            let value = matches.value_of(name).unwrap();
+            value
+                .parse::<usize>()
+                .map_err(|e| format!("invalid {} {}: {}", name, value, e).into())
+        };
+        let nb_query = parse("nb_query")?;
+        let config = BenchConfig {
+            max_nb_connection: parse("max_nb_connection")?,
+            ef_construction: parse("ef_construction")?,
+            max_layer: parse("max_layer")?,
+            knbn: parse("knbn")?,
+            ef_values: matches
+                .value_of("ef")
+                .unwrap()
+                .split(',')
+                .map(|ef| ef.trim().parse::<usize>())
+                .collect::<Result<Vec<usize>, _>>()?,
+        };
+
+        let (mut data, mut queries) = match matches.value_of("dataset") {
+            Some(path) => {
+                let mut data = bench::read_vectors(std::path::Path::new(path))?;
+                let queries = match matches.value_of("queries") {
+                    Some(path) => bench::read_vectors(std::path::Path::new(path))?,
+                    // the last vectors are held out so that queries are not in the index
+                    None => data.split_off(data.len().saturating_sub(nb_query)),
+                };
+                (data, queries)
+            }
+            None => {
+                let dim = parse("dim")?;
+                let queries = match matches.value_of("queries") {
+                    Some(path) => bench::read_vectors(std::path::Path::new(path))?,
+                    None => bench::gen_random_matrix_f32(dim, nb_query),
+                };
+                (
+                    bench::gen_random_matrix_f32(dim, parse("nb_elem")?),
+                    queries,
+                )
+            }
+        };
+        if data.is_empty() || queries.is_empty() {
+            return Err("bench needs vectors to index and queries".into());
+        }
+
+        let report = match matches.value_of("distance").unwrap() {
+            "dot" => {
+                data.iter_mut().for_each(|v| dist::l2_normalize(v));
+                queries.iter_mut().for_each(|v| dist::l2_normalize(v));
+                bench::run_bench::<dist::DistDot>(&data, &queries, &config)
+            }
+            _ => bench::run_bench::<dist::DistCosine>(&data, &queries, &config),
+        };
+        if matches.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            report.print_table();
+        }
+        Ok(())
     }
 
     fn create_socket_addr(host: &str, port: u16) -> Result<SocketAddr, AddrParseError> {
@@ -0,0 +1,360 @@
+//! Filter expressions on payloads, and the choice of how a filtered search is run.
+//!
+//! A filter is written in JSON, for instance
+//! `{"and": [{"in": {"field": "color", "values": ["red", "blue"]}}, {"range": {"field": "price", "lte": 20}}]}`,
+//! or for points within 20 km of Paris
+//! `{"geo_radius": {"field": "location", "center": {"lat": 48.85, "lon": 2.35}, "radius": 20000}}`.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::hnsw_graph::hnsw::DataId;
+use crate::payload::geo::{GeoBox, GeoPoint};
+use crate::payload::index::{field_values, range_key};
+use crate::payload::{field_at, FieldIndexes};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    /// the field, or an element of it when it is an array, equals one of values
+    In {
+        field: String,
+        values: Vec<Value>,
+    },
+    /// the field, or an element of it, is a number or a RFC 3339 timestamp within the bounds,
+    /// given as numbers or timestamps
+    Range {
+        field: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gt: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gte: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lt: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lte: Option<Value>,
+    },
+    /// the field is present and not null
+    Exists {
+        field: String,
+    },
+    /// the field, or an element of it, is a geo point within radius meters of center
+    GeoRadius {
+        field: String,
+        center: GeoPoint,
+        radius: f64,
+    },
+    /// the field, or an element of it, is a geo point within the box of the corners
+    GeoBoundingBox {
+        field: String,
+        top_left: GeoPoint,
+        bottom_right: GeoPoint,
+    },
+}
+
+/// the stricter of a strict and a loose bound, the greater one for lower bounds
+fn stricter(strict: &Option<Value>, loose: &Option<Value>, lower: bool) -> Bound<f64> {
+    let strict = strict.as_ref().and_then(range_key).map(Bound::Excluded);
+    let loose = loose.as_ref().and_then(range_key).map(Bound::Included);
+    match (strict, loose) {
+        (Some(Bound::Excluded(s)), Some(Bound::Included(l))) => {
+            if s == l || (s > l) == lower {
+                Bound::Excluded(s)
+            } else {
+                Bound::Included(l)
+            }
+        }
+        (Some(bound), _) | (None, Some(bound)) => bound,
+        (None, None) => Bound::Unbounded,
+    }
+}
+
+impl Filter {
+    /// parses a filter from JSON and checks its bounds
+    pub fn parse(json: &str) -> Result<Self, Box<dyn Error>> {
+        let filter: Filter = serde_json::from_str(json)?;
+        filter.validate()?;
+        Ok(filter)
+    }
+
+    /// checks that range bounds are numbers or timestamps, and geo areas
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Filter::And(filters) | Filter::Or(filters) => {
+                filters.iter().try_for_each(|f| f.validate())
+            }
+            Filter::Not(filter) => filter.validate(),
+            Filter::Range {
+                field,
+                gt,
+                gte,
+                lt,
+                lte,
+            } => {
+                for bound in [gt, gte, lt, lte].into_iter().flatten() {
+                    if range_key(bound).is_none() {
+                        return Err(format!(
+                            "range bound {} of {} is neither a number nor a RFC 3339 timestamp",
+                            bound, field
+                        )
+                        .into());
+                    }
+                }
+                Ok(())
+            }
+            Filter::GeoRadius { center, radius, .. } => {
+                center.validate()?;
+                if !(radius.is_finite() && *radius >= 0.) {
+                    return Err(format!("invalid geo radius {}", radius).into());
+                }
+                Ok(())
+            }
+            Filter::GeoBoundingBox {
+                top_left,
+                bottom_right,
+                ..
+            } => {
+                top_left.validate()?;
+                bottom_right.validate()?;
+                if top_left.lat < bottom_right.lat {
+                    return Err("the top left corner of a geo bounding box is south of its bottom right one".into());
+                }
+                Ok(())
+            }
+            Filter::In { .. } | Filter::Exists { .. } => Ok(()),
+        }
+    }
+
+    fn bounds(
+        gt: &Option<Value>,
+        gte: &Option<Value>,
+        lt: &Option<Value>,
+        lte: &Option<Value>,
+    ) -> (Bound<f64>, Bound<f64>) {
+        (stricter(gt, gte, true), stricter(lt, lte, false))
+    }
+
+    /// evaluates the filter on the payload of a point, None for a point without payload
+    pub fn matches(&self, payload: Option<&Value>) -> bool {
+        let field = |path: &str| {
+            payload
+                .and_then(|p| field_at(p, path))
+                .filter(|v| !v.is_null())
+        };
+        match self {
+            Filter::And(filters) => filters.iter().all(|f| f.matches(payload)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(payload)),
+            Filter::Not(filter) => !filter.matches(payload),
+            Filter::In {
+                field: path,
+                values,
+            } => field(path).is_some_and(|v| field_values(v).any(|v| values.contains(v))),
+            Filter::Range {
+                field: path,
+                gt,
+                gte,
+                lt,
+                lte,
+            } => {
+                let bounds = Filter::bounds(gt, gte, lt, lte);
+                field(path).is_some_and(|v| {
+                    field_values(v)
+                        .filter_map(range_key)
+                        .any(|x| std::ops::RangeBounds::contains(&bounds, &x))
+                })
+            }
+            Filter::Exists { field: path } => field(path).is_some(),
+            Filter::GeoRadius {
+                field: path,
+                center,
+                radius,
+            } => field(path).is_some_and(|v| {
+                field_values(v)
+                    .filter_map(GeoPoint::from_value)
+                    .any(|point| point.distance(center) <= *radius)
+            }),
+            Filter::GeoBoundingBox {
+                field: path,
+                top_left,
+                bottom_right,
+            } => {
+                let area = GeoBox::from_corners(top_left, bottom_right);
+                field(path).is_some_and(|v| {
+                    field_values(v)
+                        .filter_map(GeoPoint::from_value)
+                        .any(|point| area.contains(&point))
+                })
+            }
+        }
+    }
+
+    /// the points that may match, from the payload indexes, None when the indexes cannot
+    /// narrow the filter
+    pub(crate) fn candidates(&self, indexes: &FieldIndexes) -> Option<HashSet<DataId>> {
+        match self {
+            Filter::And(filters) => {
+                let mut sets: Vec<HashSet<DataId>> = filters
+                    .iter()
+                    .filter_map(|f| f.candidates(indexes))
+                    .collect();
+                sets.sort_unstable_by_key(|set| set.len());
+                let mut sets = sets.into_iter();
+                let smallest = sets.next()?;
+                Some(sets.fold(smallest, |acc, set| {
+                    acc.into_iter().filter(|d_id| set.contains(d_id)).collect()
+                }))
+            }
+            Filter::Or(filters) => filters.iter().try_fold(HashSet::new(), |mut acc, f| {
+                acc.extend(f.candidates(indexes)?);
+                Some(acc)
+            }),
+            Filter::In { field, values } => indexes.get(field)?.1.lookup(values),
+            Filter::Range {
+                field,
+                gt,
+                gte,
+                lt,
+                lte,
+            } => {
+                let (lower, upper) = Filter::bounds(gt, gte, lt, lte);
+                indexes.get(field)?.1.range(lower, upper)
+            }
+            Filter::GeoRadius {
+                field,
+                center,
+                radius,
+            } => indexes.get(field)?.1.geo(&GeoBox::around(center, *radius)),
+            Filter::GeoBoundingBox {
+                field,
+                top_left,
+                bottom_right,
+            } => indexes
+                .get(field)?
+                .1
+                .geo(&GeoBox::from_corners(top_left, bottom_right)),
+            Filter::Not(_) | Filter::Exists { .. } => None,
+        }
+    }
+}
+
+/// how a filtered search is run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchPlan {
+    /// the index is searched, skipping the points rejected by the filter
+    Traverse,
+    /// distances are computed to every accepted point
+    BruteForce,
+}
+
+/// chooses how to search nb_point points for ef neighbours among nb_accepted. When accepted
+/// points are spread evenly, a traversal computes about ef * nb_point / nb_accepted distances
+/// before finding ef of them, while a brute force computes nb_accepted.
+pub fn plan(nb_accepted: usize, nb_point: usize, ef: usize) -> SearchPlan {
+    if nb_accepted.saturating_mul(nb_accepted) <= ef.saturating_mul(nb_point) {
+        SearchPlan::BruteForce
+    } else {
+        SearchPlan::Traverse
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::index::{FieldIndex, PayloadIndexType};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::parse(
+            r#"{"and": [
+                {"in": {"field": "tags", "values": ["sale"]}},
+                {"range": {"field": "price", "gte": 10, "lt": 20}},
+                {"not": {"exists": {"field": "sold"}}}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(filter.matches(Some(&json!({"tags": ["new", "sale"], "price": 10}))));
+        assert!(!filter.matches(Some(&json!({"tags": ["sale"], "price": 20}))));
+        assert!(!filter.matches(Some(&json!({"tags": ["sale"], "price": 15, "sold": true}))));
+        assert!(!filter.matches(None));
+
+        let since =
+            Filter::parse(r#"{"range": {"field": "at", "gt": "2023-01-01T00:00:00Z"}}"#).unwrap();
+        assert!(since.matches(Some(&json!({"at": "2023-05-01T10:00:00+01:00"}))));
+        assert!(!since.matches(Some(&json!({"at": "2022-05-01T10:00:00Z"}))));
+        assert!(Filter::parse(r#"{"range": {"field": "at", "gt": "yesterday"}}"#).is_err());
+
+        let near_paris = Filter::parse(
+            r#"{"geo_radius": {"field": "shop", "center": {"lat": 48.8566, "lon": 2.3522}, "radius": 20000}}"#,
+        )
+        .unwrap();
+        assert!(near_paris.matches(Some(&json!({"shop": {"lat": 48.80, "lon": 2.13}}))));
+        assert!(near_paris.matches(Some(
+            &json!({"shop": [{"lat": 45.76, "lon": 4.84}, {"lat": 48.9, "lon": 2.4}]})
+        )));
+        assert!(!near_paris.matches(Some(&json!({"shop": {"lat": 45.76, "lon": 4.84}}))));
+        let pacific = Filter::parse(
+            r#"{"geo_bounding_box": {"field": "at", "top_left": {"lat": 10, "lon": 170}, "bottom_right": {"lat": -10, "lon": -170}}}"#,
+        )
+        .unwrap();
+        assert!(pacific.matches(Some(&json!({"at": {"lat": 0, "lon": -175}}))));
+        assert!(!pacific.matches(Some(&json!({"at": {"lat": 0, "lon": 0}}))));
+        assert!(Filter::parse(
+            r#"{"geo_radius": {"field": "at", "center": {"lat": 95, "lon": 0}, "radius": 10}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let mut color = FieldIndex::new(PayloadIndexType::Keyword);
+        let mut price = FieldIndex::new(PayloadIndexType::Numeric);
+        for d_id in 0..100 {
+            color.insert(d_id, &json!(if d_id % 2 == 0 { "red" } else { "blue" }));
+            price.insert(d_id, &json!(d_id));
+        }
+        let indexes = HashMap::from([
+            ("color".to_string(), (PayloadIndexType::Keyword, color)),
+            ("price".to_string(), (PayloadIndexType::Numeric, price)),
+        ]);
+        let red_and_cheap = Filter::And(vec![
+            Filter::In {
+                field: "color".to_string(),
+                values: vec![json!("red")],
+            },
+            Filter::Range {
+                field: "price".to_string(),
+                gt: None,
+                gte: None,
+                lt: Some(json!(10)),
+                lte: Some(json!(10)),
+            },
+            Filter::Exists {
+                field: "color".to_string(),
+            },
+        ]);
+        assert_eq!(
+            red_and_cheap.candidates(&indexes),
+            Some(HashSet::from([0, 2, 4, 6, 8]))
+        );
+        let unindexed = Filter::Or(vec![
+            red_and_cheap,
+            Filter::In {
+                field: "size".to_string(),
+                values: vec![json!("xl")],
+            },
+        ]);
+        assert_eq!(unindexed.candidates(&indexes), None);
+
+        assert_eq!(plan(100, 1_000_000, 100), SearchPlan::BruteForce);
+        assert_eq!(plan(500_000, 1_000_000, 100), SearchPlan::Traverse);
+    }
+}
@@ -0,0 +1,187 @@
+//! Geographic points in payloads, distances on the Earth, and the areas of geo filters.
+//!
+//! A geo point is a payload field `{"lat": 48.85, "lon": 2.35}` in degrees.
+
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// mean radius of the Earth in meters
+pub const EARTH_RADIUS: f64 = 6_371_008.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl GeoPoint {
+    /// the point of a payload value, None when it is not an object with a valid lat and lon
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let point = GeoPoint {
+            lat: value.get("lat")?.as_f64()?,
+            lon: value.get("lon")?.as_f64()?,
+        };
+        point.validate().ok().map(|_| point)
+    }
+
+    /// checks that the latitude is within [-90, 90] and the longitude within [-180, 180]
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if !(-90. ..=90.).contains(&self.lat) || !(-180. ..=180.).contains(&self.lon) {
+            return Err(format!("invalid geo point lat {} lon {}", self.lat, self.lon).into());
+        }
+        Ok(())
+    }
+
+    /// great circle distance to other in meters, by the haversine formula
+    pub fn distance(&self, other: &GeoPoint) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let half_dlat = (lat2 - lat1) / 2.;
+        let half_dlon = (other.lon - self.lon).to_radians() / 2.;
+        let h = half_dlat.sin().powi(2) + lat1.cos() * lat2.cos() * half_dlon.sin().powi(2);
+        2. * EARTH_RADIUS * h.sqrt().min(1.).asin()
+    }
+}
+
+/// An area between two latitudes and two longitudes in degrees. A box whose west longitude
+/// is greater than its east one crosses the antimeridian.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoBox {
+    pub south: f64,
+    pub north: f64,
+    pub west: f64,
+    pub east: f64,
+}
+
+impl GeoBox {
+    /// the box with the corners of a bounding box filter
+    pub fn from_corners(top_left: &GeoPoint, bottom_right: &GeoPoint) -> Self {
+        GeoBox {
+            south: bottom_right.lat,
+            north: top_left.lat,
+            west: top_left.lon,
+            east: bottom_right.lon,
+        }
+    }
+
+    /// the smallest box holding the circle of radius meters around center
+    pub fn around(center: &GeoPoint, radius: f64) -> Self {
+        let angle = radius / EARTH_RADIUS;
+        let dlat = angle.to_degrees();
+        let (south, north) = (center.lat - dlat, center.lat + dlat);
+        let whole = GeoBox {
+            south: south.max(-90.),
+            north: north.min(90.),
+            west: -180.,
+            east: 180.,
+        };
+        // circles around a pole, or wider than the Earth, span every longitude
+        if south <= -90. || north >= 90. || angle >= std::f64::consts::PI {
+            return whole;
+        }
+        let sin_dlon = angle.sin() / center.lat.to_radians().cos();
+        if sin_dlon >= 1. {
+            return whole;
+        }
+        let dlon = sin_dlon.asin().to_degrees();
+        let wrap = |lon: f64| {
+            if lon < -180. {
+                lon + 360.
+            } else if lon > 180. {
+                lon - 360.
+            } else {
+                lon
+            }
+        };
+        GeoBox {
+            west: wrap(center.lon - dlon),
+            east: wrap(center.lon + dlon),
+            ..whole
+        }
+    }
+
+    pub fn contains(&self, point: &GeoPoint) -> bool {
+        let lon_within = if self.west <= self.east {
+            self.west <= point.lon && point.lon <= self.east
+        } else {
+            self.west <= point.lon || point.lon <= self.east
+        };
+        self.south <= point.lat && point.lat <= self.north && lon_within
+    }
+
+    /// the ranges of longitudes of the box, two when it crosses the antimeridian
+    pub fn lon_ranges(&self) -> Vec<(f64, f64)> {
+        if self.west <= self.east {
+            vec![(self.west, self.east)]
+        } else {
+            vec![(self.west, 180.), (-180., self.east)]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_geo() {
+        let paris = GeoPoint {
+            lat: 48.8566,
+            lon: 2.3522,
+        };
+        let lyon = GeoPoint {
+            lat: 45.764,
+            lon: 4.8357,
+        };
+        assert!((paris.distance(&lyon) - 391_500.).abs() < 2_000.);
+        assert_eq!(
+            GeoPoint::from_value(&json!({"lat": 48.8566, "lon": 2.3522})),
+            Some(paris)
+        );
+        assert_eq!(GeoPoint::from_value(&json!({"lat": 91, "lon": 0})), None);
+        assert_eq!(GeoPoint::from_value(&json!("48.8,2.3")), None);
+
+        // the box around a circle holds the points of the circle
+        for center in [
+            paris,
+            GeoPoint {
+                lat: -33.9,
+                lon: 179.9,
+            },
+            GeoPoint { lat: 89.9, lon: 0. },
+        ] {
+            let area = GeoBox::around(&center, 20_000.);
+            for bearing in 0..36 {
+                let bearing = (bearing as f64 * 10.).to_radians();
+                let angle = 19_999. / EARTH_RADIUS;
+                let lat1 = center.lat.to_radians();
+                let lat =
+                    (lat1.sin() * angle.cos() + lat1.cos() * angle.sin() * bearing.cos()).asin();
+                let dlon = (bearing.sin() * angle.sin() * lat1.cos())
+                    .atan2(angle.cos() - lat1.sin() * lat.sin());
+                let lon = (center.lon.to_radians() + dlon).to_degrees();
+                let lon = if lon > 180. { lon - 360. } else { lon };
+                let point = GeoPoint {
+                    lat: lat.to_degrees(),
+                    lon,
+                };
+                assert!(point.distance(&center) < 20_000.);
+                assert!(area.contains(&point), "{:?} {:?}", area, point);
+            }
+        }
+        assert_eq!(
+            GeoBox::around(
+                &GeoPoint {
+                    lat: -33.9,
+                    lon: 179.9
+                },
+                20_000.
+            )
+            .lon_ranges()
+            .len(),
+            2
+        );
+    }
+}
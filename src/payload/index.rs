@@ -0,0 +1,351 @@
+//! Indexes of payload fields, giving the points that may satisfy a condition of a filter
+//! without reading their payloads.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::hnsw_graph::hnsw::DataId;
+use crate::payload::geo::{GeoBox, GeoPoint};
+use crate::payload::text::Bm25;
+
+/// size in degrees of the cells of geo indexes, about 11 km of latitude
+const GEO_CELL_DEGREES: f64 = 0.1;
+
+/// the values indexed for a payload field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadIndexType {
+    /// strings matched exactly
+    Keyword,
+    /// numbers, for ranges
+    Numeric,
+    Bool,
+    /// RFC 3339 strings or milliseconds since the epoch, for ranges
+    Timestamp,
+    /// geo points, for geo radius and bounding box filters
+    Geo,
+    /// strings split in tokens, for keyword searches ranked by BM25
+    Text,
+}
+
+impl fmt::Display for PayloadIndexType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadIndexType::Keyword => write!(f, "keyword"),
+            PayloadIndexType::Numeric => write!(f, "numeric"),
+            PayloadIndexType::Bool => write!(f, "bool"),
+            PayloadIndexType::Timestamp => write!(f, "timestamp"),
+            PayloadIndexType::Geo => write!(f, "geo"),
+            PayloadIndexType::Text => write!(f, "text"),
+        }
+    }
+}
+
+impl FromStr for PayloadIndexType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "keyword" => Ok(PayloadIndexType::Keyword),
+            "numeric" => Ok(PayloadIndexType::Numeric),
+            "bool" => Ok(PayloadIndexType::Bool),
+            "timestamp" => Ok(PayloadIndexType::Timestamp),
+            "geo" => Ok(PayloadIndexType::Geo),
+            "text" => Ok(PayloadIndexType::Text),
+            _ => Err(format!(
+                "unknown payload index type {}, expected keyword, numeric, bool, timestamp, geo or text",
+                s
+            )),
+        }
+    }
+}
+
+/// the position of a value in ranges: numbers, and RFC 3339 timestamps as milliseconds
+/// since the epoch. Other values have none.
+pub fn range_key(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .ok()
+            .map(|t| t.timestamp_millis() as f64),
+        _ => None,
+    }
+}
+
+/// the values of a field, the elements of an array being values of their own
+pub(crate) fn field_values(value: &Value) -> impl Iterator<Item = &Value> {
+    match value {
+        Value::Array(elements) => elements.iter(),
+        _ => std::slice::from_ref(value).iter(),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RangeKey(f64);
+
+impl PartialEq for RangeKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RangeKey {}
+
+impl PartialOrd for RangeKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RangeKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// ids by indexed value. The ids returned for a condition are a superset of the points
+/// satisfying it, the filter is still checked on their payloads.
+pub(crate) enum FieldIndex {
+    Keyword(HashMap<String, HashSet<DataId>>),
+    /// points with false then true
+    Bool([HashSet<DataId>; 2]),
+    Range(BTreeMap<RangeKey, HashSet<DataId>>),
+    /// points by cell of a grid of latitudes and longitudes
+    Geo(BTreeMap<(i32, i32), HashSet<DataId>>),
+    /// ranks points for keyword queries, does not narrow filters
+    Text(Bm25),
+}
+
+/// the grid cell of a latitude or a longitude
+fn geo_cell(degrees: f64) -> i32 {
+    (degrees / GEO_CELL_DEGREES).floor() as i32
+}
+
+impl FieldIndex {
+    pub(crate) fn new(index_type: PayloadIndexType) -> Self {
+        match index_type {
+            PayloadIndexType::Keyword => FieldIndex::Keyword(HashMap::new()),
+            PayloadIndexType::Bool => FieldIndex::Bool(Default::default()),
+            PayloadIndexType::Numeric | PayloadIndexType::Timestamp => {
+                FieldIndex::Range(BTreeMap::new())
+            }
+            PayloadIndexType::Geo => FieldIndex::Geo(BTreeMap::new()),
+            PayloadIndexType::Text => FieldIndex::Text(Bm25::default()),
+        }
+    }
+
+    /// indexes d_id under the values of its field, values of other types are skipped
+    pub(crate) fn insert(&mut self, d_id: DataId, value: &Value) {
+        // the strings of a field make up one text
+        if let FieldIndex::Text(bm25) = self {
+            return bm25.insert(d_id, value);
+        }
+        for value in field_values(value) {
+            match (&mut *self, value) {
+                (FieldIndex::Keyword(ids), Value::String(s)) => {
+                    ids.entry(s.clone()).or_default().insert(d_id);
+                }
+                (FieldIndex::Bool(ids), Value::Bool(b)) => {
+                    ids[*b as usize].insert(d_id);
+                }
+                (FieldIndex::Range(ids), value) => {
+                    if let Some(key) = range_key(value) {
+                        ids.entry(RangeKey(key)).or_default().insert(d_id);
+                    }
+                }
+                (FieldIndex::Geo(ids), value) => {
+                    if let Some(point) = GeoPoint::from_value(value) {
+                        let cell = (geo_cell(point.lat), geo_cell(point.lon));
+                        ids.entry(cell).or_default().insert(d_id);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// removes d_id from the values of its field
+    pub(crate) fn remove(&mut self, d_id: DataId, value: &Value) {
+        if let FieldIndex::Text(bm25) = self {
+            return bm25.remove(d_id, value);
+        }
+        for value in field_values(value) {
+            match (&mut *self, value) {
+                (FieldIndex::Keyword(ids), Value::String(s)) => {
+                    if let Some(set) = ids.get_mut(s) {
+                        set.remove(&d_id);
+                        if set.is_empty() {
+                            ids.remove(s);
+                        }
+                    }
+                }
+                (FieldIndex::Bool(ids), Value::Bool(b)) => {
+                    ids[*b as usize].remove(&d_id);
+                }
+                (FieldIndex::Range(ids), value) => {
+                    if let Some(key) = range_key(value).map(RangeKey) {
+                        if let Some(set) = ids.get_mut(&key) {
+                            set.remove(&d_id);
+                            if set.is_empty() {
+                                ids.remove(&key);
+                            }
+                        }
+                    }
+                }
+                (FieldIndex::Geo(ids), value) => {
+                    if let Some(point) = GeoPoint::from_value(value) {
+                        let cell = (geo_cell(point.lat), geo_cell(point.lon));
+                        if let Some(set) = ids.get_mut(&cell) {
+                            set.remove(&d_id);
+                            if set.is_empty() {
+                                ids.remove(&cell);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// points whose field may equal one of values, None when a value is not of the
+    /// indexed type
+    pub(crate) fn lookup(&self, values: &[Value]) -> Option<HashSet<DataId>> {
+        let mut found = HashSet::new();
+        for value in values {
+            let ids = match (self, value) {
+                (FieldIndex::Keyword(ids), Value::String(s)) => ids.get(s),
+                (FieldIndex::Bool(ids), Value::Bool(b)) => Some(&ids[*b as usize]),
+                (FieldIndex::Range(ids), value) => ids.get(&RangeKey(range_key(value)?)),
+                _ => return None,
+            };
+            found.extend(ids.into_iter().flatten());
+        }
+        Some(found)
+    }
+
+    /// points with a value of their field between lower and upper, None for indexes
+    /// without order
+    pub(crate) fn range(&self, lower: Bound<f64>, upper: Bound<f64>) -> Option<HashSet<DataId>> {
+        let ids = match self {
+            FieldIndex::Range(ids) => ids,
+            _ => return None,
+        };
+        let value = |bound: Bound<f64>| match bound {
+            Bound::Included(x) | Bound::Excluded(x) => Some(x),
+            Bound::Unbounded => None,
+        };
+        // BTreeMap::range panics on empty ranges
+        if let (Some(low), Some(high)) = (value(lower), value(upper)) {
+            let excluded =
+                matches!(lower, Bound::Excluded(_)) || matches!(upper, Bound::Excluded(_));
+            if low > high || (low == high && excluded) || low.is_nan() || high.is_nan() {
+                return Some(HashSet::new());
+            }
+        }
+        Some(
+            ids.range((lower.map(RangeKey), upper.map(RangeKey)))
+                .flat_map(|(_, set)| set.iter().copied())
+                .collect(),
+        )
+    }
+
+    /// points in the cells overlapping area, None for indexes of other types
+    pub(crate) fn geo(&self, area: &GeoBox) -> Option<HashSet<DataId>> {
+        let ids = match self {
+            FieldIndex::Geo(ids) => ids,
+            _ => return None,
+        };
+        let mut found = HashSet::new();
+        if area.south > area.north {
+            return Some(found);
+        }
+        let lon_cells: Vec<(i32, i32)> = area
+            .lon_ranges()
+            .into_iter()
+            .map(|(west, east)| (geo_cell(west), geo_cell(east)))
+            .collect();
+        for lat_cell in geo_cell(area.south)..=geo_cell(area.north) {
+            for (west, east) in &lon_cells {
+                for (_, set) in ids.range((lat_cell, *west)..=(lat_cell, *east)) {
+                    found.extend(set);
+                }
+            }
+        }
+        Some(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_field_indexes() {
+        let mut keyword = FieldIndex::new(PayloadIndexType::Keyword);
+        keyword.insert(1, &json!("red"));
+        keyword.insert(2, &json!(["red", "blue"]));
+        keyword.insert(3, &json!(7));
+        assert_eq!(keyword.lookup(&[json!("red")]), Some(HashSet::from([1, 2])));
+        assert_eq!(keyword.lookup(&[json!(7)]), None);
+        keyword.remove(2, &json!(["red", "blue"]));
+        assert_eq!(
+            keyword.lookup(&[json!("red"), json!("blue")]),
+            Some(HashSet::from([1]))
+        );
+
+        let mut timestamp = FieldIndex::new(PayloadIndexType::Timestamp);
+        timestamp.insert(1, &json!("2023-06-01T00:00:00Z"));
+        timestamp.insert(2, &json!("2023-06-02T12:00:00+02:00"));
+        timestamp.insert(3, &json!(0));
+        let june_2 = range_key(&json!("2023-06-02T00:00:00Z")).unwrap();
+        assert_eq!(
+            timestamp.range(Bound::Included(june_2), Bound::Unbounded),
+            Some(HashSet::from([2]))
+        );
+        assert_eq!(
+            timestamp.range(Bound::Excluded(0.), Bound::Excluded(june_2)),
+            Some(HashSet::from([1]))
+        );
+        assert_eq!(
+            timestamp.range(Bound::Excluded(1.), Bound::Excluded(1.)),
+            Some(HashSet::new())
+        );
+        assert_eq!(keyword.range(Bound::Unbounded, Bound::Unbounded), None);
+
+        let mut geo = FieldIndex::new(PayloadIndexType::Geo);
+        geo.insert(1, &json!({"lat": 48.8566, "lon": 2.3522}));
+        geo.insert(
+            2,
+            &json!([{"lat": 45.764, "lon": 4.8357}, {"lat": -33.9, "lon": 179.95}]),
+        );
+        geo.insert(3, &json!({"lat": 48.9}));
+        let paris = GeoBox::around(
+            &GeoPoint {
+                lat: 48.86,
+                lon: 2.35,
+            },
+            20_000.,
+        );
+        assert_eq!(geo.geo(&paris), Some(HashSet::from([1])));
+        let across = GeoBox::around(
+            &GeoPoint {
+                lat: -33.9,
+                lon: -179.95,
+            },
+            20_000.,
+        );
+        assert_eq!(geo.geo(&across), Some(HashSet::from([2])));
+        geo.remove(1, &json!({"lat": 48.8566, "lon": 2.3522}));
+        assert_eq!(geo.geo(&paris), Some(HashSet::new()));
+        assert_eq!(geo.lookup(&[json!("paris")]), None);
+    }
+}
@@ -0,0 +1,425 @@
+//! JSON payloads attached to the points of a collection.
+//!
+//! A payload is any JSON value given with a vector at insertion. It is kept beside the index,
+//! whatever its kind, persisted with it, and returned with search results, whole or reduced
+//! to some of its fields. Searches can be restricted by a filter on payloads, helped by
+//! indexes of payload fields, and text fields can be indexed for keyword searches.
+
+pub mod filter;
+pub mod geo;
+pub mod index;
+pub mod text;
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use parking_lot::{RwLock, RwLockReadGuard};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::hnsw_graph::hnsw::DataId;
+use crate::ipfs_storage::block::{Block, BlockStore, Link};
+
+use self::filter::Filter;
+use self::index::{FieldIndex, PayloadIndexType};
+
+/// number of payloads stored in a block
+const CHUNK_SIZE: usize = 1024;
+
+/// number of payloads on which a filter is evaluated to estimate its selectivity
+const SAMPLE_SIZE: usize = 256;
+
+#[derive(Serialize, Deserialize)]
+struct PayloadChunk {
+    entries: Vec<(DataId, Value)>,
+}
+
+/// Payloads by point id, and the indexes of some of their fields.
+/// Locks are taken in the order payloads, indexes.
+#[derive(Default)]
+pub struct PayloadStore {
+    payloads: RwLock<HashMap<DataId, Value>>,
+    indexes: RwLock<FieldIndexes>,
+}
+
+/// the index of each indexed field, by path
+pub(crate) type FieldIndexes = HashMap<String, (PayloadIndexType, FieldIndex)>;
+
+/// A filter ready to be evaluated on the points of a store during a search.
+/// It holds a read lock on the payloads until dropped.
+pub struct PreparedFilter<'a> {
+    filter: &'a Filter,
+    /// points that may match, None when the indexes cannot narrow the filter
+    candidates: Option<HashSet<DataId>>,
+    payloads: RwLockReadGuard<'a, HashMap<DataId, Value>>,
+}
+
+impl<'a> PreparedFilter<'a> {
+    pub fn accepts(&self, d_id: DataId) -> bool {
+        self.candidates.as_ref().is_none_or(|c| c.contains(&d_id))
+            && self.filter.matches(self.payloads.get(&d_id))
+    }
+
+    /// number of points accepted among nb_point, estimated on a sample of the candidates
+    pub fn estimate_nb_accepted(&self, nb_point: usize) -> usize {
+        let (nb_sampled, nb_matched, population) = match &self.candidates {
+            Some(candidates) => {
+                let sample = candidates.iter().take(SAMPLE_SIZE);
+                let nb_matched = sample.clone().filter(|d_id| self.accepts(**d_id)).count();
+                (sample.count(), nb_matched, candidates.len())
+            }
+            None => {
+                let sample = self.payloads.values().take(SAMPLE_SIZE);
+                let nb_matched = sample
+                    .clone()
+                    .filter(|p| self.filter.matches(Some(p)))
+                    .count();
+                (sample.count(), nb_matched, self.payloads.len())
+            }
+        };
+        let mut nb_accepted = if nb_sampled == 0 {
+            0
+        } else {
+            population * nb_matched / nb_sampled
+        };
+        // points without payload pass filters such as not exists
+        if self.candidates.is_none() && self.filter.matches(None) {
+            nb_accepted += nb_point.saturating_sub(self.payloads.len());
+        }
+        nb_accepted.min(nb_point)
+    }
+}
+
+impl PayloadStore {
+    pub fn new() -> Self {
+        PayloadStore::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.payloads.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// sets the payload of d_id, a null payload removes it
+    pub fn set(&self, d_id: DataId, payload: Value) {
+        let mut payloads = self.payloads.write();
+        let mut indexes = self.indexes.write();
+        if let Some(old) = payloads.remove(&d_id) {
+            Self::unindex(&mut indexes, d_id, &old);
+        }
+        if !payload.is_null() {
+            for (path, (_, index)) in indexes.iter_mut() {
+                if let Some(value) = field_at(&payload, path) {
+                    index.insert(d_id, value);
+                }
+            }
+            payloads.insert(d_id, payload);
+        }
+    }
+
+    /// removes d_id from the indexes of the fields of its payload
+    fn unindex(indexes: &mut FieldIndexes, d_id: DataId, payload: &Value) {
+        for (path, (_, index)) in indexes.iter_mut() {
+            if let Some(value) = field_at(payload, path) {
+                index.remove(d_id, value);
+            }
+        }
+    }
+
+    pub fn get(&self, d_id: DataId) -> Option<Value> {
+        self.payloads.read().get(&d_id).cloned()
+    }
+
+    /// the payload of d_id reduced to the fields at paths, see project
+    pub fn get_projected(&self, d_id: DataId, paths: &[String]) -> Option<Value> {
+        self.payloads
+            .read()
+            .get(&d_id)
+            .map(|payload| project(payload, paths))
+    }
+
+    pub fn remove(&self, ids: &[DataId]) {
+        let mut payloads = self.payloads.write();
+        let mut indexes = self.indexes.write();
+        for d_id in ids {
+            if let Some(old) = payloads.remove(d_id) {
+                Self::unindex(&mut indexes, *d_id, &old);
+            }
+        }
+    }
+
+    /// indexes the values of the field at path of all payloads, replacing the index of
+    /// the field if there is one
+    pub fn create_index(&self, path: &str, index_type: PayloadIndexType) {
+        let payloads = self.payloads.read();
+        let mut index = FieldIndex::new(index_type);
+        for (d_id, payload) in payloads.iter() {
+            if let Some(value) = field_at(payload, path) {
+                index.insert(*d_id, value);
+            }
+        }
+        self.indexes
+            .write()
+            .insert(path.to_string(), (index_type, index));
+    }
+
+    /// the indexed fields and the type of their index, sorted by field
+    pub fn index_schema(&self) -> Vec<(String, PayloadIndexType)> {
+        let mut schema: Vec<(String, PayloadIndexType)> = self
+            .indexes
+            .read()
+            .iter()
+            .map(|(path, (index_type, _))| (path.clone(), *index_type))
+            .collect();
+        schema.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        schema
+    }
+
+    /// computes the candidates of filter from the indexes
+    pub fn prepare<'a>(&'a self, filter: &'a Filter) -> PreparedFilter<'a> {
+        let payloads = self.payloads.read();
+        let candidates = filter.candidates(&self.indexes.read());
+        PreparedFilter {
+            filter,
+            candidates,
+            payloads,
+        }
+    }
+
+    /// the knbn points of best BM25 score for the keywords of query in the text indexed at
+    /// path, among those matching filter if there is one, best first
+    pub fn text_search(
+        &self,
+        path: &str,
+        query: &str,
+        knbn: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<(DataId, f32)>, Box<dyn Error>> {
+        let prepared = filter.map(|filter| self.prepare(filter));
+        let accepts = |d_id: DataId| prepared.as_ref().is_none_or(|p| p.accepts(d_id));
+        match self.indexes.read().get(path) {
+            Some((_, FieldIndex::Text(bm25))) => Ok(bm25.search(query, knbn, &accepts)),
+            _ => Err(format!("payload field {} has no text index", path).into()),
+        }
+    }
+
+    /// payloads sorted by id, so that persisting is deterministic
+    fn entries(&self) -> Vec<(DataId, Value)> {
+        let mut entries: Vec<(DataId, Value)> = self
+            .payloads
+            .read()
+            .iter()
+            .map(|(d_id, payload)| (*d_id, payload.clone()))
+            .collect();
+        entries.sort_unstable_by_key(|(d_id, _)| *d_id);
+        entries
+    }
+
+    /// writes the payloads to store in chunks, returns the links to the chunks
+    pub fn persist(&self, store: &dyn BlockStore) -> Result<Vec<Link>, Box<dyn Error>> {
+        let mut links = Vec::new();
+        for entries in self.entries().chunks(CHUNK_SIZE) {
+            let block = Block::encode(&PayloadChunk {
+                entries: entries.to_vec(),
+            })?;
+            links.push(Link(block.cid));
+            store.put(block)?;
+        }
+        Ok(links)
+    }
+
+    /// reads the payloads persisted in chunks
+    pub fn load(links: &[Link], store: &dyn BlockStore) -> Result<Self, Box<dyn Error>> {
+        let mut payloads = HashMap::new();
+        for link in links {
+            let chunk: PayloadChunk = store.get_block(&link.0)?.decode()?;
+            payloads.extend(chunk.entries);
+        }
+        Ok(PayloadStore {
+            payloads: RwLock::new(payloads),
+            ..Default::default()
+        })
+    }
+
+    /// the payloads of a chunk written by persist
+    pub(crate) fn chunk_entries(chunk: &Block) -> Result<Vec<(DataId, Value)>, Box<dyn Error>> {
+        Ok(chunk.decode::<PayloadChunk>()?.entries)
+    }
+
+    /// writes the payloads to a CBOR file
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_cbor::to_writer(&mut writer, &self.entries())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// reads payloads written by save
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let entries: Vec<(DataId, Value)> =
+            serde_cbor::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(PayloadStore {
+            payloads: RwLock::new(entries.into_iter().collect()),
+            ..Default::default()
+        })
+    }
+}
+
+/// the field of payload at a dotted path as "image.url"
+pub fn field_at<'a>(payload: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(payload, |value, key| value.get(key))
+}
+
+/// reduces payload to the fields at paths, dotted paths selecting nested fields as in
+/// "image.url". Fields absent from payload are skipped, no path keeps the whole payload.
+pub fn project(payload: &Value, paths: &[String]) -> Value {
+    if paths.is_empty() {
+        return payload.clone();
+    }
+    let mut projected = Value::Object(Map::new());
+    for path in paths {
+        if let Some(field) = field_at(payload, path) {
+            let keys: Vec<&str> = path.split('.').collect();
+            insert_at(&mut projected, &keys, field.clone());
+        }
+    }
+    projected
+}
+
+/// inserts field in target at the path given by keys, creating the intermediate objects
+fn insert_at(target: &mut Value, keys: &[&str], field: Value) {
+    let (last, parents) = match keys.split_last() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut target = target;
+    for key in parents {
+        target = match target {
+            Value::Object(map) => map
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Object(Map::new())),
+            _ => return,
+        };
+    }
+    if let Value::Object(map) = target {
+        map.insert(last.to_string(), field);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipfs_storage::block::MemoryBlockStore;
+    use serde_json::json;
+
+    #[test]
+    fn test_project() {
+        let payload = json!({
+            "caption": "a cat",
+            "image": {"url": "http://a/b.png", "width": 640},
+            "tags": ["cat", "pet"],
+        });
+        assert_eq!(project(&payload, &[]), payload);
+        let fields = vec![
+            "image.url".to_string(),
+            "tags".to_string(),
+            "missing.x".to_string(),
+        ];
+        assert_eq!(
+            project(&payload, &fields),
+            json!({"image": {"url": "http://a/b.png"}, "tags": ["cat", "pet"]})
+        );
+    }
+
+    #[test]
+    fn test_indexed_filter() {
+        let payloads = PayloadStore::new();
+        for d_id in 0..1000 {
+            payloads.set(
+                d_id,
+                json!({ "shop": { "city": if d_id < 10 { "Lyon" } else { "Paris" } } }),
+            );
+        }
+        payloads.create_index("shop.city", PayloadIndexType::Keyword);
+        // updates and removals reach the index
+        payloads.set(0, json!({ "shop": { "city": "Paris" } }));
+        payloads.set(1, Value::Null);
+        payloads.remove(&[2]);
+        payloads.set(2000, json!({ "shop": { "city": "Lyon" } }));
+
+        let in_lyon =
+            Filter::parse(r#"{"in": {"field": "shop.city", "values": ["Lyon"]}}"#).unwrap();
+        let prepared = payloads.prepare(&in_lyon);
+        let expected: HashSet<DataId> = (3..10).chain([2000]).collect();
+        assert_eq!(prepared.candidates, Some(expected.clone()));
+        assert!(prepared.accepts(3) && !prepared.accepts(0) && !prepared.accepts(1));
+        assert_eq!(prepared.estimate_nb_accepted(2000), 8);
+        drop(prepared);
+
+        let elsewhere = Filter::Not(Box::new(in_lyon));
+        let prepared = payloads.prepare(&elsewhere);
+        assert_eq!(prepared.candidates, None);
+        // 990 Paris payloads and the 2 points without payload, up to the sampling error
+        let estimate = prepared.estimate_nb_accepted(1000);
+        assert!(estimate > 900, "{}", estimate);
+        assert_eq!(
+            payloads.index_schema(),
+            vec![("shop.city".to_string(), PayloadIndexType::Keyword)]
+        );
+    }
+
+    #[test]
+    fn test_text_search() {
+        let payloads = PayloadStore::new();
+        payloads.set(1, json!({"name": "Trail shoes XJ-200", "stock": 3}));
+        payloads.set(2, json!({"name": "Trail shoes XJ-300", "stock": 0}));
+        payloads.set(3, json!({"name": "Road shoes"}));
+        assert!(payloads.text_search("name", "xj-200", 10, None).is_err());
+        payloads.create_index("name", PayloadIndexType::Text);
+        let found = payloads.text_search("name", "XJ-200", 10, None).unwrap();
+        assert_eq!(found[0].0, 1);
+        let in_stock = Filter::parse(r#"{"range": {"field": "stock", "gt": 0}}"#).unwrap();
+        let found = payloads
+            .text_search("name", "trail shoes", 10, Some(&in_stock))
+            .unwrap();
+        assert_eq!(
+            found.iter().map(|(d_id, _)| *d_id).collect::<Vec<_>>(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_persist_and_load() {
+        let payloads = PayloadStore::new();
+        for d_id in 0..3000 {
+            payloads.set(d_id, json!({ "rank": d_id, "even": d_id % 2 == 0 }));
+        }
+        payloads.set(7, Value::Null);
+        payloads.remove(&[8, 9]);
+        assert_eq!(payloads.len(), 2997);
+
+        let store = MemoryBlockStore::new();
+        let links = payloads.persist(&store).unwrap();
+        assert_eq!(links.len(), 3);
+        let loaded = PayloadStore::load(&links, &store).unwrap();
+        assert_eq!(loaded.len(), 2997);
+        assert_eq!(loaded.get(10), Some(json!({"rank": 10, "even": true})));
+        assert_eq!(loaded.get(7), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payloads.cbor");
+        loaded.save(&path).unwrap();
+        let opened = PayloadStore::open(&path).unwrap();
+        assert_eq!(
+            opened.get_projected(11, &["rank".to_string()]),
+            Some(json!({"rank": 11}))
+        );
+    }
+}
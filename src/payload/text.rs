@@ -0,0 +1,146 @@
+//! Full text index of a payload field, ranking points by BM25 against a keyword query.
+//!
+//! Texts are split in lowercase alphanumeric tokens, so that a product code such as
+//! "XJ-200" is found by the queries "xj-200" or "xj 200".
+
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value;
+
+use crate::hnsw_graph::hnsw::DataId;
+use crate::payload::index::field_values;
+
+/// saturation of term frequencies
+const K1: f32 = 1.2;
+/// weight of the length of a text relative to the mean length
+const B: f32 = 0.75;
+
+/// the lowercase alphanumeric tokens of text
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// the tokens of the strings of a field, or of the elements of it that are strings
+fn field_tokens(value: &Value) -> Vec<String> {
+    field_values(value)
+        .filter_map(Value::as_str)
+        .flat_map(tokenize)
+        .collect()
+}
+
+/// term frequencies by token and point, and the number of tokens of each point
+#[derive(Default)]
+pub(crate) struct Bm25 {
+    postings: HashMap<String, HashMap<DataId, u32>>,
+    lengths: HashMap<DataId, u32>,
+    total_length: u64,
+}
+
+impl Bm25 {
+    /// indexes the text of the field of d_id, which must not be indexed already
+    pub(crate) fn insert(&mut self, d_id: DataId, value: &Value) {
+        let tokens = field_tokens(value);
+        if tokens.is_empty() {
+            return;
+        }
+        self.lengths.insert(d_id, tokens.len() as u32);
+        self.total_length += tokens.len() as u64;
+        for token in tokens {
+            *self
+                .postings
+                .entry(token)
+                .or_default()
+                .entry(d_id)
+                .or_insert(0) += 1;
+        }
+    }
+
+    /// removes the text of the field of d_id
+    pub(crate) fn remove(&mut self, d_id: DataId, value: &Value) {
+        let length = match self.lengths.remove(&d_id) {
+            Some(length) => length,
+            None => return,
+        };
+        self.total_length -= length as u64;
+        for token in field_tokens(value) {
+            if let Some(points) = self.postings.get_mut(&token) {
+                points.remove(&d_id);
+                if points.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+    }
+
+    /// the knbn accepted points of best BM25 score for the tokens of query, best first
+    pub(crate) fn search(
+        &self,
+        query: &str,
+        knbn: usize,
+        accepts: &dyn Fn(DataId) -> bool,
+    ) -> Vec<(DataId, f32)> {
+        let nb_text = self.lengths.len() as f32;
+        if nb_text == 0. {
+            return Vec::new();
+        }
+        let mean_length = self.total_length as f32 / nb_text;
+        let mut scores: HashMap<DataId, f32> = HashMap::new();
+        let tokens: HashSet<String> = tokenize(query).collect();
+        for token in tokens {
+            let points = match self.postings.get(&token) {
+                Some(points) => points,
+                None => continue,
+            };
+            let nb_with = points.len() as f32;
+            let idf = (1. + (nb_text - nb_with + 0.5) / (nb_with + 0.5)).ln();
+            for (d_id, frequency) in points {
+                let frequency = *frequency as f32;
+                let length = self.lengths[d_id] as f32;
+                let norm = K1 * (1. - B + B * length / mean_length);
+                *scores.entry(*d_id).or_insert(0.) +=
+                    idf * frequency * (K1 + 1.) / (frequency + norm);
+            }
+        }
+        let mut scored: Vec<(DataId, f32)> = scores
+            .into_iter()
+            .filter(|(d_id, _)| accepts(*d_id))
+            .collect();
+        scored.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scored.truncate(knbn);
+        scored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_bm25() {
+        assert_eq!(
+            tokenize("Red shoes, XJ-200!").collect::<Vec<_>>(),
+            vec!["red", "shoes", "xj", "200"]
+        );
+
+        let mut bm25 = Bm25::default();
+        bm25.insert(1, &json!("red running shoes"));
+        bm25.insert(2, &json!(["blue shoes", "XJ-200"]));
+        bm25.insert(3, &json!("red red red dress"));
+        bm25.insert(4, &json!(42));
+        let ids =
+            |hits: Vec<(DataId, f32)>| hits.into_iter().map(|(d_id, _)| d_id).collect::<Vec<_>>();
+        assert_eq!(ids(bm25.search("xj-200", 10, &|_| true)), vec![2]);
+        // the rarer token weighs more, repeated tokens saturate
+        assert_eq!(ids(bm25.search("red shoes", 10, &|_| true)), vec![1, 3, 2]);
+        assert_eq!(ids(bm25.search("red", 10, &|d_id| d_id != 3)), vec![1]);
+        bm25.remove(1, &json!("red running shoes"));
+        assert_eq!(
+            ids(bm25.search("running", 10, &|_| true)),
+            Vec::<DataId>::new()
+        );
+        assert_eq!(ids(bm25.search("red shoes", 1, &|_| true)), vec![3]);
+    }
+}